@@ -0,0 +1,51 @@
+use clap::Parser;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+
+use pglite::benchmark::{BenchmarkDb, Scenario, BENCHMARK_ROWS};
+use pglite::config::PgLiteConfig;
+
+/* Follows is the benchmarks of the query path (query processor -> backend -> encoded rows), without the network in between */
+
+fn create_db(runtime:&Runtime, args:&[&str]) -> BenchmarkDb {
+    let config = PgLiteConfig::parse_from(["pglite"].iter().chain(args));
+    runtime.block_on(BenchmarkDb::create(config)).expect("Failed to create the benchmark database")
+}
+
+fn scenarios(c:&mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut db = create_db(&runtime, &[]);
+
+    let mut group = c.benchmark_group("query_path");
+    for scenario in Scenario::ALL {
+        let rows = match scenario {
+            Scenario::LargeResult => BENCHMARK_ROWS as u64,
+            _ => 1,
+        };
+        group.throughput(Throughput::Elements(rows));
+        let mut iteration = 0;
+        group.bench_function(scenario.name(), |b| b.iter(|| {
+            iteration += 1;
+            runtime.block_on(db.run(scenario, iteration)).unwrap()
+        }));
+    }
+    group.finish();
+}
+
+fn result_batch_sizes(c:&mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("large_result_batch_size");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(BENCHMARK_ROWS as u64));
+    for batch_size in ["100", "1000", "10000"] {
+        let mut db = create_db(&runtime, &["--result-batch-size", batch_size]);
+        group.bench_function(BenchmarkId::from_parameter(batch_size), |b| b.iter(|| {
+            runtime.block_on(db.run(Scenario::LargeResult, 0)).unwrap()
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scenarios, result_batch_sizes);
+criterion_main!(benches);
@@ -0,0 +1,245 @@
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, time::Duration};
+use async_trait::async_trait;
+use futures::stream;
+use pgwire::{api::{query::SimpleQueryHandler, results::{Response, QueryResponse, FieldInfo, FieldFormat, DataRowEncoder}, ClientInfo, Type}, error::{PgWireResult, PgWireError, ErrorInfo}};
+
+use crate::backend::{PgLitebackendFactory, PgLiteDBMessage, tokens::{tokenize, Token}};
+use crate::statement_log::StatementLogs;
+
+/* Follows is the handling of the admin commands, run as `SELECT pglite.{command}(...)` by the configured admin user */
+
+/// How long to wait for a maintenance task to finish - these can take a while on large databases
+const MAINTENANCE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The functions that are admin commands, run as `SELECT pglite.{command}(...)`
+const ADMIN_COMMANDS: [&str; 4] = ["analyze", "optimize", "statement_log", "shutdown"];
+
+/// Whether a query is an admin command (which are never passed on to the database)
+pub fn is_admin_command(query:&str) -> bool {
+    pglite_call(query).is_some_and(|tokens| ADMIN_COMMANDS.iter().any(|command| tokens[3].text.eq_ignore_ascii_case(command)))
+}
+
+/// Whether a query is `SELECT pglite.database_info()` - which isn't an admin command, any user can run it to get the size + last
+/// modified time of the database they're connected to
+pub fn is_database_info(query:&str) -> bool {
+    pglite_call(query).is_some_and(|tokens| tokens[3].text.eq_ignore_ascii_case("database_info")
+        && tokens[5..].first().is_some_and(|close| close.text == ")") && tokens[6..].iter().all(|t| t.text == ";"))
+}
+
+/// The tokens of a query that starts by calling one of the pglite functions, `SELECT pglite.{function}(` - the function's name is
+/// the fourth token, so eg. `SELECT pglite.id FROM t AS pglite` isn't one
+fn pglite_call(query:&str) -> Option<Vec<Token<'_>>> {
+    let tokens = tokenize(query);
+    let is_call = matches!(&tokens[..], [select, pglite, dot, _, open, ..] if select.text.eq_ignore_ascii_case("SELECT")
+        && pglite.text.eq_ignore_ascii_case("pglite") && dot.text == "." && open.text == "(");
+    is_call.then_some(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    /// Refresh the query planner statistics of a database
+    Analyze(String),
+    /// Run SQLite's `PRAGMA optimize` on a database
+    Optimize(String),
+    /// Show the recent statements of an open connection, by its process id
+    StatementLog(i32),
+    /// Gracefully shut the server down
+    Shutdown,
+}
+
+impl AdminCommand {
+    /// Parse an admin command, eg. `SELECT pglite.analyze('john/data.sqlite')`
+    pub fn parse(query:&str) -> PgWireResult<Self> {
+        // The command's arguments are everything between its parentheses, which have to end the query
+        let (name, args) = pglite_call(query)
+            .and_then(|tokens| {
+                let close = tokens.iter().rposition(|t| t.text != ";").filter(|close| *close > 4 && tokens[*close].text == ")")?;
+                Some((tokens[3].text, query[tokens[4].end()..tokens[close].start].trim()))
+            })
+            .ok_or_else(|| admin_error("42601", &format!("Invalid admin command: {}", query.trim())))?;
+        if name.eq_ignore_ascii_case("shutdown") {
+            return match args.is_empty() {
+                true => Ok(Self::Shutdown),
+                false => Err(admin_error("22023", "pglite.shutdown doesn't take any arguments")),
+            };
+        }
+        if name.eq_ignore_ascii_case("statement_log") {
+            return args.parse::<i32>().map(Self::StatementLog)
+                .map_err(|_| admin_error("22023", "pglite.statement_log expects the connection's process id, eg. pglite.statement_log(1234)"));
+        }
+        let database = args.strip_prefix('\'')
+            .and_then(|a| a.strip_suffix('\''))
+            .map(|a| a.replace("''", "'"));
+
+        match (name.to_lowercase().as_str(), database) {
+            ("analyze", Some(database)) => Ok(Self::Analyze(database)),
+            ("optimize", Some(database)) => Ok(Self::Optimize(database)),
+            ("analyze" | "optimize", None) => Err(admin_error("22023", &format!("pglite.{name} expects the database as a string, eg. pglite.{name}('user/database')"))),
+            _ => Err(admin_error("42883", &format!("Unknown admin command: pglite.{name}"))),
+        }
+    }
+
+    fn database(&self) -> Option<&str> {
+        match self {
+            Self::Analyze(database) | Self::Optimize(database) => Some(database),
+            Self::StatementLog(_) | Self::Shutdown => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Analyze(_) => "analyze",
+            Self::Optimize(_) => "optimize",
+            Self::StatementLog(_) => "statement_log",
+            Self::Shutdown => "shutdown",
+        }
+    }
+
+    /// The statement the backend runs for a maintenance command
+    fn maintenance_statement(&self) -> Option<&'static str> {
+        match self {
+            Self::Analyze(_) => Some("ANALYZE"),
+            Self::Optimize(_) => Some("PRAGMA optimize"),
+            Self::StatementLog(_) | Self::Shutdown => None,
+        }
+    }
+}
+
+pub struct AdminQueryHandler<F> {
+    db_factory: Arc<Mutex<F>>,
+    statement_logs: StatementLogs,
+    is_admin: bool,
+    allow_shutdown: bool,
+    shutdown_requested: AtomicBool,
+}
+
+impl <F> AdminQueryHandler<F>
+where F: PgLitebackendFactory + Send {
+    pub fn create(db_factory: Arc<Mutex<F>>, statement_logs: StatementLogs, is_admin: bool, allow_shutdown: bool) -> Self {
+        Self { db_factory, statement_logs, is_admin, allow_shutdown, shutdown_requested: AtomicBool::new(false) }
+    }
+
+    /// Whether the admin asked for the server to be shut down (which the connection starts, once the response has been sent)
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown_requested.load(Ordering::Relaxed)
+    }
+
+    /// Run a maintenance statement on the database through its backend thread, so it's run between the queries of its clients
+    async fn run_maintenance(&self, database:&str, statement:&str, command:&AdminCommand) -> PgWireResult<()> {
+        let backend = { self.db_factory.lock().unwrap().database_backend(database)? };
+
+        let (resp, waiter) = crossbeam_channel::bounded(1);
+        backend.send(PgLiteDBMessage::from_maintenance(statement.to_owned(), resp))?;
+        let result = tokio::task::spawn_blocking(move || waiter.recv_timeout(MAINTENANCE_TIMEOUT)).await
+            .map_err(|e| PgWireError::ApiError(Box::new(e)))?
+            .map_err(|_| admin_error("XX000", &format!("Timeout waiting for pglite.{} to finish on '{database}'", command.name())))?;
+        match result.error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// The recent statements of the open connection with the process id (its BackendKeyData), oldest first - one row per statement,
+    /// with when it was run + its error if it failed
+    fn statement_log<'a>(&self, process_id:i32) -> PgWireResult<Vec<Response<'a>>> {
+        let log = self.statement_logs.get(process_id)
+            .ok_or_else(|| admin_error("22023", &format!("There's no open connection with the process id {process_id}")))?;
+        let schema = Arc::new(vec![
+            FieldInfo::new("at".to_owned(), None, None, Type::TIMESTAMPTZ, FieldFormat::Text),
+            FieldInfo::new("statement".to_owned(), None, None, Type::TEXT, FieldFormat::Text),
+            FieldInfo::new("error".to_owned(), None, None, Type::TEXT, FieldFormat::Text),
+        ]);
+        let mut rows = Vec::new();
+        for entry in log.lock().unwrap().entries() {
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&entry.at.format("%Y-%m-%d %H:%M:%S%.3f+00").to_string())?;
+            encoder.encode_field(&entry.statement)?;
+            encoder.encode_field(&entry.error)?;
+            rows.push(encoder.finish());
+        }
+        Ok(vec![Response::Query(QueryResponse::new(schema, stream::iter(rows)))])
+    }
+}
+
+#[async_trait]
+impl <F> SimpleQueryHandler for AdminQueryHandler<F>
+where F: PgLitebackendFactory + Send {
+    async fn do_query<'a, 'b:'a, C>(&'b self, _client: &C, query: &'a str) -> PgWireResult<Vec<Response<'a>>>
+    where C: ClientInfo + Unpin + Send + Sync {
+        trace!("Processing Admin Command: {:?}", query);
+        if !self.is_admin {
+            return Err(admin_error("42501", "Only the admin user can run pglite admin commands"));
+        }
+        let command = AdminCommand::parse(query)?;
+        if let AdminCommand::StatementLog(process_id) = command {
+            return self.statement_log(process_id);
+        }
+        match (command.database(), command.maintenance_statement()) {
+            (Some(database), Some(statement)) => {
+                self.run_maintenance(database, statement, &command).await?;
+                info!("Admin command pglite.{} completed for: {}", command.name(), database);
+            },
+            _ if !self.allow_shutdown => return Err(admin_error("42501", "The shutdown admin command is not enabled on this server")),
+            _ => {
+                info!("Admin command pglite.shutdown received, shutting down...");
+                self.shutdown_requested.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let schema = Arc::new(vec![FieldInfo::new(command.name().to_owned(), None, None, Type::BOOL, FieldFormat::Text)]);
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&true)?;
+        let rows = stream::iter(vec![encoder.finish()]);
+        Ok(vec![Response::Query(QueryResponse::new(schema, rows))])
+    }
+}
+
+fn admin_error(code:&str, message:&str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), code.to_owned(), message.to_owned())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_only_the_admin_functions() {
+        assert!(is_admin_command("SELECT pglite.analyze('john/data.sqlite')"));
+        assert!(is_admin_command("select PGLITE.Shutdown();"));
+        assert!(is_admin_command("SELECT pglite . optimize ( 'a/b' )"));
+        assert!(is_admin_command("/* from the admin */ SELECT pglite.statement_log(12)"));
+        assert!(!is_admin_command("SELECT pglite.id FROM t AS pglite"));
+        assert!(!is_admin_command("SELECT pglite.analyze FROM t AS pglite"));
+        assert!(!is_admin_command("SELECT pglite.database_info()"));
+        assert!(!is_admin_command("SELECT 'pglite.shutdown()'"));
+    }
+
+    #[test]
+    fn matches_database_info() {
+        assert!(is_database_info("SELECT pglite.database_info()"));
+        assert!(is_database_info(" select pglite.DATABASE_INFO( ) ;"));
+        assert!(!is_database_info("SELECT pglite.database_info() FROM t"));
+        assert!(!is_database_info("SELECT pglite.database_info"));
+    }
+
+    #[test]
+    fn parses_admin_commands() {
+        assert_eq!(AdminCommand::parse("SELECT pglite.analyze('john/data.sqlite');").unwrap(), AdminCommand::Analyze("john/data.sqlite".to_owned()));
+        assert_eq!(AdminCommand::parse("SELECT pglite.optimize( 'o''brien/db' )").unwrap(), AdminCommand::Optimize("o'brien/db".to_owned()));
+        assert_eq!(AdminCommand::parse("SELECT pglite.statement_log(1234)").unwrap(), AdminCommand::StatementLog(1234));
+        assert_eq!(AdminCommand::parse("SELECT pglite.shutdown()").unwrap(), AdminCommand::Shutdown);
+    }
+
+    #[test]
+    fn rejects_invalid_admin_commands() {
+        let code = |query:&str| match AdminCommand::parse(query) {
+            Err(PgWireError::UserError(info)) => info.code().clone(),
+            result => panic!("expected an error, got: {result:?}"),
+        };
+        assert_eq!(code("SELECT pglite.analyze('a/b') + 1"), "42601");
+        assert_eq!(code("SELECT pglite.analyze(a)"), "22023");
+        assert_eq!(code("SELECT pglite.shutdown(1)"), "22023");
+        assert_eq!(code("SELECT pglite.statement_log('x')"), "22023");
+        assert_eq!(code("SELECT pglite.vacuum('a/b')"), "42883");
+    }
+}
@@ -0,0 +1,411 @@
+use std::borrow::Cow;
+use pgwire::{api::{portal::Portal, Type}, error::{PgWireResult, PgWireError, ErrorInfo}};
+use rusqlite::types::Value;
+
+use crate::backend::{PgLiteDBParam, tokens::{tokenize, Token}};
+
+/* Follows is the support for arrays - SQLite has no arrays, so an array parameter used as `= ANY($n)` is expanded into an `IN (...)`
+   list, and any other is stored as a JSON array (eg. `[1,2,null]` or `["a","b"]`, which SQLite's JSON functions can work with).
+   Array columns (eg. `int8[]`) are sent as Postgres arrays, from the JSON arrays (or arrays in the Postgres text format) they hold */
+
+/// A decoded bind parameter, either a plain value or an array (which has to be expanded into the query before it's run)
+pub enum BoundParam {
+    Value(PgLiteDBParam),
+    Array(Vec<Value>),
+}
+
+/// Whether the type is one of the supported array parameter types
+pub fn is_array_type(pg_type:&Type) -> bool {
+    matches!(*pg_type, Type::BOOL_ARRAY | Type::INT2_ARRAY | Type::INT4_ARRAY | Type::INT8_ARRAY
+        | Type::FLOAT4_ARRAY | Type::FLOAT8_ARRAY | Type::TEXT_ARRAY | Type::VARCHAR_ARRAY)
+}
+
+/// Decode an array parameter, in either the text (eg. `{1,2,3}`) or binary format
+pub fn decode_array(portal:&Portal<String>, idx:usize, pg_type:&Type) -> PgWireResult<Option<Vec<Value>>> {
+    if portal.parameter_format().is_text(idx) {
+        let Some(Some(bytes)) = portal.parameters().get(idx) else { return Ok(None) };
+        let text = String::from_utf8_lossy(bytes);
+        return parse_text_array(&text)?
+            .into_iter()
+            .map(|element| element.map_or(Ok(Value::Null), |e| parse_text_element(&e, pg_type)))
+            .collect::<PgWireResult<Vec<Value>>>()
+            .map(Some);
+    }
+
+    let values = match *pg_type {
+        Type::BOOL_ARRAY => portal.parameter::<Vec<Option<bool>>>(idx, pg_type)?
+            .map(|v| v.into_iter().map(|e| e.map_or(Value::Null, |b| Value::Integer(b as i64))).collect()),
+        Type::INT2_ARRAY => portal.parameter::<Vec<Option<i16>>>(idx, pg_type)?
+            .map(|v| v.into_iter().map(|e| e.map_or(Value::Null, |i| Value::Integer(i.into()))).collect()),
+        Type::INT4_ARRAY => portal.parameter::<Vec<Option<i32>>>(idx, pg_type)?
+            .map(|v| v.into_iter().map(|e| e.map_or(Value::Null, |i| Value::Integer(i.into()))).collect()),
+        Type::INT8_ARRAY => portal.parameter::<Vec<Option<i64>>>(idx, pg_type)?
+            .map(|v| v.into_iter().map(|e| e.map_or(Value::Null, Value::Integer)).collect()),
+        Type::FLOAT4_ARRAY => portal.parameter::<Vec<Option<f32>>>(idx, pg_type)?
+            .map(|v| v.into_iter().map(|e| e.map_or(Value::Null, |f| Value::Real(f.into()))).collect()),
+        Type::FLOAT8_ARRAY => portal.parameter::<Vec<Option<f64>>>(idx, pg_type)?
+            .map(|v| v.into_iter().map(|e| e.map_or(Value::Null, Value::Real)).collect()),
+        _ => portal.parameter::<Vec<Option<String>>>(idx, pg_type)?
+            .map(|v| v.into_iter().map(|e| e.map_or(Value::Null, Value::Text)).collect()),
+    };
+    Ok(values)
+}
+
+fn parse_text_element(element:&str, pg_type:&Type) -> PgWireResult<Value> {
+    let value = match *pg_type {
+        Type::BOOL_ARRAY => match element.to_lowercase().as_str() {
+            "t" | "true" => Some(Value::Integer(1)),
+            "f" | "false" => Some(Value::Integer(0)),
+            _ => None,
+        },
+        Type::INT2_ARRAY | Type::INT4_ARRAY | Type::INT8_ARRAY => element.parse::<i64>().ok().map(Value::Integer),
+        Type::FLOAT4_ARRAY | Type::FLOAT8_ARRAY => element.parse::<f64>().ok().map(Value::Real),
+        _ => Some(Value::Text(element.to_owned())),
+    };
+    value.ok_or_else(|| invalid_array(&format!("invalid array element: \"{element}\"")))
+}
+
+/// Parse a one dimensional array literal in the Postgres text format, eg. `{1,NULL,"a b"}`
+fn parse_text_array(text:&str) -> PgWireResult<Vec<Option<String>>> {
+    let inner = text.trim()
+        .strip_prefix('{')
+        .and_then(|t| t.strip_suffix('}'))
+        .ok_or_else(|| invalid_array(&format!("malformed array literal: \"{text}\"")))?;
+
+    let mut elements = Vec::new();
+    let mut chars = inner.chars().peekable();
+    while chars.peek().is_some() {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let element = match chars.peek() {
+            Some('"') => {
+                chars.next();
+                let mut element = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => element.extend(chars.next()),
+                        Some('"') => break,
+                        Some(c) => element.push(c),
+                        None => return Err(invalid_array(&format!("malformed array literal: \"{text}\""))),
+                    }
+                }
+                Some(element)
+            },
+            Some('{') => return Err(invalid_array("only one dimensional array parameters are supported")),
+            _ => {
+                let mut element = String::new();
+                while let Some(c) = chars.next_if(|c| *c != ',') {
+                    element.push(c);
+                }
+                let element = element.trim();
+                match element.eq_ignore_ascii_case("NULL") {
+                    true => None,
+                    false => Some(element.to_owned()),
+                }
+            }
+        };
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            Some(',') | None => elements.push(element),
+            Some(_) => return Err(invalid_array(&format!("malformed array literal: \"{text}\""))),
+        }
+    }
+    Ok(elements)
+}
+
+fn invalid_array(message:&str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22P02".to_owned(), message.to_owned())))
+}
+
+/// A `$n` placeholder in a query, with the byte range of the `= ANY($n)` expression around it (if it's used that way)
+struct Placeholder {
+    start: usize,
+    end: usize,
+    number: usize,
+    any_range: Option<(usize, usize)>,
+}
+
+/// Find the `$n` placeholders in a query - it's tokenized, so any in strings (including dollar-quoted ones), quoted identifiers or
+/// comments are skipped
+fn find_placeholders(query:&str) -> Vec<Placeholder> {
+    let tokens = tokenize(query);
+    tokens.iter().enumerate().filter_map(|(idx, token)| {
+        let digits = token.text.strip_prefix('$')?;
+        let number = digits.parse().ok().filter(|_| digits.bytes().all(|b| b.is_ascii_digit()))?;
+        Some(Placeholder { start: token.start, end: token.end(), number, any_range: any_range(&tokens, idx) })
+    }).collect()
+}
+
+/// If the placeholder token at `idx` is used as `= ANY ( $n )`, the byte range of that whole expression (along with the whitespace
+/// before it)
+fn any_range(tokens:&[Token], idx:usize) -> Option<(usize, usize)> {
+    let is = |idx:Option<usize>, text:&str| idx.and_then(|idx| tokens.get(idx)).is_some_and(|token| token.text.eq_ignore_ascii_case(text));
+    let any_call = is(idx.checked_sub(3), "=") && is(idx.checked_sub(2), "ANY") && is(idx.checked_sub(1), "(") && is(Some(idx + 1), ")");
+    let start = idx.checked_sub(4).map_or(0, |previous| tokens[previous].end());
+    any_call.then(|| (start, tokens[idx + 1].end()))
+}
+
+/// Expand any array parameters used as `= ANY($n)` into an `IN (...)` list, with each element bound as its own parameter - a NULL
+/// array is `IN (NULL)`, which like `= ANY(NULL)` is never true
+pub fn expand_array_params(query:&str, params:Vec<BoundParam>) -> PgWireResult<(Cow<'_, str>, Vec<PgLiteDBParam>)> {
+    let placeholders = find_placeholders(query);
+    if !params.iter().any(|p| matches!(p, BoundParam::Array(_))) && !placeholders.iter().any(|p| p.any_range.is_some()) {
+        let params = params.into_iter().filter_map(|p| match p { BoundParam::Value(v) => Some(v), BoundParam::Array(_) => None }).collect();
+        return Ok((Cow::Borrowed(query), params));
+    }
+
+    // The array slots are left as NULL, and its elements are bound to new parameters numbered after the existing ones
+    let mut flattened = Vec::with_capacity(params.len());
+    let mut arrays = Vec::new();
+    for (idx, param) in params.into_iter().enumerate() {
+        match param {
+            BoundParam::Value(value) => flattened.push(value),
+            BoundParam::Array(values) => {
+                flattened.push(PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value:Value::Null });
+                arrays.push((idx + 1, values));
+            }
+        }
+    }
+
+    // All placeholders are switched to `?NNN`, so the parameter numbering is explicit once the new ones are added
+    let mut rewritten = String::with_capacity(query.len() * 2);
+    let mut last_end = 0;
+    let mut highest_referenced = 0;
+    for placeholder in placeholders {
+        match arrays.iter().find(|(number, _)| *number == placeholder.number) {
+            Some((_, values)) => {
+                let Some((expr_start, expr_end)) = placeholder.any_range else {
+                    flattened[placeholder.number - 1].value = Value::Text(json_array(values));
+                    rewritten.push_str(&query[last_end..placeholder.start]);
+                    rewritten.push_str(&format!("?{}", placeholder.number));
+                    last_end = placeholder.end;
+                    highest_referenced = highest_referenced.max(placeholder.number);
+                    continue;
+                };
+                let mut elements = Vec::with_capacity(values.len());
+                for value in values {
+                    flattened.push(PgLiteDBParam{ name:None, ordinal:Some(flattened.len()), param_type:None, value:value.clone() });
+                    elements.push(format!("?{}", flattened.len()));
+                    highest_referenced = flattened.len();
+                }
+                rewritten.push_str(&query[last_end..expr_start]);
+                rewritten.push_str(&format!(" IN ({})", elements.join(", ")));
+                last_end = expr_end;
+            },
+            None => {
+                let is_null = placeholder.number.checked_sub(1).and_then(|idx| flattened.get(idx)).is_some_and(|p| p.value == Value::Null);
+                match placeholder.any_range.filter(|_| is_null) {
+                    Some((expr_start, expr_end)) => {
+                        rewritten.push_str(&query[last_end..expr_start]);
+                        rewritten.push_str(" IN (NULL)");
+                        last_end = expr_end;
+                    },
+                    None => {
+                        rewritten.push_str(&query[last_end..placeholder.start]);
+                        rewritten.push_str(&format!("?{}", placeholder.number));
+                        last_end = placeholder.end;
+                        highest_referenced = highest_referenced.max(placeholder.number);
+                    }
+                }
+            }
+        }
+    }
+    rewritten.push_str(&query[last_end..]);
+
+    // SQLite expects as many parameters as the highest one referenced, so drop any trailing slots of empty (or NULL) arrays
+    flattened.truncate(highest_referenced);
+    Ok((Cow::Owned(rewritten), flattened))
+}
+
+/// The text an array parameter is stored as, when it isn't used for `= ANY($n)` - a JSON array of its elements
+fn json_array(values:&[Value]) -> String {
+    let elements = values.iter().map(|value| match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => serde_json::Value::from(*i),
+        // JSON has no NaN or infinity, so they're kept as their text (the way Postgres writes them)
+        Value::Real(f) => match serde_json::Number::from_f64(*f) {
+            Some(number) => serde_json::Value::Number(number),
+            None if f.is_nan() => serde_json::Value::from("NaN"),
+            None => serde_json::Value::from(if *f > 0.0 { "Infinity" } else { "-Infinity" }),
+        },
+        Value::Text(t) => serde_json::Value::from(t.as_str()),
+        Value::Blob(b) => serde_json::Value::from(String::from_utf8_lossy(b)),
+    }).collect::<Vec<serde_json::Value>>();
+    serde_json::Value::Array(elements).to_string()
+}
+
+/// Whether a column's type is one of the array types its values are sent as arrays for
+pub fn is_array_column_type(pg_type:&Type) -> bool {
+    matches!(*pg_type, Type::INT8_ARRAY | Type::FLOAT8_ARRAY | Type::TEXT_ARRAY)
+}
+
+/// The elements of an array stored in an array column (as their text) - it's stored as a JSON array, or in the Postgres text format
+/// (eg. from a literal `'{1,2,3}'` in an INSERT)
+fn stored_elements(text:&str) -> Option<Vec<Option<String>>> {
+    if !text.trim_start().starts_with('[') {
+        return parse_text_array(text).ok();
+    }
+    match serde_json::from_str::<serde_json::Value>(text).ok()? {
+        serde_json::Value::Array(elements) => Some(elements.into_iter().map(|element| match element {
+            serde_json::Value::Null => None,
+            serde_json::Value::String(element) => Some(element),
+            element => Some(element.to_string()),
+        }).collect()),
+        _ => None,
+    }
+}
+
+/// An array column's value in the Postgres text format, eg. `{1,2,NULL}` or `{a,"b c"}` - None when it isn't an array
+pub fn format_text_array(text:&str) -> Option<String> {
+    let elements = stored_elements(text)?.into_iter()
+        .map(|element| match element {
+            None => Cow::Borrowed("NULL"),
+            Some(element) => quote_element(element),
+        })
+        .collect::<Vec<Cow<'_, str>>>();
+    Some(format!("{{{}}}", elements.join(",")))
+}
+
+/// An element of an array in the text format, quoted when it'd otherwise be taken for something else (eg. `NULL`, or one with a comma)
+fn quote_element(element:String) -> Cow<'static, str> {
+    let needs_quotes = element.is_empty() || element.eq_ignore_ascii_case("NULL")
+        || element.chars().any(|c| matches!(c, '{' | '}' | ',' | '"' | '\\') || c.is_whitespace());
+    match needs_quotes {
+        true => Cow::Owned(format!("\"{}\"", element.replace('\\', "\\\\").replace('"', "\\\""))),
+        false => Cow::Owned(element),
+    }
+}
+
+/// An array column's value in the Postgres binary format - the number of dimensions, whether any of the elements are NULL, the
+/// element type + the (one) dimension's length + lower bound, followed by the length + binary value of each element
+pub fn encode_binary_array(text:&str, pg_type:&Type) -> PgWireResult<Vec<u8>> {
+    let elements = stored_elements(text).ok_or_else(|| invalid_array(&format!("malformed array literal: \"{text}\"")))?;
+    let element_type = match *pg_type {
+        Type::INT8_ARRAY => Type::INT8,
+        Type::FLOAT8_ARRAY => Type::FLOAT8,
+        _ => Type::TEXT,
+    };
+    let mut bytes = Vec::with_capacity(20 + elements.len() * 12);
+    bytes.extend(i32::from(!elements.is_empty()).to_be_bytes());
+    bytes.extend(i32::from(elements.iter().any(Option::is_none)).to_be_bytes());
+    bytes.extend(element_type.oid().to_be_bytes());
+    if !elements.is_empty() {
+        bytes.extend((elements.len() as i32).to_be_bytes());
+        bytes.extend(1i32.to_be_bytes());
+    }
+    for element in &elements {
+        let Some(element) = element else {
+            bytes.extend((-1i32).to_be_bytes());
+            continue;
+        };
+        let encoded = match element_type {
+            Type::INT8 => element.trim().parse::<i64>().ok().map(|i| i.to_be_bytes().to_vec()),
+            Type::FLOAT8 => element.trim().parse::<f64>().ok().map(|f| f.to_be_bytes().to_vec()),
+            _ => Some(element.as_bytes().to_vec()),
+        }.ok_or_else(|| invalid_array(&format!("invalid input syntax for type {}: \"{element}\"", element_type.name())))?;
+        bytes.extend((encoded.len() as i32).to_be_bytes());
+        bytes.extend(encoded);
+    }
+    Ok(bytes)
+}
+
+/// SQLite can't prepare `= ANY($n)`, so for describing a statement it's swapped for the equivalent `IN ($n)`
+pub fn rewrite_any_for_describe(query:&str) -> Cow<'_, str> {
+    let mut rewritten = String::new();
+    let mut last_end = 0;
+    for placeholder in find_placeholders(query) {
+        if let Some((expr_start, expr_end)) = placeholder.any_range {
+            rewritten.push_str(&query[last_end..expr_start]);
+            rewritten.push_str(&format!(" IN (${})", placeholder.number));
+            last_end = expr_end;
+        }
+    }
+    match last_end {
+        0 => Cow::Borrowed(query),
+        _ => {
+            rewritten.push_str(&query[last_end..]);
+            Cow::Owned(rewritten)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(idx:usize, value:Value) -> BoundParam {
+        BoundParam::Value(PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value })
+    }
+
+    fn values(params:&[PgLiteDBParam]) -> Vec<Value> {
+        params.iter().map(|p| p.value.clone()).collect()
+    }
+
+    #[test]
+    fn expands_any_into_an_in_list() {
+        let params = vec![value(0, Value::Text("a".to_owned())), BoundParam::Array(vec![Value::Integer(1), Value::Integer(2)])];
+        let (query, params) = expand_array_params("SELECT * FROM t WHERE n = $1 AND id = ANY($2)", params).unwrap();
+        assert_eq!(query, "SELECT * FROM t WHERE n = ?1 AND id IN (?3, ?4)");
+        assert_eq!(values(&params), [Value::Text("a".to_owned()), Value::Null, Value::Integer(1), Value::Integer(2)]);
+    }
+
+    #[test]
+    fn expands_an_empty_array_into_an_empty_list() {
+        let (query, params) = expand_array_params("SELECT * FROM t WHERE id = ANY ( $1 )", vec![BoundParam::Array(vec![])]).unwrap();
+        assert_eq!(query, "SELECT * FROM t WHERE id IN ()");
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn expands_a_null_array_into_in_null() {
+        let (query, params) = expand_array_params("SELECT * FROM t WHERE id = ANY($1) AND n = $2", vec![value(0, Value::Null), value(1, Value::Integer(3))]).unwrap();
+        assert_eq!(query, "SELECT * FROM t WHERE id IN (NULL) AND n = ?2");
+        assert_eq!(values(&params), [Value::Null, Value::Integer(3)]);
+
+        let (query, params) = expand_array_params("SELECT * FROM t WHERE id = any($1)", vec![value(0, Value::Null)]).unwrap();
+        assert_eq!(query, "SELECT * FROM t WHERE id IN (NULL)");
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn stores_other_array_params_as_json() {
+        let params = vec![BoundParam::Array(vec![Value::Integer(1), Value::Null, Value::Text("b".to_owned())])];
+        let (query, params) = expand_array_params("INSERT INTO t (tags) VALUES ($1)", params).unwrap();
+        assert_eq!(query, "INSERT INTO t (tags) VALUES (?1)");
+        assert_eq!(values(&params), [Value::Text("[1,null,\"b\"]".to_owned())]);
+    }
+
+    #[test]
+    fn leaves_queries_without_arrays_alone() {
+        let (query, params) = expand_array_params("SELECT $1", vec![value(0, Value::Integer(1))]).unwrap();
+        assert!(matches!(query, Cow::Borrowed("SELECT $1")));
+        assert_eq!(values(&params), [Value::Integer(1)]);
+    }
+
+    #[test]
+    fn skips_placeholders_in_strings_and_comments() {
+        let query = "SELECT '$1', $$ = ANY($1) $$, \"$1\" -- $1\n, $2 /* $1 */";
+        let numbers = find_placeholders(query).iter().map(|p| p.number).collect::<Vec<_>>();
+        assert_eq!(numbers, [2]);
+    }
+
+    #[test]
+    fn rewrites_any_for_describe() {
+        assert_eq!(rewrite_any_for_describe("SELECT * FROM t WHERE id = ANY($1)"), "SELECT * FROM t WHERE id IN ($1)");
+        assert_eq!(rewrite_any_for_describe("SELECT * FROM t WHERE id = $1"), "SELECT * FROM t WHERE id = $1");
+    }
+
+    #[test]
+    fn parses_text_arrays() {
+        assert_eq!(parse_text_array("{1, NULL,\"a b\",\"x\\\"y\"}").unwrap(), [Some("1".to_owned()), None, Some("a b".to_owned()), Some("x\"y".to_owned())]);
+        assert!(parse_text_array("{}").unwrap().is_empty());
+        assert!(parse_text_array("1,2").is_err());
+        assert!(parse_text_array("{{1}}").is_err());
+    }
+
+    #[test]
+    fn formats_stored_arrays_as_text() {
+        assert_eq!(format_text_array("[1,null,\"a b\"]").as_deref(), Some("{1,NULL,\"a b\"}"));
+        assert_eq!(format_text_array("{1,2}").as_deref(), Some("{1,2}"));
+        assert_eq!(format_text_array("not an array"), None);
+    }
+}
@@ -2,20 +2,21 @@ use std::{collections::HashMap, path::PathBuf};
 use pgwire::{error::{ErrorInfo, PgWireError}, messages::startup::{Authentication, PasswordMessageFamily}};
 use async_trait::async_trait;
 use futures::SinkExt;
+use subtle::ConstantTimeEq;
 
 use crate::implement_startup_handler;
-use super::{PgLiteAuthenticator, PgLiteAuthenticatorFactory};
+use super::{PgLiteAuthenticator, PgLiteAuthenticatorFactory, credentials::Credentials};
 
 pub struct BasicPasswordAuthenticator { 
-    expected_password:String
+    credentials:Credentials
 }
 implement_startup_handler!(BasicPasswordAuthenticator);
 
 pub struct BasicPasswordAuthenticatorFactory {}
 impl PgLiteAuthenticatorFactory<BasicPasswordAuthenticator> for BasicPasswordAuthenticatorFactory {
     fn create_authenticator(&mut self, config:&crate::config::PgLiteConfig) -> Result<BasicPasswordAuthenticator, PgWireError> {
-        let expected_password = config.auth_config.to_owned().unwrap_or(String::from("123"));
-        Ok(BasicPasswordAuthenticator{  expected_password })
+        let credentials = Credentials::load(config).map_err(|err| PgWireError::ApiError(err.into()))?;
+        Ok(BasicPasswordAuthenticator{  credentials })
     }
 }
 impl BasicPasswordAuthenticatorFactory {
@@ -31,14 +32,19 @@ impl PgLiteAuthenticator for BasicPasswordAuthenticator {
         Authentication::CleartextPassword
     }
 
-    async fn verify_identity(&self, credential_data:PasswordMessageFamily, username:String, database: String) -> Result<HashMap<String, String>, ErrorInfo> {
+    fn reload(&self) -> Result<(), String> {
+        self.credentials.reload()
+    }
+
+    async fn verify_identity(&self, credential_data:PasswordMessageFamily, username:String, database: String, _metadata:&HashMap<String, String>) -> Result<HashMap<String, String>, ErrorInfo> {
         let Ok(psw_data) = credential_data.into_password() else { return Err(ErrorInfo::new( "FATAL".to_owned(),"28P01".to_owned(),
             "Authentication was not successful, please check you have provided all the credentials required for this database.".to_owned(),
         ))};
         let password = psw_data.password();
         
-        // TODO: do something real here :p
-        if self.expected_password.eq(password) {
+        // Unknown users (+ users that can't connect to the database) fail the same way as a wrong password, and the password is compared in
+        // constant time
+        if self.credentials.password_for(&username, &database).is_some_and(|expected_password| bool::from(expected_password.as_bytes().ct_eq(password.as_bytes()))) {
             // Correct Password, save data to connection + move on
             let mut result = HashMap::new();
             result.insert(String::from("user"), username.clone());
@@ -0,0 +1,79 @@
+use std::{collections::HashMap, fs, path::PathBuf, sync::RwLock};
+use serde::Deserialize;
+
+use crate::config::PgLiteConfig;
+
+/// The password used when there's no auth config
+const DEFAULT_PASSWORD: &str = "123";
+
+/// A user in the credentials file, either just their password or their password + the databases they can connect to
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum UserEntry {
+    Password(String),
+    Restricted { password:String, databases:Option<Vec<String>> },
+}
+
+impl UserEntry {
+    fn password(&self) -> &str {
+        match self {
+            UserEntry::Password(password) | UserEntry::Restricted { password, .. } => password,
+        }
+    }
+
+    fn allows_database(&self, database:&str) -> bool {
+        match self {
+            UserEntry::Restricted { databases:Some(databases), .. } => databases.iter().any(|allowed| allowed == database),
+            _ => true,
+        }
+    }
+}
+
+/// The passwords the password authenticators check against - either the static password from `--auth-config`, or the users (+
+/// their passwords) in the `--auth-users-file`
+pub enum Credentials {
+    Static(String),
+    File { path:PathBuf, users:RwLock<HashMap<String, UserEntry>> },
+}
+
+impl Credentials {
+    pub fn load(config:&PgLiteConfig) -> Result<Self, String> {
+        match (&config.auth_users_file, &config.auth_config) {
+            (Some(path), _) => {
+                let users = read_users(path)?;
+                info!("Loaded {} users from the credentials file {:?}", users.len(), path);
+                Ok(Credentials::File { path:path.clone(), users:RwLock::new(users) })
+            },
+            (None, Some(password)) => Ok(Credentials::Static(password.clone())),
+            (None, None) => Ok(Credentials::Static(DEFAULT_PASSWORD.to_owned())),
+        }
+    }
+
+    /// The user's password, if they can connect to the database (any session options, eg. `?readonly`, are ignored)
+    pub fn password_for(&self, username:&str, database:&str) -> Option<String> {
+        match self {
+            Credentials::Static(password) => Some(password.clone()),
+            Credentials::File { users, .. } => {
+                let database = database.split('?').next().unwrap_or_default();
+                users.read().unwrap().get(username)
+                    .filter(|user| user.allows_database(database))
+                    .map(|user| user.password().to_owned())
+            },
+        }
+    }
+
+    /// Re-read the credentials file (if there is one), keeping the users already loaded if it can't be read
+    pub fn reload(&self) -> Result<(), String> {
+        if let Credentials::File { path, users } = self {
+            let reloaded = read_users(path)?;
+            info!("Reloaded {} users from the credentials file {:?}", reloaded.len(), path);
+            *users.write().unwrap() = reloaded;
+        }
+        Ok(())
+    }
+}
+
+fn read_users(path:&PathBuf) -> Result<HashMap<String, UserEntry>, String> {
+    let contents = fs::read_to_string(path).map_err(|err| format!("Unable to read the credentials file {path:?}: {err}"))?;
+    serde_json::from_str(&contents).map_err(|err| format!("Unable to parse the credentials file {path:?}: {err}"))
+}
@@ -0,0 +1,94 @@
+use std::{collections::HashMap, path::PathBuf};
+use pgwire::{error::{ErrorInfo, PgWireError}, messages::startup::{Authentication, PasswordMessageFamily}};
+use async_trait::async_trait;
+use futures::SinkExt;
+use subtle::ConstantTimeEq;
+
+use crate::implement_startup_handler;
+use super::{PgLiteAuthenticator, PgLiteAuthenticatorFactory, credentials::Credentials, METADATA_MD5_SALT};
+
+/// Authenticates with the same passwords as the basic authenticator, but the client only sends an MD5 hash of it (salted
+/// per connection) - for older clients + drivers that default to MD5
+pub struct Md5PasswordAuthenticator { 
+    credentials:Credentials
+}
+implement_startup_handler!(Md5PasswordAuthenticator);
+
+pub struct Md5PasswordAuthenticatorFactory {}
+impl PgLiteAuthenticatorFactory<Md5PasswordAuthenticator> for Md5PasswordAuthenticatorFactory {
+    fn create_authenticator(&mut self, config:&crate::config::PgLiteConfig) -> Result<Md5PasswordAuthenticator, PgWireError> {
+        let credentials = Credentials::load(config).map_err(|err| PgWireError::ApiError(err.into()))?;
+        Ok(Md5PasswordAuthenticator{  credentials })
+    }
+}
+impl Md5PasswordAuthenticatorFactory {
+    pub fn load_and_create_authenticator(config:&crate::config::PgLiteConfig) -> Result<Md5PasswordAuthenticator, PgWireError> {
+        let mut factory = Md5PasswordAuthenticatorFactory{};
+        factory.create_authenticator(config)
+    }
+}
+
+#[async_trait]
+impl PgLiteAuthenticator for Md5PasswordAuthenticator {
+    fn pg_auth_type(&self) -> Authentication {
+        Authentication::MD5Password(rand::random::<[u8; 4]>().to_vec())
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        self.credentials.reload()
+    }
+
+    async fn verify_identity(&self, credential_data:PasswordMessageFamily, username:String, database: String, metadata:&HashMap<String, String>) -> Result<HashMap<String, String>, ErrorInfo> {
+        let (Ok(psw_data), Some(salt)) = (credential_data.into_password(), metadata.get(METADATA_MD5_SALT).and_then(|salt| decode_salt(salt))) else {
+            return Err(ErrorInfo::new( "FATAL".to_owned(),"28P01".to_owned(),
+                "Authentication was not successful, please check you have provided all the credentials required for this database.".to_owned(),
+            ))
+        };
+
+        // The client hashes the digest Postgres would store for the user (`md5(password + username)`) again with the salt, ie.
+        // `"md5" + md5(md5(password + username) + salt)` - unknown users fail the same way as a wrong password
+        let expected_hash = self.credentials.password_for(&username, &database).map(|password| {
+            let stored_digest = md5_hex(format!("{password}{username}"));
+            format!("md5{}", md5_hex([stored_digest.as_bytes(), &salt].concat()))
+        });
+        // Compared in constant time, so how long a failed login takes doesn't tell how much of the hash was right
+        if expected_hash.is_some_and(|expected_hash| bool::from(expected_hash.as_bytes().ct_eq(psw_data.password().as_bytes()))) {
+            // Correct Password, save data to connection + move on
+            let mut result = HashMap::new();
+            result.insert(String::from("user"), username.clone());
+            result.insert(String::from("database"), database.clone());
+            result.insert(String::from("dbpath"), PathBuf::from(&username).join(&database).to_string_lossy().to_string());
+            Ok(result)
+        } else {
+            // Incorrect Password
+            Err(ErrorInfo::new(
+                "FATAL".to_owned(),
+                "28P01".to_owned(),
+                "Authentication was not successful, please check you have provided the correct credentials for this database.".to_owned(),
+            ))
+        }
+    }
+}
+
+fn md5_hex(data:impl AsRef<[u8]>) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
+/// The salt's bytes, from the hex it's kept as in the client's metadata
+fn decode_salt(salt:&str) -> Option<Vec<u8>> {
+    (0..salt.len()).step_by(2).map(|idx| salt.get(idx..idx + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_hex_salts() {
+        assert_eq!(decode_salt("00a1ff7e"), Some(vec![0x00, 0xa1, 0xff, 0x7e]));
+        assert_eq!(decode_salt("00A1FF7E"), Some(vec![0x00, 0xa1, 0xff, 0x7e]));
+        assert_eq!(decode_salt(""), Some(vec![]));
+        assert_eq!(decode_salt("00a"), None);
+        assert_eq!(decode_salt("zz00"), None);
+    }
+}
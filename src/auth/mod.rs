@@ -1,16 +1,30 @@
 use std::{fmt::Debug, collections::HashMap};
 use async_trait::async_trait;
+use futures::SinkExt;
 use pgwire::{error::{PgWireError, ErrorInfo}, api::auth::StartupHandler, messages::startup::{Authentication, PasswordMessageFamily}};
 
 mod basic_authenticator;
-use basic_authenticator::BasicPasswordAuthenticatorFactory;
+mod md5_authenticator;
+mod trust_authenticator;
+mod credentials;
+use basic_authenticator::{BasicPasswordAuthenticator, BasicPasswordAuthenticatorFactory};
+use md5_authenticator::{Md5PasswordAuthenticator, Md5PasswordAuthenticatorFactory};
+use trust_authenticator::{TrustAuthenticator, TrustAuthenticatorFactory};
 
 use crate::config::PgLiteConfig;
 
+/// The client metadata entry holding the (hex) salt the client was sent to hash its password with, for MD5 auth
+pub const METADATA_MD5_SALT: &str = "md5_salt";
+
 #[async_trait]
 pub trait PgLiteAuthenticator : StartupHandler + Send + Sync { 
     fn pg_auth_type(&self) -> Authentication;
-    async fn verify_identity(&self, credential_data:PasswordMessageFamily, username:String, database: String) -> Result<HashMap<String, String>, ErrorInfo>;
+    /// Reload the authenticator's users (eg. on SIGHUP), for authenticators that load them
+    fn reload(&self) -> Result<(), String> {
+        Ok(())
+    }
+    /// Check the client's credentials - the client's metadata has its startup parameters, plus the MD5 salt (if it was sent one)
+    async fn verify_identity(&self, credential_data:PasswordMessageFamily, username:String, database: String, metadata:&HashMap<String, String>) -> Result<HashMap<String, String>, ErrorInfo>;
 }
 
 #[macro_export]
@@ -23,38 +37,53 @@ macro_rules! implement_startup_handler {
                 C: pgwire::api::ClientInfo + futures_sink::Sink<pgwire::messages::PgWireBackendMessage> + Unpin + Send,
                 C::Error: std::fmt::Debug,
                 PgWireError: From<<C as futures_sink::Sink<pgwire::messages::PgWireBackendMessage>>::Error> {
-                    match message {
+                    let credential_data = match message {
                         pgwire::messages::PgWireFrontendMessage::Startup(sm) => {
                             // Save startup parameters to the metadata
                             pgwire::api::auth::save_startup_parameters_to_metadata(client, &sm);
                             // Set the state to Auth in progress
                             client.set_state(pgwire::api::PgWireConnectionState::AuthenticationInProgress);
-                            // Request the authentication data from the client
-                            client.send(pgwire::messages::PgWireBackendMessage::Authentication(self.pg_auth_type())).await?;
-                            return Ok(());
-                        },
-                        pgwire::messages::PgWireFrontendMessage::PasswordMessageFamily(pwd) => {
-                            // Extract the name of the database that the client wishes to connect to
-                            let database = client.metadata().get(pgwire::api::METADATA_DATABASE).unwrap_or(&String::from("unknown")).clone();
-                            let username = client.metadata().get(pgwire::api::METADATA_USER).unwrap_or(&String::from("unknown")).clone();
-                            // Verify the identity of the client and save the metadata to the client
-                            match self.verify_identity(pwd, username, database).await {
-                                Ok(metadata) => {
-                                    // Copy the metadata from the auth provider into the client
-                                    let client_meta = client.metadata_mut();
-                                    metadata.into_iter().for_each(|(k,v)| { client_meta.insert(k, v); } );
-                                    pgwire::api::auth::finish_authentication(client, &crate::server::PgLiteServerParameterProvider).await;
-                                    Ok(())
-                                },
-                                Err(error_info) => {
-                                    // Identity Verification failed - return an auth error
-                                    client.feed(pgwire::messages::PgWireBackendMessage::ErrorResponse(error_info.into())).await?;
-                                    client.close().await?;
-                                    Ok(())
+                            let authentication = self.pg_auth_type();
+                            if let pgwire::messages::startup::Authentication::Ok = authentication {
+                                // There's nothing to ask the client for (eg. trust auth), so its identity is verified without any credentials
+                                pgwire::messages::startup::PasswordMessageFamily::Raw(bytes::BytesMut::new())
+                            } else {
+                                // Request the authentication data from the client - keeping hold of any salt it's asked to hash its password with
+                                if let pgwire::messages::startup::Authentication::MD5Password(salt) = &authentication {
+                                    let salt = salt.iter().map(|byte| format!("{byte:02x}")).collect();
+                                    client.metadata_mut().insert($crate::auth::METADATA_MD5_SALT.to_owned(), salt);
                                 }
+                                client.send(pgwire::messages::PgWireBackendMessage::Authentication(authentication)).await?;
+                                return Ok(());
                             }
                         },
-                        _ => Ok(())
+                        pgwire::messages::PgWireFrontendMessage::PasswordMessageFamily(pwd) => pwd,
+                        // The client gave up part way through authenticating (eg. the password prompt was cancelled)
+                        pgwire::messages::PgWireFrontendMessage::Terminate(_) => {
+                            return Err(PgWireError::ApiError("{TERMINATE}".into()));
+                        },
+                        _ => return Ok(())
+                    };
+
+                    // Extract the name of the database that the client wishes to connect to
+                    let database = client.metadata().get(pgwire::api::METADATA_DATABASE).unwrap_or(&String::from("unknown")).clone();
+                    let username = client.metadata().get(pgwire::api::METADATA_USER).unwrap_or(&String::from("unknown")).clone();
+                    // Verify the identity of the client and save the metadata to the client
+                    let verified = self.verify_identity(credential_data, username, database, client.metadata()).await;
+                    client.metadata_mut().remove($crate::auth::METADATA_MD5_SALT);
+                    match verified {
+                        Ok(metadata) => {
+                            // Copy the metadata from the auth provider into the client
+                            let client_meta = client.metadata_mut();
+                            metadata.into_iter().for_each(|(k,v)| { client_meta.insert(k, v); } );
+                            $crate::server::finish_authentication(client).await
+                        },
+                        Err(error_info) => {
+                            // Identity Verification failed - return an auth error
+                            client.feed(pgwire::messages::PgWireBackendMessage::ErrorResponse(error_info.into())).await?;
+                            client.close().await?;
+                            Ok(())
+                        }
                     }
             }
         }
@@ -71,11 +100,54 @@ where T: PgLiteAuthenticator {
 pub enum PgLiteAuthType {
     #[clap(alias = "basic")]
     BasicPasswordAuthenticator,
+    #[clap(alias = "md5")]
+    Md5PasswordAuthenticator,
+    #[clap(alias = "trust")]
+    Trust,
 }
 
-pub fn load_authenticator(config:&PgLiteConfig) -> impl PgLiteAuthenticator {
-    match config.authenticator {
-        PgLiteAuthType::BasicPasswordAuthenticator => BasicPasswordAuthenticatorFactory::load_and_create_authenticator(config).unwrap(),
-        // todo: add other auth handlers...
+/// The authenticator selected by the config - the server takes a single authenticator type, so this passes each call on to it
+pub enum ConfiguredAuthenticator {
+    Basic(BasicPasswordAuthenticator),
+    Md5(Md5PasswordAuthenticator),
+    Trust(TrustAuthenticator),
+}
+implement_startup_handler!(ConfiguredAuthenticator);
+
+#[async_trait]
+impl PgLiteAuthenticator for ConfiguredAuthenticator {
+    fn pg_auth_type(&self) -> Authentication {
+        match self {
+            ConfiguredAuthenticator::Basic(authenticator) => authenticator.pg_auth_type(),
+            ConfiguredAuthenticator::Md5(authenticator) => authenticator.pg_auth_type(),
+            ConfiguredAuthenticator::Trust(authenticator) => authenticator.pg_auth_type(),
+        }
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        match self {
+            ConfiguredAuthenticator::Basic(authenticator) => authenticator.reload(),
+            ConfiguredAuthenticator::Md5(authenticator) => authenticator.reload(),
+            ConfiguredAuthenticator::Trust(authenticator) => authenticator.reload(),
+        }
+    }
+
+    async fn verify_identity(&self, credential_data:PasswordMessageFamily, username:String, database: String, metadata:&HashMap<String, String>) -> Result<HashMap<String, String>, ErrorInfo> {
+        match self {
+            ConfiguredAuthenticator::Basic(authenticator) => authenticator.verify_identity(credential_data, username, database, metadata).await,
+            ConfiguredAuthenticator::Md5(authenticator) => authenticator.verify_identity(credential_data, username, database, metadata).await,
+            ConfiguredAuthenticator::Trust(authenticator) => authenticator.verify_identity(credential_data, username, database, metadata).await,
+        }
     }
+}
+
+/// Load the configured authenticator - failing if it can't be created, eg. its credentials file can't be parsed
+pub fn load_authenticator(config:&PgLiteConfig) -> Result<impl PgLiteAuthenticator, String> {
+    let authenticator = match config.authenticator {
+        PgLiteAuthType::BasicPasswordAuthenticator => BasicPasswordAuthenticatorFactory::load_and_create_authenticator(config).map(ConfiguredAuthenticator::Basic),
+        PgLiteAuthType::Md5PasswordAuthenticator => Md5PasswordAuthenticatorFactory::load_and_create_authenticator(config).map(ConfiguredAuthenticator::Md5),
+        PgLiteAuthType::Trust => TrustAuthenticatorFactory::load_and_create_authenticator(config).map(ConfiguredAuthenticator::Trust),
+        // todo: add other auth handlers...
+    };
+    authenticator.map_err(|err| err.to_string())
 }
\ No newline at end of file
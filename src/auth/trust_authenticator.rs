@@ -0,0 +1,44 @@
+use std::{collections::HashMap, path::PathBuf};
+use pgwire::{error::{ErrorInfo, PgWireError}, messages::startup::{Authentication, PasswordMessageFamily}};
+use async_trait::async_trait;
+use futures::SinkExt;
+
+use crate::implement_startup_handler;
+use super::{PgLiteAuthenticator, PgLiteAuthenticatorFactory};
+
+/// Authenticates every user without asking for a password - only for local development + testing
+pub struct TrustAuthenticator {}
+implement_startup_handler!(TrustAuthenticator);
+
+pub struct TrustAuthenticatorFactory {}
+impl PgLiteAuthenticatorFactory<TrustAuthenticator> for TrustAuthenticatorFactory {
+    fn create_authenticator(&mut self, config:&crate::config::PgLiteConfig) -> Result<TrustAuthenticator, PgWireError> {
+        // Trust has to be allowed explicitly, so it can't be turned on by a mistyped `--auth`
+        if !config.allow_trust_auth {
+            return Err(PgWireError::ApiError("Trust authentication lets anyone connect without a password, run with --allow-trust-auth to use it".into()));
+        }
+        warn!("Trust authentication is enabled, any user can connect without a password - only use this for local development + testing!");
+        Ok(TrustAuthenticator{})
+    }
+}
+impl TrustAuthenticatorFactory {
+    pub fn load_and_create_authenticator(config:&crate::config::PgLiteConfig) -> Result<TrustAuthenticator, PgWireError> {
+        let mut factory = TrustAuthenticatorFactory{};
+        factory.create_authenticator(config)
+    }
+}
+
+#[async_trait]
+impl PgLiteAuthenticator for TrustAuthenticator {
+    fn pg_auth_type(&self) -> Authentication {
+        Authentication::Ok
+    }
+
+    async fn verify_identity(&self, _credential_data:PasswordMessageFamily, username:String, database: String, _metadata:&HashMap<String, String>) -> Result<HashMap<String, String>, ErrorInfo> {
+        let mut result = HashMap::new();
+        result.insert(String::from("user"), username.clone());
+        result.insert(String::from("database"), database.clone());
+        result.insert(String::from("dbpath"), PathBuf::from(&username).join(&database).to_string_lossy().to_string());
+        Ok(result)
+    }
+}
@@ -0,0 +1,369 @@
+use std::borrow::Cow;
+use regex::Regex;
+use rusqlite::{Connection, Error, functions::FunctionFlags};
+
+use super::information_schema::replace_identifier;
+use super::tokens::{tokenize, Token};
+
+/* Follows is the translation of the Postgres-isms that clients + drivers send (often as soon as they connect), which SQLite doesn't
+   understand - each is a step in REWRITES, which are applied in turn to every query. The Postgres functions they can call are
+   created on each connection */
+
+/// A rewrite of a query, returning the rewritten query when there was anything in it to rewrite
+struct Rewrite {
+    name: &'static str,
+    rewrite: fn(&str) -> Option<String>,
+}
+
+const REWRITES: [Rewrite; 6] = [
+    Rewrite { name: "qualified functions", rewrite: unqualify_functions },
+    Rewrite { name: "SQL value functions", rewrite: call_value_functions },
+    Rewrite { name: "casts", rewrite: rewrite_casts },
+    Rewrite { name: "regular expression operators", rewrite: rewrite_regex_operators },
+    Rewrite { name: "trim functions", rewrite: rewrite_trim },
+    Rewrite { name: "array column types", rewrite: rewrite_array_types },
+];
+
+/// The emulated Postgres functions, which clients may qualify with `pg_catalog.` - SQLite doesn't allow qualified function names
+const FUNCTIONS: [&str; 4] = ["version", "current_schema", "current_database", "pg_typeof"];
+
+/// The SQL value functions that Postgres lets be called without parentheses, and the function they're called as
+const VALUE_FUNCTIONS: [(&str, &str); 2] = [("current_schema", "current_schema()"), ("current_catalog", "current_database()")];
+
+/// Create the emulated Postgres functions on a connection - `database` is the name of its database
+pub fn create_functions(con:&Connection, database:&str) -> Result<(), Error> {
+    let version = format!("PostgreSQL {} (pglite, SQLite {})", env!("CARGO_PKG_VERSION"), rusqlite::version());
+    con.create_scalar_function("version", 0, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |_| {
+        Ok(version.clone())
+    })?;
+    // Every table is in the one schema
+    con.create_scalar_function("current_schema", 0, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |_| {
+        Ok("public")
+    })?;
+    let database = database.to_owned();
+    con.create_scalar_function("current_database", 0, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |_| {
+        Ok(database.clone())
+    })?;
+    // SQLite's `text REGEXP pattern` calls `regexp(pattern, text)` - the pattern is compiled once per statement
+    con.create_scalar_function("regexp", 2, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        let Some(text) = ctx.get::<Option<String>>(1)? else { return Ok(None) };
+        if ctx.get_raw(0).as_str_or_null()?.is_none() {
+            return Ok(None);
+        }
+        let pattern = ctx.get_or_create_aux(0, |value| Regex::new(value.as_str()?).map_err(|err| Error::UserFunctionError(Box::new(err))))?;
+        Ok(Some(pattern.is_match(&text)))
+    })
+}
+
+/// Apply each of the rewrites to the query, logging the ones that changed it
+pub fn rewrite_query(query:&str) -> Cow<'_, str> {
+    let mut query = Cow::Borrowed(query);
+    for rewrite in REWRITES.iter() {
+        if let Some(rewritten) = (rewrite.rewrite)(&query) {
+            debug!("Rewrote the {} of: {}, as: {}", rewrite.name, query, rewritten);
+            query = Cow::Owned(rewritten);
+        }
+    }
+    query
+}
+
+/// `pg_catalog.version()` => `version()`
+fn unqualify_functions(query:&str) -> Option<String> {
+    if !query.to_ascii_lowercase().contains("pg_catalog.") {
+        return None;
+    }
+    let mut rewritten:Option<String> = None;
+    for function in FUNCTIONS {
+        let current = rewritten.as_deref().unwrap_or(query);
+        if let Some(unqualified) = replace_identifier(current, &format!("pg_catalog.{function}"), function) {
+            rewritten = Some(unqualified);
+        }
+    }
+    rewritten
+}
+
+/// `SELECT current_schema` => `SELECT current_schema()` - but not in a `CREATE` or `ALTER`, where it can be the name of a column
+fn call_value_functions(query:&str) -> Option<String> {
+    let lowercase_query = query.to_ascii_lowercase();
+    if !VALUE_FUNCTIONS.iter().any(|(name, _)| lowercase_query.contains(name)) {
+        return None;
+    }
+    let tokens = tokenize(query);
+    if tokens.first().is_some_and(|t| t.text.eq_ignore_ascii_case("CREATE") || t.text.eq_ignore_ascii_case("ALTER")) {
+        return None;
+    }
+    let edits = tokens.iter().enumerate().filter_map(|(idx, token)| {
+        let (_, call) = VALUE_FUNCTIONS.iter().find(|(name, _)| token.text.eq_ignore_ascii_case(name))?;
+        // Not when it's already called, or is a column of a table (eg. `t.current_schema`)
+        let called = tokens.get(idx + 1).is_some_and(|next| next.text == "(");
+        let qualified = idx > 0 && tokens[idx - 1].text == ".";
+        (!called && !qualified).then_some((token.start, token.end(), *call))
+    }).collect::<Vec<_>>();
+    apply_edits(query, edits)
+}
+
+/// Postgres's `value::type` casts, as SQLite's `CAST(value AS type)` - SQLite only has the storage classes to cast to, so the casts
+/// to any other types (eg. `::date`) are left out, leaving the value as it is. A `::regclass` is looked up in the emulated pg_class
+fn rewrite_casts(query:&str) -> Option<String> {
+    if !query.contains("::") {
+        return None;
+    }
+    // One cast at a time, so a cast of a cast (eg. `a::int::text`) sees the rewritten inner cast as its value
+    let mut rewritten = query.to_owned();
+    let mut changed = false;
+    loop {
+        let tokens = tokenize(&rewritten);
+        let Some(cast) = find_cast(&tokens) else { break };
+        let value = &rewritten[tokens[cast.value_start].start..tokens[cast.value_end].end()];
+        let replacement = match sqlite_cast_type(&cast.type_name) {
+            Some(sqlite_type) => format!("CAST({value} AS {sqlite_type})"),
+            None if cast.type_name == "regclass" => regclass(value),
+            None => value.to_owned(),
+        };
+        rewritten.replace_range(tokens[cast.value_start].start..tokens[cast.type_end].end(), &replacement);
+        changed = true;
+    }
+    changed.then_some(rewritten)
+}
+
+/// Postgres's regular expression operators (`~`, `~*`, `!~` + `!~*`, also as eg. `OPERATOR(pg_catalog.~)`), as SQLite's `REGEXP` -
+/// along with the `COLLATE pg_catalog.default` psql adds to them
+fn rewrite_regex_operators(query:&str) -> Option<String> {
+    if !query.contains('~') {
+        return None;
+    }
+    let tokens = tokenize(query);
+    let adjacent = |idx:usize, text:&str| tokens.get(idx).is_some_and(|t| t.text == text && t.start == tokens[idx - 1].end());
+    let mut edits = Vec::new();
+    let mut idx = 0;
+    while idx < tokens.len() {
+        let token = &tokens[idx];
+        // `OPERATOR(pg_catalog.~)`, where the operator is between the parentheses
+        let (start, operator_idx, end) = if token.text.eq_ignore_ascii_case("OPERATOR") && tokens.get(idx + 1).is_some_and(|t| t.text == "(") {
+            let close = idx + tokens[idx..].iter().position(|t| t.text == ")")?;
+            let operator = match tokens.get(idx + 3).is_some_and(|t| t.text == ".") {
+                true => idx + 4,
+                false => idx + 2,
+            };
+            (idx, operator, close)
+        } else if (token.text == "~" || (token.text == "!" && adjacent(idx + 1, "~"))) && idx > 0 && is_value(&tokens[idx - 1]) {
+            let last = if token.text == "!" { idx + 1 } else { idx };
+            let last = if adjacent(last + 1, "*") { last + 1 } else { last };
+            (idx, idx, last)
+        } else if token.text.eq_ignore_ascii_case("COLLATE") && tokens.get(idx + 1).is_some_and(|t| t.text.eq_ignore_ascii_case("pg_catalog"))
+            && tokens.get(idx + 3).is_some_and(|t| t.text.eq_ignore_ascii_case("default")) {
+            edits.push((token.start, tokens[idx + 3].end(), ""));
+            idx += 4;
+            continue;
+        } else {
+            idx += 1;
+            continue;
+        };
+
+        let negated = tokens[operator_idx].text == "!";
+        let tilde = if negated { operator_idx + 1 } else { operator_idx };
+        if tokens.get(tilde).map(|t| t.text) != Some("~") {
+            idx += 1;
+            continue;
+        }
+        let insensitive = adjacent(tilde + 1, "*");
+        let operator = match (negated, insensitive) {
+            (false, false) => "REGEXP",
+            (true, false) => "NOT REGEXP",
+            (false, true) => "REGEXP '(?i)' ||",
+            (true, true) => "NOT REGEXP '(?i)' ||",
+        };
+        edits.push((tokens[start].start, tokens[end].end(), operator));
+        idx = end + 1;
+    }
+    apply_edits(query, edits)
+}
+
+/// The SQL standard's `trim([LEADING | TRAILING | BOTH] [characters] FROM value)`, as SQLite's `ltrim`, `rtrim` + `trim(value,
+/// characters)`
+fn rewrite_trim(query:&str) -> Option<String> {
+    if !query.to_ascii_lowercase().contains("trim") {
+        return None;
+    }
+    let tokens = tokenize(query);
+    let mut edits = Vec::new();
+    let mut idx = 0;
+    while idx < tokens.len() {
+        if !tokens[idx].text.eq_ignore_ascii_case("TRIM") || tokens.get(idx + 1).map(|t| t.text) != Some("(") {
+            idx += 1;
+            continue;
+        }
+        // The closing parenthesis, and the FROM that's directly inside them
+        let mut depth = 0;
+        let mut from = None;
+        let mut close = None;
+        for (arg_idx, token) in tokens.iter().enumerate().skip(idx + 1) {
+            match token.text {
+                "(" => depth += 1,
+                ")" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(arg_idx);
+                        break;
+                    }
+                },
+                _ if depth == 1 && from.is_none() && token.text.eq_ignore_ascii_case("FROM") => from = Some(arg_idx),
+                _ => {},
+            }
+        }
+        let (Some(from), Some(close)) = (from, close) else {
+            idx += 1;
+            continue;
+        };
+        let side = match tokens[idx + 2].text.to_ascii_uppercase().as_str() {
+            "LEADING" => Some("ltrim"),
+            "TRAILING" => Some("rtrim"),
+            "BOTH" => Some("trim"),
+            _ => None,
+        };
+        let chars_start = if side.is_some() { idx + 3 } else { idx + 2 };
+        let function = side.unwrap_or("trim");
+        let value = &query[tokens[from].end()..tokens[close].start].trim();
+        let replacement = match chars_start < from {
+            true => format!("{function}({value}, {})", &query[tokens[chars_start].start..tokens[from - 1].end()]),
+            false => format!("{function}({value})"),
+        };
+        edits.push((tokens[idx].start, tokens[close].end(), replacement));
+        idx = close + 1;
+    }
+    let edits = edits.iter().map(|(start, end, replacement)| (*start, *end, replacement.as_str())).collect();
+    apply_edits(query, edits)
+}
+
+/// Array column types SQLite can't parse, eg. `varchar(20)[]` or `int[3][3]`, as ones it can - `varchar[]` + `int[]` (it keeps the
+/// `[]`, SQLite's quotes for identifiers, as part of the declared type)
+fn rewrite_array_types(query:&str) -> Option<String> {
+    let statement = query.trim_start().get(..6).unwrap_or_default();
+    if !query.contains('[') || !(statement.eq_ignore_ascii_case("CREATE") || statement.eq_ignore_ascii_case("ALTER ")) {
+        return None;
+    }
+    let is_dimension = |token:&Token| token.text.starts_with('[') && token.text[1..token.text.len() - 1].bytes().all(|b| b.is_ascii_digit());
+    let tokens = tokenize(query);
+    let mut edits = Vec::new();
+    for idx in 1..tokens.len() {
+        let previous = &tokens[idx - 1];
+        if !is_dimension(&tokens[idx]) {
+            continue;
+        }
+        if is_dimension(previous) {
+            // Any more dimensions are dropped, arrays are stored the same whatever their dimensions
+            edits.push((tokens[idx].start, tokens[idx].end(), ""));
+        } else if previous.text == ")" {
+            // The type's specifiers (eg. a length) have to go for SQLite to take the `[]` after them
+            let Some(open) = tokens[..idx - 1].iter().rposition(|token| token.text == "(") else { continue };
+            edits.push((tokens[open].start, previous.end(), ""));
+            edits.push((tokens[idx].start, tokens[idx].end(), "[]"));
+        } else if is_word(previous) && tokens[idx].text != "[]" {
+            edits.push((tokens[idx].start, tokens[idx].end(), "[]"));
+        }
+    }
+    apply_edits(query, edits)
+}
+
+/// Whether a token can be the end of the value on the left of a binary operator (rather than `~` being SQLite's bitwise not)
+fn is_value(token:&Token) -> bool {
+    const KEYWORDS: [&str; 14] = ["SELECT", "WHERE", "AND", "OR", "NOT", "ON", "WHEN", "THEN", "ELSE", "CASE", "BY", "SET", "IN", "IS"];
+    token.text == ")" || token.text.starts_with('\'') || (is_word(token) && !KEYWORDS.iter().any(|k| token.text.eq_ignore_ascii_case(k)))
+}
+
+/// A regclass is shown as the name of the table, but compared as its oid - so an oid is cast to the table's name, and a name (which
+/// may be qualified with `public.`) to its oid
+fn regclass(value:&str) -> String {
+    format!("(SELECT CASE WHEN typeof({value}) = 'integer' THEN relname ELSE oid END FROM temp.pglite_pg_class \
+        WHERE oid = {value} OR relname = {value} OR 'public.' || relname = {value})")
+}
+
+/// The tokens of a `value::type` cast
+struct Cast {
+    value_start: usize,
+    value_end: usize,
+    type_name: String,
+    type_end: usize,
+}
+
+/// Find the first `::` cast in the tokens, with the value it casts - a literal, parameter, (qualified) column, function call or
+/// parenthesized expression
+fn find_cast(tokens:&[Token]) -> Option<Cast> {
+    let colons = tokens.windows(2).position(|pair| pair[0].text == ":" && pair[1].text == ":" && pair[0].end() == pair[1].start)?;
+    let value_end = colons.checked_sub(1)?;
+    let mut value_start = value_end;
+    if tokens[value_end].text == ")" {
+        let mut depth = 0;
+        loop {
+            match tokens[value_start].text {
+                ")" => depth += 1,
+                "(" => depth -= 1,
+                _ => {},
+            }
+            if depth == 0 {
+                break;
+            }
+            value_start = value_start.checked_sub(1)?;
+        }
+        // The name of the function being called, which is right before its parentheses
+        if value_start > 0 && tokens[value_start - 1].end() == tokens[value_start].start && is_word(&tokens[value_start - 1]) {
+            value_start -= 1;
+        }
+    }
+    while value_start >= 2 && tokens[value_start - 1].text == "." && is_word(&tokens[value_start - 2]) {
+        value_start -= 2;
+    }
+
+    // The type's name, which can be several words (eg. `double precision`), qualified (eg. `pg_catalog.int4`), have a length or
+    // precision (eg. `varchar(20)`) + be an array (eg. `int[]`)
+    let mut type_end = colons + 2;
+    if tokens.get(type_end + 1).is_some_and(|t| t.text == ".") && tokens.get(type_end + 2).is_some() {
+        type_end += 2;
+    }
+    let mut type_name = tokens.get(type_end)?.text.trim_matches('"').to_ascii_lowercase();
+    for words in [&["precision"][..], &["varying"], &["with", "time", "zone"], &["without", "time", "zone"]] {
+        let matches = words.iter().enumerate().all(|(idx, word)| tokens.get(type_end + 1 + idx).is_some_and(|t| t.text.eq_ignore_ascii_case(word)));
+        if matches {
+            type_name = format!("{} {}", type_name, words.join(" "));
+            type_end += words.len();
+        }
+    }
+    if tokens.get(type_end + 1).is_some_and(|t| t.text == "(") {
+        type_end += tokens[type_end + 1..].iter().position(|t| t.text == ")")? + 1;
+    }
+    // (`[]` is a single token, as it's the quotes of an identifier in SQLite)
+    while tokens.get(type_end + 1).is_some_and(|t| t.text == "[]") {
+        type_name.push_str("[]");
+        type_end += 1;
+    }
+    Some(Cast { value_start, value_end, type_name, type_end })
+}
+
+fn is_word(token:&Token) -> bool {
+    token.text.starts_with(|c:char| c.is_alphanumeric() || c == '_' || c == '$' || c == '"')
+}
+
+/// The SQLite type to cast to for a Postgres type, when there's one that matches it
+fn sqlite_cast_type(pg_type:&str) -> Option<&'static str> {
+    let sqlite_type = match pg_type {
+        "int" | "integer" | "int2" | "int4" | "int8" | "smallint" | "bigint" | "oid" => "INTEGER",
+        "real" | "float" | "float4" | "float8" | "double precision" => "REAL",
+        "numeric" | "decimal" => "NUMERIC",
+        "text" | "varchar" | "character varying" | "char" | "character" | "bpchar" | "name" => "TEXT",
+        "bytea" => "BLOB",
+        _ => return None,
+    };
+    Some(sqlite_type)
+}
+
+/// Replace the byte ranges of the query (which are in order, and don't overlap)
+fn apply_edits(query:&str, edits:Vec<(usize, usize, &str)>) -> Option<String> {
+    if edits.is_empty() {
+        return None;
+    }
+    let mut rewritten = query.to_owned();
+    for (start, end, replacement) in edits.into_iter().rev() {
+        rewritten.replace_range(start..end, replacement);
+    }
+    Some(rewritten)
+}
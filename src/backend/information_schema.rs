@@ -0,0 +1,187 @@
+use std::{borrow::Cow, sync::Arc};
+use rusqlite::{Connection, Error, functions::FunctionFlags};
+
+use super::tokens::tokenize;
+use super::types::PgLiteTypeMap;
+
+/* Follows is the emulation of the Postgres information_schema views, built on top of sqlite_master + the table pragmas */
+
+/// The information_schema views that are emulated, these are created as TEMP views named `pglite_information_schema_{view}`
+const VIEWS: [&str; 4] = ["tables", "columns", "table_constraints", "key_column_usage"];
+
+/// Whether column `c` of table `m` is the table's INTEGER PRIMARY KEY, which SQLite makes an alias of the rowid (unless the table is
+/// `WITHOUT ROWID`)
+pub(super) const IS_ROWID_ALIAS: &str = "(upper(c.type) = 'INTEGER' AND c.pk = 1 \
+    AND (SELECT count(*) FROM pragma_table_info(m.name) WHERE pk > 0) = 1 \
+    AND NOT EXISTS (SELECT 1 FROM pragma_table_list(m.name) WHERE wr))";
+
+/// Create the emulated information_schema views (+ the functions they use) on a connection
+pub fn create_views(con:&Connection, catalog:&str, type_map:Arc<PgLiteTypeMap>) -> Result<(), Error> {
+    // The data_type + udt_name of a column come from the same type mapping used for query results
+    let udt_type_map = type_map.clone();
+    con.create_scalar_function("pglite_data_type", 2, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |ctx| {
+        let declared_type = ctx.get::<Option<String>>(0)?.unwrap_or_default();
+        Ok(standard_type_name(&type_map.lookup_column(&declared_type, ctx.get(1)?).pg_type))
+    })?;
+    con.create_scalar_function("pglite_udt_name", 2, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |ctx| {
+        let declared_type = ctx.get::<Option<String>>(0)?.unwrap_or_default();
+        Ok(udt_type_map.lookup_column(&declared_type, ctx.get(1)?).pg_type.name().to_owned())
+    })?;
+
+    let catalog = catalog.replace('\'', "''");
+    // UNIQUE column constraints are backed by an automatic index, so name them the way Postgres would (eg. `users_email_key`)
+    let unique_constraint_name = "CASE WHEN i.name LIKE 'sqlite_autoindex_%' \
+        THEN m.name || '_' || (SELECT name FROM pragma_index_info(i.name) WHERE seqno = 0) || '_key' ELSE i.name END";
+    con.execute_batch(&format!(r#"
+        CREATE TEMP VIEW IF NOT EXISTS pglite_information_schema_tables AS
+        SELECT '{catalog}' AS table_catalog, 'public' AS table_schema, m.name AS table_name,
+            CASE m.type WHEN 'view' THEN 'VIEW' ELSE 'BASE TABLE' END AS table_type,
+            NULL AS self_referencing_column_name, NULL AS reference_generation,
+            NULL AS user_defined_type_catalog, NULL AS user_defined_type_schema, NULL AS user_defined_type_name,
+            CASE m.type WHEN 'view' THEN 'NO' ELSE 'YES' END AS is_insertable_into, 'NO' AS is_typed, NULL AS commit_action
+        FROM main.sqlite_master m
+        WHERE m.type IN ('table', 'view') AND m.name NOT LIKE 'sqlite_%';
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_information_schema_columns AS
+        SELECT '{catalog}' AS table_catalog, 'public' AS table_schema, m.name AS table_name, c.name AS column_name,
+            c.cid + 1 AS ordinal_position, c.dflt_value AS column_default,
+            CASE WHEN c."notnull" OR c.pk > 0 THEN 'NO' ELSE 'YES' END AS is_nullable,
+            pglite_data_type(c.type, {IS_ROWID_ALIAS}) AS data_type, NULL AS character_maximum_length,
+            NULL AS numeric_precision, NULL AS numeric_scale,
+            '{catalog}' AS udt_catalog, 'pg_catalog' AS udt_schema, pglite_udt_name(c.type, {IS_ROWID_ALIAS}) AS udt_name,
+            'NO' AS is_identity, 'NEVER' AS is_generated,
+            CASE m.type WHEN 'view' THEN 'NO' ELSE 'YES' END AS is_updatable
+        FROM main.sqlite_master m JOIN pragma_table_info(m.name) c
+        WHERE m.type IN ('table', 'view') AND m.name NOT LIKE 'sqlite_%';
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_information_schema_table_constraints AS
+        SELECT '{catalog}' AS constraint_catalog, 'public' AS constraint_schema, m.name || '_pkey' AS constraint_name,
+            '{catalog}' AS table_catalog, 'public' AS table_schema, m.name AS table_name,
+            'PRIMARY KEY' AS constraint_type, 'NO' AS is_deferrable, 'NO' AS initially_deferred, 'YES' AS enforced
+        FROM main.sqlite_master m
+        WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE pk > 0)
+        UNION ALL
+        SELECT '{catalog}', 'public', {unique_constraint_name}, '{catalog}', 'public', m.name, 'UNIQUE', 'NO', 'NO', 'YES'
+        FROM main.sqlite_master m JOIN pragma_index_list(m.name) i
+        WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' AND i.origin = 'u'
+        UNION ALL
+        SELECT '{catalog}', 'public', m.name || '_' || f."from" || '_fkey', '{catalog}', 'public', m.name, 'FOREIGN KEY', 'NO', 'NO', 'YES'
+        FROM main.sqlite_master m JOIN pragma_foreign_key_list(m.name) f
+        WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' AND f.seq = 0;
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_information_schema_key_column_usage AS
+        SELECT '{catalog}' AS constraint_catalog, 'public' AS constraint_schema, m.name || '_pkey' AS constraint_name,
+            '{catalog}' AS table_catalog, 'public' AS table_schema, m.name AS table_name, c.name AS column_name,
+            c.pk AS ordinal_position, NULL AS position_in_unique_constraint
+        FROM main.sqlite_master m JOIN pragma_table_info(m.name) c
+        WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' AND c.pk > 0
+        UNION ALL
+        SELECT '{catalog}', 'public', {unique_constraint_name}, '{catalog}', 'public', m.name, ii.name, ii.seqno + 1, NULL
+        FROM main.sqlite_master m JOIN pragma_index_list(m.name) i JOIN pragma_index_info(i.name) ii
+        WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' AND i.origin = 'u'
+        UNION ALL
+        SELECT '{catalog}', 'public',
+            m.name || '_' || (SELECT "from" FROM pragma_foreign_key_list(m.name) WHERE id = f.id AND seq = 0) || '_fkey',
+            '{catalog}', 'public', m.name, f."from", f.seq + 1, f.seq + 1
+        FROM main.sqlite_master m JOIN pragma_foreign_key_list(m.name) f
+        WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%';
+    "#))
+}
+
+/// Point any references to the emulated information_schema views at their TEMP view equivalents
+pub fn rewrite_query(query:&str) -> Cow<'_, str> {
+    let mut query = Cow::Borrowed(query);
+    for view in VIEWS {
+        for reference in [format!("information_schema.{view}"), format!("\"information_schema\".\"{view}\"")] {
+            if let Some(rewritten) = replace_identifier(&query, &reference, &format!("pglite_information_schema_{view}")) {
+                query = Cow::Owned(rewritten);
+            }
+        }
+    }
+    query
+}
+
+/// Case-insensitive replace of a (qualified) identifier, that isn't just part of a longer identifier - the query is tokenized, so an
+/// identifier inside a string or comment (eg. `SELECT 'information_schema.tables'`) is left as it is
+pub(super) fn replace_identifier(query:&str, identifier:&str, replacement:&str) -> Option<String> {
+    let parts = tokenize(identifier);
+    let tokens = tokenize(query);
+    let mut rewritten = String::with_capacity(query.len());
+    let mut last_end = 0;
+    let mut idx = 0;
+    while idx + parts.len() <= tokens.len() {
+        let matches = parts.iter().zip(&tokens[idx..]).all(|(part, token)| token.text.eq_ignore_ascii_case(part.text));
+        // Not when it's qualified by something else (eg. `t.tables`)
+        let qualified = idx > 0 && tokens[idx - 1].text == "." && parts.len() == 1;
+        if !matches || qualified {
+            idx += 1;
+            continue;
+        }
+        let last = idx + parts.len() - 1;
+        rewritten.push_str(&query[last_end..tokens[idx].start]);
+        rewritten.push_str(replacement);
+        last_end = tokens[last].end();
+        idx = last + 1;
+    }
+    match last_end {
+        0 => None,
+        _ => {
+            rewritten.push_str(&query[last_end..]);
+            Some(rewritten)
+        }
+    }
+}
+
+/// The SQL standard name for a type, as used in information_schema's data_type columns
+pub(super) fn standard_type_name(pg_type:&pgwire::api::Type) -> String {
+    use pgwire::api::Type as PgType;
+    let name = match *pg_type {
+        PgType::INT8 => "bigint",
+        PgType::INT4 => "integer",
+        PgType::INT2 => "smallint",
+        PgType::FLOAT8 => "double precision",
+        PgType::FLOAT4 => "real",
+        PgType::BOOL => "boolean",
+        PgType::VARCHAR => "character varying",
+        PgType::BPCHAR => "character",
+        PgType::TIMESTAMP => "timestamp without time zone",
+        PgType::TIMESTAMPTZ => "timestamp with time zone",
+        PgType::TIME => "time without time zone",
+        PgType::TIMETZ => "time with time zone",
+        PgType::INT8_ARRAY | PgType::FLOAT8_ARRAY | PgType::TEXT_ARRAY => "ARRAY",
+        _ => pg_type.name(),
+    };
+    name.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_references_to_the_views() {
+        assert_eq!(rewrite_query("SELECT * FROM information_schema.tables"), "SELECT * FROM pglite_information_schema_tables");
+        assert_eq!(rewrite_query("SELECT * FROM \"information_schema\".\"columns\" c"), "SELECT * FROM pglite_information_schema_columns c");
+        assert_eq!(rewrite_query("SELECT * FROM INFORMATION_SCHEMA.Tables"), "SELECT * FROM pglite_information_schema_tables");
+    }
+
+    #[test]
+    fn leaves_strings_and_comments_alone() {
+        for query in [
+            "SELECT 'information_schema.tables'",
+            "SELECT 1 -- information_schema.tables",
+            "SELECT 1 /* information_schema.tables */",
+            "SELECT $$information_schema.tables$$",
+            "SELECT * FROM information_schema.tables_extra",
+        ] {
+            assert_eq!(rewrite_query(query), query);
+        }
+    }
+
+    #[test]
+    fn replaces_only_whole_identifiers() {
+        assert_eq!(replace_identifier("SELECT lastval()", "lastval()", "last_insert_rowid()").as_deref(), Some("SELECT last_insert_rowid()"));
+        assert_eq!(replace_identifier("SELECT my_lastval()", "lastval()", "last_insert_rowid()"), None);
+        assert_eq!(replace_identifier("SELECT t.pg_class FROM t", "pg_class", "temp.pglite_pg_class"), None);
+    }
+}
@@ -1,27 +1,63 @@
 
 mod simple_backend;
+mod types;
+mod information_schema;
+mod pg_catalog;
+mod serial;
+mod compat;
+pub mod tokens;
 use std::collections::HashMap;
-use crossbeam_channel::Sender;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use crossbeam_channel::{Sender, TrySendError};
 use pgwire::api::results::FieldFormat;
 use pgwire::api::results::FieldInfo;
+use pgwire::error::ErrorInfo;
 use pgwire::error::PgWireError;
 use pgwire::error::PgWireResult;
 use rusqlite::types::Type;
 use rusqlite::types::Value;
-pub use simple_backend::SimplePgLiteDBBackend;
-pub use simple_backend::SimplePgLiteDBBackendFactory;
+pub use simple_backend::{SimplePgLiteDBBackendFactory, CachedDatabase};
+pub use types::PgLiteTypeRule;
+pub use pg_catalog::references_pg_database;
+pub use serial::replace_lastval;
+use tokens::tokenize;
 
+use crate::cancel::CancelToken;
 use crate::config::PgLiteConfig;
 
 pub trait PgLiteDBBackend { 
     fn close(&self) -> Result<(), PgWireError>;
-    fn query(&self, query:&str) -> PgWireResult<PgLiteDBResponse>;
-    fn query_with_params(&self, query:&str, params:Vec<PgLiteDBParam>) -> PgWireResult<PgLiteDBResponse>;
+    /// Runs a query, sending its response (+ any further batches of records) to `respond` - errors are left for the caller to send
+    /// When `read_only` is set, a statement that would write to the database is rejected
+    fn query(&self, query:&str, read_only:bool, respond:&Sender<PgLiteDBResponse>) -> PgWireResult<()>;
+    fn query_with_params(&self, query:&str, params:Vec<PgLiteDBParam>, read_only:bool, respond:&Sender<PgLiteDBResponse>) -> PgWireResult<()>;
     fn describe_query(&self, query:&str) -> PgWireResult<PgLiteDBResponse>;
+    fn keepalive(&self) -> Result<(), PgWireError>;
+    /// Runs a maintenance statement (eg. `ANALYZE`) that doesn't return anything
+    fn maintenance(&self, statement:&str) -> PgWireResult<PgLiteDBResponse>;
+    /// Reports the size + last modified time of the database, as a single row with the `database_info_schema()` columns
+    fn database_info(&self) -> PgWireResult<PgLiteDBResponse>;
+    /// Loads the databases a client can connect to, for its next query of pg_database
+    fn load_databases(&self, databases:&DatabaseList) -> PgWireResult<()>;
+    /// Inserts the rows of a COPY with the insert statement, in a transaction of their own (unless the client's session is already
+    /// in one) that's ended before the next message is handled - reporting the number of rows inserted
+    fn copy_rows(&self, insert_query:&str, rows:Vec<Vec<Value>>, read_only:bool) -> PgWireResult<PgLiteDBResponse>;
 }
 
 pub trait PgLitebackendFactory {
     fn create_backend(&self, metadata:&HashMap<String, String>) -> Result<BackendConnection, PgWireError>;
+    /// The databases the connected user is allowed to connect to, for pg_database (eg. psql's `\l`)
+    fn list_databases(&self, metadata:&HashMap<String, String>) -> PgWireResult<DatabaseList>;
+    /// The backend of an existing database, named by its path within the database root (eg. `john/data.sqlite`) - for the admin
+    /// commands, so it's resolved the same way as a client's database, but never created
+    fn database_backend(&self, database:&str) -> PgWireResult<BackendConnection>;
+    /// Whether databases can still be opened (eg. the database root hasn't gone away), for the health checks - with the reason when they can't
+    fn check_health(&self) -> Result<(), String>;
+    /// Release all the database handles, when the server is shutting down
+    fn shutdown(&self);
 }
 
 
@@ -29,16 +65,96 @@ pub trait PgLitebackendFactory {
 pub enum PgLiteBackendType {
     #[clap(alias = "simple")]
     SimplePgLiteDBBackend,
+    /// Like the simple backend, but each database is held in memory (until its handle is released) rather than in a file
+    #[clap(alias = "memory")]
+    InMemoryPgLiteDBBackend,
 }
 
 
-pub fn load_backend_factory(config:&PgLiteConfig) -> impl PgLitebackendFactory {
+/// Load the configured backend, failing if it can't be used with the configuration (eg. the database root doesn't exist)
+pub fn load_backend_factory(config:&PgLiteConfig) -> Result<impl PgLitebackendFactory, String> {
     match config.backend {
-        PgLiteBackendType::SimplePgLiteDBBackend => SimplePgLiteDBBackendFactory::new(config),
+        PgLiteBackendType::SimplePgLiteDBBackend | PgLiteBackendType::InMemoryPgLiteDBBackend => SimplePgLiteDBBackendFactory::new(config),
         // todo: add additional backends...
     }
 }
 
+/// The statement from its first keyword, skipping any whitespace + comments before it - eg. for the command it runs
+pub fn strip_leading_comments(mut query:&str) -> &str {
+    loop {
+        query = query.trim_start();
+        if let Some(comment) = query.strip_prefix("--") {
+            query = comment.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(comment) = query.strip_prefix("/*") {
+            query = comment.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            return query;
+        }
+    }
+}
+
+
+/// Options that a client can select for its session by suffixing the database name, eg. `reports.db?readonly`
+/// Multiple options are separated by `&`, eg. `reports.db?readonly&attach:crm.db`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PgLiteSessionOptions {
+    pub read_only: bool,
+    /// The other databases attached to the session's database (`attach:{database}`), for queries across them
+    pub attached: Vec<AttachedDatabase>,
+}
+
+/// A database attached to a session's database, eg. `sales.db?attach:crm.db` - whose tables are queried as `crm.{table}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttachedDatabase {
+    /// The schema its tables are in - the name of its file, without the extension
+    pub alias: String,
+    /// The database's path - as the client named it, until it's resolved the same way as the session's database
+    pub path: PathBuf,
+}
+
+impl AttachedDatabase {
+    fn parse(database:&str) -> PgWireResult<Self> {
+        let alias = Path::new(database).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+        let is_identifier = alias.starts_with(|c:char| c.is_ascii_alphabetic() || c == '_') && alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_identifier || alias.eq_ignore_ascii_case("main") || alias.eq_ignore_ascii_case("temp") {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new("FATAL".to_owned(), "22023".to_owned(),
+                format!("Unable to attach \"{database}\", its name (without the extension) has to be an identifier other than main or temp")))));
+        }
+        Ok(Self { alias, path: PathBuf::from(database) })
+    }
+}
+
+impl PgLiteSessionOptions {
+    /// Splits any session options off the end of the DB path, returning the DB path without them + the parsed options
+    pub fn parse(dbpath:&str) -> PgWireResult<(&str, Self)> {
+        let Some((path, option_str)) = dbpath.rsplit_once('?') else { return Ok((dbpath, Self::default())) };
+        let mut options = Self::default();
+        for option in option_str.split('&').filter(|o| !o.is_empty()) {
+            match option.to_lowercase().as_str() {
+                "readonly" => options.read_only = true,
+                lowercase if lowercase.starts_with("attach:") => {
+                    let attached = AttachedDatabase::parse(&option["attach:".len()..])?;
+                    if options.attached.iter().any(|other| other.alias.eq_ignore_ascii_case(&attached.alias)) {
+                        return Err(PgWireError::UserError(Box::new(ErrorInfo::new("FATAL".to_owned(), "22023".to_owned(),
+                            format!("More than one database is attached as \"{}\"", attached.alias)))));
+                    }
+                    options.attached.push(attached);
+                },
+                _ => return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "FATAL".to_owned(),
+                    "22023".to_owned(),
+                    format!("Unsupported session option: {option}"),
+                )))),
+            }
+        }
+        Ok((path, options))
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 
 /* Follows is the types used to communicate between the pglite "connection" and the "backend" */
 
@@ -47,6 +163,24 @@ pub struct Field {
     pub ordinal: usize,
     pub name: String,
     pub field_type: Type,
+    pub pg_type: pgwire::api::Type,
+    /// The modifier of the column's declared type, eg. the length of a `VARCHAR(50)` (as Postgres encodes it) - or -1 when it has none
+    pub type_modifier: i32,
+}
+
+/// The columns of `SELECT pglite.database_info()`
+pub fn database_info_schema() -> Vec<Field> {
+    [
+        ("size_bytes", Type::Integer, pgwire::api::Type::INT8),
+        ("last_modified", Type::Text, pgwire::api::Type::TIMESTAMPTZ),
+        ("page_count", Type::Integer, pgwire::api::Type::INT8),
+        ("wal_size_bytes", Type::Integer, pgwire::api::Type::INT8),
+        ("statement_cache_hits", Type::Integer, pgwire::api::Type::INT8),
+        ("statement_cache_misses", Type::Integer, pgwire::api::Type::INT8),
+    ].into_iter()
+        .enumerate()
+        .map(|(ordinal, (name, field_type, pg_type))| Field { ordinal, name:name.to_owned(), field_type, pg_type, type_modifier:-1 })
+        .collect()
 }
 
 #[derive(Debug, Clone)]
@@ -54,16 +188,34 @@ pub struct Record {
     pub values: Vec<Value>
 }
 
+/// The databases a user can connect to (by the database name they'd connect with), as listed in pg_database
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseList {
+    pub owner: String,
+    pub databases: Vec<String>,
+}
+
+/// The remaining batches of records for a result that didn't fit in a single batch
+pub type RecordBatchReceiver = tokio::sync::mpsc::Receiver<PgWireResult<Vec<Record>>>;
+
 pub struct PgLiteDBResponse {
     pub result_schema: Option<Vec<Field>>,
-    pub result:Option<Vec<Record>>,
-    pub error:Option<PgWireError>
+    pub result:Option<Vec<Record>>,     // The first batch of records
+    pub more_records:Option<RecordBatchReceiver>,
+    pub affected_rows:Option<usize>,    // Set for statements that don't return rows
+    pub error:Option<PgWireError>,
+    pub in_transaction:bool,    // Whether the DB connection is inside a transaction block once the message was handled
+    pub notices:Vec<ErrorInfo>,     // Sent to the client ahead of the result (eg. that the statement was rewritten)
+    pub last_insert_id:Option<i64>,     // The rowid of the last row the statement inserted, for the session's lastval()
 }
 
 #[derive(Debug, Clone)]
 pub struct PgLiteDBParam {
+    #[allow(unused)]
     pub name:Option<String>,    // Name based params are not currently supported, so this is here for future use
+    #[allow(unused)]
     pub ordinal:Option<usize>, 
+    #[allow(unused)]
     pub param_type:Option<Type>, 
     pub value:Value
 }
@@ -72,7 +224,11 @@ pub struct PgLiteDBParam {
 pub enum MessageType {
     SimpleQuery, 
     QueryWithParams, 
-    Describe
+    Describe,
+    Keepalive,
+    Maintenance,
+    DatabaseInfo,
+    CopyRows
 }
 
 #[derive(Debug, Clone)]
@@ -80,44 +236,203 @@ pub struct PgLiteDBMessage {
     pub message_type:MessageType,
     pub query:String,
     pub params:Option<Vec<PgLiteDBParam>>,
+    pub read_only:bool,     // Whether the client's session is read-only, so writes are rejected
+    pub databases:Option<DatabaseList>,    // The client's databases, for a query of pg_database
+    pub main_handle:bool,   // Whether the client's session has to stay on the main handle (eg. it's inside a transaction block)
+    pub cancel_token:Option<CancelToken>,   // For the client to cancel the statement while it's running
+    pub rows:Option<Vec<Vec<Value>>>,   // The rows of a COPY, which are each inserted with the query
     pub respond: Sender<PgLiteDBResponse>
 }
 
 impl PgLiteDBMessage {
-    pub fn from_query(query:String, respond: Sender<PgLiteDBResponse>) -> Self {
-        Self { message_type:MessageType::SimpleQuery, query, respond, params:None }
+    pub fn from_query(query:String, read_only:bool, respond: Sender<PgLiteDBResponse>) -> Self {
+        Self { message_type:MessageType::SimpleQuery, query, respond, params:None, read_only, databases:None, main_handle:false, cancel_token:None, rows:None }
     }
-    pub fn from_query_with_params(query:String, params:Vec<PgLiteDBParam>, respond: Sender<PgLiteDBResponse>) -> Self {
-        Self { message_type:MessageType::QueryWithParams, query, respond, params:Some(params) }
+    pub fn from_query_with_params(query:String, params:Vec<PgLiteDBParam>, read_only:bool, respond: Sender<PgLiteDBResponse>) -> Self {
+        Self { message_type:MessageType::QueryWithParams, query, respond, params:Some(params), read_only, databases:None, main_handle:false, cancel_token:None, rows:None }
     }
     pub fn from_describe(query:String, respond: Sender<PgLiteDBResponse>) -> Self {
-        Self { message_type:MessageType::Describe, query, respond, params:None }
+        Self { message_type:MessageType::Describe, query, respond, params:None, read_only:false, databases:None, main_handle:false, cancel_token:None, rows:None }
+    }
+    pub fn from_maintenance(statement:String, respond: Sender<PgLiteDBResponse>) -> Self {
+        Self { message_type:MessageType::Maintenance, query:statement, respond, params:None, read_only:false, databases:None, main_handle:false, cancel_token:None, rows:None }
+    }
+    pub fn from_database_info(respond: Sender<PgLiteDBResponse>) -> Self {
+        Self { message_type:MessageType::DatabaseInfo, query:String::new(), respond, params:None, read_only:false, databases:None, main_handle:false, cancel_token:None, rows:None }
+    }
+    pub fn from_copy_rows(insert_query:String, rows:Vec<Vec<Value>>, read_only:bool, respond: Sender<PgLiteDBResponse>) -> Self {
+        Self { message_type:MessageType::CopyRows, query:insert_query, respond, params:None, read_only, databases:None, main_handle:false, cancel_token:None, rows:Some(rows) }
+    }
+    pub fn from_keepalive(respond: Sender<PgLiteDBResponse>) -> Self {
+        Self { message_type:MessageType::Keepalive, query:String::new(), respond, params:None, read_only:false, databases:None, main_handle:false, cancel_token:None, rows:None }
+    }
+    /// Include the client's databases, for a query of pg_database
+    pub fn with_databases(mut self, databases:Option<DatabaseList>) -> Self {
+        self.databases = databases;
+        self
+    }
+    /// Mark the message as sent from a session that has to stay on the main handle - one inside a transaction block, or that's
+    /// left its own state on the handle (see `changes_handle_state`)
+    pub fn on_main_handle(mut self, main_handle:bool) -> Self {
+        self.main_handle = main_handle;
+        self
+    }
+    /// Let the client cancel the statement while it's running
+    pub fn with_cancel_token(mut self, cancel_token:CancelToken) -> Self {
+        self.cancel_token = Some(cancel_token);
+        self
     }
+    /// Whether the message can be handled by one of the database's readers: a SELECT from outside of a transaction block - a
+    /// transaction's statements have to see its own changes, so they all run on the main handle (as do the SELECTs that read the
+    /// main handle's own state, and those of a session that's created TEMP tables or set PRAGMAs on it)
+    fn can_run_on_reader(&self) -> bool {
+        let command = strip_leading_comments(&self.query).split(|c:char| c.is_whitespace() || c == '(').next().unwrap_or_default();
+        matches!(self.message_type, MessageType::SimpleQuery | MessageType::QueryWithParams)
+            && !self.main_handle
+            && command.eq_ignore_ascii_case("SELECT")
+            && !reads_handle_state(&self.query)
+    }
+}
+
+/// The functions whose result depends on what was last run on the handle, rather than on the database
+const HANDLE_STATE_FUNCTIONS: [&str; 3] = ["last_insert_rowid", "changes", "total_changes"];
+
+/// Whether a query reads state that only the main handle has - the functions about the statements it last ran (eg.
+/// `last_insert_rowid()`), or its temp schema (eg. `temp.t` or `sqlite_temp_master`), where its TEMP tables are
+fn reads_handle_state(query:&str) -> bool {
+    let tokens = tokenize(query);
+    tokens.iter().enumerate().any(|(idx, token)| {
+        let next = tokens.get(idx + 1).map(|t| t.text);
+        let name = token.text.trim_matches('"');
+        (HANDLE_STATE_FUNCTIONS.iter().any(|function| name.eq_ignore_ascii_case(function)) && next == Some("("))
+            || (["temp", "temporary"].iter().any(|schema| name.eq_ignore_ascii_case(schema)) && next == Some("."))
+            || ["sqlite_temp_master", "sqlite_temp_schema"].iter().any(|table| name.eq_ignore_ascii_case(table))
+    })
+}
+
+/// Whether a query leaves state on the handle that the session's later queries can depend on, without naming the temp schema - it
+/// creates a TEMP table, view or trigger (eg. `CREATE TEMP TABLE t` or `CREATE TABLE temp.t`), or sets a PRAGMA (eg.
+/// `PRAGMA case_sensitive_like = on`), which the readers' handles won't have
+pub fn changes_handle_state(query:&str) -> bool {
+    let tokens = tokenize(query);
+    let is_temp = |text:&str| ["temp", "temporary"].iter().any(|schema| text.trim_matches('"').eq_ignore_ascii_case(schema));
+    tokens.iter().enumerate().any(|(idx, token)| {
+        let statement = tokens[idx + 1..].iter().take_while(|token| token.text != ";");
+        (token.text.eq_ignore_ascii_case("CREATE") && statement.take_while(|token| token.text != "(" && !token.text.eq_ignore_ascii_case("AS")).any(|token| is_temp(token.text)))
+            || (token.text.eq_ignore_ascii_case("PRAGMA") && tokens[idx + 1..].iter().take_while(|token| token.text != ";").any(|token| token.text == "=" || token.text == "("))
+    })
 }
 
 #[derive(Debug, Clone)]
 pub struct BackendConnection {
-    pub sender:Sender<PgLiteDBMessage>
+    pub sender:Sender<PgLiteDBMessage>,
+    /// The queue of the database's readers, when it has any
+    pub readers:Option<Sender<PgLiteDBMessage>>,
+    /// The number of client connections pinned to the backend - it isn't closed for being idle while any are
+    pub pins:Arc<AtomicUsize>,
+    /// When the backend last handled a message (or was opened)
+    pub last_access:Arc<Mutex<SystemTime>>,
+}
+
+impl BackendConnection {
+    /// Queue a message for the backend - when its queue is full the message is rejected rather than waited on, so queries can't
+    /// pile up without limit. SELECTs go to the database's readers, when it has them
+    pub fn send(&self, message:PgLiteDBMessage) -> PgWireResult<()> {
+        let message = match &self.readers {
+            Some(readers) if message.can_run_on_reader() => match readers.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(_)) => return Err(queue_full(readers)),
+                // None of the readers could be opened, so the main handle runs everything
+                Err(TrySendError::Disconnected(message)) => message,
+            },
+            _ => message,
+        };
+        match self.sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(queue_full(&self.sender)),
+            Err(TrySendError::Disconnected(_)) => Err(PgWireError::UserError(Box::new(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(),
+                "Was disconnected from the database backend".to_owned())))),
+        }
+    }
+}
+
+impl BackendConnection {
+    /// Pin a client connection to the backend for as long as the pin is held, so its database handle (along with any transaction,
+    /// temp tables or PRAGMA settings on it) is kept for the whole session
+    pub fn pin(&self) -> PinnedBackend {
+        self.pins.fetch_add(1, Ordering::Relaxed);
+        PinnedBackend(self.clone())
+    }
+}
+
+/// A backend that a client connection is pinned to, until it's dropped
+#[derive(Debug)]
+pub struct PinnedBackend(BackendConnection);
+
+impl std::ops::Deref for PinnedBackend {
+    type Target = BackendConnection;
+
+    fn deref(&self) -> &BackendConnection {
+        &self.0
+    }
+}
+
+impl Drop for PinnedBackend {
+    fn drop(&mut self) {
+        self.0.pins.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn queue_full(sender:&Sender<PgLiteDBMessage>) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "53300".to_owned(),
+        format!("The database is busy, its queue of waiting queries is full (capacity {})", sender.capacity().unwrap_or_default()))))
 }
 
-impl Into<FieldInfo> for &Field {
-    fn into(self) -> FieldInfo {
+impl From<&Field> for FieldInfo {
+    fn from(field: &Field) -> FieldInfo {
         FieldInfo::new(
-            self.name.clone(),
+            field.name.clone(),
             None,
             None,
-            get_pgwiretype_for_type(&self.field_type),
-            match self.field_type {  Type::Blob => FieldFormat::Binary, _ => FieldFormat::Text }
+            field.pg_type.clone(),
+            FieldFormat::Text
         )
     }
 }
 
-fn get_pgwiretype_for_type(field_type:&Type) -> pgwire::api::Type {
-    match field_type {  
-        Type::Integer => pgwire::api::Type::INT8,
-        Type::Real => pgwire::api::Type::FLOAT8,
-        Type::Text => pgwire::api::Type::TEXT,
-        Type::Blob => pgwire::api::Type::BYTEA,
-        _ => pgwire::api::Type::VARCHAR
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_reads_of_the_handle_state() {
+        assert!(reads_handle_state("SELECT last_insert_rowid()"));
+        assert!(reads_handle_state("SELECT CHANGES (), total_changes()"));
+        assert!(reads_handle_state("SELECT * FROM temp.t"));
+        assert!(reads_handle_state("SELECT name FROM \"temp\".sqlite_master"));
+        assert!(reads_handle_state("SELECT name FROM sqlite_temp_master"));
+        assert!(!reads_handle_state("SELECT changes, temp FROM t"));
+        assert!(!reads_handle_state("SELECT 'last_insert_rowid()' -- temp.t"));
+    }
+
+    #[test]
+    fn finds_changes_to_the_handle_state() {
+        assert!(changes_handle_state("CREATE TEMP TABLE t (a)"));
+        assert!(changes_handle_state("create temporary view v AS SELECT 1"));
+        assert!(changes_handle_state("CREATE TABLE IF NOT EXISTS \"temp\".t (a)"));
+        assert!(changes_handle_state("SELECT 1; PRAGMA case_sensitive_like = on"));
+        assert!(changes_handle_state("PRAGMA main.cache_size(-4000)"));
+        assert!(!changes_handle_state("CREATE TABLE t (temp TEXT)"));
+        assert!(!changes_handle_state("CREATE VIEW v AS SELECT * FROM temp.t"));
+        assert!(!changes_handle_state("PRAGMA foreign_keys; SELECT 'PRAGMA x = 1'"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn strips_leading_comments() {
+        assert_eq!(strip_leading_comments("  -- first\n/* second */ SELECT 1 -- last"), "SELECT 1 -- last");
+        assert_eq!(strip_leading_comments("/* a */\n-- b\n\tINSERT INTO t VALUES (1)"), "INSERT INTO t VALUES (1)");
+        assert_eq!(strip_leading_comments("SELECT '--'"), "SELECT '--'");
+        assert_eq!(strip_leading_comments("-- unterminated"), "");
+        assert_eq!(strip_leading_comments("/* unterminated"), "");
+    }
+}
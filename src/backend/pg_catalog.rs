@@ -0,0 +1,561 @@
+use std::{borrow::Cow, sync::Arc};
+use pgwire::api::Type as PgType;
+use rusqlite::{Connection, Error, functions::FunctionFlags, types::{Type, ValueRef}};
+
+use super::information_schema::{replace_identifier, standard_type_name, IS_ROWID_ALIAS};
+use super::tokens::tokenize;
+use super::types::PgLiteTypeMap;
+
+/* Follows is the emulation of the Postgres catalogs that psql's describe commands query (eg. `\l`, `\dt` + `\d table`):
+   - pg_database lists the databases the client can connect to - these come from the backend factory, and are loaded into a TEMP
+     table just before each query that uses it
+   - pg_class, pg_attribute, pg_attrdef, pg_index + pg_constraint are TEMP tables (so their columns have the catalogs' types, eg.
+     booleans are sent as `t` / `f`) loaded from views of sqlite_master + the table pragmas just before each query that uses them,
+     with every table in the `public` schema. The oid of a table, view or index is derived from its rowid in sqlite_master - and the
+     PRIMARY KEY index of a table whose key is its rowid (which SQLite doesn't create) has the oid after its table's
+   - pg_namespace, pg_type, pg_am, pg_collation + pg_roles only have the rows they're created with
+   - the catalogs psql also queries for the features SQLite doesn't have (eg. pg_inherits, pg_policy or pg_rewrite) are empty */
+
+/// The catalog functions psql uses, which SQLite doesn't allow to be qualified with `pg_catalog.`
+const FUNCTIONS: [&str; 6] = ["pg_get_userbyid", "pg_encoding_to_char", "array_to_string", "pg_table_is_visible", "format_type", "pg_get_expr"];
+
+/// The emulated functions that are always NULL - there are no comments on anything, pg_trigger + pg_rewrite are empty, and the size
+/// of a table isn't known (SQLite only has the database's size)
+const NULL_FUNCTIONS: [&str; 8] = [
+    "obj_description", "col_description", "pg_get_triggerdef", "pg_get_ruledef", "pg_table_size", "pg_relation_size",
+    "pg_total_relation_size", "pg_size_pretty",
+];
+
+/// The emulated catalogs (+ their columns), which are TEMP tables named `pglite_{catalog}` - the columns have their Postgres types
+/// declared, so they're sent as them (eg. a boolean as `t`, which psql checks for)
+const CATALOG_TABLES: [(&str, &str); 19] = [
+    ("pg_database", "oid INTEGER, datname TEXT, datdba TEXT, encoding INTEGER, datlocprovider TEXT, datistemplate BOOLEAN, \
+        datallowconn BOOLEAN, datconnlimit INTEGER, datcollate TEXT, datctype TEXT, datlocale TEXT, daticulocale TEXT, daticurules TEXT, \
+        datacl TEXT"),
+    ("pg_namespace", "oid INTEGER, nspname TEXT, nspowner INTEGER, nspacl TEXT"),
+    ("pg_class", "oid INTEGER, relname TEXT, relnamespace INTEGER, reltype INTEGER, reloftype INTEGER, relowner INTEGER, relam INTEGER, \
+        relfilenode INTEGER, reltablespace INTEGER, relpages INTEGER, reltuples REAL, relallvisible INTEGER, reltoastrelid INTEGER, \
+        relhasindex BOOLEAN, relisshared BOOLEAN, relpersistence TEXT, relkind TEXT, relnatts INTEGER, relchecks INTEGER, \
+        relhasrules BOOLEAN, relhastriggers BOOLEAN, relhassubclass BOOLEAN, relrowsecurity BOOLEAN, relforcerowsecurity BOOLEAN, \
+        relispopulated BOOLEAN, relreplident TEXT, relispartition BOOLEAN, relrewrite INTEGER, relfrozenxid INTEGER, \
+        relminmxid INTEGER, relacl TEXT, reloptions TEXT, relpartbound TEXT, relhasoids BOOLEAN, pglite_viewdef TEXT"),
+    ("pg_attribute", "attrelid INTEGER, attname TEXT, atttypid INTEGER, attstattarget INTEGER, attlen INTEGER, attnum INTEGER, \
+        attndims INTEGER, attcacheoff INTEGER, atttypmod INTEGER, attbyval BOOLEAN, attstorage TEXT, attalign TEXT, attnotnull BOOLEAN, \
+        atthasdef BOOLEAN, atthasmissing BOOLEAN, attidentity TEXT, attgenerated TEXT, attisdropped BOOLEAN, attislocal BOOLEAN, \
+        attinhcount INTEGER, attcollation INTEGER, attacl TEXT, attoptions TEXT, attfdwoptions TEXT, attmissingval TEXT, \
+        attcompression TEXT"),
+    ("pg_attrdef", "oid INTEGER, adrelid INTEGER, adnum INTEGER, adbin TEXT"),
+    ("pg_type", "oid INTEGER, typname TEXT, typnamespace INTEGER, typowner INTEGER, typlen INTEGER, typbyval BOOLEAN, typtype TEXT, \
+        typrelid INTEGER, typelem INTEGER, typarray INTEGER, typbasetype INTEGER, typtypmod INTEGER, typnotnull BOOLEAN, \
+        typcollation INTEGER, typdelim TEXT"),
+    ("pg_index", "indexrelid INTEGER, indrelid INTEGER, indnatts INTEGER, indnkeyatts INTEGER, indisunique BOOLEAN, \
+        indnullsnotdistinct BOOLEAN, indisprimary BOOLEAN, indisexclusion BOOLEAN, indimmediate BOOLEAN, indisclustered BOOLEAN, \
+        indisvalid BOOLEAN, indcheckxmin BOOLEAN, indisready BOOLEAN, indislive BOOLEAN, indisreplident BOOLEAN, indkey TEXT, \
+        indcollation TEXT, indclass TEXT, indoption TEXT, indexprs TEXT, indpred TEXT, pglite_indexdef TEXT"),
+    ("pg_constraint", "oid INTEGER, conname TEXT, connamespace INTEGER, contype TEXT, condeferrable BOOLEAN, condeferred BOOLEAN, \
+        convalidated BOOLEAN, conrelid INTEGER, contypid INTEGER, conindid INTEGER, conparentid INTEGER, confrelid INTEGER, \
+        confupdtype TEXT, confdeltype TEXT, confmatchtype TEXT, conislocal BOOLEAN, coninhcount INTEGER, connoinherit BOOLEAN, \
+        conkey TEXT, confkey TEXT, conbin TEXT, pglite_condef TEXT"),
+    ("pg_am", "oid INTEGER, amname TEXT, amtype TEXT"),
+    ("pg_collation", "oid INTEGER, collname TEXT, collnamespace INTEGER, collprovider TEXT"),
+    ("pg_roles", "oid INTEGER, rolname TEXT, rolsuper BOOLEAN, rolcanlogin BOOLEAN"),
+    ("pg_inherits", "inhrelid INTEGER, inhparent INTEGER, inhseqno INTEGER, inhdetachpending BOOLEAN"),
+    ("pg_trigger", "oid INTEGER, tgrelid INTEGER, tgparentid INTEGER, tgname TEXT, tgfoid INTEGER, tgtype INTEGER, tgenabled TEXT, \
+        tgisinternal BOOLEAN, tgconstrrelid INTEGER, tgconstrindid INTEGER, tgconstraint INTEGER, tgdeferrable BOOLEAN, \
+        tginitdeferred BOOLEAN, tgnargs INTEGER"),
+    ("pg_policy", "oid INTEGER, polname TEXT, polrelid INTEGER, polcmd TEXT, polpermissive BOOLEAN, polroles TEXT, polqual TEXT, \
+        polwithcheck TEXT"),
+    ("pg_statistic_ext", "oid INTEGER, stxrelid INTEGER, stxname TEXT, stxnamespace INTEGER, stxowner INTEGER, stxstattarget INTEGER, \
+        stxkeys TEXT, stxkind TEXT, stxexprs TEXT"),
+    ("pg_publication", "oid INTEGER, pubname TEXT, pubowner INTEGER, puballtables BOOLEAN, pubinsert BOOLEAN, pubupdate BOOLEAN, \
+        pubdelete BOOLEAN, pubtruncate BOOLEAN, pubviaroot BOOLEAN"),
+    ("pg_publication_rel", "oid INTEGER, prpubid INTEGER, prrelid INTEGER, prqual TEXT, prattrs TEXT"),
+    ("pg_description", "objoid INTEGER, classoid INTEGER, objsubid INTEGER, description TEXT"),
+    ("pg_rewrite", "oid INTEGER, rulename TEXT, ev_class INTEGER, ev_type TEXT, ev_enabled TEXT, is_instead BOOLEAN"),
+];
+
+/// The catalogs of the database's schema (+ the definitions of its objects), which are loaded from the `pglite_{catalog}_rows` views
+/// before a query that uses them - the rest are empty, or only have the rows they're created with
+const SCHEMA_CATALOGS: [&str; 6] = ["pg_class", "pg_attribute", "pg_attrdef", "pg_index", "pg_constraint", "definitions"];
+
+/// The functions that describe a catalog object from its oid (eg. `pg_get_indexdef(oid, 0, true)`), with the kind of object their
+/// definitions are looked up as - the lookup table has none of the catalogs' column names (nor a rowid, which SQLite also calls
+/// `oid`), so the oid given can't refer to its own
+const DEFINITION_FUNCTIONS: [(&str, &str); 3] = [("pg_get_indexdef", "index"), ("pg_get_constraintdef", "constraint"), ("pg_get_viewdef", "view")];
+
+/// The encoding number of UTF8 in Postgres
+const UTF8_ENCODING: i64 = 6;
+
+/// The oid of the bootstrap superuser, who owns every table
+const OWNER_OID: i64 = 10;
+
+/// The types in pg_type - the types columns are mapped to
+const TYPES: [PgType; 28] = [
+    PgType::BOOL, PgType::BYTEA, PgType::NAME, PgType::INT8, PgType::INT2, PgType::INT4, PgType::TEXT, PgType::OID, PgType::JSON,
+    PgType::FLOAT4, PgType::FLOAT8, PgType::MONEY, PgType::BPCHAR, PgType::VARCHAR, PgType::DATE, PgType::TIME, PgType::TIMESTAMP,
+    PgType::TIMESTAMPTZ, PgType::INTERVAL, PgType::TIMETZ, PgType::NUMERIC, PgType::UUID, PgType::JSONB, PgType::INET, PgType::CIDR,
+    PgType::INT8_ARRAY, PgType::FLOAT8_ARRAY, PgType::TEXT_ARRAY,
+];
+
+/// Create the emulated catalogs (+ the functions used to query them) on a connection
+pub fn create_tables(con:&Connection, type_map:Arc<PgLiteTypeMap>) -> Result<(), Error> {
+    // The owner of a database is stored as the user's name, so there's no user catalog to look it up in - SQLite has no owners of
+    // its tables, so they're owned by the bootstrap superuser
+    con.create_scalar_function("pg_get_userbyid", 1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        Ok(match ctx.get_raw(0) {
+            ValueRef::Integer(OWNER_OID) => Some("pglite".to_owned()),
+            _ => ctx.get::<Option<String>>(0)?,
+        })
+    })?;
+    con.create_scalar_function("pg_encoding_to_char", 1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        Ok(match ctx.get::<Option<i64>>(0)? {
+            Some(UTF8_ENCODING) => "UTF8",
+            _ => "",
+        })
+    })?;
+    // There are no arrays, so the only "array" this is ever given is a NULL datacl
+    con.create_scalar_function("array_to_string", 2, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        ctx.get::<Option<String>>(0)
+    })?;
+    // The Postgres type of a value is named after its SQLite storage class, eg. `int8` for an integer
+    con.create_scalar_function("pg_typeof", 1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        Ok(match ctx.get_raw(0).data_type() {
+            Type::Integer => "int8",
+            Type::Real => "float8",
+            Type::Text => "text",
+            Type::Blob => "bytea",
+            Type::Null => "unknown",
+        })
+    })?;
+
+    // Every table is in the one schema, and there's nothing to hide it
+    con.create_scalar_function("pg_table_is_visible", 1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |_| {
+        Ok(true)
+    })?;
+    // The type of a column is named the way Postgres does, from the type it's mapped to + any modifier in its declared type
+    con.create_scalar_function("format_type", 2, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        let Some(oid) = ctx.get::<Option<u32>>(0)? else { return Ok(None) };
+        let Some(pg_type) = PgType::from_oid(oid) else { return Ok(Some("???".to_owned())) };
+        if let Some(element_type) = array_element_type(&pg_type) {
+            return Ok(Some(format!("{}[]", standard_type_name(&element_type))));
+        }
+        Ok(Some(format!("{}{}", standard_type_name(&pg_type), type_modifier_suffix(&pg_type, ctx.get::<Option<i64>>(1)?.unwrap_or(-1)))))
+    })?;
+    con.create_scalar_function("pglite_type_oid", 2, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |ctx| {
+        let declared_type = ctx.get::<Option<String>>(0)?.unwrap_or_default();
+        Ok(type_map.lookup_column(&declared_type, ctx.get(1)?).pg_type.oid())
+    })?;
+    con.create_scalar_function("pglite_type_modifier", 1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        Ok(type_modifier(&ctx.get::<Option<String>>(0)?.unwrap_or_default()))
+    })?;
+    // The expressions (eg. a column's default) are stored as their SQL
+    con.create_scalar_function("pg_get_expr", -1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        ctx.get::<Option<String>>(0)
+    })?;
+    for function in NULL_FUNCTIONS {
+        con.create_scalar_function(function, -1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |_| {
+            Ok(None::<String>)
+        })?;
+    }
+
+    let unique_constraint_name = "r.name || '_' || (SELECT name FROM pragma_index_info(i.name) WHERE seqno = 0) || '_key'";
+    let rowid_alias = IS_ROWID_ALIAS;
+    let tables = CATALOG_TABLES.iter()
+        .map(|(catalog, columns)| format!("CREATE TEMP TABLE IF NOT EXISTS pglite_{catalog} ({columns});"))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let types = TYPES.iter().map(|pg_type| {
+        let collation = if matches!(*pg_type, PgType::TEXT | PgType::VARCHAR | PgType::BPCHAR | PgType::NAME) { 100 } else { 0 };
+        let length = type_length(pg_type);
+        let element = array_element_type(pg_type).map_or(0, |element_type| element_type.oid());
+        format!("({}, '{}', 11, {OWNER_OID}, {length}, {}, 'b', 0, {element}, 0, 0, -1, 0, {collation}, ',')", pg_type.oid(), pg_type.name(), length > 0)
+    }).collect::<Vec<String>>().join(", ");
+    con.execute_batch(&format!(r#"
+        {tables}
+
+        DELETE FROM temp.pglite_pg_namespace;
+        INSERT INTO temp.pglite_pg_namespace VALUES (11, 'pg_catalog', {OWNER_OID}, NULL), (2200, 'public', {OWNER_OID}, NULL);
+        DELETE FROM temp.pglite_pg_type;
+        INSERT INTO temp.pglite_pg_type VALUES {types};
+        DELETE FROM temp.pglite_pg_am;
+        INSERT INTO temp.pglite_pg_am VALUES (2, 'heap', 't'), (403, 'btree', 'i');
+        DELETE FROM temp.pglite_pg_collation;
+        INSERT INTO temp.pglite_pg_collation VALUES (100, 'default', 11, 'd');
+        DELETE FROM temp.pglite_pg_roles;
+        INSERT INTO temp.pglite_pg_roles VALUES ({OWNER_OID}, 'pglite', 1, 1);
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_relations AS
+        SELECT CAST(16384 + 2 * m.rowid AS INTEGER) AS oid, m.name AS name, m.type AS type, m.sql AS sql
+        FROM main.sqlite_master m
+        WHERE m.type IN ('table', 'view') AND m.name NOT LIKE 'sqlite_%';
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_indexes AS
+        SELECT CAST(coalesce(16384 + 2 * (SELECT rowid FROM main.sqlite_master WHERE type = 'index' AND name = i.name), r.oid + 1) AS INTEGER) AS oid,
+            CASE i.origin WHEN 'pk' THEN r.name || '_pkey' WHEN 'u' THEN {unique_constraint_name} ELSE i.name END AS name,
+            r.oid AS table_oid, r.name AS table_name, i.origin AS origin, i."unique" AS is_unique,
+            (SELECT group_concat(coalesce(name, 'expression'), ', ') FROM (SELECT name FROM pragma_index_info(i.name) ORDER BY seqno)) AS columns,
+            (SELECT group_concat(cid + 1, ' ') FROM (SELECT cid FROM pragma_index_info(i.name) ORDER BY seqno)) AS keys,
+            (SELECT count(*) FROM pragma_index_info(i.name)) AS key_count
+        FROM pglite_relations r JOIN pragma_index_list(r.name) i
+        WHERE r.type = 'table'
+        UNION ALL
+        SELECT m.oid + 1, m.name || '_pkey', m.oid, m.name, 'pk', 1, c.name, c.cid + 1, 1
+        FROM pglite_relations m JOIN pragma_table_info(m.name) c
+        WHERE m.type = 'table' AND {rowid_alias};
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_pg_class_rows AS
+        SELECT r.oid AS oid, r.name AS relname, 2200 AS relnamespace, 0 AS reltype, 0 AS reloftype, {OWNER_OID} AS relowner,
+            CASE r.type WHEN 'table' THEN 2 ELSE 0 END AS relam, r.oid AS relfilenode, 0 AS reltablespace, 0 AS relpages,
+            -1 AS reltuples, 0 AS relallvisible, 0 AS reltoastrelid,
+            EXISTS (SELECT 1 FROM pglite_indexes WHERE table_oid = r.oid) AS relhasindex, 0 AS relisshared, 'p' AS relpersistence,
+            CASE r.type WHEN 'view' THEN 'v' ELSE 'r' END AS relkind, (SELECT count(*) FROM pragma_table_info(r.name)) AS relnatts,
+            0 AS relchecks, r.type = 'view' AS relhasrules,
+            EXISTS (SELECT 1 FROM main.sqlite_master WHERE type = 'trigger' AND tbl_name = r.name)
+                OR EXISTS (SELECT 1 FROM pragma_foreign_key_list(r.name))
+                OR EXISTS (SELECT 1 FROM pglite_relations o JOIN pragma_foreign_key_list(o.name) f WHERE f."table" = r.name COLLATE NOCASE)
+                AS relhastriggers,
+            0 AS relhassubclass, 0 AS relrowsecurity, 0 AS relforcerowsecurity, 1 AS relispopulated,
+            CASE r.type WHEN 'view' THEN 'n' ELSE 'd' END AS relreplident, 0 AS relispartition, 0 AS relrewrite, 0 AS relfrozenxid,
+            0 AS relminmxid, NULL AS relacl, NULL AS reloptions, NULL AS relpartbound, 0 AS relhasoids,
+            CASE r.type WHEN 'view' THEN substr(r.sql, instr(upper(r.sql), ' AS ') + 4) END AS pglite_viewdef
+        FROM pglite_relations r
+        UNION ALL
+        SELECT i.oid, i.name, 2200, 0, 0, {OWNER_OID}, 403, i.oid, 0, 0, -1, 0, 0, 0, 0, 'p', 'i', i.key_count, 0, 0, 0, 0, 0, 0, 1,
+            'n', 0, 0, 0, 0, NULL, NULL, NULL, 0, NULL
+        FROM pglite_indexes i;
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_pg_attribute_rows AS
+        SELECT m.oid AS attrelid, c.name AS attname, pglite_type_oid(c.type, {rowid_alias}) AS atttypid, -1 AS attstattarget,
+            -1 AS attlen, CAST(c.cid + 1 AS INTEGER) AS attnum, 0 AS attndims, -1 AS attcacheoff,
+            pglite_type_modifier(c.type) AS atttypmod, 0 AS attbyval, 'x' AS attstorage, 'i' AS attalign,
+            (c."notnull" OR c.pk > 0) AS attnotnull, c.dflt_value IS NOT NULL AS atthasdef, 0 AS atthasmissing, '' AS attidentity,
+            '' AS attgenerated, 0 AS attisdropped, 1 AS attislocal, 0 AS attinhcount, 0 AS attcollation, NULL AS attacl,
+            NULL AS attoptions, NULL AS attfdwoptions, NULL AS attmissingval, '' AS attcompression
+        FROM pglite_relations m JOIN pragma_table_xinfo(m.name) c
+        WHERE c.hidden <> 1;
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_pg_attrdef_rows AS
+        SELECT CAST(m.oid * 10000 + c.cid + 1 AS INTEGER) AS oid, m.oid AS adrelid, CAST(c.cid + 1 AS INTEGER) AS adnum,
+            c.dflt_value AS adbin
+        FROM pglite_relations m JOIN pragma_table_info(m.name) c
+        WHERE m.type = 'table' AND c.dflt_value IS NOT NULL;
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_pg_index_rows AS
+        SELECT i.oid AS indexrelid, i.table_oid AS indrelid, i.key_count AS indnatts, i.key_count AS indnkeyatts,
+            i.is_unique AS indisunique, 0 AS indnullsnotdistinct, i.origin = 'pk' AS indisprimary, 0 AS indisexclusion,
+            1 AS indimmediate, 0 AS indisclustered, 1 AS indisvalid, 0 AS indcheckxmin, 1 AS indisready, 1 AS indislive,
+            0 AS indisreplident, i.keys AS indkey, NULL AS indcollation, NULL AS indclass, NULL AS indoption, NULL AS indexprs,
+            NULL AS indpred,
+            'CREATE ' || CASE WHEN i.is_unique THEN 'UNIQUE ' ELSE '' END || 'INDEX ' || i.name || ' ON public.' || i.table_name
+                || ' USING btree (' || i.columns || ')' AS pglite_indexdef
+        FROM pglite_indexes i;
+
+        CREATE TEMP VIEW IF NOT EXISTS pglite_pg_constraint_rows AS
+        SELECT i.oid AS oid, i.name AS conname, 2200 AS connamespace, CASE i.origin WHEN 'pk' THEN 'p' ELSE 'u' END AS contype,
+            0 AS condeferrable, 0 AS condeferred, 1 AS convalidated, i.table_oid AS conrelid, 0 AS contypid, i.oid AS conindid,
+            0 AS conparentid, 0 AS confrelid, ' ' AS confupdtype, ' ' AS confdeltype, ' ' AS confmatchtype, 1 AS conislocal,
+            0 AS coninhcount, 1 AS connoinherit, i.keys AS conkey, NULL AS confkey, NULL AS conbin,
+            CASE i.origin WHEN 'pk' THEN 'PRIMARY KEY' ELSE 'UNIQUE' END || ' (' || i.columns || ')' AS pglite_condef
+        FROM pglite_indexes i
+        WHERE i.origin IN ('pk', 'u')
+        UNION ALL
+        SELECT m.oid * 1000 + f.id + 1, m.name || '_' || f."from" || '_fkey', 2200, 'f', 0, 0, 1, m.oid, 0, 0, 0,
+            coalesce((SELECT oid FROM pglite_relations WHERE name = f."table" COLLATE NOCASE), 0),
+            {update_action}, {delete_action}, 's', 1, 0, 1,
+            (SELECT group_concat(cid, ' ') FROM (SELECT c.cid + 1 AS cid FROM pragma_foreign_key_list(m.name) k
+                JOIN pragma_table_info(m.name) c ON c.name = k."from" WHERE k.id = f.id ORDER BY k.seq)),
+            NULL, NULL,
+            'FOREIGN KEY (' || (SELECT group_concat("from", ', ') FROM (SELECT "from" FROM pragma_foreign_key_list(m.name) WHERE id = f.id ORDER BY seq))
+                || ') REFERENCES ' || f."table" || '('
+                || (SELECT group_concat(coalesce(k."to", (SELECT name FROM pragma_table_info(k."table") WHERE pk = k.seq + 1)), ', ')
+                    FROM (SELECT * FROM pragma_foreign_key_list(m.name) WHERE id = f.id ORDER BY seq) k) || ')'
+                || CASE WHEN f.on_update <> 'NO ACTION' THEN ' ON UPDATE ' || f.on_update ELSE '' END
+                || CASE WHEN f.on_delete <> 'NO ACTION' THEN ' ON DELETE ' || f.on_delete ELSE '' END
+        FROM pglite_relations m JOIN pragma_foreign_key_list(m.name) f
+        WHERE m.type = 'table' AND f.seq = 0;
+
+        CREATE TEMP TABLE IF NOT EXISTS pglite_definitions (
+            pglite_kind TEXT, pglite_object INTEGER, pglite_definition TEXT, PRIMARY KEY (pglite_kind, pglite_object)
+        ) WITHOUT ROWID;
+        CREATE TEMP VIEW IF NOT EXISTS pglite_definitions_rows AS
+        SELECT 'index', indexrelid, pglite_indexdef FROM pglite_pg_index_rows
+        UNION ALL SELECT 'constraint', oid, pglite_condef FROM pglite_pg_constraint_rows
+        UNION ALL SELECT 'view', oid, pglite_viewdef FROM pglite_pg_class_rows WHERE relkind = 'v';
+    "#, update_action = fk_action("f.on_update"), delete_action = fk_action("f.on_delete")))
+}
+
+/// Reload the catalogs of the database's schema that a (rewritten) query uses, as the schema may have changed since they were last
+/// loaded
+pub fn load_catalogs(con:&Connection, query:&str) -> Result<(), Error> {
+    for catalog in SCHEMA_CATALOGS.iter().filter(|catalog| query.contains(&format!("pglite_{catalog}"))) {
+        con.prepare_cached(&format!("DELETE FROM temp.pglite_{catalog}"))?.execute(())?;
+        con.prepare_cached(&format!("INSERT INTO temp.pglite_{catalog} SELECT * FROM temp.pglite_{catalog}_rows"))?.execute(())?;
+    }
+    Ok(())
+}
+
+/// The foreign key action code (eg. `c` for CASCADE) of a SQLite foreign key's action
+fn fk_action(column:&str) -> String {
+    format!("CASE {column} WHEN 'CASCADE' THEN 'c' WHEN 'SET NULL' THEN 'n' WHEN 'SET DEFAULT' THEN 'd' WHEN 'RESTRICT' THEN 'r' ELSE 'a' END")
+}
+
+/// The type modifier of a declared type, eg. `VARCHAR(20)` is 24 (the length + 4, like Postgres) - or -1 when it has none
+fn type_modifier(declared_type:&str) -> i64 {
+    let Some((_, modifiers)) = declared_type.split_once('(') else { return -1 };
+    let modifiers = modifiers.trim_end_matches(')').split(',').map(|m| m.trim().parse::<i64>()).collect::<Result<Vec<i64>, _>>();
+    match modifiers.as_deref() {
+        Ok([length]) => length + 4,
+        Ok([precision, scale]) => ((precision << 16) | scale) + 4,
+        _ => -1,
+    }
+}
+
+/// The type modifier of a result column, for the types that Postgres has modifiers for (eg. a `VARCHAR(50)`, but not an `INT(11)`)
+pub fn column_type_modifier(declared_type:&str, pg_type:&PgType) -> i32 {
+    match *pg_type {
+        PgType::VARCHAR | PgType::BPCHAR | PgType::NUMERIC => i32::try_from(type_modifier(declared_type)).unwrap_or(-1),
+        _ => -1,
+    }
+}
+
+/// The modifier of a type, as formatted after its name, eg. `(20)` for a varchar of 20 characters
+fn type_modifier_suffix(pg_type:&PgType, modifier:i64) -> String {
+    if modifier < 4 {
+        return String::new();
+    }
+    match *pg_type {
+        PgType::VARCHAR | PgType::BPCHAR => format!("({})", modifier - 4),
+        PgType::NUMERIC => format!("({},{})", (modifier - 4) >> 16, (modifier - 4) & 0xffff),
+        _ => String::new(),
+    }
+}
+
+/// The type of the elements of an array type, or None when it isn't an array
+fn array_element_type(pg_type:&PgType) -> Option<PgType> {
+    match *pg_type {
+        PgType::INT8_ARRAY => Some(PgType::INT8),
+        PgType::FLOAT8_ARRAY => Some(PgType::FLOAT8),
+        PgType::TEXT_ARRAY => Some(PgType::TEXT),
+        _ => None,
+    }
+}
+
+/// The size in bytes of a fixed-size type, or -1 for a variable length type
+fn type_length(pg_type:&PgType) -> i32 {
+    match *pg_type {
+        PgType::BOOL => 1,
+        PgType::INT2 => 2,
+        PgType::INT4 | PgType::OID | PgType::FLOAT4 | PgType::DATE => 4,
+        PgType::INT8 | PgType::FLOAT8 | PgType::MONEY | PgType::TIME | PgType::TIMESTAMP | PgType::TIMESTAMPTZ => 8,
+        PgType::TIMETZ => 12,
+        PgType::INTERVAL | PgType::UUID => 16,
+        PgType::NAME => 64,
+        _ => -1,
+    }
+}
+
+/// Replace the rows of the emulated pg_database with the databases (+ their owner) a client can connect to
+pub fn load_databases(con:&Connection, owner:&str, databases:&[String]) -> Result<(), Error> {
+    con.execute("DELETE FROM temp.pglite_pg_database", ())?;
+    let mut insert = con.prepare_cached("INSERT INTO temp.pglite_pg_database VALUES (?, ?, ?, ?, 'c', 0, 1, -1, 'C', 'C', NULL, NULL, NULL, NULL)")?;
+    for (idx, database) in databases.iter().enumerate() {
+        insert.execute((idx as i64 + 1, database, owner, UTF8_ENCODING))?;
+    }
+    Ok(())
+}
+
+/// Whether a query refers to pg_database, so needs the client's databases loaded - it's tokenized the same way as when the catalog
+/// is rewritten, so a mention in a string or comment, or a column of another table (eg. `t.pg_database`), isn't one
+pub fn references_pg_database(query:&str) -> bool {
+    catalog_references("pg_database").iter().any(|reference| replace_identifier(query, reference, "").is_some())
+}
+
+/// The ways a query can refer to one of the catalog tables
+fn catalog_references(catalog:&str) -> [String; 3] {
+    [format!("pg_catalog.{catalog}"), format!("\"pg_catalog\".\"{catalog}\""), catalog.to_owned()]
+}
+
+/// Point any references to the catalogs at the emulated ones, and make the rest of the query something SQLite can run (the
+/// functions it uses aren't qualified, the definitions of objects are looked up + escape strings are plain strings)
+pub fn rewrite_query(query:&str) -> Cow<'_, str> {
+    let lowercase_query = query.to_ascii_lowercase();
+    let mut names = CATALOG_TABLES.iter().map(|(catalog, _)| *catalog)
+        .chain(FUNCTIONS)
+        .chain(NULL_FUNCTIONS)
+        .chain(DEFINITION_FUNCTIONS.iter().map(|(function, _)| *function));
+    if !names.any(|name| lowercase_query.contains(name)) {
+        return Cow::Borrowed(query);
+    }
+    let mut query = Cow::Borrowed(query);
+    for (catalog, _) in CATALOG_TABLES {
+        for reference in catalog_references(catalog) {
+            if let Some(rewritten) = replace_identifier(&query, &reference, &format!("temp.pglite_{catalog}")) {
+                query = Cow::Owned(rewritten);
+            }
+        }
+    }
+    for function in FUNCTIONS.iter().chain(&NULL_FUNCTIONS).chain(DEFINITION_FUNCTIONS.iter().map(|(function, _)| function)) {
+        if let Some(rewritten) = replace_identifier(&query, &format!("pg_catalog.{function}"), function) {
+            query = Cow::Owned(rewritten);
+        }
+    }
+    for rewrite in [replace_definition_functions, replace_arrays] {
+        if let Some(rewritten) = rewrite(&query) {
+            query = Cow::Owned(rewritten);
+        }
+    }
+    match replace_escape_strings(&query) {
+        Some(rewritten) => Cow::Owned(rewritten),
+        None => query,
+    }
+}
+
+/// Replace any arrays (eg. `array['toast.' || x]` or `array(select ...)`) with NULL - every array column in the catalogs (eg.
+/// reloptions) is NULL, so an array made with them is too
+fn replace_arrays(query:&str) -> Option<String> {
+    let tokens = tokenize(query);
+    let mut rewritten = String::with_capacity(query.len());
+    let mut last_end = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        let open = tokens.get(idx + 1).map(|t| t.text);
+        if token.start < last_end || !token.text.eq_ignore_ascii_case("array") || !matches!(open, Some("(") | Some("[")) {
+            continue;
+        }
+        // An `[...]` is a single token (as SQLite's quotes of an identifier), otherwise it's up to the matching parenthesis
+        let end = match open {
+            Some("(") => {
+                let mut depth = 0;
+                tokens[idx + 1..].iter().position(|t| {
+                    depth += match t.text { "(" => 1, ")" => -1, _ => 0 };
+                    depth == 0
+                }).map(|offset| idx + 1 + offset)
+            },
+            _ => Some(idx + 1),
+        };
+        let Some(end) = end else { continue };
+        rewritten.push_str(&query[last_end..token.start]);
+        rewritten.push_str("NULL");
+        last_end = tokens[end].end();
+    }
+    match last_end {
+        0 => None,
+        _ => {
+            rewritten.push_str(&query[last_end..]);
+            Some(rewritten)
+        }
+    }
+}
+
+/// Replace the calls of the functions that describe an object (eg. `pg_get_indexdef(i.indexrelid, 0, true)`) with a lookup of its
+/// description in the catalog
+fn replace_definition_functions(query:&str) -> Option<String> {
+    let tokens = tokenize(query);
+    let mut rewritten = String::with_capacity(query.len());
+    let mut last_end = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        let Some((_, kind)) = DEFINITION_FUNCTIONS.iter().find(|(function, _)| token.text.eq_ignore_ascii_case(function)) else { continue };
+        if token.start < last_end || tokens.get(idx + 1).map(|t| t.text) != Some("(") {
+            continue;
+        }
+        // The object's oid is the first argument, up to the comma (or parenthesis) that ends it
+        let mut depth = 0;
+        let mut end = None;
+        let mut oid_end = None;
+        for (offset, t) in tokens[idx + 1..].iter().enumerate() {
+            match t.text {
+                "(" => depth += 1,
+                ")" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        oid_end.get_or_insert(idx + offset);
+                        end = Some(idx + 1 + offset);
+                        break;
+                    }
+                },
+                "," if depth == 1 => { oid_end.get_or_insert(idx + offset); },
+                _ => {},
+            }
+        }
+        let (Some(end), Some(oid_end)) = (end, oid_end.filter(|oid_end| *oid_end > idx + 1)) else { continue };
+        let oid = &query[tokens[idx + 2].start..tokens[oid_end].end()];
+        rewritten.push_str(&query[last_end..token.start]);
+        rewritten.push_str(&format!("(SELECT pglite_definition FROM temp.pglite_definitions WHERE pglite_kind = '{kind}' AND pglite_object = ({oid}))"));
+        last_end = tokens[end].end();
+    }
+    match last_end {
+        0 => None,
+        _ => {
+            rewritten.push_str(&query[last_end..]);
+            Some(rewritten)
+        }
+    }
+}
+
+/// Replace Postgres escape strings (eg. `E'\n'`) with plain SQLite strings, where the escaped characters are just part of the string
+fn replace_escape_strings(query:&str) -> Option<String> {
+    let tokens = tokenize(query);
+    let mut escape_strings = tokens.iter().filter(|token| token.text.starts_with(['E', 'e']) && token.text[1..].starts_with('\'')).peekable();
+    escape_strings.peek()?;
+    let mut rewritten = String::with_capacity(query.len());
+    let mut last_end = 0;
+    for token in escape_strings {
+        rewritten.push_str(&query[last_end..token.start]);
+        rewritten.push_str(&unescape_string(&token.text[2..]));
+        last_end = token.end();
+    }
+    rewritten.push_str(&query[last_end..]);
+    Some(rewritten)
+}
+
+/// The body of an escape string (after its opening quote) as a plain SQLite string
+fn unescape_string(body:&str) -> String {
+    let mut unescaped = String::with_capacity(body.len() + 2);
+    unescaped.push('\'');
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('r') => unescaped.push('\r'),
+                Some('t') => unescaped.push('\t'),
+                Some('\'') => unescaped.push_str("''"),
+                Some(escaped) => unescaped.push(escaped),
+                None => {},
+            },
+            '\'' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                unescaped.push_str("''");
+            },
+            '\'' => break,
+            c => unescaped.push(c),
+        }
+    }
+    unescaped.push('\'');
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_escape_strings() {
+        assert_eq!(replace_escape_strings("SELECT E'a\\nb', e'it\\'s', E'x''y'").as_deref(), Some("SELECT 'a\nb', 'it''s', 'x''y'"));
+        assert_eq!(replace_escape_strings("SELECT 'a\\nb'"), None);
+    }
+
+    #[test]
+    fn leaves_escape_strings_in_other_strings_alone() {
+        assert_eq!(replace_escape_strings("SELECT $$ E'\\n' $$, 'E''\\n''', \"E'\" -- E'\\n'"), None);
+        assert_eq!(replace_escape_strings("SELECT name'x' FROM t"), None);
+    }
+
+    #[test]
+    fn finds_references_to_pg_database() {
+        assert!(references_pg_database("SELECT datname FROM pg_database"));
+        assert!(references_pg_database("SELECT datname FROM PG_CATALOG.PG_DATABASE d"));
+        assert!(references_pg_database("SELECT datname FROM \"pg_catalog\".\"pg_database\""));
+        assert!(!references_pg_database("SELECT 'pg_database' -- pg_database"));
+        assert!(!references_pg_database("SELECT t.pg_database, pg_databases FROM t"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::borrow::Cow;
+use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
+
+use super::information_schema::replace_identifier;
+use super::tokens::{tokenize, Token};
+
+/* Follows is the support for Postgres's SERIAL columns - SQLite has no sequences, so a SERIAL primary key is created as an
+   INTEGER PRIMARY KEY AUTOINCREMENT column (an alias of the rowid, which SQLite generates), and `lastval()` is the rowid of the
+   last row the session inserted */
+
+const SERIAL_TYPES: [&str; 6] = ["SERIAL", "SERIAL4", "BIGSERIAL", "SERIAL8", "SMALLSERIAL", "SERIAL2"];
+
+/// Rewrite the SERIAL columns of a `CREATE TABLE` into SQLite's autoincrement idiom, eg. `id SERIAL PRIMARY KEY` (or `id SERIAL`
+/// with a `PRIMARY KEY (id)` table constraint) is created as `id INTEGER PRIMARY KEY AUTOINCREMENT`
+pub fn rewrite_query(query:&str) -> PgWireResult<Cow<'_, str>> {
+    if !query.to_ascii_uppercase().contains("SERIAL") {
+        return Ok(Cow::Borrowed(query));
+    }
+    match rewrite_create_table(query)? {
+        Some(rewritten) => Ok(Cow::Owned(rewritten)),
+        None => Ok(Cow::Borrowed(query)),
+    }
+}
+
+/// Replace `lastval()` with the rowid of the last row the session inserted - SQLite's `last_insert_rowid()` is the handle's, which
+/// is shared by every session (+ isn't the one a reader would answer with). Like Postgres, it's an error before the session has
+/// inserted a row
+pub fn replace_lastval(query:&str, last_insert_id:Option<i64>) -> PgWireResult<Cow<'_, str>> {
+    if !query.to_ascii_lowercase().contains("lastval") {
+        return Ok(Cow::Borrowed(query));
+    }
+    let Some(rewritten) = replace_identifier(query, "lastval()", &last_insert_id.map(|id| format!("({id})")).unwrap_or_default()) else {
+        return Ok(Cow::Borrowed(query));
+    };
+    match last_insert_id {
+        Some(_) => Ok(Cow::Owned(rewritten)),
+        None => Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "55000".to_owned(),
+            "lastval is not yet defined in this session".to_owned())))),
+    }
+}
+
+fn rewrite_create_table(query:&str) -> PgWireResult<Option<String>> {
+    let tokens = tokenize(query);
+    let Some(items) = table_elements(&tokens) else { return Ok(None) };
+
+    // The SERIAL columns (by their item), and the single column of the table's PRIMARY KEY constraint (if it has one)
+    let serial_columns = items.iter().enumerate()
+        .filter(|(_, item)| !is_table_constraint(item) && item.len() > 1 && SERIAL_TYPES.iter().any(|t| item[1].text.eq_ignore_ascii_case(t)))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<usize>>();
+    if serial_columns.is_empty() {
+        return Ok(None);
+    }
+    let primary_key = items.iter().enumerate().find_map(|(idx, item)| Some((idx, primary_key_columns(item)?)));
+
+    // The edits are made from the end of the query backwards, so the earlier offsets stay the same
+    let mut edits:Vec<(usize, usize, &str)> = Vec::new();
+    for idx in serial_columns {
+        let item = &items[idx];
+        let column = unquote(item[0].text);
+        let inline_key = find_keyword(item, &["PRIMARY", "KEY"]);
+        // The item of the table's PRIMARY KEY constraint, when it's just this column
+        let table_key = primary_key.as_ref()
+            .filter(|(_, columns)| columns.len() == 1 && columns[0].eq_ignore_ascii_case(&column))
+            .map(|(key_idx, _)| *key_idx);
+        if inline_key.is_none() && table_key.is_none() {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "0A000".to_owned(),
+                format!("SERIAL columns are only supported as the table's primary key, eg. \"{column} SERIAL PRIMARY KEY\"")))));
+        }
+
+        edits.push((item[1].start, item[1].end(), "INTEGER"));
+        let autoincrement = find_keyword(item, &["AUTOINCREMENT"]).is_none();
+        match (inline_key, table_key) {
+            // AUTOINCREMENT follows the key's sort order + conflict clause, eg. `PRIMARY KEY ASC ON CONFLICT FAIL AUTOINCREMENT`
+            (Some(key), _) if autoincrement => {
+                let mut end = key;
+                if item.get(end + 1).is_some_and(|t| t.text.eq_ignore_ascii_case("ASC") || t.text.eq_ignore_ascii_case("DESC")) {
+                    end += 1;
+                }
+                if item.get(end + 1).is_some_and(|t| t.text.eq_ignore_ascii_case("ON")) {
+                    end = (end + 3).min(item.len() - 1);
+                }
+                edits.push((item[end].end(), item[end].end(), " AUTOINCREMENT"));
+            },
+            (Some(_), _) | (None, None) => {},
+            // The table's constraint is moved onto the column, as only a column's own PRIMARY KEY can be AUTOINCREMENT
+            (None, Some(key_idx)) => {
+                let last = &item[item.len() - 1];
+                edits.push((last.end(), last.end(), " PRIMARY KEY AUTOINCREMENT"));
+                // Along with the comma that separates it from the item before it (or after it, when it's the first)
+                let key_item = &items[key_idx];
+                let (key_start, key_end) = match key_idx {
+                    0 => (key_item[0].start, items[1][0].start),
+                    _ => (items[key_idx - 1][items[key_idx - 1].len() - 1].end(), key_item[key_item.len() - 1].end()),
+                };
+                edits.push((key_start, key_end, ""));
+            },
+        }
+    }
+
+    edits.sort_by_key(|(start, end, _)| (*start, *end));
+    let mut rewritten = query.to_owned();
+    for (start, end, replacement) in edits.into_iter().rev() {
+        rewritten.replace_range(start..end, replacement);
+    }
+    debug!("Rewrote the SERIAL columns of: {}, as: {}", query, rewritten);
+    Ok(Some(rewritten))
+}
+
+/// The column definitions + table constraints of a `CREATE [TEMP] TABLE [IF NOT EXISTS] name (...)` - None for any other statement
+/// (including `CREATE TABLE ... AS SELECT`)
+fn table_elements<'t, 'a>(tokens:&'t [Token<'a>]) -> Option<Vec<&'t [Token<'a>]>> {
+    let keyword = |idx:usize, word:&str| tokens.get(idx).is_some_and(|t| t.text.eq_ignore_ascii_case(word));
+    if !keyword(0, "CREATE") {
+        return None;
+    }
+    let mut idx = 1;
+    if keyword(idx, "TEMP") || keyword(idx, "TEMPORARY") {
+        idx += 1;
+    }
+    if !keyword(idx, "TABLE") {
+        return None;
+    }
+    idx += 1;
+    if keyword(idx, "IF") && keyword(idx + 1, "NOT") && keyword(idx + 2, "EXISTS") {
+        idx += 3;
+    }
+    // The table's name, which may be qualified by its schema
+    idx += match tokens.get(idx + 1).is_some_and(|t| t.text == ".") {
+        true => 3,
+        false => 1,
+    };
+    let open = idx;
+    if tokens.get(open)?.text != "(" {
+        return None;
+    }
+
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut item_start = open + 1;
+    for (idx, token) in tokens.iter().enumerate().skip(open) {
+        match token.text {
+            "(" => depth += 1,
+            ")" => {
+                depth -= 1;
+                if depth == 0 {
+                    items.push(&tokens[item_start..idx]);
+                    return Some(items.into_iter().filter(|item| !item.is_empty()).collect());
+                }
+            },
+            "," if depth == 1 => {
+                items.push(&tokens[item_start..idx]);
+                item_start = idx + 1;
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+fn is_table_constraint(item:&[Token]) -> bool {
+    item.first().is_some_and(|t| ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"].iter().any(|word| t.text.eq_ignore_ascii_case(word)))
+}
+
+/// The columns of a `[CONSTRAINT name] PRIMARY KEY (columns)` table constraint
+fn primary_key_columns(item:&[Token]) -> Option<Vec<String>> {
+    if !is_table_constraint(item) {
+        return None;
+    }
+    let key = find_keyword(item, &["PRIMARY", "KEY"])?;
+    let columns = item.get(key + 1..)?;
+    if columns.first()?.text != "(" {
+        return None;
+    }
+    Some(columns[1..].iter()
+        .take_while(|t| t.text != ")")
+        .filter(|t| t.text != ",")
+        .map(|t| unquote(t.text))
+        .collect())
+}
+
+/// The index of the last token of a sequence of keywords in an item (eg. `PRIMARY KEY`)
+fn find_keyword(item:&[Token], keywords:&[&str]) -> Option<usize> {
+    item.windows(keywords.len())
+        .position(|window| window.iter().zip(keywords).all(|(t, keyword)| t.text.eq_ignore_ascii_case(keyword)))
+        .map(|idx| idx + keywords.len() - 1)
+}
+
+fn unquote(identifier:&str) -> String {
+    match identifier.chars().next() {
+        Some('"') | Some('`') | Some('[') => identifier[1..identifier.len() - 1].to_owned(),
+        _ => identifier.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_lastval_with_the_sessions_last_insert_id() {
+        assert_eq!(replace_lastval("SELECT lastval(), 'lastval()'", Some(7)).unwrap(), "SELECT (7), 'lastval()'");
+        assert_eq!(replace_lastval("SELECT 1 - LASTVAL()", Some(-2)).unwrap(), "SELECT 1 - (-2)");
+        assert!(matches!(replace_lastval("SELECT my_lastval()", None).unwrap(), Cow::Borrowed(_)));
+        let Err(PgWireError::UserError(err)) = replace_lastval("SELECT lastval()", None) else { panic!("lastval() should be undefined") };
+        assert_eq!(err.code(), "55000");
+    }
+}
@@ -1,37 +1,384 @@
-use std::{path::PathBuf, sync::{Arc, RwLock}, collections::HashMap, time::Duration};
+use std::{ffi::{c_char, CStr, CString}, path::{Component, Path, PathBuf}, sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, Ordering}}, collections::{HashMap, HashSet, VecDeque}, time::{Duration, SystemTime}, borrow::Cow, cell::{Cell, RefCell}};
 
-use crossbeam_channel::RecvTimeoutError;
+use crossbeam_channel::{RecvTimeoutError, Sender};
 use pgwire::error::{PgWireResult, PgWireError, ErrorInfo};
-use rusqlite::{Connection, Error, Rows, types::{Value, Type}, Statement, ToSql};
+use rusqlite::{ffi, Connection, CachedStatement, InterruptHandle, Error, ErrorCode, LoadExtensionGuard, OpenFlags, Rows, types::Value, Statement, ToSql, limits::Limit};
+use rusqlite::hooks::{Action, AuthAction, AuthContext, Authorization};
 use tokio::task::spawn_blocking;
 
-use crate::{config::PgLiteConfig, backend::{PgLiteDBResponse, MessageType}};
-use super::{PgLitebackendFactory, PgLiteDBBackend, PgLiteDBMessage, BackendConnection, Field, Record, PgLiteDBParam};
+use crate::{config::{PgLiteConfig, SqliteJournalMode, SqliteSynchronous, TypeInference}, backend::{PgLiteDBResponse, MessageType, PgLiteBackendType}, session::format_utc_timestamp, telemetry};
+use super::{PgLitebackendFactory, PgLiteDBBackend, PgLiteDBMessage, BackendConnection, Field, Record, PgLiteDBParam, PgLiteSessionOptions, AttachedDatabase, DatabaseList, database_info_schema, strip_leading_comments};
+use super::types::PgLiteTypeMap;
+use super::{compat, information_schema, pg_catalog, serial};
 
 pub struct SimplePgLiteDBBackend {
-    con:Connection
+    con:Connection,
+    type_map:Arc<PgLiteTypeMap>,
+    information_schema:bool,
+    result_batch_size:usize,
+    /// The schema version the cached statements were prepared against
+    schema_version:Cell<i64>,
+    /// The (lowercase) table + column names of the INTEGER PRIMARY KEY columns, when they have their own type mapping
+    rowid_aliases:RefCell<HashSet<(String, String)>>,
+    /// The indexes of the result columns that come straight from an INTEGER PRIMARY KEY column, by the SQL of the statement
+    rowid_alias_columns:RefCell<HashMap<String, Vec<usize>>>,
+    /// The name of the database, for the statement cache metrics
+    database:String,
+    statement_cache:RefCell<StatementCacheStats>,
+    in_memory:bool,
+    /// Whether the handle was opened read-only, so every write statement is rejected
+    read_only:bool,
+    /// Interrupts the statement the handle is running, when its client cancels it
+    interrupt:Arc<InterruptHandle>,
+    /// The notices for the client of the statement being run, which are sent along with its response
+    notices:RefCell<Vec<ErrorInfo>>,
+    /// Whether the statement being run has stepped to a row - once it has, it's too late to retry it after a schema change
+    stepped:Cell<bool>,
+    /// The rowid of the last row inserted by the statement being run (set by the handle's update hook), for its session's lastval()
+    last_insert_id:Arc<Mutex<Option<i64>>>,
+}
+
+/// The hits + misses of the prepared statement cache - rusqlite doesn't say whether a statement came from its cache, so this keeps
+/// a copy of the cache's keys (the SQL, least recently used first) to tell
+struct StatementCacheStats {
+    capacity:usize,
+    statements:VecDeque<String>,
+    hits:u64,
+    misses:u64,
+}
+
+impl StatementCacheStats {
+    fn new(capacity:usize) -> Self {
+        Self { capacity, statements:VecDeque::with_capacity(capacity), hits:0, misses:0 }
+    }
+
+    /// Whether the statement for the SQL is cached - like the cache, it becomes the most recently used (evicting the least recently
+    /// used, when it wasn't cached and the cache is full)
+    fn lookup(&mut self, sql:&str) -> bool {
+        let sql = sql.trim();
+        let cached = match self.statements.iter().position(|statement| statement == sql) {
+            Some(idx) => self.statements.remove(idx).is_some(),
+            None => false,
+        };
+        if self.capacity > 0 {
+            self.statements.push_back(sql.to_owned());
+            if self.statements.len() > self.capacity {
+                self.statements.pop_front();
+            }
+        }
+        cached
+    }
+
+    /// Count whether a client's statement came from the cache
+    fn record(&mut self, sql:&str) -> bool {
+        let hit = self.lookup(sql);
+        match hit {
+            true => self.hits += 1,
+            false => self.misses += 1,
+        }
+        hit
+    }
+}
+
+/// The SQLite settings applied to each database handle when it's opened
+#[derive(Debug, Clone)]
+pub struct SqlitePragmas {
+    pub journal_mode:Option<SqliteJournalMode>,
+    pub synchronous:Option<SqliteSynchronous>,
+    /// How long to wait for a lock held by another process, before giving up
+    pub busy_timeout:Duration,
+    /// The loadable extensions loaded into the handle (`--sqlite-extensions`)
+    pub extensions:Vec<PathBuf>,
+    /// The most bind parameters a statement can have, if it's been set (`--sqlite-max-variables`)
+    pub max_variables:Option<i32>,
+}
+
+impl SqlitePragmas {
+    /// Apply the settings to a newly opened handle - the journal mode is stored in the database, so a read-only handle can't change it
+    fn apply(&self, con:&Connection, read_only:bool) -> Result<(), Error> {
+        con.busy_timeout(self.busy_timeout)?;
+        if !self.extensions.is_empty() {
+            // Loading is only enabled while the configured extensions are loaded, so the clients' SQL can't load any others (with
+            // SQLite's `load_extension()`) - which extensions are loaded is checked when the server starts
+            unsafe {
+                let _guard = LoadExtensionGuard::new(con)?;
+                for extension in &self.extensions {
+                    con.load_extension(extension, None)?;
+                }
+            }
+        }
+        if let Some(journal_mode) = self.journal_mode.filter(|_| !read_only) {
+            // SQLite keeps the mode it's in when the new one can't be used (eg. WAL for an in-memory database), so it's only a warning
+            let mode = con.pragma_update_and_check(None, "journal_mode", journal_mode.name(), |row| row.get::<_, String>(0))?;
+            if !mode.eq_ignore_ascii_case(journal_mode.name()) {
+                warn!("Unable to set the journal mode of the database to {}, it's using: {}", journal_mode.name(), mode);
+            }
+        }
+        if let Some(synchronous) = self.synchronous {
+            con.pragma_update(None, "synchronous", synchronous.name())?;
+        }
+        if let Some(max_variables) = self.max_variables {
+            // SQLite silently lowers a limit to the maximum it was compiled with, so it's read back to tell whether it was
+            con.set_limit(Limit::SQLITE_LIMIT_VARIABLE_NUMBER, max_variables);
+            let limit = con.limit(Limit::SQLITE_LIMIT_VARIABLE_NUMBER);
+            if limit != max_variables {
+                warn!("Unable to raise the maximum bind parameters of a statement to {}, it's limited to SQLite's compile-time maximum: {}", max_variables, limit);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How deep into the sub-directories of a user's directory to look for databases, when listing them
+const MAX_LISTING_DEPTH: usize = 8;
+
+/// The statements pglite runs (from the statement cache) before + after the client's statements
+const SCHEMA_VERSION: &str = "PRAGMA schema_version";
+const TOTAL_CHANGES: &str = "SELECT total_changes()";
+
+/// The directory of SQLite's memdb VFS that the in-memory databases are named within - a database is shared by all of the handles
+/// to it within the process, and freed when the last of them is closed
+const MEMORY_DB_ROOT: &str = "/pglite";
+
+/// The header every SQLite database file starts with
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// A database that has an open handle in the cache, for reporting on it (eg. from an admin endpoint)
+#[derive(Debug, Clone)]
+pub struct CachedDatabase {
+    /// The database's path (with its session options, eg. `?readonly` for a read-only handle)
+    pub name:String,
+    /// When the handle last handled a message (or was opened)
+    pub last_access:SystemTime,
+    /// How long the handle can be idle before it's released
+    pub idle_timeout:Duration,
 }
 
 type BackendMap = HashMap<String, BackendConnection>;
 pub struct SimplePgLiteDBBackendFactory { 
     db_root:PathBuf,
     db_idle_timeout:Duration,
+    /// The idle timeouts of the databases whose path starts with a prefix, longest prefix first
+    db_idle_timeout_overrides:Vec<(String, Duration)>,
+    allow_session_options:bool,
+    per_user_root:bool,
+    db_template:Option<PathBuf>,
+    /// Whether a database file that doesn't exist yet is created when it's connected to
+    create_if_missing:bool,
+    type_map:Arc<PgLiteTypeMap>,
+    information_schema:bool,
+    result_batch_size:usize,
+    backend_queue_size:usize,
+    statement_cache_size:usize,
+    /// Whether the databases are held in memory (`--backend memory`), rather than in files under the database root
+    in_memory:bool,
+    /// Whether every database is opened read-only (`--read-only`), whatever the session options
+    read_only:bool,
+    pragmas:SqlitePragmas,
+    /// The number of read-only handles each database gets, for running SELECTs alongside its main handle (0 for none)
+    db_readers:usize,
     db_cache: Arc<RwLock<BackendMap>>
 }
 
 impl SimplePgLiteDBBackendFactory {
-    pub fn new(config:&PgLiteConfig) -> Self {
-        Self { 
-            db_root: PathBuf::from(config.db_root.clone()), 
+    pub fn new(config:&PgLiteConfig) -> Result<Self, String> {
+        let in_memory = config.backend == PgLiteBackendType::InMemoryPgLiteDBBackend;
+        if !in_memory {
+            check_db_root(&config.db_root)?;
+        }
+        check_extensions(config)?;
+        let mut db_idle_timeout_overrides = config.db_idle_timeout_overrides.iter()
+            .map(|rule| (rule.prefix.clone(), Duration::from_secs(rule.timeout)))
+            .collect::<Vec<_>>();
+        db_idle_timeout_overrides.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Ok(Self { 
+            db_root: config.db_root.clone(), 
             db_idle_timeout:Duration::from_secs(config.db_idle_timeout), 
+            db_idle_timeout_overrides,
+            allow_session_options: config.allow_session_options,
+            per_user_root: config.per_user_root,
+            db_template: config.db_template.clone(),
+            create_if_missing: config.create_if_missing || config.db_template.is_some(),
+            type_map: Arc::new(PgLiteTypeMap::new(&config.type_mappings, config.int4_primary_keys, config.type_inference == TypeInference::Value)),
+            information_schema: config.information_schema,
+            result_batch_size: config.result_batch_size as usize,
+            backend_queue_size: config.backend_queue_size as usize,
+            statement_cache_size: config.statement_cache_size,
+            in_memory,
+            read_only: config.read_only,
+            pragmas: SqlitePragmas {
+                journal_mode: config.sqlite_journal_mode,
+                synchronous: config.sqlite_synchronous,
+                busy_timeout: Duration::from_millis(config.sqlite_busy_timeout),
+                extensions: config.sqlite_extensions.clone(),
+                max_variables: config.sqlite_max_variables,
+            },
+            db_readers: config.db_readers as usize,
             db_cache: Arc::new(RwLock::new(HashMap::with_capacity(100))) 
+        })
+    }
+
+    /// The number of databases with an open handle
+    pub fn cache_size(&self) -> usize {
+        self.db_cache.read().map(|cache| cache.len()).unwrap_or_default()
+    }
+
+    /// The databases with an open handle, with when each was last used - sorted by name
+    pub fn cached_databases(&self) -> Vec<CachedDatabase> {
+        let Ok(cache) = self.db_cache.read() else { return Vec::new() };
+        let mut databases = cache.iter()
+            .map(|(name, backend)| CachedDatabase {
+                name: name.clone(),
+                last_access: *backend.last_access.lock().unwrap(),
+                idle_timeout: self.idle_timeout(Path::new(name.split_once('?').map_or(name.as_str(), |(path, _)| path))),
+            })
+            .collect::<Vec<_>>();
+        databases.sort_by(|a, b| a.name.cmp(&b.name));
+        databases
+    }
+
+    /// The idle timeout of a database - the override with the longest prefix of its path (relative to the database root) if there
+    /// is one, otherwise `--db-idle-timeout`
+    fn idle_timeout(&self, db_path:&Path) -> Duration {
+        if self.db_idle_timeout_overrides.is_empty() {
+            return self.db_idle_timeout;
+        }
+        // With per-user roots the path has been canonicalised, so it may be relative to the canonical database root instead
+        let relative = db_path.strip_prefix(&self.db_root).ok().map(Path::to_path_buf)
+            .or_else(|| db_path.strip_prefix(self.db_root.canonicalize().ok()?).ok().map(Path::to_path_buf))
+            .unwrap_or_else(|| db_path.to_path_buf());
+        let relative = relative.to_string_lossy();
+        self.db_idle_timeout_overrides.iter()
+            .find(|(prefix, _)| relative.starts_with(prefix.as_str()))
+            .map(|(_, timeout)| *timeout)
+            .unwrap_or(self.db_idle_timeout)
+    }
+
+    /// Resolve the path of the requested database + any databases attached to it, which must resolve to somewhere within the
+    /// database root - or with per-user roots, within `{db_root}/{user}`
+    fn resolve_db_path(&self, metadata:&HashMap<String, String>) -> PgWireResult<(PathBuf, PgLiteSessionOptions)> {
+        let (db_path, mut session_options) = self.resolve_requested_path(metadata)?;
+        if session_options.attached.is_empty() {
+            return Ok((db_path, session_options));
+        }
+        // An attached database is named the same way as the session's database (eg. relative to the user's directory), so it's
+        // resolved by swapping it in for the session's database - the authenticator's path ends with the name the client sent
+        let database = metadata.get("database").map(String::as_str).unwrap_or_default();
+        let dbpath = metadata.get("dbpath").map(String::as_str).unwrap_or_default();
+        let dbpath_prefix = dbpath.strip_suffix(database).unwrap_or_default();
+        for attached in session_options.attached.iter_mut() {
+            let name = attached.path.to_string_lossy().to_string();
+            let mut attached_metadata = metadata.clone();
+            attached_metadata.insert("database".to_owned(), name.clone());
+            attached_metadata.insert("dbpath".to_owned(), format!("{dbpath_prefix}{name}"));
+            let (path, _) = self.resolve_requested_path(&attached_metadata)?;
+            // Attaching a database that doesn't exist would create it, which only connecting to a database can do
+            if !self.in_memory && !path.exists() {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "FATAL".to_owned(), "3D000".to_owned(), format!("database \"{name}\" does not exist, so can't be attached"),
+                ))));
+            }
+            attached.path = path;
+        }
+        Ok((db_path, session_options))
+    }
+
+    fn resolve_requested_path(&self, metadata:&HashMap<String, String>) -> PgWireResult<(PathBuf, PgLiteSessionOptions)> {
+        if self.in_memory {
+            return self.resolve_memory_db_path(metadata);
         }
+        if !self.per_user_root {
+            // The authenticator's path is built from the user + database names the client sent, so it can't be trusted either
+            let (dbpath, session_options) = PgLiteSessionOptions::parse(metadata.get("dbpath").map(String::as_str).unwrap_or("blackhole"))?;
+            let db_root = self.db_root.canonicalize().map_err(|_| PgWireError::UserError(Box::new(ErrorInfo::new(
+                "FATAL".to_owned(), "3D000".to_owned(), "The database root doesn't exist".to_owned(),
+            ))))?;
+            return Ok((resolve_within_root(&db_root, dbpath)?, session_options));
+        }
+
+        let user = metadata.get("user").map(String::as_str).unwrap_or_default();
+        let (database, session_options) = PgLiteSessionOptions::parse(metadata.get("database").map(String::as_str).unwrap_or_default())?;
+        if !matches!(Path::new(user).components().collect::<Vec<Component>>()[..], [Component::Normal(_)]) {
+            return Err(outside_user_root());
+        }
+        let user_root = self.db_root.join(user).canonicalize().map_err(|_| PgWireError::UserError(Box::new(ErrorInfo::new(
+            "FATAL".to_owned(), "3D000".to_owned(), format!("There is no database root for user \"{user}\""),
+        ))))?;
+        Ok((resolve_within_root(&user_root, database)?, session_options))
     }
 
-    fn spawn_backend_connection(&self, db_path:PathBuf) -> BackendConnection  {
-        let (tx, rx) = crossbeam_channel::unbounded::<PgLiteDBMessage>();
-        let backend_conn: BackendConnection = BackendConnection{ sender:tx };
-        let db_path_string = db_path.to_string_lossy().to_string();
+    /// In-memory databases have no files to resolve, so they're named by the database path (which can't use `..` or be absolute,
+    /// like a file's) - with per-user roots, always the user's name followed by the requested database (which can't use `..` to
+    /// name another user's database)
+    fn resolve_memory_db_path(&self, metadata:&HashMap<String, String>) -> PgWireResult<(PathBuf, PgLiteSessionOptions)> {
+        if !self.per_user_root {
+            let (dbpath, session_options) = PgLiteSessionOptions::parse(metadata.get("dbpath").map(String::as_str).unwrap_or("blackhole"))?;
+            return match is_relative_path(dbpath) {
+                true => Ok((PathBuf::from(dbpath), session_options)),
+                false => Err(outside_user_root()),
+            };
+        }
+
+        let user = metadata.get("user").map(String::as_str).unwrap_or_default();
+        let (database, session_options) = PgLiteSessionOptions::parse(metadata.get("database").map(String::as_str).unwrap_or_default())?;
+        let is_single_dir = matches!(Path::new(user).components().collect::<Vec<Component>>()[..], [Component::Normal(_)]);
+        match is_single_dir && is_relative_path(database) {
+            true => Ok((Path::new(user).join(database), session_options)),
+            false => Err(outside_user_root()),
+        }
+    }
+
+    /// Find the SQLite databases under a user's directory (+ its sub-directories), named by their path relative to it - hidden
+    /// files are skipped, and with per-user roots so is anything that resolves outside of the user's root
+    fn find_databases(&self, user_root:&Path, dir:&Path, depth:usize, databases:&mut Vec<String>) {
+        let Ok(entries) = std::fs::read_dir(dir) else { return };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if self.per_user_root && !path.canonicalize().is_ok_and(|path| path.starts_with(user_root)) {
+                continue;
+            }
+            if path.is_dir() {
+                if depth < MAX_LISTING_DEPTH {
+                    self.find_databases(user_root, &path, depth + 1, databases);
+                }
+            } else if is_sqlite_file(&path) {
+                if let Ok(name) = path.strip_prefix(user_root) {
+                    databases.push(name.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"));
+                }
+            }
+        }
+    }
+
+    fn cache_key(db_path:&Path, session_options:&PgLiteSessionOptions) -> String {
+        // Sessions with different options need their own DB handle, as the options are applied when the handle is opened
+        let mut options = Vec::with_capacity(1 + session_options.attached.len());
+        if session_options.read_only {
+            options.push("readonly".to_owned());
+        }
+        options.extend(session_options.attached.iter().map(|attached| format!("attach:{}={}", attached.alias, attached.path.to_string_lossy())));
+        match options.is_empty() {
+            true => db_path.to_string_lossy().to_string(),
+            false => format!("{}?{}", db_path.to_string_lossy(), options.join("&")),
+        }
+    }
+
+    fn spawn_backend_connection(&self, db_path:PathBuf, session_options:PgLiteSessionOptions) -> BackendConnection  {
+        let (tx, rx) = crossbeam_channel::bounded::<PgLiteDBMessage>(self.backend_queue_size);
+        // With readers, SELECTs outside of a transaction block go to a queue of their own, that any idle reader takes them from
+        let (reader_tx, reader_rx) = match self.db_readers {
+            0 => (None, None),
+            _ => {
+                let (reader_tx, reader_rx) = crossbeam_channel::bounded::<PgLiteDBMessage>(self.backend_queue_size);
+                (Some(reader_tx), Some(reader_rx))
+            }
+        };
+        let backend_conn: BackendConnection = BackendConnection{ sender:tx, readers:reader_tx, pins:Arc::default(), last_access:Arc::new(Mutex::new(SystemTime::now())) };
+        let pins = backend_conn.pins.clone();
+        let last_access = backend_conn.last_access.clone();
+        let db_path_string = Self::cache_key(&db_path, &session_options);
 
         // Add the DB Connection (aka. the channel for sending messages to the backend) to the cache - for later use...
         {
@@ -45,42 +392,82 @@ impl SimplePgLiteDBBackendFactory {
 
         // Spawn a thread to handle queries into this DB
         let cache_ref = self.db_cache.clone();
-        let idle_timeout = self.db_idle_timeout.clone();
+        let idle_timeout = self.idle_timeout(&db_path);
+        let type_map = self.type_map.clone();
+        let information_schema = self.information_schema;
+        let result_batch_size = self.result_batch_size;
+        let statement_cache_size = self.statement_cache_size;
+        let db_template = self.db_template.clone().filter(|_| !self.in_memory && !session_options.read_only);
+        let in_memory = self.in_memory;
+        let pragmas = self.pragmas.clone();
+        let db_readers = self.db_readers;
         spawn_blocking(move || {
-            let backend: SimplePgLiteDBBackend = SimplePgLiteDBBackend::open(db_path).unwrap();
+            let opened = create_from_template(&db_path, db_template.as_deref())
+                .map_err(|err| format!("Unable to create the database from the template: {err}"))
+                .and_then(|_| SimplePgLiteDBBackend::open(db_path.clone(), session_options.read_only, &session_options.attached, in_memory, &pragmas, type_map.clone(), information_schema, result_batch_size, statement_cache_size)
+                    .map_err(|err| format!("Unable to open the database: {err}")));
+            let backend: SimplePgLiteDBBackend = match opened {
+                Ok(backend) => backend,
+                Err(reason) => {
+                    // Remove the database from the cache (so the next connection retries), and let anyone waiting know why
+                    error!("[{}] Failed to open the DB Handle, Error: {}", &db_path_string, reason);
+                    cache_ref.write().unwrap().remove(&db_path_string);
+                    for message in rx.try_iter().chain(reader_rx.iter().flat_map(|reader_rx| reader_rx.try_iter())) {
+                        let error = PgWireError::UserError(Box::new(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), reason.clone())));
+                        let _ = message.respond.send(PgLiteDBResponse{ result_schema:None, result:None, more_records:None, affected_rows:None, error:Some(error), in_transaction:false, notices:Vec::new(), last_insert_id:None });
+                    }
+                    return;
+                }
+            };
             trace!("[{}] Opened new DB Handle", &db_path_string);
 
-            // Loop + handle messages endlessly until the the IDLE timeout has passed (or the sending stream is closed, which shouldn't happen :p)...
+            // The readers are only opened once the database exists (eg. it's been created from the template), they run until the
+            // pool's senders are all gone - each time one handles a message it counts as the pool being used, for the idle timeout
+            let reads_handled = Arc::new(AtomicBool::new(false));
+            if let Some(reader_rx) = reader_rx {
+                for reader in 0..db_readers {
+                    let (db_path, type_map, pragmas, reader_rx, reads_handled, last_access) = (db_path.clone(), type_map.clone(), pragmas.clone(), reader_rx.clone(), reads_handled.clone(), last_access.clone());
+                    let attached = session_options.attached.clone();
+                    let reader_name = format!("{db_path_string}#reader{reader}");
+                    spawn_blocking(move || {
+                        let backend = match SimplePgLiteDBBackend::open(db_path, true, &attached, in_memory, &pragmas, type_map, information_schema, result_batch_size, statement_cache_size) {
+                            Ok(backend) => backend,
+                            Err(err) => {
+                                // Once there are no readers left, their messages go to the database's main handle instead
+                                error!("[{}] Failed to open the DB Handle, Error: {}", &reader_name, err);
+                                return;
+                            }
+                        };
+                        for message in reader_rx.iter() {
+                            reads_handled.store(true, Ordering::Relaxed);
+                            *last_access.lock().unwrap() = SystemTime::now();
+                            handle_message(&backend, message, &reader_name);
+                        }
+                        trace!("[{}] Closing the reader's database handle", &reader_name);
+                    });
+                }
+            }
+
+            // Loop + handle messages endlessly until the the IDLE timeout has passed (or the sending stream is closed, eg. when the server is shutting down)...
             loop {
                 let message = match rx.recv_timeout(idle_timeout) {
                     Ok(msg) => msg,
-                    Err(RecvTimeoutError::Timeout) => { break; /* DB hasn't been used for the IDLE timeout period, so exit */ }, 
-                    Err(RecvTimeoutError::Disconnected) => { break; /* Connection to the DB was lost for some reason?! So exit */ }
-                };
-
-                trace!("[{}] Handling {:#?} Message with query: {:#?}", &db_path_string, &message.message_type, &message.query);
-                let result = match message.message_type {
-                    MessageType::SimpleQuery => backend.query(message.query.as_str()), 
-                    MessageType::QueryWithParams => backend.query_with_params(&message.query.as_str(), message.params.unwrap_or_default()),
-                    MessageType::Describe => { backend.describe_query(message.query.as_str()) }, 
-                };
-                
-                match result {
-                    Ok(res) => {
-                        if message.respond.send(res).is_err() {
-                            trace!("[{}] Unable to send response to client - it's been disconnected...", &db_path_string);
-                        }
-                    }, 
-                    Err(err) => {
-                        if message.respond.send(PgLiteDBResponse{ result_schema:None, result:None, error:Some(err) }).is_err() {
-                            trace!("[{}] Unable to send an error response to client - it's been disconnected...", &db_path_string);
-                        }
+                    Err(RecvTimeoutError::Timeout) if reads_handled.swap(false, Ordering::Relaxed) => { continue; /* The readers have been used, so the DB isn't idle */ },
+                    Err(RecvTimeoutError::Timeout) if pins.load(Ordering::Relaxed) > 0 => { continue; /* Connected clients are pinned to the DB handle, so keep it */ },
+                    Err(RecvTimeoutError::Timeout) => {
+                        info!("[{}] Closing the database handle - it hasn't been used for its idle timeout ({}s)", &db_path_string, idle_timeout.as_secs());
+                        break;
+                    },
+                    Err(RecvTimeoutError::Disconnected) => {
+                        debug!("[{}] Closing the database handle - it's been removed from the cache", &db_path_string);
+                        break;
                     }
-                }
+                };
+                *last_access.lock().unwrap() = SystemTime::now();
+                handle_message(&backend, message, &db_path_string);
             }
 
             // Remove the database from the cache
-            debug!("[{}] Closing the database handle - it hasn't been used for the IDLE timeout period", &db_path_string);
             cache_ref.write().unwrap().remove(&db_path_string);
 
             // Finally, close the handle to the database
@@ -93,68 +480,336 @@ impl SimplePgLiteDBBackendFactory {
     }
 }
 
+/// Handle a message for a database, sending its response (or error) to the client that sent it
+fn handle_message(backend:&SimplePgLiteDBBackend, message:PgLiteDBMessage, db_path_string:&str) {
+    trace!("[{}] Handling {:#?} Message with query: {:#?}", db_path_string, &message.message_type, &message.query);
+    // Only the rows inserted by the message's own statement count for its session's lastval()
+    backend.take_last_insert_id();
+    // A query of pg_database lists the databases of the client that sent it
+    if let Some(databases) = &message.databases {
+        if let Err(err) = backend.load_databases(databases) {
+            send_response(&message.respond, PgLiteDBResponse{ result_schema:None, result:None, more_records:None, affected_rows:None, error:Some(err), in_transaction:backend.in_transaction(), notices:backend.take_notices(), last_insert_id:None });
+            return;
+        }
+    }
+    // While the statement is running, its client can interrupt the handle to cancel it
+    let cancel_token = message.cancel_token.clone();
+    if let Some(cancel_token) = &cancel_token {
+        cancel_token.running(backend.interrupt.clone());
+    }
+    let result = match message.message_type {
+        MessageType::SimpleQuery => backend.query(message.query.as_str(), message.read_only, &message.respond),
+        MessageType::QueryWithParams => backend.query_with_params(message.query.as_str(), message.params.unwrap_or_default(), message.read_only, &message.respond),
+        MessageType::Describe => backend.describe_query(message.query.as_str()).map(|res| send_response(&message.respond, res)),
+        MessageType::Maintenance => backend.maintenance(message.query.as_str()).map(|res| send_response(&message.respond, res)),
+        MessageType::DatabaseInfo => backend.database_info().map(|res| send_response(&message.respond, res)),
+        MessageType::CopyRows => backend.copy_rows(message.query.as_str(), message.rows.unwrap_or_default(), message.read_only).map(|res| send_response(&message.respond, res)),
+        MessageType::Keepalive => {
+            // Nobody is waiting on a keepalive, receiving it has already reset the idle timeout
+            if let Err(err) = backend.keepalive() {
+                debug!("[{}] Keepalive failed, Error: {}", db_path_string, err);
+            }
+            return;
+        }
+    };
+    if let Some(cancel_token) = &cancel_token {
+        cancel_token.finished();
+    }
+    
+    match result {
+        Ok(()) => {},
+        Err(err) => {
+            if message.respond.send(PgLiteDBResponse{ result_schema:None, result:None, more_records:None, affected_rows:None, error:Some(err), in_transaction:backend.in_transaction(), notices:backend.take_notices(), last_insert_id:None }).is_err() {
+                trace!("[{}] Unable to send an error response to client - it's been disconnected...", db_path_string);
+            }
+        }
+    }
+}
+
 impl PgLitebackendFactory for SimplePgLiteDBBackendFactory {
     fn create_backend(&self, metadata:&HashMap<String, String>) -> Result<BackendConnection, PgWireError> {
-        // The DB Path is extracted from the connection metadata
-        let db_path = self.db_root.join(metadata.get("dbpath").unwrap_or(&String::from("blackhole")).to_owned());
+        // The DB Path is extracted from the connection metadata, after splitting off any session options the client requested
+        let (db_path, session_options) = self.resolve_db_path(metadata)?;
+        if !session_options.is_default() && !self.allow_session_options {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "FATAL".to_owned(),
+                "42501".to_owned(),
+                "Session options are not permitted by this server".to_owned(),
+            ))));
+        }
+
+        // On a read-only server every session is read-only, so they share the read-only handles
+        let session_options = PgLiteSessionOptions { read_only: session_options.read_only || self.read_only, ..session_options };
 
         // Check if we already have a handle to this database in the cache - and return it if we do
         {
             let cache_lock_res = self.db_cache.read();
             if let Ok(cache_lock) = cache_lock_res {
-                if let Some(cached_backend) = cache_lock.get(&db_path.to_string_lossy().to_string())  {
+                if let Some(cached_backend) = cache_lock.get(&Self::cache_key(&db_path, &session_options))  {
                     trace!("[{}] Using Cached DB Handle", db_path.to_string_lossy());
                     return Ok(cached_backend.clone());
                 }
             }
         }
 
+        // In-memory databases only exist while they're open, so connecting to one is always how it's created
+        if !self.in_memory && !self.create_if_missing && !db_path.exists() {
+            let database = metadata.get("database").or_else(|| metadata.get("dbpath")).map(String::as_str).unwrap_or_default();
+            let database = PgLiteSessionOptions::parse(database).map(|(database, _)| database).unwrap_or(database);
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "FATAL".to_owned(), "3D000".to_owned(), format!("database \"{database}\" does not exist"),
+            ))));
+        }
+
         // Not in cache, so spawn a new thread to handle this DB path
-        let conn = self.spawn_backend_connection(db_path);
-        return Ok(conn);
+        let conn = self.spawn_backend_connection(db_path, session_options);
+        Ok(conn)
+    }
+
+    fn database_backend(&self, database:&str) -> PgWireResult<BackendConnection> {
+        // The metadata a client connecting to the database would have - with per-user roots, the first name is the user's directory
+        let (user, user_database) = database.split_once('/').unwrap_or((database, ""));
+        let metadata = HashMap::from([("user", user), ("database", user_database), ("dbpath", database)])
+            .into_iter().map(|(key, value)| (key.to_owned(), value.to_owned())).collect::<HashMap<String, String>>();
+        // The command is run from the admin's own connection, which the database's errors mustn't close
+        let (db_path, session_options) = self.resolve_db_path(&metadata).map_err(not_fatal)?;
+        let exists = match self.in_memory {
+            true => self.db_cache.read().is_ok_and(|cache| cache.contains_key(&Self::cache_key(&db_path, &session_options))),
+            false => db_path.is_file(),
+        };
+        if !exists {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "ERROR".to_owned(), "3D000".to_owned(), format!("database \"{database}\" does not exist"),
+            ))));
+        }
+        self.create_backend(&metadata).map_err(not_fatal)
+    }
+
+    fn list_databases(&self, metadata:&HashMap<String, String>) -> PgWireResult<DatabaseList> {
+        // Users connect to the databases in their own directory, `{db_root}/{user}` - so those are the only ones listed
+        let user = metadata.get("user").map(String::as_str).unwrap_or_default();
+        let mut list = DatabaseList { owner:user.to_owned(), databases:Vec::new() };
+        if !matches!(Path::new(user).components().collect::<Vec<Component>>()[..], [Component::Normal(_)]) {
+            return Ok(list);
+        }
+        if self.in_memory {
+            // In-memory databases only exist while their handle is open, so it's the user's cached handles that are listed
+            if let Ok(cache) = self.db_cache.read() {
+                list.databases = cache.keys()
+                    .filter_map(|key| Path::new(key).strip_prefix(user).ok())
+                    .map(|name| name.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"))
+                    .filter(|name| !name.is_empty() && !name.contains('?'))
+                    .collect();
+            }
+            list.databases.sort();
+            return Ok(list);
+        }
+        let user_root = match self.per_user_root {
+            true => match self.db_root.join(user).canonicalize() {
+                Ok(user_root) => user_root,
+                Err(_) => return Ok(list),
+            },
+            false => self.db_root.join(user),
+        };
+        self.find_databases(&user_root, &user_root, 0, &mut list.databases);
+        list.databases.sort();
+        Ok(list)
+    }
+
+    fn check_health(&self) -> Result<(), String> {
+        if self.db_cache.read().is_err() {
+            return Err("The database handle cache was poisoned by a panic".to_owned());
+        }
+        if !self.in_memory {
+            let db_root_name = self.db_root.to_string_lossy();
+            std::fs::read_dir(&self.db_root).map_err(|err| format!("The database root '{db_root_name}' can't be read: {err}"))?;
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        // Once the cached senders are gone, each backend thread closes its DB handle after the messages already sent to it
+        if let Ok(mut cache) = self.db_cache.write() {
+            cache.clear();
+        }
     }
 }
 
 impl SimplePgLiteDBBackend {
-    pub fn open(db_path:PathBuf) -> Result<Self, Error> {
-        let con = Connection::open(db_path)?;   // todo: Check the open flags we should use...
-        Ok(Self { con })
-    }
-
-    fn get_sqlite_type_for_type(&self, name: &str) -> PgWireResult<Type> {
-        // Ignore the additional specifiers like the field length (which aren't important for sqlite)
-        let type_str = name
-                .to_uppercase()
-                .chars()
-                .take_while(|&ch| ch != ' ' && ch != '(')
-                .collect::<String>();
-    
-        // Match the Postgres type + return the sqlite equivalent type
-        match type_str.as_ref() {
-            "INT" => Ok(Type::Integer),
-            "VARCHAR" => Ok(Type::Text),
-            "DATE" => Ok(Type::Real),
-            "TIME" => Ok(Type::Real),
-            "TIMESTAMP" => Ok(Type::Real),
-            "TEXT" => Ok(Type::Text),
-            "BINARY" => Ok(Type::Blob),
-            "FLOAT" => Ok(Type::Real),
-            "SERIAL" => Ok(Type::Integer), // todo: Handle SERIAL properly ... 
-            _ => Err(PgWireError::UserError(Box::new(ErrorInfo::new(
-                "ERROR".to_owned(),
-                "42846".to_owned(),
-                format!("Unsupported data type: {name}"),
-            )))),
-        }
-    }
-
-    fn build_record_schema_from_statement(&self, stmt: &Statement) -> Vec<Field> {
-        stmt.columns()
+    #[allow(clippy::too_many_arguments)]
+    pub fn open(db_path:PathBuf, read_only:bool, attached:&[AttachedDatabase], in_memory:bool, pragmas:&SqlitePragmas, type_map:Arc<PgLiteTypeMap>, information_schema:bool, result_batch_size:usize, statement_cache_size:usize) -> Result<Self, Error> {
+        let catalog = db_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let con = match (in_memory, read_only) {
+            (true, true) => Connection::open_with_flags(memory_db_uri(&db_path), OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX)?,
+            (true, false) => Connection::open_with_flags(memory_db_uri(&db_path), OpenFlags::default())?,
+            (false, true) => Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX)?,
+            (false, false) => Connection::open(db_path)?   // todo: Check the open flags we should use...
+        };
+        pragmas.apply(&con, read_only)?;
+        attach_databases(&con, attached, in_memory)?;
+        con.set_prepared_statement_cache_capacity(statement_cache_size);
+        pg_catalog::create_tables(&con, type_map.clone())?;
+        compat::create_functions(&con, &catalog)?;
+        if information_schema {
+            information_schema::create_views(&con, &catalog, type_map.clone())?;
+        }
+        let interrupt = Arc::new(con.get_interrupt_handle());
+        let last_insert_id = Arc::new(Mutex::new(None));
+        let inserted = last_insert_id.clone();
+        con.update_hook(Some(move |action, database:&str, table:&str, rowid| {
+            // The emulated catalogs are reloaded into the temp schema before the statements that query them
+            if action == Action::SQLITE_INSERT && !(database == "temp" && table.starts_with("pglite_")) {
+                *inserted.lock().unwrap() = Some(rowid);
+            }
+        }));
+        Ok(Self {
+            con, type_map, information_schema, result_batch_size, schema_version:Cell::new(0), rowid_aliases:RefCell::new(HashSet::new()),
+            rowid_alias_columns:RefCell::new(HashMap::new()),
+            database:catalog, statement_cache:RefCell::new(StatementCacheStats::new(statement_cache_size)), in_memory, read_only, interrupt,
+            notices:RefCell::new(Vec::new()), stepped:Cell::new(false), last_insert_id,
+        })
+    }
+
+    /// Get a prepared statement from the cache - after a schema change (eg. another client's `ALTER TABLE`), the cache is flushed
+    /// first. SQLite re-prepares a stale statement itself once it's run, but until then it still has the old columns, which the
+    /// record schema is built from (eg. a `SELECT *` would be missing an added column, or have one that's been dropped)
+    fn prepare_cached(&self, query:&str) -> Result<CachedStatement<'_>, Error> {
+        self.check_schema_version()?;
+        let hit = self.statement_cache.borrow_mut().record(query);
+        telemetry::record_statement_cache(&self.database, hit);
+        self.con.prepare_cached(query)
+    }
+
+    /// Catch up with any schema change since the last statement was prepared
+    fn check_schema_version(&self) -> Result<(), Error> {
+        // The statements pglite runs itself take up room in the cache too
+        self.statement_cache.borrow_mut().lookup(SCHEMA_VERSION);
+        let schema_version = self.con
+            .prepare_cached(SCHEMA_VERSION)?
+            .query_row((), |row| row.get(0))?;
+        if self.schema_version.replace(schema_version) != schema_version {
+            trace!("The schema has changed (version {}), flushing the prepared statement cache", schema_version);
+            self.con.flush_prepared_statement_cache();
+            self.statement_cache.borrow_mut().statements.clear();
+            if self.type_map.int4_primary_keys() {
+                self.rowid_alias_columns.borrow_mut().clear();
+                self.load_rowid_aliases()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_rowid_aliases(&self) -> Result<(), Error> {
+        let query = format!(
+            "SELECT lower(m.name), lower(c.name) FROM main.sqlite_master m JOIN pragma_table_info(m.name) c \
+            WHERE m.type = 'table' AND {}", information_schema::IS_ROWID_ALIAS
+        );
+        self.statement_cache.borrow_mut().lookup(&query);
+        let mut statement = self.con.prepare_cached(&query)?;
+        let rowid_aliases = statement.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<HashSet<_>, Error>>()?;
+        self.rowid_aliases.replace(rowid_aliases);
+        Ok(())
+    }
+
+    fn run_query_with_params(&self, query:&str, params:&[PgLiteDBParam], read_only:bool, respond:&Sender<PgLiteDBResponse>) -> PgWireResult<()> {
+        self.stepped.set(false);
+        // Prepare the statement or get from cache
+        let mut statement = self
+                .prepare_cached(query)
+                .map_err(|err| self.limit_error(err))?;
+        if (read_only || self.read_only) && !statement.readonly() {
+            return Err(read_only_transaction(query));
+        }
+
+        // Prepare the params for the statement
+        let sql_params: Vec<Box<dyn ToSql>> = params.iter().map(|p| { Box::new(p.value.clone()) as Box<dyn ToSql> }).collect();
+        let sql_params_ref = sql_params.iter()
+            .map(|f| f.as_ref())
+            .collect::<Vec<&dyn rusqlite::ToSql>>();
+
+        // Execute the Statement / Query
+        match statement.column_count() > 0 {
+            true => {
+                let fields = self.build_record_schema_from_statement(query, &statement);
+                let row_data = statement.query::<&[&dyn rusqlite::ToSql]>(sql_params_ref.as_ref())
+                    .map_err(sqlite_error)?;
+                self.send_records(fields, row_data, false, respond)
+            },
+            false => {
+                let affected_rows = self.execute_counting_changes(|| statement.execute::<&[&dyn rusqlite::ToSql]>(sql_params_ref.as_ref()))
+                    .map_err(sqlite_error)?;
+                self.send_affected_rows(affected_rows, respond);
+                Ok(())
+            }
+        }
+    }
+
+    /// Insert each of the rows with the statement, returning how many were inserted
+    fn insert_rows(&self, insert_query:&str, rows:Vec<Vec<Value>>, read_only:bool) -> PgWireResult<usize> {
+        let mut statement = self
+                .prepare_cached(insert_query)
+                .map_err(|err| self.limit_error(err))?;
+        if (read_only || self.read_only) && !statement.readonly() {
+            return Err(read_only_transaction(insert_query));
+        }
+        let count = rows.len();
+        for row in rows {
+            statement.execute(rusqlite::params_from_iter(row)).map_err(sqlite_error)?;
+        }
+        Ok(count)
+    }
+
+    /// Rewrite the Postgres-isms of a query that SQLite doesn't understand - the client is sent a notice when its statement is
+    /// changed, unless it's a query of the emulated catalogs (eg. psql's `\d`), which always has to be rewritten
+    fn rewrite_query<'a>(&self, query:&'a str) -> PgWireResult<Cow<'a, str>> {
+        let query = serial::rewrite_query(query)?;
+        let query = then_rewrite(query, compat::rewrite_query);
+        let rewritten = matches!(query, Cow::Owned(_)).then(|| query.to_string());
+        let query = then_rewrite(query, pg_catalog::rewrite_query);
+        let query = match self.information_schema {
+            true => then_rewrite(query, information_schema::rewrite_query),
+            false => query,
+        };
+        if rewritten.is_some_and(|rewritten| rewritten == query) {
+            let mut notice = ErrorInfo::new("NOTICE".to_owned(), "00000".to_owned(), "the statement was rewritten for SQLite".to_owned());
+            notice.set_detail(Some(format!("It was run as: {query}")));
+            self.notices.borrow_mut().push(notice);
+        }
+        // The catalogs of the schema are loaded for the queries that use them, eg. psql's `\d table`
+        pg_catalog::load_catalogs(&self.con, &query).map_err(sqlite_error)?;
+        Ok(query)
+    }
+
+    /// The notices for the client of the statement that's been run
+    fn take_notices(&self) -> Vec<ErrorInfo> {
+        self.notices.take()
+    }
+
+    /// The rowid of the last row inserted by the statement that's been run, if it inserted any
+    fn take_last_insert_id(&self) -> Option<i64> {
+        self.last_insert_id.lock().unwrap().take()
+    }
+
+    fn build_record_schema_from_statement(&self, query:&str, stmt: &Statement) -> Vec<Field> {
+        let columns = stmt.columns();
+        let rowid_alias_columns = match columns.iter().any(|col| col.decl_type().is_some_and(|decl_type| decl_type.eq_ignore_ascii_case("INTEGER"))) {
+            true => self.rowid_alias_columns(query),
+            false => Vec::new(),
+        };
+        columns
             .iter()
             .enumerate()
             .map(|(idx, col)| {
+                // A result column's declared type is the one of the table column it comes from (the origin that rowid_alias_columns
+                // reads), through any views, CTEs + subqueries - so there's no more to trace back. Only expression columns (eg. `i + 1`)
+                // have no declared type, as they've no origin column, and they fall back to VARCHAR (or are typed by their values)
+                let is_rowid_alias = rowid_alias_columns.contains(&idx);
+                let mapping = self.type_map.lookup_column(col.decl_type().unwrap_or_default(), is_rowid_alias);
+                let type_modifier = pg_catalog::column_type_modifier(col.decl_type().unwrap_or_default(), &mapping.pg_type);
                 Field { 
-                    field_type:self.get_sqlite_type_for_type(col.decl_type().unwrap()).unwrap(), 
+                    field_type:mapping.sqlite_type, 
+                    pg_type:mapping.pg_type,
+                    type_modifier,
                     name:col.name().to_owned(), 
                     ordinal:idx
                 }
@@ -162,19 +817,378 @@ impl SimplePgLiteDBBackend {
             .collect()
     }
 
-    fn build_records(&self, mut row_data: Rows, num_fields: usize) -> Vec<Record> {
-        let mut records = Vec::new();   // todo: consider whether we can stream records back as we go through the recordset?! 
-        while let Ok(Some(row)) = row_data.next() {
+    /// The indexes of a statement's result columns that come straight from a table's INTEGER PRIMARY KEY (whatever they're named in
+    /// the result, eg. `SELECT id AS key`) - rusqlite doesn't expose SQLite's column metadata, so the statement is prepared again on
+    /// the raw handle to read the table + column each result column comes from
+    fn rowid_alias_columns(&self, query:&str) -> Vec<usize> {
+        if self.rowid_aliases.borrow().is_empty() {
+            return Vec::new();
+        }
+        if let Some(columns) = self.rowid_alias_columns.borrow().get(query) {
+            return columns.clone();
+        }
+        let Ok(sql) = CString::new(query) else { return Vec::new() };
+        let mut columns = Vec::new();
+        // The statement has already been prepared successfully on this handle, so it's only prepared here to read its metadata (and
+        // finalized straight after)
+        unsafe {
+            let mut stmt = std::ptr::null_mut();
+            if ffi::sqlite3_prepare_v2(self.con.handle(), sql.as_ptr(), -1, &mut stmt, std::ptr::null_mut()) != ffi::SQLITE_OK || stmt.is_null() {
+                return columns;
+            }
+            let name = |ptr:*const c_char| (!ptr.is_null()).then(|| CStr::from_ptr(ptr).to_string_lossy().to_lowercase());
+            for idx in 0..ffi::sqlite3_column_count(stmt) {
+                let database = name(ffi::sqlite3_column_database_name(stmt, idx));
+                let origin = name(ffi::sqlite3_column_table_name(stmt, idx)).zip(name(ffi::sqlite3_column_origin_name(stmt, idx)));
+                if database.as_deref() == Some("main") && origin.is_some_and(|origin| self.rowid_aliases.borrow().contains(&origin)) {
+                    columns.push(idx as usize);
+                }
+            }
+            ffi::sqlite3_finalize(stmt);
+        }
+        let mut cached = self.rowid_alias_columns.borrow_mut();
+        if cached.len() >= self.statement_cache.borrow().capacity {
+            cached.clear();
+        }
+        cached.insert(query.to_owned(), columns.clone());
+        columns
+    }
+
+    /// Map the columns that have no declared type (eg. `count(*)`) from their first non-NULL value in the first batch of records -
+    /// a column whose values are all NULL stays VARCHAR, and one that holds values of different storage classes is typed by its first
+    fn infer_field_types(&self, fields:&mut [Field], untyped_columns:&[usize], records:&[Record]) {
+        for field in fields.iter_mut().filter(|field| untyped_columns.contains(&field.ordinal)) {
+            let mapping = records.iter()
+                .find_map(|record| record.values.get(field.ordinal).and_then(|value| self.type_map.lookup_value(value)));
+            if let Some(mapping) = mapping {
+                field.field_type = mapping.sqlite_type;
+                field.pg_type = mapping.pg_type;
+            }
+        }
+    }
+
+    /// Statements that run into one of SQLite's limits (which are much tighter than Postgres's, eg. the number of bind parameters in
+    /// a large generated IN list) fail to prepare with a terse message, so say which limit it was + what it's set to
+    fn limit_error(&self, err:Error) -> PgWireError {
+        let message = match &err {
+            Error::SqlInputError { msg, .. } | Error::SqliteFailure(_, Some(msg)) => msg,
+            _ => return sqlite_error(err),
+        };
+        let (code, limit, description) = if message.starts_with("too many SQL variables") || message.starts_with("variable number must be between") {
+            ("54000", Limit::SQLITE_LIMIT_VARIABLE_NUMBER, "Too many bind parameters")
+        } else if message.starts_with("too many columns") {
+            ("54011", Limit::SQLITE_LIMIT_COLUMN, "Too many columns")
+        } else if message.starts_with("Expression tree is too large") {
+            ("54001", Limit::SQLITE_LIMIT_EXPR_DEPTH, "The statement is too complex, its expressions are nested too deeply")
+        } else if message.starts_with("too many terms in compound SELECT") {
+            ("54001", Limit::SQLITE_LIMIT_COMPOUND_SELECT, "Too many terms in a compound SELECT")
+        } else if message.starts_with("too many arguments on function") {
+            ("54023", Limit::SQLITE_LIMIT_FUNCTION_ARG, "Too many arguments to a function")
+        } else if message.starts_with("statement too long") {
+            ("54000", Limit::SQLITE_LIMIT_SQL_LENGTH, "The statement is too long")
+        } else {
+            return sqlite_error(err);
+        };
+        let too_many_parameters = matches!(limit, Limit::SQLITE_LIMIT_VARIABLE_NUMBER);
+        let mut error_info = ErrorInfo::new("ERROR".to_owned(), code.to_owned(), format!("{description}, the SQLite limit is {}", self.con.limit(limit)));
+        error_info.set_detail(Some(message.clone()));
+        if too_many_parameters {
+            error_info.set_hint(Some("Split the statement into smaller batches, eg. run a large IN list as several queries".to_owned()));
+        }
+        PgWireError::UserError(Box::new(error_info))
+    }
+
+    fn in_transaction(&self) -> bool {
+        // SQLite leaves autocommit mode for the duration of a transaction block
+        !self.con.is_autocommit()
+    }
+
+    /// The affected row count for a statement: sqlite's `changes()` only counts the rows changed by the statement itself (not by
+    /// triggers), but it's only reset by INSERT/UPDATE/DELETE - so if nothing changed, don't report the count from an earlier statement
+    fn execute_counting_changes<F>(&self, execute:F) -> Result<usize, Error>
+    where F: FnOnce() -> Result<usize, Error> {
+        let total_changes_before = self.total_changes()?;
+        let changes = execute()?;
+        match self.total_changes()? == total_changes_before {
+            true => Ok(0),
+            false => Ok(changes),
+        }
+    }
+
+    fn total_changes(&self) -> Result<i64, Error> {
+        self.statement_cache.borrow_mut().lookup(TOTAL_CHANGES);
+        self.con
+            .prepare_cached(TOTAL_CHANGES)?
+            .query_row((), |row| row.get(0))
+    }
+
+    /// Send the records of a result in batches: the first batch goes in the response along with the schema, then any more are
+    /// sent through a bounded channel - so the pace the client reads at applies backpressure to this thread
+    fn send_records(&self, mut fields:Vec<Field>, mut row_data: Rows, infer_types:bool, respond:&Sender<PgLiteDBResponse>) -> PgWireResult<()> {
+        let num_fields = fields.len();
+        // The statement's columns have to be looked at before it's stepped, as it's reset once all of its rows have been read
+        let untyped_columns = match (infer_types, row_data.as_ref()) {
+            (true, Some(statement)) => statement.columns().iter().enumerate().filter(|(_, column)| column.decl_type().is_none()).map(|(idx, _)| idx).collect(),
+            _ => Vec::new(),
+        };
+        let first_batch = self.next_batch(&mut row_data, num_fields)?;
+        self.infer_field_types(&mut fields, &untyped_columns, &first_batch);
+        if first_batch.len() < self.result_batch_size {
+            send_response(respond, PgLiteDBResponse { result_schema:Some(fields), result:Some(first_batch), more_records:None, affected_rows:None, error:None, in_transaction:self.in_transaction(), notices:self.take_notices(), last_insert_id:self.take_last_insert_id() });
+            return Ok(());
+        }
+
+        let (batch_sender, batch_receiver) = tokio::sync::mpsc::channel(1);
+        if respond.send(PgLiteDBResponse { result_schema:Some(fields), result:Some(first_batch), more_records:Some(batch_receiver), affected_rows:None, error:None, in_transaction:self.in_transaction(), notices:self.take_notices(), last_insert_id:self.take_last_insert_id() }).is_err() {
+            trace!("Unable to send response to client - it's been disconnected...");
+            return Ok(());
+        }
+        loop {
+            let batch = self.next_batch(&mut row_data, num_fields);
+            let is_last = !matches!(&batch, Ok(records) if records.len() == self.result_batch_size);
+            if matches!(&batch, Ok(records) if records.is_empty()) {
+                break;
+            }
+            if batch_sender.blocking_send(batch).is_err() {
+                trace!("Unable to send records to client - it's been disconnected...");
+                break;
+            }
+            if is_last {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_batch(&self, row_data: &mut Rows, num_fields: usize) -> PgWireResult<Vec<Record>> {
+        let mut records = Vec::new();
+        while records.len() < self.result_batch_size {
+            let Some(row) = row_data.next().map_err(sqlite_error)? else { break };
+            self.stepped.set(true);
             let mut record = Record{ values:Vec::with_capacity(num_fields) };
             for field_num in 0..num_fields {
-                let data = row.get_unwrap(field_num);
+                let data = row.get(field_num).map_err(sqlite_error)?;
                 record.values.push(data);
             }
             records.push(record);
         }
-        records
+        Ok(records)
+    }
+
+    fn send_affected_rows(&self, affected_rows:usize, respond:&Sender<PgLiteDBResponse>) {
+        // There are no rows, clients only get the command tag (eg. `UPDATE 3`) built from the count
+        send_response(respond, PgLiteDBResponse { result_schema:None, result:None, more_records:None, affected_rows:Some(affected_rows), error:None, in_transaction:self.in_transaction(), notices:self.take_notices(), last_insert_id:self.take_last_insert_id() });
+    }
+}
+
+/// Apply a rewrite to a query that may already have been rewritten
+fn then_rewrite<'a>(query:Cow<'a, str>, rewrite:fn(&str) -> Cow<'_, str>) -> Cow<'a, str> {
+    match query {
+        Cow::Borrowed(query) => rewrite(query),
+        Cow::Owned(query) => Cow::Owned(rewrite(&query).into_owned()),
+    }
+}
+
+/// The URI of an in-memory database in SQLite's memdb VFS, eg. `file:/pglite/john/data.sqlite?vfs=memdb` - the characters that are
+/// special in a URI are percent-encoded
+fn memory_db_uri(db_path:&Path) -> String {
+    let name = db_path.to_string_lossy().bytes().fold(String::new(), |mut name, b| {
+        match b {
+            b'%' | b'?' | b'#' | b'&' | b'=' | b'\0'..=b' ' | 0x7f..=0xff => name.push_str(&format!("%{b:02X}")),
+            _ => name.push(b as char),
+        }
+        name
+    });
+    format!("file:{MEMORY_DB_ROOT}/{}?vfs=memdb", name.trim_start_matches('/'))
+}
+
+/// Create a database that doesn't exist yet as a copy of the template, the copy is made under a temporary name + then linked into
+/// place - so no connection ever sees a partial copy, and if another connection beat us to it their copy is left alone
+fn create_from_template(db_path:&Path, template:Option<&Path>) -> std::io::Result<()> {
+    let Some(template) = template else { return Ok(()) };
+    if db_path.exists() {
+        return Ok(());
+    }
+
+    let file_name = db_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let temp_path = db_path.with_file_name(format!(".{file_name}.{}.tmp", uuid::Uuid::new_v4()));
+    let result = std::fs::copy(template, &temp_path).and_then(|_| std::fs::hard_link(&temp_path, db_path));
+    let _ = std::fs::remove_file(&temp_path);
+    match result {
+        Ok(()) => {
+            info!("Created the database {} from the template: {}", db_path.to_string_lossy(), template.to_string_lossy());
+            Ok(())
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// The database root has to be a directory that can be read, or no database could ever be opened - it doesn't have to be writable
+/// itself (the databases are created in the users' directories within it), but usually is, so a read-only root is just a warning
+fn check_db_root(db_root:&Path) -> Result<(), String> {
+    let db_root_name = db_root.to_string_lossy();
+    match std::fs::metadata(db_root) {
+        Ok(metadata) if metadata.is_dir() => {},
+        Ok(_) => return Err(format!("The database root '{db_root_name}' isn't a directory (--db-root)")),
+        Err(err) => return Err(format!("The database root '{db_root_name}' can't be used (--db-root): {err}")),
+    }
+    std::fs::read_dir(db_root).map_err(|err| format!("The database root '{db_root_name}' can't be read (--db-root): {err}"))?;
+
+    let probe = db_root.join(format!(".pglite-{}.tmp", uuid::Uuid::new_v4()));
+    match std::fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => { let _ = std::fs::remove_file(&probe); },
+        Err(err) => warn!("The database root '{}' isn't writable, databases can only be created in the user directories within it: {}", db_root_name, err),
+    }
+    Ok(())
+}
+
+/// The SQLite extensions have to be allowed explicitly, as they run native code in the server's process - and each is loaded into
+/// a scratch handle, so one that can't be loaded stops the server starting rather than every database failing to open
+fn check_extensions(config:&PgLiteConfig) -> Result<(), String> {
+    if config.sqlite_extensions.is_empty() {
+        return Ok(());
+    }
+    if !config.allow_sqlite_extensions {
+        return Err("SQLite extensions run native code inside the server, run with --allow-sqlite-extensions to load them (--sqlite-extensions)".to_owned());
+    }
+    let con = Connection::open_in_memory().map_err(|err| format!("Unable to check the SQLite extensions: {err}"))?;
+    for extension in &config.sqlite_extensions {
+        unsafe {
+            LoadExtensionGuard::new(&con).and_then(|_guard| con.load_extension(extension, None))
+        }.map_err(|err| format!("Unable to load the SQLite extension '{}' (--sqlite-extensions): {err}", extension.to_string_lossy()))?;
+        warn!("Loading the SQLite extension '{}' into every database, it can do anything the server's process can", extension.to_string_lossy());
+    }
+    Ok(())
+}
+
+/// Attach the session's other databases (which are opened read-only on a read-only handle, like its own database) - the handle is
+/// shared by every session with the same options, so its clients can't attach (or detach) any databases themselves, and any they
+/// could name wouldn't have been resolved within their root
+fn attach_databases(con:&Connection, attached:&[AttachedDatabase], in_memory:bool) -> Result<(), Error> {
+    for database in attached {
+        let path = match in_memory {
+            true => memory_db_uri(&database.path),
+            false => database.path.to_string_lossy().to_string(),
+        };
+        con.execute("ATTACH DATABASE ?1 AS ?2", (path, &database.alias))?;
+    }
+    con.authorizer(Some(|context:AuthContext<'_>| match context.action {
+        AuthAction::Attach { .. } | AuthAction::Detach { .. } => Authorization::Deny,
+        _ => Authorization::Allow,
+    }));
+    Ok(())
+}
+
+/// Resolve a database's path within a (canonical) root directory - the path has to be relative without any `..`, and still be
+/// within the root once any symlinks are followed, or the database could be any file the server can open (or create)
+fn resolve_within_root(root:&Path, database:&str) -> PgWireResult<PathBuf> {
+    if !is_relative_path(database) {
+        return Err(outside_user_root());
+    }
+    let requested = root.join(database);
+    let db_path = match requested.canonicalize() {
+        Ok(db_path) => db_path,
+        // A dangling symlink would have SQLite create the database wherever it points, so it's never allowed
+        Err(_) if requested.symlink_metadata().is_ok() => return Err(outside_user_root()),
+        // The database doesn't exist yet (SQLite will create it), so it's the directory it'll be created in that's checked
+        Err(_) => match (requested.parent().and_then(|dir| dir.canonicalize().ok()), requested.file_name()) {
+            (Some(dir), Some(file_name)) => dir.join(file_name),
+            _ => return Err(outside_user_root()),
+        }
+    };
+    match db_path.starts_with(root) {
+        true => Ok(db_path),
+        false => Err(outside_user_root()),
+    }
+}
+
+/// Whether a database path is relative, and only made of names (no `..`, root or drive prefix)
+fn is_relative_path(database:&str) -> bool {
+    !database.is_empty() && Path::new(database).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// A connection's FATAL error as an ERROR, for when it's from opening a database other than the connection's own
+fn not_fatal(err:PgWireError) -> PgWireError {
+    match err {
+        PgWireError::UserError(mut info) if info.severity() == "FATAL" => {
+            info.set_severity("ERROR".to_owned());
+            PgWireError::UserError(info)
+        },
+        err => err,
+    }
+}
+
+fn outside_user_root() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("FATAL".to_owned(), "42501".to_owned(), "The requested database is outside of your database root".to_owned())))
+}
+
+fn is_sqlite_file(path:&Path) -> bool {
+    let mut header = [0u8; 16];
+    std::fs::File::open(path).and_then(|mut file| std::io::Read::read_exact(&mut file, &mut header)).is_ok() && &header == SQLITE_HEADER
+}
+
+/// Map a SQLite error to a PgWire error, a locked database is reported as `lock_not_available`, a write to a read-only database as
+/// `read_only_sql_transaction`, an oversized value as `program_limit_exceeded` + a write to a generated column as `generated_always`
+/// rather than an internal error
+fn sqlite_error(err:Error) -> PgWireError {
+    if let Some(error_info) = generated_column_error(&err) {
+        return PgWireError::UserError(Box::new(error_info));
+    }
+    match err.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy) => {
+            let mut error_info = ErrorInfo::new("ERROR".to_owned(), "55P03".to_owned(), "The database is locked by another process".to_owned());
+            // Connections share a single handle per database, so the database can only be locked by another process
+            error_info.set_detail(Some(format!("Gave up waiting for the lock after the busy timeout (--sqlite-busy-timeout): {err}")));
+            error_info.set_hint(Some("This is usually temporary (eg. a backup is running), retry the statement later".to_owned()));
+            PgWireError::UserError(Box::new(error_info))
+        },
+        // Any write the statement check didn't catch (eg. a `PRAGMA` that writes), on a read-only handle
+        Some(ErrorCode::ReadOnly) => PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "25006".to_owned(), format!("The database is read-only: {err}")))),
+        Some(ErrorCode::OperationInterrupted) => PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "57014".to_owned(), "canceling statement due to user request".to_owned()))),
+        Some(ErrorCode::AuthorizationForStatementDenied) => PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "42501".to_owned(),
+            "ATTACH + DETACH aren't allowed, databases are attached with the attach session option (eg. sales.db?attach:crm.db)".to_owned()))),
+        Some(ErrorCode::TooBig) => PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "54000".to_owned(), format!("The value is too large for SQLite: {err}")))),
+        _ => PgWireError::ApiError(Box::new(err)),
+    }
+}
+
+/// Whether a statement failed because the schema changed under it, and SQLite couldn't re-prepare it
+fn is_schema_changed(err:&PgWireError) -> bool {
+    match err {
+        PgWireError::ApiError(err) => err.downcast_ref::<Error>().and_then(Error::sqlite_error_code) == Some(ErrorCode::SchemaChanged),
+        _ => false,
+    }
+}
+
+/// SQLite rejects a statement that writes to a generated column when it's prepared, so report it with Postgres's error for it
+fn generated_column_error(err:&Error) -> Option<ErrorInfo> {
+    let message = match err {
+        Error::SqlInputError { msg, .. } | Error::SqliteFailure(_, Some(msg)) => msg,
+        _ => return None,
+    };
+    let (message, column) = if let Some(column) = message.strip_prefix("cannot INSERT into generated column ") {
+        (format!("cannot insert a non-DEFAULT value into column {column}"), column)
+    } else if let Some(column) = message.strip_prefix("cannot UPDATE generated column ") {
+        (format!("column {column} can only be updated to DEFAULT"), column)
+    } else {
+        return None;
+    };
+    let mut error_info = ErrorInfo::new("ERROR".to_owned(), "428C9".to_owned(), message);
+    error_info.set_detail(Some(format!("Column {column} is a generated column.")));
+    Some(error_info)
+}
+
+/// The error for a write statement in a read-only session, eg. `cannot execute INSERT in a read-only transaction`
+fn read_only_transaction(query:&str) -> PgWireError {
+    let command = strip_leading_comments(query).split_whitespace().next().unwrap_or_default().to_uppercase();
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "25006".to_owned(), format!("cannot execute {command} in a read-only transaction"))))
+}
+
+fn send_response(respond:&Sender<PgLiteDBResponse>, response:PgLiteDBResponse) {
+    if respond.send(response).is_err() {
+        trace!("Unable to send response to client - it's been disconnected...");
     }
-    
 }
 
 impl PgLiteDBBackend for SimplePgLiteDBBackend {
@@ -182,84 +1196,232 @@ impl PgLiteDBBackend for SimplePgLiteDBBackend {
         // We'll rely on the drop functionality - as we cannot call close() on self.con as this method will attempt to take ownership of self :p
         Ok(())
     }
-    fn query(&self, query:&str) -> PgWireResult<PgLiteDBResponse> {
-        let result = match query.to_uppercase().starts_with("SELECT") {
+    fn query(&self, query:&str, read_only:bool, respond:&Sender<PgLiteDBResponse>) -> PgWireResult<()> {
+        let query = self.rewrite_query(query)?;
+        let query = query.as_ref();
+        self.check_schema_version().map_err(sqlite_error)?;
+        let mut statement = self.con
+            .prepare(query)
+            .map_err(|err| self.limit_error(err))?;
+        if (read_only || self.read_only) && !statement.readonly() {
+            return Err(read_only_transaction(query));
+        }
+
+        // Whether a statement returns rows is decided the same way as when it's described
+        match statement.column_count() > 0 {
             true => {
-                let mut statement = self.con
-                    .prepare(query)
-                    .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
-
-                let fields = self.build_record_schema_from_statement(&statement);
-                let num_fields = fields.len();
-                statement.query(())
-                    .map(|row_data| {
-                        (fields, self.build_records(row_data, num_fields))
-                    })
-                    .map_err(|e| PgWireError::ApiError(Box::new(e)))
+                let fields = self.build_record_schema_from_statement(query, &statement);
+                let row_data = statement.query(())
+                    .map_err(sqlite_error)?;
+                // A simple query's columns are described along with its rows, so (unlike a described statement's) the types of
+                // its columns can come from their values
+                self.send_records(fields, row_data, self.type_map.infers_from_values(), respond)
             },
             false => {
-                self.con
-                    .execute(query, ())
-                    .map(|affected_rows| {
-                        let fields = vec![Field{ name:String::from("OK"), field_type:Type::Integer, ordinal:0 }];
-                        let record = Record{ values:vec![ Value::Integer(affected_rows as i64) ] };
-                        (fields, vec![record])
-                    })
-                    .map_err(|e| PgWireError::ApiError(Box::new(e)))
+                let affected_rows = self.execute_counting_changes(|| statement.execute(()))
+                    .map_err(sqlite_error)?;
+                self.send_affected_rows(affected_rows, respond);
+                Ok(())
             }
-        };
+        }
+    }
 
-        match result {
-            Ok( (record_schema, records)) => PgWireResult::Ok(PgLiteDBResponse { result_schema:Some(record_schema), result: Some(records), error: None  }),
-            Err(err) => Err(err)
+    fn query_with_params(&self, query:&str, params:Vec<PgLiteDBParam>, read_only:bool, respond:&Sender<PgLiteDBResponse>) -> PgWireResult<()> {
+        let query = self.rewrite_query(query)?;
+        let query = query.as_ref();
+        // If SQLite gives up re-preparing a cached statement after a schema change, start again from a fresh statement (once) - but only
+        // when it failed on its first step, before it returned any rows
+        match self.run_query_with_params(query, &params, read_only, respond) {
+            Err(err) if is_schema_changed(&err) && !self.stepped.get() => {
+                debug!("The schema changed while running a cached statement, retrying it: {}", query);
+                self.con.flush_prepared_statement_cache();
+                self.run_query_with_params(query, &params, read_only, respond)
+            },
+            result => result,
         }
     }
 
-    fn query_with_params(&self, query:&str, params:Vec<PgLiteDBParam>) -> PgWireResult<PgLiteDBResponse> {
-        // Prepare the statement or get from cache
-        let mut statement = self.con
+    fn describe_query(&self, query:&str) -> PgWireResult<PgLiteDBResponse> {
+        let query = self.rewrite_query(query)?;
+        let query = query.as_ref();
+        // The notices are sent when the statement is run, rather than each time it's described
+        self.take_notices();
+        // Simply prepare the statement and get the schema
+        let statement = self
                 .prepare_cached(query)
-                .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+                .map_err(|err| self.limit_error(err))?;
+        let fields = self.build_record_schema_from_statement(query, &statement);
+        PgWireResult::Ok(PgLiteDBResponse { result_schema:Some(fields), result: None, more_records: None, affected_rows: None, error: None, in_transaction:self.in_transaction(), notices:self.take_notices(), last_insert_id:None })
+    }
 
-        // Prepare the params for the statement
-        let sql_params: Vec<Box<dyn ToSql>> = params.iter().map(|p| { Box::new(p.value.clone()) as Box<dyn ToSql> }).collect();
-        let sql_params_ref = sql_params.iter()
-            .map(|f| f.as_ref())
-            .collect::<Vec<&dyn rusqlite::ToSql>>();
+    fn maintenance(&self, statement:&str) -> PgWireResult<PgLiteDBResponse> {
+        self.con
+            .execute_batch(statement)
+            .map_err(sqlite_error)?;
+        PgWireResult::Ok(PgLiteDBResponse { result_schema:None, result:None, more_records:None, affected_rows:None, error:None, in_transaction:self.in_transaction(), notices:self.take_notices(), last_insert_id:None })
+    }
 
-        // Execute the Statement / Query
-        let result = match query.to_uppercase().starts_with("SELECT") {
-                true => {
-                    let fields = self.build_record_schema_from_statement(&statement);
-                    let num_fields = fields.len();
-                    statement.query::<&[&dyn rusqlite::ToSql]>(sql_params_ref.as_ref())
-                    .map(|row_data| {
-                        (fields, self.build_records(row_data, num_fields))
-                    })
-                    .map_err(|e| PgWireError::ApiError(Box::new(e)))
-                }, 
-                false => {
-                    statement.execute::<&[&dyn rusqlite::ToSql]>(sql_params_ref.as_ref())
-                    .map(|affected_rows| {
-                        let fields = vec![Field{ name:String::from("OK"), field_type:Type::Integer, ordinal:0 }];
-                        let record = Record{ values:vec![ Value::Integer(affected_rows as i64) ] };
-                        (fields, vec![record])
-                    })
-                    .map_err(|e| PgWireError::ApiError(Box::new(e)))
-                }
-            };
-        match result {
-            Ok( (record_schema, records)) => PgWireResult::Ok(PgLiteDBResponse { result_schema:Some(record_schema), result: Some(records), error: None  }),
-            Err(err) => Err(err)
+    fn database_info(&self) -> PgWireResult<PgLiteDBResponse> {
+        let pragma = |name:&str| self.con.query_row(&format!("PRAGMA {name}"), (), |row| row.get::<_, i64>(0)).map_err(sqlite_error);
+        let page_count = pragma("page_count")?;
+        let (size_bytes, last_modified, wal_size_bytes) = match self.con.path().filter(|path| !path.is_empty() && !self.in_memory) {
+            Some(path) => {
+                // The file can't be read (eg. it's been removed from under the open handle) - an error for this query, not the connection
+                let db_file = std::fs::metadata(path).map_err(|err| PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "58030".to_owned(), format!("could not stat file \"{path}\": {err}")))))?;
+                let wal_file = std::fs::metadata(format!("{path}-wal")).ok();
+                // In WAL mode the latest changes are only in the WAL file, until they've been checkpointed
+                let last_modified = [Some(&db_file), wal_file.as_ref()].into_iter().flatten()
+                    .filter_map(|file| file.modified().ok())
+                    .max()
+                    .unwrap_or_else(SystemTime::now);
+                (db_file.len() as i64, last_modified, wal_file.map_or(0, |file| file.len() as i64))
+            },
+            // An in-memory database has no files, so its size is just its pages + it's always up to date
+            None => (page_count * pragma("page_size")?, SystemTime::now(), 0),
+        };
+        let last_modified = chrono::DateTime::<chrono::Utc>::from(last_modified).naive_utc();
+        let record = Record { values:vec![
+            Value::Integer(size_bytes),
+            Value::Text(format_utc_timestamp(&last_modified)),
+            Value::Integer(page_count),
+            Value::Integer(wal_size_bytes),
+            Value::Integer(self.statement_cache.borrow().hits as i64),
+            Value::Integer(self.statement_cache.borrow().misses as i64),
+        ]};
+        PgWireResult::Ok(PgLiteDBResponse { result_schema:Some(database_info_schema()), result:Some(vec![record]), more_records:None, affected_rows:None, error:None, in_transaction:self.in_transaction(), notices:self.take_notices(), last_insert_id:None })
+    }
+
+    fn copy_rows(&self, insert_query:&str, rows:Vec<Vec<Value>>, read_only:bool) -> PgWireResult<PgLiteDBResponse> {
+        // The rows only arrive once the client has sent all of them, so the transaction is never left open while waiting on the client
+        let began_transaction = !self.in_transaction();
+        if began_transaction {
+            self.con.execute_batch("BEGIN").map_err(sqlite_error)?;
+        }
+        let result = self.insert_rows(insert_query, rows, read_only)
+            .and_then(|count| match began_transaction {
+                true => self.con.execute_batch("COMMIT").map(|_| count).map_err(sqlite_error),
+                false => Ok(count),
+            });
+        // A COPY that fails doesn't leave any of its rows behind - unless it's part of the client's transaction, which then fails
+        if result.is_err() && began_transaction && self.in_transaction() {
+            if let Err(err) = self.con.execute_batch("ROLLBACK") {
+                error!("Failed to roll back the failed COPY: {}", err);
+            }
         }
+        let count = result?;
+        PgWireResult::Ok(PgLiteDBResponse { result_schema:None, result:None, more_records:None, affected_rows:Some(count), error:None, in_transaction:self.in_transaction(), notices:self.take_notices(), last_insert_id:self.take_last_insert_id() })
     }
 
-    fn describe_query(&self, query:&str) -> PgWireResult<PgLiteDBResponse> {
-        // Simply prepare the statement and get the schema
-        let statement = self.con
-                .prepare_cached(query)
-                .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
-        let fields = self.build_record_schema_from_statement(&statement);
-        PgWireResult::Ok(PgLiteDBResponse { result_schema:Some(fields), result: None, error: None  })
+    fn load_databases(&self, databases:&DatabaseList) -> PgWireResult<()> {
+        pg_catalog::load_databases(&self.con, &databases.owner, &databases.databases).map_err(sqlite_error)
+    }
+
+    fn keepalive(&self) -> Result<(), PgWireError> {
+        // A cheap query that still touches the DB file, to make sure the handle is still usable
+        self.con
+            .query_row("PRAGMA schema_version", (), |_| Ok(()))
+            .map_err(sqlite_error)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use clap::Parser;
+    use pgwire::api::Type as PgType;
+
+    fn open_int4_primary_keys(name:&str) -> SimplePgLiteDBBackend {
+        let pragmas = SqlitePragmas { journal_mode:None, synchronous:None, busy_timeout:Duration::from_secs(1), extensions:Vec::new(), max_variables:None };
+        let type_map = Arc::new(PgLiteTypeMap::new(&[], true, false));
+        SimplePgLiteDBBackend::open(PathBuf::from(name), false, &[], true, &pragmas, type_map, false, 100, 16).unwrap()
+    }
+
+    fn column_types(backend:&SimplePgLiteDBBackend, query:&str) -> Vec<PgType> {
+        backend.describe_query(query).unwrap().result_schema.unwrap().into_iter().map(|field| field.pg_type).collect()
+    }
+
+    #[test]
+    fn narrows_rowid_aliases_by_their_table_column() {
+        let backend = open_int4_primary_keys("rowid_aliases");
+        backend.con.execute_batch("CREATE TABLE a (id INTEGER PRIMARY KEY, n INTEGER); CREATE TABLE b (id INTEGER, a_id INTEGER)").unwrap();
+        assert_eq!(column_types(&backend, "SELECT id, n FROM a"), vec![PgType::INT4, PgType::INT8]);
+        assert_eq!(column_types(&backend, "SELECT id AS key FROM a"), vec![PgType::INT4]);
+        // An int8 column named (or aliased) like a primary key stays int8
+        assert_eq!(column_types(&backend, "SELECT b.id, a.n AS id FROM b, a"), vec![PgType::INT8, PgType::INT8]);
+        assert_eq!(column_types(&backend, "SELECT b.id, a.id FROM b JOIN a ON a.id = b.a_id"), vec![PgType::INT8, PgType::INT4]);
+    }
+
+    #[test]
+    fn counts_only_the_rows_the_statement_changed() {
+        let backend = open_int4_primary_keys("affected_rows");
+        backend.con.execute_batch("CREATE TABLE item (id INTEGER PRIMARY KEY, version INTEGER); CREATE TABLE audit (item_id INTEGER);
+            CREATE TRIGGER audit_item AFTER UPDATE ON item BEGIN INSERT INTO audit VALUES (new.id); INSERT INTO audit VALUES (new.id); END;
+            INSERT INTO item VALUES (1, 1), (2, 1)").unwrap();
+        let execute = |query:&str| backend.execute_counting_changes(|| backend.con.execute(query, ())).unwrap();
+        assert_eq!(execute("UPDATE item SET version = 2 WHERE id = 1 AND version = 1"), 1);
+        assert_eq!(execute("UPDATE item SET version = 3 WHERE id = 1 AND version = 1"), 0);
+        assert_eq!(execute("UPDATE item SET version = version + 1"), 2);
+        // changes() still holds the UPDATE's count, it's only reset by another INSERT/UPDATE/DELETE
+        assert_eq!(execute("CREATE TABLE other (n)"), 0);
+    }
+
+    #[test]
+    fn rejects_a_db_root_that_isnt_a_directory() {
+        let dir = std::env::temp_dir().join(format!("pglite-db-root-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let factory = |db_root:PathBuf, backend:&str| {
+            let config = PgLiteConfig::parse_from(["pglite", "--backend", backend, "--db-root"].map(OsString::from).into_iter().chain([db_root.into_os_string()]));
+            SimplePgLiteDBBackendFactory::new(&config).err()
+        };
+
+        let missing = factory(dir.join("missing"), "simple").unwrap();
+        assert!(missing.starts_with(&format!("The database root '{}' can't be used (--db-root): ", dir.join("missing").display())), "{missing}");
+        assert_eq!(factory(dir.join("file"), "simple"), Some(format!("The database root '{}' isn't a directory (--db-root)", dir.join("file").display())));
+        assert_eq!(factory(dir.clone(), "simple"), None);
+        // The in-memory backend doesn't use the database root
+        assert_eq!(factory(dir.join("missing"), "memory"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn percent_encodes_memory_db_uris() {
+        assert_eq!(memory_db_uri(Path::new("/john/data.sqlite")), "file:/pglite/john/data.sqlite?vfs=memdb");
+        assert_eq!(memory_db_uri(Path::new("a b/c?d#e&f=g%h.db")), "file:/pglite/a%20b/c%3Fd%23e%26f%3Dg%25h.db?vfs=memdb");
+        assert_eq!(memory_db_uri(Path::new("caf\u{e9}.db")), "file:/pglite/caf%C3%A9.db?vfs=memdb");
+    }
+
+    #[test]
+    fn only_accepts_relative_paths_of_names() {
+        assert!(is_relative_path("data.db"));
+        assert!(is_relative_path("./reports/2024.db"));
+        assert!(!is_relative_path(""));
+        assert!(!is_relative_path("/etc/passwd"));
+        assert!(!is_relative_path("../other/data.db"));
+        assert!(!is_relative_path("reports/../../data.db"));
+    }
+
+    #[test]
+    fn resolves_databases_within_the_root() {
+        let dir = std::env::temp_dir().join(format!("pglite-resolve-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("reports")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        let root = root.canonicalize().unwrap();
+        std::fs::write(root.join("data.db"), b"").unwrap();
+
+        assert_eq!(resolve_within_root(&root, "data.db").unwrap(), root.join("data.db"));
+        assert_eq!(resolve_within_root(&root, "reports/new.db").unwrap(), root.join("reports/new.db"));
+        assert!(resolve_within_root(&root, "missing/new.db").is_err());
+        assert!(resolve_within_root(&root, "../outside/data.db").is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("outside"), root.join("escape")).unwrap();
+            std::os::unix::fs::symlink(dir.join("outside/missing.db"), root.join("dangling.db")).unwrap();
+            let err = resolve_within_root(&root, "escape/data.db").unwrap_err();
+            assert!(matches!(err, PgWireError::UserError(info) if info.code() == "42501"));
+            assert!(resolve_within_root(&root, "dangling.db").is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
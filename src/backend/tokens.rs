@@ -0,0 +1,90 @@
+/* Follows is a minimal tokenizer for SQL statements, for the rewrites that need to know what's a keyword or identifier - as opposed
+   to part of a string, quoted identifier or comment */
+
+/// A token of the statement (a word, a quoted string or identifier, or a single character of punctuation) - comments + whitespace
+/// are left out. Strings are a single token, including escape strings (`E'...'`) + dollar-quoted strings (`$tag$...$tag$`)
+#[derive(Debug)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub start: usize,
+}
+
+impl Token<'_> {
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+}
+
+pub fn tokenize(query:&str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let bytes = query.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let start = pos;
+        match bytes[pos] {
+            b if b.is_ascii_whitespace() => {
+                pos += 1;
+                continue;
+            },
+            b'-' if bytes.get(pos + 1) == Some(&b'-') => {
+                pos = query[pos..].find('\n').map_or(bytes.len(), |end| pos + end);
+                continue;
+            },
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                pos = query[pos + 2..].find("*/").map_or(bytes.len(), |end| pos + 2 + end + 2);
+                continue;
+            },
+            quote @ (b'\'' | b'"' | b'`' | b'[') => {
+                let close = if quote == b'[' { b']' } else { quote };
+                pos += 1;
+                while pos < bytes.len() {
+                    pos += 1;
+                    if bytes[pos - 1] == close {
+                        // A doubled quote is an escaped quote, within the string
+                        if close != b']' && bytes.get(pos) == Some(&close) {
+                            pos += 1;
+                            continue;
+                        }
+                        break;
+                    }
+                }
+            },
+            // A dollar-quoted string, eg. `$$it's$$` or `$body$...$body$`
+            b'$' if dollar_quote(&query[pos..]).is_some() => {
+                let tag = dollar_quote(&query[pos..]).unwrap_or_default();
+                pos = query[pos + tag.len()..].find(tag).map_or(bytes.len(), |end| pos + tag.len() + end + tag.len());
+            },
+            // An escape string, eg. `E'it\'s'` - where a backslash escapes the quote after it
+            b'E' | b'e' if bytes.get(pos + 1) == Some(&b'\'') && !query[..pos].ends_with(|c:char| c.is_alphanumeric() || c == '_' || c == '$') => {
+                pos += 2;
+                while pos < bytes.len() {
+                    match bytes[pos] {
+                        b'\\' => pos += 2,
+                        b'\'' if bytes.get(pos + 1) == Some(&b'\'') => pos += 2,
+                        b'\'' => {
+                            pos += 1;
+                            break;
+                        },
+                        _ => pos += 1,
+                    }
+                }
+                pos = pos.min(bytes.len());
+            },
+            b if b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || !b.is_ascii() => {
+                while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_' || bytes[pos] == b'$' || !bytes[pos].is_ascii()) {
+                    pos += 1;
+                }
+            },
+            _ => pos += 1,
+        }
+        tokens.push(Token { text: &query[start..pos], start });
+    }
+    tokens
+}
+
+/// The opening `$tag$` of a dollar-quoted string at the start of the text - a parameter (eg. `$1`) isn't one
+fn dollar_quote(text:&str) -> Option<&str> {
+    let end = text[1..].find(|c:char| !(c.is_alphanumeric() || c == '_'))? + 1;
+    let starts_with_digit = text[1..].starts_with(|c:char| c.is_ascii_digit());
+    (text[end..].starts_with('$') && !starts_with_digit).then(|| &text[..end + 1])
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use rusqlite::types::{Type, Value};
+
+/// How values of a declared column type are stored in SQLite, and the Postgres type that's advertised to clients for them
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgLiteTypeMapping {
+    pub sqlite_type: Type,
+    pub pg_type: pgwire::api::Type,
+}
+
+/// A type mapping rule from the config, in the form `declared_type=sqlite_type:pg_type` (eg. `INET=text:inet`)
+#[derive(Debug, Clone)]
+pub struct PgLiteTypeRule {
+    pub declared_type: String,
+    pub mapping: PgLiteTypeMapping,
+}
+
+impl std::str::FromStr for PgLiteTypeRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (declared_type, target) = rule.split_once('=')
+            .ok_or_else(|| format!("Invalid type mapping '{rule}', expected: declared_type=sqlite_type:pg_type"))?;
+        let (sqlite_type, pg_type) = target.split_once(':')
+            .ok_or_else(|| format!("Invalid type mapping '{rule}', expected: declared_type=sqlite_type:pg_type"))?;
+
+        let sqlite_type = match sqlite_type.trim().to_lowercase().as_str() {
+            "integer" => Type::Integer,
+            "real" => Type::Real,
+            "text" => Type::Text,
+            "blob" => Type::Blob,
+            other => return Err(format!("Unknown SQLite storage type '{other}', expected one of: integer, real, text, blob")),
+        };
+        let pg_type = pg_type_from_name(pg_type.trim())
+            .ok_or_else(|| format!("Unknown or unsupported Postgres type '{}'", pg_type.trim()))?;
+
+        Ok(Self { declared_type: normalise_declared_type(declared_type), mapping: PgLiteTypeMapping { sqlite_type, pg_type } })
+    }
+}
+
+/// The table used to map the declared type of a column to a SQLite storage type + Postgres type
+#[derive(Debug, Clone)]
+pub struct PgLiteTypeMap {
+    mappings: HashMap<String, PgLiteTypeMapping>,
+    /// The mapping for INTEGER PRIMARY KEY columns (the rowid alias), when they're not INT8 like any other integer
+    rowid_alias: Option<PgLiteTypeMapping>,
+    /// Whether the columns with no declared type are mapped from their values (`--type-inference value`)
+    infer_from_values: bool,
+}
+
+impl PgLiteTypeMap {
+    /// Build the type map from the default mappings, overridden by the provided rules - with `int4_primary_keys`, SERIAL + INTEGER
+    /// PRIMARY KEY columns are INT4 (like a Postgres `serial`) instead of the 64-bit integers they really are - and with
+    /// `infer_from_values`, the columns with no declared type are mapped from their values (see `lookup_value`)
+    pub fn new(rules: &[PgLiteTypeRule], int4_primary_keys: bool, infer_from_values: bool) -> Self {
+        use pgwire::api::Type as PgType;
+        let serial_type = if int4_primary_keys { PgType::INT4 } else { PgType::INT8 };
+        let defaults = [
+            (&["INT", "INTEGER", "INT2", "INT4", "INT8", "SMALLINT", "BIGINT", "TINYINT", "MEDIUMINT"][..], Type::Integer, PgType::INT8),
+            (&["SERIAL"][..], Type::Integer, serial_type),
+            (&["BIGSERIAL", "SMALLSERIAL"][..], Type::Integer, PgType::INT8),
+            (&["FLOAT", "FLOAT4", "FLOAT8", "REAL", "DOUBLE", "DOUBLE PRECISION"][..], Type::Real, PgType::FLOAT8),
+            (&["NUMERIC", "DECIMAL"][..], Type::Real, PgType::NUMERIC),
+            (&["BOOLEAN", "BOOL"][..], Type::Integer, PgType::BOOL),
+            (&["TEXT", "CLOB", "NAME"][..], Type::Text, PgType::TEXT),
+            (&["VARCHAR", "CHARACTER VARYING"][..], Type::Text, PgType::VARCHAR),
+            (&["CHAR", "CHARACTER", "BPCHAR", "NCHAR"][..], Type::Text, PgType::BPCHAR),
+            (&["BINARY", "BLOB", "BYTEA"][..], Type::Blob, PgType::BYTEA),
+            (&["DATE"][..], Type::Text, PgType::DATE),
+            (&["TIME"][..], Type::Text, PgType::TIME),
+            (&["TIMESTAMP", "DATETIME"][..], Type::Text, PgType::TIMESTAMP),
+            (&["TIMESTAMPTZ", "TIMESTAMP WITH TIME ZONE"][..], Type::Text, PgType::TIMESTAMPTZ),
+            (&["INET"][..], Type::Text, PgType::INET),
+            (&["CIDR"][..], Type::Text, PgType::CIDR),
+            (&["MACADDR"][..], Type::Text, PgType::MACADDR),
+            (&["INTERVAL"][..], Type::Text, PgType::INTERVAL),
+            (&["UUID"][..], Type::Text, PgType::UUID),
+            (&["JSON"][..], Type::Text, PgType::JSON),
+            (&["JSONB"][..], Type::Text, PgType::JSONB),
+            (&["MONEY"][..], Type::Text, PgType::TEXT),
+            // Arrays are stored as JSON arrays (see `array_params`)
+            (&["INT[]", "INTEGER[]", "INT2[]", "INT4[]", "INT8[]", "SMALLINT[]", "BIGINT[]"][..], Type::Text, PgType::INT8_ARRAY),
+            (&["FLOAT[]", "FLOAT4[]", "FLOAT8[]", "REAL[]", "DOUBLE[]", "DOUBLE PRECISION[]"][..], Type::Text, PgType::FLOAT8_ARRAY),
+            (&["TEXT[]", "VARCHAR[]", "CHARACTER VARYING[]"][..], Type::Text, PgType::TEXT_ARRAY),
+        ];
+
+        let mut mappings = HashMap::with_capacity(64);
+        for (declared_types, sqlite_type, pg_type) in defaults {
+            for declared_type in declared_types {
+                mappings.insert(declared_type.to_string(), PgLiteTypeMapping { sqlite_type: sqlite_type.clone(), pg_type: pg_type.clone() });
+            }
+        }
+        for rule in rules {
+            mappings.insert(rule.declared_type.clone(), rule.mapping.clone());
+        }
+        let rowid_alias = int4_primary_keys.then_some(PgLiteTypeMapping { sqlite_type: Type::Integer, pg_type: PgType::INT4 });
+        Self { mappings, rowid_alias, infer_from_values }
+    }
+
+    /// Whether INTEGER PRIMARY KEY columns have their own mapping, so need to be told apart from other integer columns
+    pub fn int4_primary_keys(&self) -> bool {
+        self.rowid_alias.is_some()
+    }
+
+    /// Find the mapping for a declared column type, falling back to TEXT for types we don't know about
+    pub fn lookup(&self, declared_type: &str) -> PgLiteTypeMapping {
+        // Try the full type name first (eg. `DOUBLE PRECISION`), then just the first word of it (eg. `TIMESTAMP WITHOUT TIME ZONE`)
+        let type_str = normalise_declared_type(declared_type);
+        let first_word = type_str.split(' ').next().unwrap_or_default();
+        self.mappings.get(&type_str)
+            .or_else(|| self.mappings.get(first_word))
+            .cloned()
+            .unwrap_or(PgLiteTypeMapping { sqlite_type: Type::Text, pg_type: pgwire::api::Type::VARCHAR })
+    }
+
+    /// Whether the columns with no declared type are mapped from their values, rather than as VARCHAR
+    pub fn infers_from_values(&self) -> bool {
+        self.infer_from_values
+    }
+
+    /// Find the mapping for a column with no declared type from the storage class of one of its values - or None for a NULL
+    pub fn lookup_value(&self, value: &Value) -> Option<PgLiteTypeMapping> {
+        use pgwire::api::Type as PgType;
+        let (sqlite_type, pg_type) = match value {
+            Value::Null => return None,
+            Value::Integer(_) => (Type::Integer, PgType::INT8),
+            Value::Real(_) => (Type::Real, PgType::FLOAT8),
+            Value::Text(_) => (Type::Text, PgType::TEXT),
+            Value::Blob(_) => (Type::Blob, PgType::BYTEA),
+        };
+        Some(PgLiteTypeMapping { sqlite_type, pg_type })
+    }
+
+    /// Find the mapping for a column that may be its table's INTEGER PRIMARY KEY (the rowid alias)
+    pub fn lookup_column(&self, declared_type: &str, is_rowid_alias: bool) -> PgLiteTypeMapping {
+        match &self.rowid_alias {
+            Some(mapping) if is_rowid_alias && normalise_declared_type(declared_type) == "INTEGER" => mapping.clone(),
+            _ => self.lookup(declared_type),
+        }
+    }
+}
+
+/// Ignore the additional specifiers like the field length (which aren't important for sqlite), eg. `varchar(20)` => `VARCHAR` - and
+/// the dimensions of arrays, eg. `int[3][3]` or `int ARRAY` => `INT[]`
+fn normalise_declared_type(declared_type: &str) -> String {
+    let without_specifiers = match declared_type.split_once('(') {
+        Some((name, rest)) => format!("{} {}", name, rest.split_once(')').map(|(_, after)| after).unwrap_or_default()),
+        None => declared_type.to_owned()
+    };
+    let normalised = without_specifiers.split_whitespace().collect::<Vec<&str>>().join(" ").to_uppercase();
+    match normalised.split_once('[').map(|(name, _)| name).or_else(|| normalised.strip_suffix(" ARRAY")) {
+        Some(element_type) => format!("{}[]", element_type.trim_end()),
+        None => normalised,
+    }
+}
+
+/// Look up a Postgres type by its name (as used in the Postgres catalog, or one of its common aliases)
+pub fn pg_type_from_name(name: &str) -> Option<pgwire::api::Type> {
+    use pgwire::api::Type as PgType;
+    let pg_type = match name.to_lowercase().as_str() {
+        "bool" | "boolean" => PgType::BOOL,
+        "int2" | "smallint" => PgType::INT2,
+        "int4" | "int" | "integer" => PgType::INT4,
+        "int8" | "bigint" => PgType::INT8,
+        "float4" | "real" => PgType::FLOAT4,
+        "float8" | "double precision" => PgType::FLOAT8,
+        "numeric" | "decimal" => PgType::NUMERIC,
+        "money" => PgType::MONEY,
+        "text" => PgType::TEXT,
+        "varchar" => PgType::VARCHAR,
+        "bpchar" | "char" => PgType::BPCHAR,
+        "name" => PgType::NAME,
+        "bytea" => PgType::BYTEA,
+        "date" => PgType::DATE,
+        "time" => PgType::TIME,
+        "timetz" => PgType::TIMETZ,
+        "timestamp" => PgType::TIMESTAMP,
+        "timestamptz" => PgType::TIMESTAMPTZ,
+        "interval" => PgType::INTERVAL,
+        "uuid" => PgType::UUID,
+        "json" => PgType::JSON,
+        "jsonb" => PgType::JSONB,
+        "xml" => PgType::XML,
+        "inet" => PgType::INET,
+        "cidr" => PgType::CIDR,
+        "macaddr" => PgType::MACADDR,
+        "bit" => PgType::BIT,
+        "varbit" => PgType::VARBIT,
+        "oid" => PgType::OID,
+        "_int8" | "int8[]" | "bigint[]" => PgType::INT8_ARRAY,
+        "_float8" | "float8[]" | "double precision[]" => PgType::FLOAT8_ARRAY,
+        "_text" | "text[]" => PgType::TEXT_ARRAY,
+        _ => return None,
+    };
+    Some(pg_type)
+}
@@ -0,0 +1,208 @@
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use pgwire::{api::{query::{SimpleQueryHandler, ExtendedQueryHandler}, results::Response, portal::Portal, stmt::{StoredStatement, NoopQueryParser}, store::MemPortalStore, ClientInfo, PgWireConnectionState, Type}, error::{PgWireResult, PgWireError}, messages::extendedquery::Bind};
+
+use crate::backend::{load_backend_factory, PgLitebackendFactory};
+use crate::config::PgLiteConfig;
+use crate::query_handler::PgQueryProcessor;
+use crate::session::PgLiteSession;
+
+/* Follows is the benchmark workload, run against a temporary database with `--benchmark` or by the criterion benches (`cargo bench`) */
+
+/// The number of rows the benchmark table is seeded with
+pub const BENCHMARK_ROWS: i64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scenario {
+    /// A single row looked up by its primary key, as a simple query
+    PointSelect,
+    /// The same lookup as a parameterized (extended protocol) query
+    ParameterizedSelect,
+    /// Every row of the benchmark table, as a simple query
+    LargeResult,
+    /// A single row inserted, as a simple query
+    Insert,
+}
+
+impl Scenario {
+    pub const ALL: [Scenario; 4] = [Scenario::PointSelect, Scenario::ParameterizedSelect, Scenario::LargeResult, Scenario::Insert];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PointSelect => "point select",
+            Self::ParameterizedSelect => "parameterized select",
+            Self::LargeResult => "large result",
+            Self::Insert => "insert",
+        }
+    }
+
+    /// The number of times the scenario is run by `--benchmark`
+    fn iterations(&self) -> usize {
+        match self {
+            Self::PointSelect | Self::ParameterizedSelect => 5000,
+            Self::LargeResult => 50,
+            Self::Insert => 1000,
+        }
+    }
+}
+
+/// A query processor connected to a temporary, seeded database - which is removed when it's dropped
+pub struct BenchmarkDb {
+    db_factory: Box<dyn PgLitebackendFactory>,
+    processor: PgQueryProcessor,
+    client: BenchmarkClient,
+    db_root: PathBuf,
+}
+
+impl BenchmarkDb {
+    /// Create the temporary database (with the configured backend + its settings) and seed it with the benchmark table
+    pub async fn create(mut config:PgLiteConfig) -> PgWireResult<Self> {
+        let db_root = std::env::temp_dir().join(format!("pglite-benchmark-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&db_root).map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+        config.db_root = db_root.clone();
+        config.per_user_root = false;
+        config.db_template = None;
+        config.create_if_missing = true;
+
+        let db_factory = load_backend_factory(&config).map_err(|e| PgWireError::ApiError(e.into()))?;
+        let metadata = HashMap::from([
+            (String::from("user"), String::from("pglite")),
+            (String::from("database"), String::from("benchmark.db")),
+            (String::from("dbpath"), String::from("benchmark.db")),
+        ]);
+        let backend = db_factory.create_backend(&metadata)?;
+        let processor = PgQueryProcessor::create(backend, Arc::new(MemPortalStore::new()), Arc::new(NoopQueryParser::new()), Arc::new(Mutex::new(PgLiteSession::new())), None, config.max_result_bytes, uuid::Uuid::nil());
+        let client = BenchmarkClient { socket_addr:([127, 0, 0, 1], 0).into(), state:PgWireConnectionState::ReadyForQuery, metadata };
+
+        let mut db = Self { db_factory:Box::new(db_factory), processor, client, db_root };
+        db.simple_query("CREATE TABLE bench (id INTEGER PRIMARY KEY, name TEXT, score REAL, created TIMESTAMP)").await?;
+        db.simple_query("CREATE TABLE bench_writes (id INTEGER PRIMARY KEY, name TEXT, score REAL)").await?;
+        db.simple_query(&format!("INSERT INTO bench (id, name, score, created) \
+            WITH RECURSIVE seq(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM seq WHERE n < {BENCHMARK_ROWS}) \
+            SELECT n, 'name ' || n, n * 0.25, datetime('2023-01-01', '+' || n || ' minutes') FROM seq")).await?;
+        Ok(db)
+    }
+
+    /// Run one iteration of a scenario, returning the number of rows it returned
+    pub async fn run(&mut self, scenario:Scenario, iteration:usize) -> PgWireResult<usize> {
+        // Spread the lookups over the table, rather than hitting the same pages every time
+        let id = (iteration as i64 * 7919) % BENCHMARK_ROWS + 1;
+        match scenario {
+            Scenario::PointSelect => self.simple_query(&format!("SELECT id, name, score, created FROM bench WHERE id = {id}")).await,
+            Scenario::ParameterizedSelect => self.parameterized_query("SELECT id, name, score, created FROM bench WHERE id = $1", id).await,
+            Scenario::LargeResult => self.simple_query("SELECT id, name, score, created FROM bench").await,
+            Scenario::Insert => self.simple_query(&format!("INSERT INTO bench_writes (name, score) VALUES ('row {iteration}', {id})")).await,
+        }
+    }
+
+    async fn simple_query(&mut self, query:&str) -> PgWireResult<usize> {
+        let mut rows = 0;
+        for response in SimpleQueryHandler::do_query(&self.processor, &self.client, query).await? {
+            rows += count_rows(response).await?;
+        }
+        Ok(rows)
+    }
+
+    async fn parameterized_query(&mut self, query:&str, param:i64) -> PgWireResult<usize> {
+        let statement = StoredStatement::new(String::new(), query.to_owned(), vec![Type::INT8]);
+        let bind = Bind::new(None, None, vec![1], vec![Some(Bytes::copy_from_slice(&param.to_be_bytes()))], vec![]);
+        let portal = Portal::try_new(&bind, Arc::new(statement))?;
+        let response = ExtendedQueryHandler::do_query(&self.processor, &mut self.client, &portal, 0).await?;
+        count_rows(response).await
+    }
+}
+
+impl Drop for BenchmarkDb {
+    fn drop(&mut self) {
+        self.db_factory.shutdown();
+        let _ = std::fs::remove_dir_all(&self.db_root);
+    }
+}
+
+/// The client the benchmark queries are run for, in place of a network connection
+struct BenchmarkClient {
+    socket_addr: SocketAddr,
+    state: PgWireConnectionState,
+    metadata: HashMap<String, String>,
+}
+
+impl ClientInfo for BenchmarkClient {
+    fn socket_addr(&self) -> &SocketAddr {
+        &self.socket_addr
+    }
+
+    fn is_secure(&self) -> bool {
+        false
+    }
+
+    fn state(&self) -> &PgWireConnectionState {
+        &self.state
+    }
+
+    fn set_state(&mut self, new_state:PgWireConnectionState) {
+        self.state = new_state;
+    }
+
+    fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut self.metadata
+    }
+}
+
+/// Consume a response's rows (so they're encoded, as they would be for a client), returning how many there were
+async fn count_rows(response:Response<'_>) -> PgWireResult<usize> {
+    match response {
+        Response::Query(results) => results.data_rows().try_fold(0, |rows, _| async move { Ok(rows + 1) }).await,
+        Response::Error(err) => Err(PgWireError::UserError(err)),
+        _ => Ok(0),
+    }
+}
+
+/// Run the `--benchmark` workload, printing the throughput + latency of each scenario
+pub async fn run(config:PgLiteConfig) -> PgWireResult<()> {
+    println!("Seeding a temporary database with {BENCHMARK_ROWS} rows...");
+    let mut db = BenchmarkDb::create(config).await?;
+
+    println!("{:<22} {:>6} {:>10} {:>12} {:>10} {:>10} {:>10} {:>10}", "scenario", "ops", "ops/sec", "rows/sec", "avg", "p50", "p99", "max");
+    for scenario in Scenario::ALL {
+        // A few untimed runs first, so the statement cache + database pages are warm
+        for iteration in 0..10 {
+            db.run(scenario, iteration).await?;
+        }
+        let mut latencies = Vec::with_capacity(scenario.iterations());
+        let mut rows = 0;
+        let started = Instant::now();
+        for iteration in 0..scenario.iterations() {
+            let start = Instant::now();
+            rows += db.run(scenario, iteration).await?;
+            latencies.push(start.elapsed());
+        }
+        let elapsed = started.elapsed().as_secs_f64();
+        latencies.sort();
+
+        let average = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+        println!("{:<22} {:>6} {:>10.0} {:>12.0} {:>10} {:>10} {:>10} {:>10}",
+            scenario.name(),
+            latencies.len(),
+            latencies.len() as f64 / elapsed,
+            rows as f64 / elapsed,
+            format_latency(average),
+            format_latency(percentile(&latencies, 50)),
+            format_latency(percentile(&latencies, 99)),
+            format_latency(latencies[latencies.len() - 1]),
+        );
+    }
+    Ok(())
+}
+
+fn percentile(sorted:&[Duration], percent:usize) -> Duration {
+    sorted[(sorted.len() * percent / 100).min(sorted.len() - 1)]
+}
+
+fn format_latency(latency:Duration) -> String {
+    format!("{:.3}ms", latency.as_secs_f64() * 1000.0)
+}
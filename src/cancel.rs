@@ -0,0 +1,87 @@
+use std::{collections::HashMap, fmt::Debug, sync::{Arc, Mutex}};
+use rusqlite::InterruptHandle;
+
+/* Follows is the support for cancelling queries - a client sends a CancelRequest on a new connection, with the key it was given in
+   its BackendKeyData, which interrupts the SQLite handle while it's running one of the client's statements */
+
+/// The client metadata entry holding the connection's backend key (`{process id} {secret key}`), until it's sent to the client
+pub const METADATA_BACKEND_KEY: &str = "pglite_backend_key";
+
+/// The SQLite handle that's running one of a session's statements (if any), so the statement can be interrupted
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    running: Arc<Mutex<Option<Arc<InterruptHandle>>>>,
+}
+
+impl CancelToken {
+    /// Called by the backend as it starts running one of the session's statements on the handle
+    pub fn running(&self, handle:Arc<InterruptHandle>) {
+        *self.running.lock().unwrap() = Some(handle);
+    }
+
+    /// Called by the backend once it's finished with the statement - the handle goes on to run other sessions' statements, so it
+    /// mustn't be interrupted after this
+    pub fn finished(&self) {
+        self.running.lock().unwrap().take();
+    }
+
+    /// Interrupt the session's running statement, returning whether there was one
+    pub fn cancel(&self) -> bool {
+        match self.running.lock().unwrap().as_ref() {
+            Some(handle) => {
+                handle.interrupt();
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl Debug for CancelToken {
+    fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancelToken").field("running", &self.running.lock().unwrap().is_some()).finish()
+    }
+}
+
+/// The sessions whose statements can be cancelled, by their backend key (process id + secret key)
+#[derive(Clone, Default)]
+pub struct CancelRegistry {
+    sessions: Arc<Mutex<HashMap<(i32, i32), CancelToken>>>,
+}
+
+impl CancelRegistry {
+    /// Register a session under a new (random) key - it's removed from the registry once the key is dropped
+    pub fn register(&self, token:CancelToken) -> BackendKey {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = loop {
+            // Like a Postgres process id, the process id is always positive - and it's unique, as it identifies the connection on its own
+            // (eg. to the admin commands)
+            let key = (rand::random::<i32>() & i32::MAX, rand::random::<i32>());
+            if !sessions.keys().any(|(process_id, _)| *process_id == key.0) {
+                break key;
+            }
+        };
+        sessions.insert(key, token);
+        BackendKey { process_id:key.0, secret_key:key.1, registry:self.clone() }
+    }
+
+    /// Cancel the running statement of the session with the key, returning whether there was one to cancel - a key that doesn't
+    /// match any session is ignored, like Postgres
+    pub fn cancel(&self, process_id:i32, secret_key:i32) -> bool {
+        let token = self.sessions.lock().unwrap().get(&(process_id, secret_key)).cloned();
+        token.is_some_and(|token| token.cancel())
+    }
+}
+
+/// A session's key for cancelling its statements, sent to the client in its BackendKeyData
+pub struct BackendKey {
+    pub process_id: i32,
+    pub secret_key: i32,
+    registry: CancelRegistry,
+}
+
+impl Drop for BackendKey {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&(self.process_id, self.secret_key));
+    }
+}
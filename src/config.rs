@@ -2,8 +2,9 @@ use std::{path::PathBuf, net::SocketAddr};
 use clap::Parser;
 use log::LevelFilter;
 
-use crate::{backend::PgLiteBackendType, auth::PgLiteAuthType};
+use crate::{backend::{PgLiteBackendType, PgLiteTypeRule}, auth::PgLiteAuthType};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
 pub enum PgLiteLogLevel {
     #[clap(alias = "off")]
@@ -20,9 +21,9 @@ pub enum PgLiteLogLevel {
     TRACE,
 }
 
-impl Into<LevelFilter> for PgLiteLogLevel {
-    fn into(self) -> LevelFilter {
-        match self {
+impl From<PgLiteLogLevel> for LevelFilter {
+    fn from(level: PgLiteLogLevel) -> LevelFilter {
+        match level {
             PgLiteLogLevel::OFF => LevelFilter::Off,
             PgLiteLogLevel::ERROR => LevelFilter::Error,
             PgLiteLogLevel::WARN => LevelFilter::Warn,
@@ -33,19 +34,145 @@ impl Into<LevelFilter> for PgLiteLogLevel {
     }
 }
 
+/// SQLite's journal modes, for `PRAGMA journal_mode`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SqliteJournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl SqliteJournalMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SqliteJournalMode::Delete => "delete",
+            SqliteJournalMode::Truncate => "truncate",
+            SqliteJournalMode::Persist => "persist",
+            SqliteJournalMode::Memory => "memory",
+            SqliteJournalMode::Wal => "wal",
+            SqliteJournalMode::Off => "off",
+        }
+    }
+}
+
+/// What happens to a new connection once the server has `--max-connections` open
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MaxConnectionsMode {
+    /// Turn the client away with the error `53300`
+    Reject,
+    /// Hold the connection (without reading its startup message) until another connection closes
+    Queue,
+}
+
+/// How the values of UUID parameters are stored in SQLite
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum UuidStorage {
+    /// As canonical text, eg. `a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11`
+    #[default]
+    Text,
+    /// As 16 byte blobs
+    Blob,
+}
+
+/// How the Postgres type of a result column that has no declared type (eg. an expression like `count(*)`) is chosen
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum TypeInference {
+    /// From the declared type, so they're all sent as varchar
+    #[default]
+    Declared,
+    /// From the SQLite storage class of the column's first non-NULL value, eg. an integer is sent as int8 (simple queries only)
+    Value,
+}
+
+/// SQLite's synchronous settings, for `PRAGMA synchronous`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SqliteSynchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl SqliteSynchronous {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SqliteSynchronous::Off => "off",
+            SqliteSynchronous::Normal => "normal",
+            SqliteSynchronous::Full => "full",
+            SqliteSynchronous::Extra => "extra",
+        }
+    }
+}
+
+/// The idle timeout of the databases whose path starts with a prefix, in the form `prefix=seconds` (eg. `reports/=60`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbIdleTimeoutOverride {
+    /// The start of the database's path, relative to the database root (or the name of an in-memory database)
+    pub prefix: String,
+    pub timeout: u64,
+}
+
+impl std::str::FromStr for DbIdleTimeoutOverride {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (prefix, timeout) = rule.rsplit_once('=')
+            .ok_or_else(|| format!("Invalid idle timeout override '{rule}', expected: prefix=seconds"))?;
+        let timeout = timeout.trim().parse()
+            .map_err(|_| format!("Invalid idle timeout '{}' for '{}', expected a number of seconds", timeout.trim(), prefix.trim()))?;
+        Ok(Self { prefix: prefix.trim().to_owned(), timeout })
+    }
+}
+
 
 #[derive(Debug, Parser)]
 #[command(name = "pglite")]
 #[command(about = "SQLite over Postgres", long_about = "This process will provide access to SQLite databases over a Postgres connnection.")]
 pub struct PgLiteConfig {
-    /// The address on which the process will listen on
+    /// The addresses on which the process will listen on, separated by commas (eg. `0.0.0.0:5432,[::]:5432` for IPv4 + IPv6)
     #[clap(
         long = "listen-address", 
         short = 'a', 
+        value_delimiter = ',',
         env = "PGLITE_LISTEN_ADDR", 
         default_value = "0.0.0.0:5432"
     )]
-    pub listen_addr: SocketAddr,
+    pub listen_addrs: Vec<SocketAddr>,
+
+    /// Start as long as one of the listen addresses (or the Unix socket) can be bound, rather than failing if any of them can't -
+    /// each address that can't be bound is logged
+    #[clap(
+        long = "allow-partial-listen",
+        env = "PGLITE_ALLOW_PARTIAL_LISTEN"
+    )]
+    pub allow_partial_listen: bool,
+
+    /// A Unix socket to listen at as well (eg. `/var/run/postgresql`) - when it's a directory, the socket is created in it with the
+    /// name Postgres clients look for (`.s.PGSQL.{port}`, with the port of the first listen address)
+    #[clap(
+        long = "unix-socket",
+        env = "PGLITE_UNIX_SOCKET"
+    )]
+    pub unix_socket: Option<PathBuf>,
+
+    /// The certificate chain (PEM) to offer clients that request TLS - clients can only use TLS if this + `--tls-key` are set
+    #[clap(
+        long = "tls-cert",
+        requires = "tls_key",
+        env = "PGLITE_TLS_CERT"
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The private key (PEM) of the TLS certificate
+    #[clap(
+        long = "tls-key",
+        requires = "tls_cert",
+        env = "PGLITE_TLS_KEY"
+    )]
+    pub tls_key: Option<PathBuf>,
     
     /// The Database backend to use
     #[clap(
@@ -67,14 +194,29 @@ pub struct PgLiteConfig {
     )]
     pub authenticator: PgLiteAuthType,
 
-    /// The Configuration data for the authenticator
+    /// The Configuration data for the authenticator - the static password every user connects with
     #[clap(
         long = "auth-config",
         short = 'y',
+        conflicts_with = "auth_users_file",
         env = "PGLITE_AUTH_CONFIG"
     )]
     pub auth_config: Option<String>,
 
+    /// A JSON file of users + their passwords (+ the databases they can connect to), instead of one static password
+    #[clap(
+        long = "auth-users-file",
+        env = "PGLITE_AUTH_USERS_FILE"
+    )]
+    pub auth_users_file: Option<PathBuf>,
+
+    /// Allow `--auth trust`, which lets any user connect without a password (for local development + testing only)
+    #[clap(
+        long = "allow-trust-auth",
+        env = "PGLITE_ALLOW_TRUST_AUTH"
+    )]
+    pub allow_trust_auth: bool,
+
     /// The Log level to use for the console Log
     #[clap(
         long = "consolelog-level",
@@ -121,4 +263,365 @@ pub struct PgLiteConfig {
         env = "PGLITE_DB_IDLE_TIMEOUT"
     )]
     pub db_idle_timeout: u64,
+
+    /// The idle timeouts of particular databases, in the form `prefix=seconds` (eg. `hot/=3600,reports/=60`) - a database whose
+    /// path (relative to the database root) starts with the prefix is released after that many idle seconds, instead of the
+    /// `--db-idle-timeout`. The longest matching prefix wins
+    #[clap(
+        long = "db-idle-timeout-override",
+        value_delimiter = ',',
+        env = "PGLITE_DB_IDLE_TIMEOUT_OVERRIDES"
+    )]
+    pub db_idle_timeout_overrides: Vec<DbIdleTimeoutOverride>,
+
+    /// Keep the database handle of a connected client warm by pinging the backend at this percentage of the idle timeout (0 disables)
+    #[clap(
+        long = "db-keepalive-percent",
+        default_value = "0",
+        value_parser = clap::value_parser!(u8).range(0..100),
+        env = "PGLITE_DB_KEEPALIVE_PERCENT"
+    )]
+    pub db_keepalive_percent: u8,
+
+    /// The journal mode to set on each database when its handle is opened (eg. `wal`, for concurrent readers + a writer) - left as
+    /// it is if not set. WAL mode is persistent, it stays set in the database file
+    #[clap(
+        long = "sqlite-journal-mode",
+        value_enum,
+        env = "PGLITE_SQLITE_JOURNAL_MODE"
+    )]
+    pub sqlite_journal_mode: Option<SqliteJournalMode>,
+
+    /// The synchronous setting for each database handle (eg. `normal`, which is safe with `wal`) - SQLite's default if not set
+    #[clap(
+        long = "sqlite-synchronous",
+        value_enum,
+        env = "PGLITE_SQLITE_SYNCHRONOUS"
+    )]
+    pub sqlite_synchronous: Option<SqliteSynchronous>,
+
+    /// The number of milliseconds to wait for a lock held by another process (eg. an external writer or a backup) before a statement
+    /// fails as the database is locked
+    #[clap(
+        long = "sqlite-busy-timeout",
+        default_value = "5000",
+        env = "PGLITE_SQLITE_BUSY_TIMEOUT"
+    )]
+    pub sqlite_busy_timeout: u64,
+
+    /// The most bind parameters a statement can have (SQLite's default is 32766) - SQLite won't raise it above the maximum it was
+    /// compiled with, so a higher value is lowered to that (with a warning)
+    #[clap(
+        long = "sqlite-max-variables",
+        value_parser = clap::value_parser!(i32).range(1..),
+        env = "PGLITE_SQLITE_MAX_VARIABLES"
+    )]
+    pub sqlite_max_variables: Option<i32>,
+
+    /// SQLite loadable extensions (`.so`, `.dylib` or `.dll` files) to load into every database handle as it's opened, separated by
+    /// commas - an extension runs native code inside the server's process, so they're only loaded with `--allow-sqlite-extensions`
+    #[clap(
+        long = "sqlite-extensions",
+        value_delimiter = ',',
+        env = "PGLITE_SQLITE_EXTENSIONS"
+    )]
+    pub sqlite_extensions: Vec<PathBuf>,
+
+    /// Allow `--sqlite-extensions` to be loaded (an extension can do anything the server's process can)
+    #[clap(
+        long = "allow-sqlite-extensions",
+        env = "PGLITE_ALLOW_SQLITE_EXTENSIONS"
+    )]
+    pub allow_sqlite_extensions: bool,
+
+    /// The number of read-only handles each database gets for running SELECTs (outside of a transaction block) alongside its main
+    /// handle, so reads don't wait on each other - writes are still run one at a time by the main handle (0 for no readers)
+    #[clap(
+        long = "db-readers",
+        default_value = "0",
+        env = "PGLITE_DB_READERS"
+    )]
+    pub db_readers: u32,
+
+    /// Whether clients may select session options by suffixing the database name (eg. `reports.db?readonly`)
+    #[clap(
+        long = "allow-session-options",
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        env = "PGLITE_ALLOW_SESSION_OPTIONS"
+    )]
+    pub allow_session_options: bool,
+
+    /// Open every database read-only, so no client can change them (eg. to serve reference datasets) - write statements fail with
+    /// a read-only error, and databases that don't exist yet aren't created
+    #[clap(
+        long = "read-only",
+        env = "PGLITE_READ_ONLY"
+    )]
+    pub read_only: bool,
+
+    /// Whether a transaction the client leaves open when it disconnects is rolled back - database handles are shared, so otherwise
+    /// it carries on into the next client's statements on the same handle
+    #[clap(
+        long = "rollback-on-close",
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        env = "PGLITE_ROLLBACK_ON_CLOSE"
+    )]
+    pub rollback_on_close: bool,
+
+    /// A SQLite database that's copied to create any database that doesn't exist yet, when it's first connected to (instead of
+    /// the database starting out empty) - eg. to give each test run its own pre-seeded database
+    #[clap(
+        long = "db-template",
+        env = "PGLITE_DB_TEMPLATE"
+    )]
+    pub db_template: Option<PathBuf>,
+
+    /// Create a database that doesn't exist yet when it's first connected to (implied by `--db-template`) - otherwise connecting
+    /// to it fails with the error `3D000`, so a mistyped database name doesn't leave a new empty database behind
+    #[clap(
+        long = "create-if-missing",
+        env = "PGLITE_CREATE_IF_MISSING"
+    )]
+    pub create_if_missing: bool,
+
+    /// Confine each user to their own `{db-root}/{user}` directory, the requested database is resolved within it (following any
+    /// symlinks) regardless of the authenticator's database path - connections to a database outside of it are rejected
+    #[clap(
+        long = "per-user-root",
+        env = "PGLITE_PER_USER_ROOT"
+    )]
+    pub per_user_root: bool,
+
+    /// Additional mappings of declared column types, in the form `declared_type=sqlite_type:pg_type` (eg. `INET=text:inet`)
+    #[clap(
+        long = "type-mapping",
+        value_delimiter = ',',
+        env = "PGLITE_TYPE_MAPPINGS"
+    )]
+    pub type_mappings: Vec<PgLiteTypeRule>,
+
+    /// Send INTEGER PRIMARY KEY (+ SERIAL) columns as INT4 rather than INT8, for clients expecting the `serial` primary keys of a
+    /// Postgres schema - values outside of the INT4 range are an error
+    #[clap(
+        long = "int4-primary-keys",
+        env = "PGLITE_INT4_PRIMARY_KEYS"
+    )]
+    pub int4_primary_keys: bool,
+
+    /// How UUID parameters are stored: as canonical text, or as 16 byte blobs - UUID columns are sent to clients as uuids either way
+    #[clap(
+        long = "uuid-storage",
+        value_enum,
+        default_value = "text",
+        env = "PGLITE_UUID_STORAGE"
+    )]
+    pub uuid_storage: UuidStorage,
+
+    /// How the type of a result column with no declared type (eg. `count(*)`) is chosen: `declared` sends them all as varchar,
+    /// `value` picks the type from the storage class of the column's first non-NULL value in the result of a simple query
+    #[clap(
+        long = "type-inference",
+        value_enum,
+        default_value = "declared",
+        env = "PGLITE_TYPE_INFERENCE"
+    )]
+    pub type_inference: TypeInference,
+
+    /// Expose the SQLite schema through emulated information_schema views (tables, columns, table_constraints + key_column_usage)
+    #[clap(
+        long = "information-schema",
+        env = "PGLITE_INFORMATION_SCHEMA"
+    )]
+    pub information_schema: bool,
+
+    /// The number of result rows the backend sends to the connection at a time - smaller batches use less memory + return the first
+    /// rows sooner, larger batches have better throughput for bulk transfers
+    #[clap(
+        long = "result-batch-size",
+        default_value = "1000",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "PGLITE_RESULT_BATCH_SIZE"
+    )]
+    pub result_batch_size: u32,
+
+    /// The number of queries that can be waiting to run on a database at a time, any more are rejected as the database is busy -
+    /// bounds the memory a flood of queries (or a stuck database) can take up
+    #[clap(
+        long = "backend-queue-size",
+        default_value = "10000",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "PGLITE_BACKEND_QUEUE_SIZE"
+    )]
+    pub backend_queue_size: u32,
+
+    /// The number of prepared statements each database handle keeps cached, for re-running the same SQL without preparing it again -
+    /// each cached statement holds its compiled program (typically a few KB, more for large statements), so size it to the number
+    /// of distinct statements the clients run
+    #[clap(
+        long = "statement-cache-size",
+        default_value = "128",
+        env = "PGLITE_STATEMENT_CACHE_SIZE"
+    )]
+    pub statement_cache_size: usize,
+
+    /// The maximum number of open client connections - each one takes a file descriptor + a task, so this stops a flood of
+    /// connections exhausting them (unlimited if not set)
+    #[clap(
+        long = "max-connections",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "PGLITE_MAX_CONNECTIONS"
+    )]
+    pub max_connections: Option<u32>,
+
+    /// What to do with a new connection once `--max-connections` are open: reject it, or queue it until another connection closes
+    #[clap(
+        long = "max-connections-mode",
+        value_enum,
+        default_value = "reject",
+        env = "PGLITE_MAX_CONNECTIONS_MODE"
+    )]
+    pub max_connections_mode: MaxConnectionsMode,
+
+    /// The maximum number of open connections from a single client IP, any more are rejected - so one misbehaving client (or a
+    /// retry storm) can't take up all of the server's connections (unlimited if not set)
+    #[clap(
+        long = "max-connections-per-ip",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "PGLITE_MAX_CONNECTIONS_PER_IP"
+    )]
+    pub max_connections_per_ip: Option<u32>,
+
+    /// The maximum length (in bytes) of a SQL statement, longer statements are rejected before they're prepared (unlimited if not set)
+    #[clap(
+        long = "max-query-length",
+        env = "PGLITE_MAX_QUERY_LENGTH"
+    )]
+    pub max_query_length: Option<usize>,
+
+    /// The maximum size (in bytes) of the encoded field values a query can return, the query is aborted once its result set grows
+    /// past it - protects memory from result sets of a few very wide rows, eg. large blobs (unlimited if not set)
+    #[clap(
+        long = "max-result-bytes",
+        env = "PGLITE_MAX_RESULT_BYTES"
+    )]
+    pub max_result_bytes: Option<usize>,
+
+    /// The maximum number of rows a query can return, the query is aborted once its result set grows past it - protects shared
+    /// deployments from a `SELECT *` of a huge table (0 = unlimited)
+    #[clap(
+        long = "max-result-rows",
+        default_value = "0",
+        env = "PGLITE_MAX_RESULT_ROWS"
+    )]
+    pub max_result_rows: usize,
+
+    /// The number of seconds to wait for the database to respond to a query, before it fails with a timeout (0 = no timeout) - raise
+    /// it for long-running queries, eg. analytics over large databases
+    #[clap(
+        long = "query-timeout",
+        default_value = "10",
+        env = "PGLITE_QUERY_TIMEOUT"
+    )]
+    pub query_timeout: u64,
+
+    /// The number of seconds a client can go without sending anything before its connection is closed (0 = no timeout) - so an
+    /// abandoned client doesn't hold its task (+ database handle) forever. A query that's running doesn't count as idle
+    #[clap(
+        long = "client-idle-timeout",
+        default_value = "0",
+        env = "PGLITE_CLIENT_IDLE_TIMEOUT"
+    )]
+    pub client_idle_timeout: u64,
+
+    /// The number of recent statements each connection keeps, for `pglite.statement_log(pid)` + to be written to the debug log if the
+    /// connection closes abnormally (0 disables)
+    #[clap(
+        long = "statement-log-size",
+        default_value = "20",
+        env = "PGLITE_STATEMENT_LOG_SIZE"
+    )]
+    pub statement_log_size: usize,
+
+    /// Log (at INFO) each query that takes at least this many milliseconds, with its duration + the number of rows - measured until
+    /// its results have been sent to the client (0 logs every query, not set disables)
+    #[clap(
+        long = "log-min-duration",
+        env = "PGLITE_LOG_MIN_DURATION"
+    )]
+    pub log_min_duration: Option<u64>,
+
+    /// The number of characters of a statement written to the slow query log, longer statements are truncated (the whole statement if not set)
+    #[clap(
+        long = "log-statement-max-length",
+        env = "PGLITE_LOG_STATEMENT_MAX_LENGTH"
+    )]
+    pub log_statement_max_length: Option<usize>,
+
+    /// Include the values of a statement's bound parameters in the slow query log - they're left out by default, as they may hold sensitive data
+    #[clap(
+        long = "log-parameters",
+        env = "PGLITE_LOG_PARAMETERS"
+    )]
+    pub log_parameters: bool,
+
+    /// A username that health probes can connect as without authenticating - probe connections can only run `SELECT 1`, and never open a database
+    #[clap(
+        long = "probe-user",
+        env = "PGLITE_PROBE_USER"
+    )]
+    pub probe_user: Option<String>,
+
+    /// An address to answer HTTP health checks at (eg. `0.0.0.0:8080`, for Kubernetes' probes) - any request gets `200 OK` while the
+    /// server is accepting connections + can open databases, or `503` when it can't (disabled if not set)
+    #[clap(
+        long = "health-addr",
+        env = "PGLITE_HEALTH_ADDR"
+    )]
+    pub health_addr: Option<SocketAddr>,
+
+    /// A user that can run the pglite admin commands (eg. `SELECT pglite.analyze('user/database')`) once authenticated
+    #[clap(
+        long = "admin-user",
+        env = "PGLITE_ADMIN_USER"
+    )]
+    pub admin_user: Option<String>,
+
+    /// Allow the admin user to shut the server down with `SELECT pglite.shutdown()`
+    #[clap(
+        long = "admin-shutdown",
+        env = "PGLITE_ADMIN_SHUTDOWN"
+    )]
+    pub admin_shutdown: bool,
+
+    /// The number of seconds to wait for open connections to close when shutting down (on SIGTERM, or the admin shutdown command)
+    #[clap(
+        long = "shutdown-timeout",
+        default_value = "30",
+        env = "PGLITE_SHUTDOWN_TIMEOUT"
+    )]
+    pub shutdown_timeout: u64,
+
+    /// The OpenTelemetry collector (OTLP over gRPC, eg. `http://localhost:4317`) to export query traces + metrics to - only
+    /// available in builds with the `otel` feature (disabled if not set)
+    #[clap(
+        long = "otlp-endpoint",
+        env = "PGLITE_OTLP_ENDPOINT"
+    )]
+    pub otlp_endpoint: Option<String>,
+
+    /// The fraction of connections (0.0 - 1.0) whose traces are exported, the metrics cover every query
+    #[clap(
+        long = "otlp-sample-ratio",
+        default_value = "1.0",
+        env = "PGLITE_OTLP_SAMPLE_RATIO"
+    )]
+    pub otlp_sample_ratio: f64,
+
+    /// Run a fixed workload against a temporary database (using the configured backend), report its throughput + latency and exit
+    #[clap(
+        long = "benchmark"
+    )]
+    pub benchmark: bool,
 }
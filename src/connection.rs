@@ -1,28 +1,43 @@
+use std::collections::HashMap;
 use std::io::Error as IOError;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use bytes::Buf;
-use futures::{SinkExt, StreamExt, future::poll_fn};
+use crossbeam_channel::RecvTimeoutError;
+use futures::{SinkExt, StreamExt};
 use pgwire::api::stmt::NoopQueryParser;
-use pgwire::api::store::MemPortalStore;
-use pgwire::api::{ClientInfoHolder, ClientInfo, PgWireConnectionState};
+use pgwire::api::store::{MemPortalStore, PortalStore};
+use pgwire::api::{ClientInfoHolder, ClientInfo, PgWireConnectionState, DEFAULT_NAME};
 use pgwire::api::query::{SimpleQueryHandler, ExtendedQueryHandler};
 use pgwire::error::{PgWireResult, PgWireError, ErrorInfo};
-use pgwire::messages::response::{READY_STATUS_IDLE, ReadyForQuery};
-use pgwire::messages::startup::SslRequest;
+use pgwire::messages::extendedquery::TARGET_TYPE_BYTE_STATEMENT;
+use pgwire::messages::response::ReadyForQuery;
+use pgwire::messages::startup::{SslRequest, Startup};
 use pgwire::messages::{PgWireFrontendMessage, PgWireBackendMessage};
 use pgwire::tokio::PgWireMessageServerCodec;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
 use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::Framed;
+use tracing::Instrument;
 use uuid::Uuid;
 
+use crate::admin::{self, AdminQueryHandler};
 use crate::auth::PgLiteAuthenticator;
-use crate::backend::PgLitebackendFactory;
-use crate::query_handler::PgQueryProcessor;
+use crate::cancel::{BackendKey, CancelRegistry, CancelToken, METADATA_BACKEND_KEY};
+use crate::backend::{BackendConnection, PgLitebackendFactory, PgLiteDBMessage, PgLiteDBResponse, PinnedBackend, references_pg_database};
+use crate::config::{PgLiteConfig, UuidStorage};
+use crate::query_handler::{CopyInState, PgQueryProcessor, SuspendedPortals};
+use crate::probe::ProbeQueryHandler;
+use crate::server::{finish_authentication, ShutdownTrigger};
+use crate::session::{PgLiteSession, TransactionStatus};
+use crate::statement_log::{StatementLog, StatementLogRegistration, StatementLogs};
+use crate::stream::ClientStream;
+use crate::telemetry::{self, SlowQueryLog};
 
 const GSSENC_REQUEST_MAGIC_NUMBER: i32 = 80877104;
+const CANCEL_REQUEST_MAGIC_NUMBER: i32 = 80877102;
 
 pub struct PgLiteConnection<F, A>  {
     pub connection_id: Uuid,
@@ -36,13 +51,52 @@ pub struct PgLiteConnection<F, A>  {
     authenticator: Arc<A>,
     portal_store: Arc<MemPortalStore<String>>,
     query_parser: Arc<NoopQueryParser>,
+    session: Arc<Mutex<PgLiteSession>>,
+    suspended_portals: SuspendedPortals,
+    /// The backend the client is pinned to once it's run its first query, so all of its statements run on the same database handle
+    backend: Option<PinnedBackend>,
+    copy_in: CopyInState,
+    cancel_token: CancelToken,
+    cancel_registry: CancelRegistry,
+    /// The key the client cancels its statements with, from another connection - it's registered while the connection is open
+    backend_key: Option<BackendKey>,
+    keepalive_interval: Option<Duration>,
+    /// How long the client can go without sending a message, before its connection is closed
+    client_idle_timeout: Option<Duration>,
+    probe_user: Option<String>,
+    is_probe: bool,
+    admin_user: Option<String>,
+    admin_shutdown: bool,
+    rollback_on_close: bool,
+    shutdown: ShutdownTrigger,
+    /// The recent statements, which the admin can look at while the connection is open - it's registered under the backend key's
+    /// process id
+    statement_log: Arc<Mutex<StatementLog>>,
+    statement_logs: StatementLogs,
+    statement_log_registration: Option<StatementLogRegistration>,
+    terminated: bool,
+    ignore_till_sync: bool,
+    max_query_length: Option<usize>,
+    max_result_bytes: Option<usize>,
+    max_result_rows: Option<usize>,
+    query_timeout: Option<Duration>,
+    slow_query_log: Option<Arc<SlowQueryLog>>,
+    uuid_storage: UuidStorage,
+    /// The error to turn the client away with once it's sent its startup message, when the connection isn't allowed
+    rejection: Option<ErrorInfo>,
+    /// Accepts the TLS connections of clients that request it, when the server has a certificate
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
 }
 
 impl <F, A> PgLiteConnection<F, A> 
-where F:PgLitebackendFactory, A: PgLiteAuthenticator {
-    pub fn create(db_factory: Arc<Mutex<F>>, authenticator: Arc<A>) -> Self {
+where F:PgLitebackendFactory + Send, A: PgLiteAuthenticator {
+    pub fn create(db_factory: Arc<Mutex<F>>, authenticator: Arc<A>, config: Arc<PgLiteConfig>, shutdown: ShutdownTrigger, tls_acceptor: Option<Arc<TlsAcceptor>>, cancel_registry: CancelRegistry, statement_logs: StatementLogs) -> Self {
         let connection_id: Uuid = Uuid::new_v4();
 
+        // Keepalives are sent at a percentage of the backend's idle timeout, so the DB handle doesn't get released while the client is connected
+        let keepalive_interval = Some(Duration::from_millis(config.db_idle_timeout * 10 * config.db_keepalive_percent as u64))
+            .filter(|interval| !interval.is_zero());
+
         PgLiteConnection {
             connection_id,
             socket_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
@@ -52,89 +106,147 @@ where F:PgLitebackendFactory, A: PgLiteAuthenticator {
             authenticator,
             portal_store: Arc::new(MemPortalStore::new()),
             query_parser: Arc::new(NoopQueryParser::new()),
+            session: Arc::new(Mutex::new(PgLiteSession::new())),
+            suspended_portals: SuspendedPortals::default(),
+            backend: None,
+            copy_in: CopyInState::default(),
+            cancel_token: CancelToken::default(),
+            cancel_registry,
+            backend_key: None,
+            keepalive_interval,
+            client_idle_timeout: Some(Duration::from_secs(config.client_idle_timeout)).filter(|timeout| !timeout.is_zero()),
+            probe_user: config.probe_user.clone(),
+            is_probe: false,
+            admin_user: config.admin_user.clone(),
+            admin_shutdown: config.admin_shutdown,
+            rollback_on_close: config.rollback_on_close,
+            shutdown,
+            statement_log: Arc::new(Mutex::new(StatementLog::new(config.statement_log_size))),
+            statement_logs,
+            statement_log_registration: None,
+            terminated: false,
+            ignore_till_sync: false,
+            max_query_length: config.max_query_length,
+            max_result_bytes: config.max_result_bytes,
+            max_result_rows: Some(config.max_result_rows).filter(|max_result_rows| *max_result_rows > 0),
+            query_timeout: Some(Duration::from_secs(config.query_timeout)).filter(|timeout| !timeout.is_zero()),
+            slow_query_log: SlowQueryLog::from_config(&config),
+            uuid_storage: config.uuid_storage,
+            rejection: None,
+            tls_acceptor,
         }
     }
 
-    pub async fn handle(&mut self, mut stream: TcpStream, socket_addr:SocketAddr) -> Result<(), IOError> {
-        // Configure Socket
-        stream.set_nodelay(true)?;
+    /// Turn the client away with the (FATAL) error, instead of authenticating it
+    pub fn reject(&mut self, error:ErrorInfo) {
+        self.rejection = Some(error);
+    }
+
+    /// Handle the client's connection (over TCP or a Unix socket) until it's closed
+    pub async fn handle<S>(&mut self, stream: S, socket_addr:SocketAddr) -> Result<(), IOError>
+    where S: AsyncRead + AsyncWrite + Unpin + Send + Sync, {
+        // Everything the connection does is traced under its span (when traces are being exported)
+        let span = telemetry::connection_span(&self.connection_id, &socket_addr);
+        self.handle_connection(stream, socket_addr).instrument(span).await
+    }
+
+    async fn handle_connection<S>(&mut self, stream: S, socket_addr:SocketAddr) -> Result<(), IOError>
+    where S: AsyncRead + AsyncWrite + Unpin + Send + Sync, {
+        let mut stream = ClientStream::new(stream);
+
+        // A CancelRequest is sent on a connection of its own, which is closed once the statement has been cancelled
+        if self.handle_cancel_request(&mut stream).await? {
+            return Ok(());
+        }
 
         // First peek for GSSENC - and always reply NO if requested
         self.peek_for_gssenc_request(&mut stream).await?;   
 
-        // Check for a TLS connection
-        let tls_acceptor:Option<TlsAcceptor> = None; // TODO: Handle TLS...
-        self.is_tls = self.peek_for_tls_request(&mut stream, tls_acceptor.is_some()).await?;
+        // Check for a TLS connection - without a certificate, clients that request it are told it isn't supported
+        self.is_tls = self.peek_for_tls_request(&mut stream, self.tls_acceptor.is_some()).await?;
         
         // Build Client Info
         let client_info: ClientInfoHolder = ClientInfoHolder::new(socket_addr, self.is_tls);
 
         trace!("[{}] Is SSL: {}", &self.connection_id, &self.is_tls);
 
-        if self.is_tls {
-            self.process_tls(stream, tls_acceptor.unwrap(), client_info).await?;
-        } else {
-            self.process(stream, client_info).await?;
-        }
+        let result = match self.tls_acceptor.clone() {
+            Some(tls_acceptor) if self.is_tls => {
+                let tls_stream = tls_acceptor.accept(stream).await?;
+                self.process(tls_stream, client_info).await
+            },
+            _ => self.process(stream, client_info).await
+        };
 
-        Ok(())
+        // The client didn't say goodbye, so log what it was doing in case it's something we did...
+        if !self.terminated {
+            self.statement_log.lock().unwrap().dump(&self.connection_id);
+        }
+        result
     }
 
-    async fn process(&mut self, stream: TcpStream, client_info: ClientInfoHolder) -> Result<(), IOError> {
+    async fn process<S>(&mut self, stream: S, client_info: ClientInfoHolder) -> Result<(), IOError>
+    where S: AsyncRead + AsyncWrite + Unpin + Send + Sync, {
         let mut socket = Framed::new(stream, PgWireMessageServerCodec::new(client_info));
+        // The client is sent its key once it's authenticated
+        let backend_key = self.cancel_registry.register(self.cancel_token.clone());
+        socket.metadata_mut().insert(METADATA_BACKEND_KEY.to_owned(), format!("{} {}", backend_key.process_id, backend_key.secret_key));
+        debug!("[{}] Process id: {}", self.connection_id, backend_key.process_id);
+        self.statement_log_registration = Some(self.statement_logs.register(backend_key.process_id, self.statement_log.clone()));
+        self.backend_key = Some(backend_key);
+        let mut keepalive = self.keepalive_timer();
+        // The client is idle from when its last message has been handled, so a long-running query never counts
+        let mut idle_since = Instant::now();
+        let mut result = Ok(());
         loop {
-            if let Some(msg_opt) = socket.next().await {
-                match msg_opt {
-                    Ok(msg) => {
-                        if let Err(e) = self.process_message(msg, &mut socket).await {
-                            if e.to_string().contains("{TERMINATE}") {
-                                break;
-                            } else {
-                                self.send_error_to_client(&mut socket, e).await?;
+            tokio::select! {
+                msg_opt = socket.next() => {
+                    match msg_opt {
+                        Some(Ok(msg)) => {
+                            let processed = self.process_message(msg, &mut socket).await;
+                            idle_since = Instant::now();
+                            if let Err(e) = processed {
+                                if e.to_string().contains("{TERMINATE}") {
+                                    self.terminated = true;
+                                    break;
+                                } else if let Err(err) = self.send_error_to_client(&mut socket, e).await {
+                                    result = Err(err);
+                                    break;
+                                }
                             }
-                        }
-                    },
-                    Err(err) => {
-                        if err.to_string().contains("Connection reset by peer") {
-                            debug!("[{}] Connection was closed by peer", self.connection_id);
-                            break;
-                        } else {
-                            debug!("[{}] Unexpected connection Error: {:#?}", self.connection_id, err);
-                        }
-                    }
-                }
-            }
-        }
-        Ok(())
-    }
-    async fn process_tls(&mut self, stream: TcpStream, tls_acceptor:TlsAcceptor, client_info: ClientInfoHolder) -> Result<(), IOError> {
-        let ssl_socket = tls_acceptor.accept(stream).await?;
-        let mut socket = Framed::new(ssl_socket, PgWireMessageServerCodec::new(client_info));
-        // todo: No need to repeat this loop from the non-tls version... :p
-        loop {
-            if let Some(msg_opt) = socket.next().await {
-                match msg_opt {
-                    Ok(msg) => {
-                        if let Err(e) = self.process_message(msg, &mut socket).await {
-                            if e.to_string().contains("{TERMINATE}") {
+                        },
+                        Some(Err(err)) => {
+                            if err.to_string().contains("Connection reset by peer") {
+                                debug!("[{}] Connection was closed by peer", self.connection_id);
                                 break;
                             } else {
-                                self.send_error_to_client(&mut socket, e).await?;
+                                debug!("[{}] Unexpected connection Error: {:#?}", self.connection_id, err);
                             }
-                        }
-                    },
-                    Err(err) => {
-                        if err.to_string().contains("Connection reset by peer") {
+                        },
+                        None => {
                             debug!("[{}] Connection was closed by peer", self.connection_id);
                             break;
-                        } else {
-                            debug!("[{}] Unexpected connection Error: {:#?}", self.connection_id, err);
                         }
                     }
+                },
+                _ = Self::next_keepalive(&mut keepalive) => {
+                    if let PgWireConnectionState::ReadyForQuery = socket.state() {
+                        self.send_keepalive(socket.metadata());
+                    }
+                },
+                _ = Self::client_idle(self.client_idle_timeout, idle_since) => {
+                    info!("[{}] Closing the connection - the client hasn't sent anything for {}s", self.connection_id, self.client_idle_timeout.unwrap_or_default().as_secs());
+                    let error = ErrorInfo::new("FATAL".to_owned(), "57P05".to_owned(), "terminating connection due to idle-session timeout".to_owned());
+                    if let Err(err) = socket.send(PgWireBackendMessage::ErrorResponse(error.into())).await {
+                        debug!("[{}] Unable to send the idle timeout to the client: {:#?}", self.connection_id, err);
+                    }
+                    break;
                 }
             }
         }
-        Ok(())
+        self.suspended_portals.lock().unwrap().clear();
+        self.rollback_open_transaction(socket.metadata()).await;
+        result
     }
 
     async fn process_message<S>(&mut self, message: PgWireFrontendMessage, socket: &mut Framed<S, PgWireMessageServerCodec>) -> PgWireResult<()> 
@@ -142,75 +254,262 @@ where F:PgLitebackendFactory, A: PgLiteAuthenticator {
         match socket.state() {
             PgWireConnectionState::AwaitingStartup
             | PgWireConnectionState::AuthenticationInProgress => {
-                // Handle Authentication phase .... 
-                self.authenticator.on_startup(socket, message).await?;
-            }
-            _ => {
-                // Reload the backend - in case it's been disconnected and needs to be re-opened since the last query was done...
-                let backend = { self.db_factory.lock().unwrap().create_backend(socket.metadata())? };
-                let portal = self.portal_store.clone();
-                let parser = self.query_parser.clone();
-                let query_handler = PgQueryProcessor::create(backend, portal, parser);
-                // Process Query Message
-                trace!("Handling Message: {:#?}", message);
+                if let Some(error) = self.rejection.take() {
+                    socket.send(PgWireBackendMessage::ErrorResponse(error.into())).await?;
+                    return Err(PgWireError::ApiError("{TERMINATE}".into()));
+                }
                 match message {
-                    PgWireFrontendMessage::Query(query) => {
-                        query_handler.on_query(socket, query).await?;
-                    }
-                    PgWireFrontendMessage::Parse(parse) => {
-                        query_handler.on_parse(socket, parse).await?;
-                    }
-                    PgWireFrontendMessage::Bind(bind) => {
-                        query_handler.on_bind(socket, bind).await?;
-                    }
-                    PgWireFrontendMessage::Execute(execute) => {
-                        query_handler.on_execute(socket, execute).await?;
+                    PgWireFrontendMessage::Startup(startup) if self.is_probe_startup(&startup) => {
+                        // Health probes skip authentication, as they never get access to a database
+                        trace!("[{}] Accepted a health probe connection", self.connection_id);
+                        self.is_probe = true;
+                        pgwire::api::auth::save_startup_parameters_to_metadata(socket, &startup);
+                        finish_authentication(socket).await?;
                     }
-                    PgWireFrontendMessage::Describe(describe) => {
-                        query_handler.on_describe(socket, describe).await?;
-                    }
-                    PgWireFrontendMessage::Sync(sync) => {
-                        query_handler.on_sync(socket, sync).await?;
+                    // Handle Authentication phase .... 
+                    _ => {
+                        self.authenticator.on_startup(socket, message).await?;
+                        if let PgWireConnectionState::ReadyForQuery = socket.state() {
+                            // The session starts out with the defaults the authenticator gave it, as reported in the server parameters
+                            let _ = self.session.lock().unwrap().apply_client_parameters(socket.metadata());
+                            let span = tracing::Span::current();
+                            span.record("user", socket.metadata().get(pgwire::api::METADATA_USER));
+                            span.record("database", socket.metadata().get(pgwire::api::METADATA_DATABASE));
+                        }
                     }
-                    PgWireFrontendMessage::Close(close) => {
-                        query_handler.on_close(socket, close).await?;
+                }
+            }
+            _ if self.is_probe => {
+                match message {
+                    PgWireFrontendMessage::Query(query) => {
+                        ProbeQueryHandler.on_query(socket, query).await?;
                     }
                     PgWireFrontendMessage::Terminate(_) => {
                         return Err(PgWireError::ApiError("{TERMINATE}".into()));
                     }
-                    // todo: should we be handling the flush message?
-                    _ => { } 
+                    _ => {
+                        return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "0A000".to_owned(), "Health probe connections only support simple queries".to_owned()))));
+                    }
                 }
             }
+            _ => {
+                // After an error in the extended protocol, the client's (possibly pipelined) messages are discarded until its Sync
+                let is_extended = matches!(message, PgWireFrontendMessage::Parse(_) | PgWireFrontendMessage::Bind(_)
+                    | PgWireFrontendMessage::Execute(_) | PgWireFrontendMessage::Describe(_) | PgWireFrontendMessage::Close(_)
+                    | PgWireFrontendMessage::Flush(_));
+                if self.ignore_till_sync && is_extended {
+                    trace!("[{}] Discarding message until Sync: {:?}", self.connection_id, message);
+                    return Ok(());
+                }
+                if let PgWireFrontendMessage::Sync(_) = message {
+                    self.ignore_till_sync = false;
+                }
+
+                let result = self.process_query_message(message, socket).await;
+                if result.is_err() && is_extended {
+                    self.ignore_till_sync = true;
+                }
+                result?;
+            }
         }
         Ok(())
     }
 
+    /// Process a message from an authenticated client, with a query processor for its database
+    async fn process_query_message<S>(&mut self, message: PgWireFrontendMessage, socket: &mut Framed<S, PgWireMessageServerCodec>) -> PgWireResult<()>
+    where S: AsyncRead + AsyncWrite + Unpin + Send + Sync, {
+        let backend = self.pinned_backend(socket.metadata())?;
+        let portal = self.portal_store.clone();
+        let parser = self.query_parser.clone();
+        // Queries of pg_database (eg. psql's `\l`) list the databases the client can connect to
+        let databases = match self.message_query(&message) {
+            Some(query) if references_pg_database(&query) => Some(self.db_factory.lock().unwrap().list_databases(socket.metadata())?),
+            _ => None,
+        };
+        let query_handler = PgQueryProcessor::create(backend, portal, parser, self.session.clone(), self.max_query_length, self.max_result_bytes, self.connection_id).with_databases(databases).with_query_timeout(self.query_timeout).with_suspended_portals(self.suspended_portals.clone()).with_copy_in(self.copy_in.clone()).with_cancel_token(self.cancel_token.clone()).with_slow_query_log(self.slow_query_log.clone()).with_uuid_storage(self.uuid_storage).with_max_result_rows(self.max_result_rows);
+        // Process Query Message
+        trace!("Handling Message: {:#?}", message);
+        let statement = StatementLog::statement_for(&message);
+        let result = match message {
+            PgWireFrontendMessage::Query(query) if admin::is_admin_command(query.query()) => {
+                let is_admin = self.admin_user.is_some() && socket.metadata().get(pgwire::api::METADATA_USER) == self.admin_user.as_ref();
+                let admin_handler = AdminQueryHandler::create(self.db_factory.clone(), self.statement_logs.clone(), is_admin, self.admin_shutdown);
+                let result = admin_handler.on_query(socket, query).await;
+                // The shutdown is only started once the admin has been sent the response
+                if admin_handler.shutdown_requested() {
+                    self.shutdown.trigger();
+                }
+                result
+            },
+            PgWireFrontendMessage::Query(query) => query_handler.on_query(socket, query).await,
+            PgWireFrontendMessage::Parse(parse) => query_handler.on_parse(socket, parse).await,
+            PgWireFrontendMessage::Bind(bind) => query_handler.on_bind(socket, bind).await,
+            PgWireFrontendMessage::Execute(execute) => query_handler.on_execute(socket, execute).await,
+            PgWireFrontendMessage::Describe(describe) => query_handler.on_describe(socket, describe).await,
+            PgWireFrontendMessage::Sync(sync) => query_handler.on_sync(socket, sync).await,
+            PgWireFrontendMessage::Close(close) => query_handler.on_close(socket, close).await,
+            PgWireFrontendMessage::Flush(_) => socket.flush().await.map_err(PgWireError::from),
+            PgWireFrontendMessage::CopyData(data) => query_handler.on_copy_data(socket, data).await,
+            PgWireFrontendMessage::CopyDone(done) => query_handler.on_copy_done(socket, done).await,
+            PgWireFrontendMessage::CopyFail(fail) => query_handler.on_copy_fail(socket, fail).await,
+            PgWireFrontendMessage::Terminate(_) => {
+                return Err(PgWireError::ApiError("{TERMINATE}".into()));
+            }
+            _ => Ok(())
+        };
+        if let Some(statement) = statement {
+            self.statement_log.lock().unwrap().record(statement, &result);
+        }
+        result
+    }
+
+    /// The backend the client is pinned to - while it's pinned, the database handle stays open for the rest of the connection. It's
+    /// still reloaded for each message, so if the handle has gone anyway (eg. it failed to open) the client is pinned to the new one
+    fn pinned_backend(&mut self, metadata: &HashMap<String, String>) -> PgWireResult<BackendConnection> {
+        let backend = self.db_factory.lock().unwrap().create_backend(metadata)?;
+        match &self.backend {
+            Some(pinned) if pinned.sender.same_channel(&backend.sender) => {},
+            _ => self.backend = Some(backend.pin()),
+        }
+        Ok(backend)
+    }
+
+    /// The SQL a message runs (or describes) - for extended queries, that of the statement it refers to
+    fn message_query(&self, message:&PgWireFrontendMessage) -> Option<String> {
+        match message {
+            PgWireFrontendMessage::Query(query) => Some(query.query().to_owned()),
+            PgWireFrontendMessage::Parse(parse) => Some(parse.query().to_owned()),
+            PgWireFrontendMessage::Describe(describe) => {
+                let name = describe.name().as_deref().unwrap_or(DEFAULT_NAME);
+                match describe.target_type() {
+                    TARGET_TYPE_BYTE_STATEMENT => self.portal_store.get_statement(name).map(|statement| statement.statement().clone()),
+                    _ => self.portal_store.get_portal(name).map(|portal| portal.statement().statement().clone()),
+                }
+            },
+            PgWireFrontendMessage::Execute(execute) => {
+                let name = execute.name().as_deref().unwrap_or(DEFAULT_NAME);
+                self.portal_store.get_portal(name).map(|portal| portal.statement().statement().clone())
+            },
+            _ => None,
+        }
+    }
+
+    fn is_probe_startup(&self, startup:&Startup) -> bool {
+        self.probe_user.as_ref().is_some_and(|probe_user| startup.parameters().get(pgwire::api::METADATA_USER) == Some(probe_user))
+    }
+
+    fn keepalive_timer(&self) -> Option<Interval> {
+        self.keepalive_interval.map(|period| {
+            let mut timer = tokio::time::interval_at(Instant::now() + period, period);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            timer
+        })
+    }
+
+    async fn next_keepalive(timer: &mut Option<Interval>) {
+        match timer {
+            Some(timer) => { timer.tick().await; },
+            None => std::future::pending().await
+        }
+    }
+
+    /// Resolves once the client has been idle for the timeout (never, without one)
+    async fn client_idle(timeout: Option<Duration>, idle_since: Instant) {
+        match timeout {
+            Some(timeout) => tokio::time::sleep_until(idle_since + timeout).await,
+            None => std::future::pending().await
+        }
+    }
+
+    fn send_keepalive(&self, metadata: &HashMap<String, String>) {
+        if self.is_probe {
+            return;
+        }
+        trace!("[{}] Sending keepalive to the backend", self.connection_id);
+        let backend = match &self.backend {
+            Some(backend) => Ok((**backend).clone()),
+            None => self.db_factory.lock().unwrap().create_backend(metadata),
+        };
+        match backend {
+            Ok(backend) => {
+                let (resp, _) = crossbeam_channel::bounded(1);
+                let _ = backend.send(PgLiteDBMessage::from_keepalive(resp));
+            },
+            Err(err) => debug!("[{}] Unable to send keepalive to the backend: {:#?}", self.connection_id, err)
+        }
+    }
+
+    /// Roll back the transaction the client left open when it disconnected, so it isn't carried on by the next client to use the
+    /// (shared) database handle - it waits for the backend (up to the query timeout) on a blocking thread, as the backend may still be
+    /// busy with another client's statement
+    async fn rollback_open_transaction(&self, metadata: &HashMap<String, String>) {
+        if !self.rollback_on_close || self.is_probe || self.session.lock().unwrap().transaction_status == TransactionStatus::Idle {
+            return;
+        }
+        debug!("[{}] Rolling back the transaction left open by the client", self.connection_id);
+        let backend = match self.backend.as_ref().map(|backend| Ok((**backend).clone())).unwrap_or_else(|| self.db_factory.lock().unwrap().create_backend(metadata)) {
+            Ok(backend) => backend,
+            Err(err) => {
+                warn!("[{}] Unable to roll back the open transaction: {:#?}", self.connection_id, err);
+                return;
+            }
+        };
+        let (resp, waiter) = crossbeam_channel::bounded(1);
+        if let Err(err) = backend.send(PgLiteDBMessage::from_query("ROLLBACK".to_owned(), false, resp)) {
+            warn!("[{}] Unable to roll back the open transaction: {:#?}", self.connection_id, err);
+            return;
+        }
+        let query_timeout = self.query_timeout;
+        let response = tokio::task::spawn_blocking(move || match query_timeout {
+            Some(timeout) => waiter.recv_timeout(timeout),
+            None => waiter.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        }).await;
+        match response {
+            Ok(Ok(PgLiteDBResponse { error:Some(err), .. })) => warn!("[{}] Unable to roll back the open transaction: {:#?}", self.connection_id, err),
+            Ok(Ok(_)) => {},
+            Ok(Err(err)) => warn!("[{}] Unable to roll back the open transaction: {:#?}", self.connection_id, err),
+            Err(err) => warn!("[{}] Unable to roll back the open transaction: {:#?}", self.connection_id, err),
+        }
+    }
+
     async fn send_error_to_client<S>(&mut self, socket: &mut Framed<S, PgWireMessageServerCodec>, error: PgWireError) -> Result<(), IOError>
     where S: AsyncRead + AsyncWrite + Unpin + Send + Sync {
-        match error {
-            PgWireError::UserError(error_info) => {
-                socket.feed(PgWireBackendMessage::ErrorResponse((*error_info).into())).await?;
-                socket.feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(READY_STATUS_IDLE))).await?;
-                socket.flush().await?;
+        // An error inside a transaction block aborts the transaction, so make sure the client is told about it
+        let ready_status = {
+            let mut session = self.session.lock().unwrap();
+            session.fail_transaction();
+            session.transaction_status.ready_status()
+        };
+
+        let error_info = match error {
+            PgWireError::UserError(error_info) => *error_info,
+            PgWireError::PortalNotFound(name) => {
+                // Referring to a closed (or never created) portal/statement is the client's mistake, so the connection can carry on
+                ErrorInfo::new("ERROR".to_owned(), "34000".to_owned(), format!("portal \"{name}\" does not exist"))
             }
-            PgWireError::ApiError(e) => {
-                let error_info = ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), e.to_string());
-                socket.feed(PgWireBackendMessage::ErrorResponse(error_info.into())).await?;
-                socket.feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(READY_STATUS_IDLE))).await?;
-                socket.flush().await?;
+            PgWireError::StatementNotFound(name) => {
+                ErrorInfo::new("ERROR".to_owned(), "26000".to_owned(), format!("prepared statement \"{name}\" does not exist"))
             }
+            PgWireError::ApiError(e) => ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), e.to_string()),
             _ => {
                 let error_info = ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), error.to_string());
                 socket.send(PgWireBackendMessage::ErrorResponse(error_info.into())).await?;
                 socket.close().await?;
+                return Ok(());
             }
+        };
+        socket.feed(PgWireBackendMessage::ErrorResponse(error_info.into())).await?;
+        // In the extended protocol the client is only told the server is ready again in response to its Sync
+        if !self.ignore_till_sync {
+            socket.feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(ready_status))).await?;
         }
+        socket.flush().await?;
 
         Ok(())
     }
 
-    async fn peek_for_tls_request(&self, tcp_socket: &mut TcpStream, tls_supported: bool) -> Result<bool, IOError> {
+    async fn peek_for_tls_request<S>(&self, tcp_socket: &mut ClientStream<S>, tls_supported: bool) -> Result<bool, IOError>
+    where S: AsyncRead + AsyncWrite + Unpin, {
         let found = self.peek_for_magic(tcp_socket, SslRequest::BODY_MAGIC_NUMBER, true).await?;
         if found {
             if tls_supported {
@@ -224,7 +523,24 @@ where F:PgLitebackendFactory, A: PgLiteAuthenticator {
         Ok(false)
     }
 
-    async fn peek_for_gssenc_request(&self, tcp_socket: &mut TcpStream) -> Result<bool, IOError> {
+    /// Cancel the running statement of the session the CancelRequest has the key of (if there is one) - like Postgres, the client
+    /// isn't sent a response either way
+    async fn handle_cancel_request<S>(&self, tcp_socket: &mut ClientStream<S>) -> Result<bool, IOError>
+    where S: AsyncRead + Unpin, {
+        if !self.peek_for_magic(tcp_socket, CANCEL_REQUEST_MAGIC_NUMBER, true).await? {
+            return Ok(false);
+        }
+        let process_id = tcp_socket.read_i32().await?;
+        let secret_key = tcp_socket.read_i32().await?;
+        match self.cancel_registry.cancel(process_id, secret_key) {
+            true => debug!("[{}] Cancelled the running statement of process {}", self.connection_id, process_id),
+            false => debug!("[{}] Nothing to cancel for process {}", self.connection_id, process_id),
+        }
+        Ok(true)
+    }
+
+    async fn peek_for_gssenc_request<S>(&self, tcp_socket: &mut ClientStream<S>) -> Result<bool, IOError>
+    where S: AsyncRead + AsyncWrite + Unpin, {
         let found = self.peek_for_magic(tcp_socket, GSSENC_REQUEST_MAGIC_NUMBER, true).await?;
         if found {
             tcp_socket.write_all(b"N").await?;  // Always NO - we don't support!    
@@ -233,31 +549,20 @@ where F:PgLitebackendFactory, A: PgLiteAuthenticator {
     }
 
 
-    async fn peek_for_magic(&self, tcp_socket: &mut TcpStream, magic_number:i32, consume_bytes_if_found:bool) -> Result<bool, IOError> {
-        let mut buf: [u8; 8] = [0u8; SslRequest::BODY_SIZE];
-        let mut buf = ReadBuf::new(&mut buf);
-        loop {
-            let size = poll_fn(|cx| tcp_socket.poll_peek(cx, &mut buf)).await?;
-            if size == 0 {
-                // the tcp_stream has ended
-                return Ok(false);
-            }
-
-            if size == SslRequest::BODY_SIZE {
-                let mut buf_ref = buf.filled();
-
-                buf_ref.get_i32(); // skip first 4 bytes (it's the length)
-                if buf_ref.get_i32() == magic_number {
-                    if consume_bytes_if_found {
-                        // Consume the bytes
-                        tcp_socket.read_exact(&mut [0u8; SslRequest::BODY_SIZE]).await?;
-                    }
-                    return Ok(true);
-                } else { 
-                    return Ok(false);
-                }
-            }
+    async fn peek_for_magic<S>(&self, tcp_socket: &mut ClientStream<S>, magic_number:i32, consume_bytes_if_found:bool) -> Result<bool, IOError>
+    where S: AsyncRead + Unpin, {
+        let Some(mut buf_ref) = tcp_socket.peek(SslRequest::BODY_SIZE).await? else {
+            // the stream has ended
+            return Ok(false);
+        };
+        buf_ref.get_i32(); // skip first 4 bytes (it's the length)
+        if buf_ref.get_i32() != magic_number {
+            return Ok(false);
+        }
+        if consume_bytes_if_found {
+            tcp_socket.consume(SslRequest::BODY_SIZE);
         }
+        Ok(true)
     }
 }
 
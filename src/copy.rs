@@ -0,0 +1,698 @@
+use pgwire::api::Type;
+use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
+use rusqlite::types::Value;
+
+use crate::backend::strip_leading_comments;
+
+/// A `COPY ... FROM STDIN` or `COPY ... TO STDOUT` statement - COPY with a file (or program) isn't supported, as the files would be
+/// on the server rather than the client
+#[derive(Debug, Clone)]
+pub struct CopyStatement {
+    pub source: CopySource,
+    pub direction: CopyDirection,
+    pub options: CopyOptions,
+}
+
+#[derive(Debug, Clone)]
+pub enum CopySource {
+    /// A table, and the columns to copy (all of them, when there aren't any)
+    Table { name: String, columns: Vec<String> },
+    /// The rows of a query, eg. `COPY (SELECT ...) TO STDOUT`
+    Query(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyDirection {
+    In,
+    Out,
+}
+
+/// The format of the rows, in Postgres' text format (the default) or CSV
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    pub csv: bool,
+    pub delimiter: u8,
+    pub null: String,
+    pub header: bool,
+    pub quote: u8,
+    pub escape: u8,
+}
+
+impl CopyStatement {
+    /// The query for the rows that are copied out - or for a COPY in, that describes the columns the rows are copied into
+    pub fn select_query(&self) -> String {
+        match &self.source {
+            CopySource::Table { name, columns } if columns.is_empty() => format!("SELECT * FROM {name}"),
+            CopySource::Table { name, columns } => format!("SELECT {} FROM {name}", columns.join(", ")),
+            CopySource::Query(query) => query.clone(),
+        }
+    }
+
+    /// The statement that inserts each row that's copied in, with a parameter for each of the columns
+    pub fn insert_query(&self, columns:&[String]) -> String {
+        let name = match &self.source {
+            CopySource::Table { name, .. } => name.as_str(),
+            CopySource::Query(_) => "",
+        };
+        let names = columns.iter().map(|column| format!("\"{}\"", column.replace('"', "\"\""))).collect::<Vec<String>>();
+        let params = (1..=columns.len()).map(|idx| format!("?{idx}")).collect::<Vec<String>>();
+        format!("INSERT INTO {name} ({}) VALUES ({})", names.join(", "), params.join(", "))
+    }
+}
+
+/// Whether a statement is a COPY
+pub fn is_copy_statement(query:&str) -> bool {
+    strip_leading_comments(query).split(|c: char| c.is_whitespace() || c == '(').next().is_some_and(|word| word.eq_ignore_ascii_case("COPY"))
+}
+
+/// Parse a COPY statement, eg. `COPY users (id, name) FROM STDIN WITH (FORMAT csv, HEADER)` - the options can also be given the
+/// way older versions of Postgres took them, eg. `COPY users TO STDOUT WITH CSV HEADER`. Returns None for any other statement
+pub fn parse_copy_statement(query:&str) -> PgWireResult<Option<CopyStatement>> {
+    if !is_copy_statement(query) {
+        return Ok(None);
+    }
+    let mut tokens = Tokens { query: strip_leading_comments(query), pos: 0 };
+    tokens.token();
+
+    let source = match tokens.peek() {
+        Some("(") => CopySource::Query(tokens.parenthesized().ok_or_else(|| syntax_error(query))?.trim().to_owned()),
+        Some(name) if !is_punctuation(name) => {
+            tokens.token();
+            let mut columns = Vec::new();
+            if tokens.peek() == Some("(") {
+                tokens.token();
+                loop {
+                    match tokens.token() {
+                        Some(column) if !is_punctuation(column) => columns.push(column.to_owned()),
+                        _ => return Err(syntax_error(query)),
+                    }
+                    match tokens.token() {
+                        Some(",") => continue,
+                        Some(")") => break,
+                        _ => return Err(syntax_error(query)),
+                    }
+                }
+            }
+            CopySource::Table { name: name.to_owned(), columns }
+        },
+        _ => return Err(syntax_error(query)),
+    };
+
+    let direction = match (tokens.token().map(str::to_uppercase).as_deref(), tokens.token()) {
+        (Some("FROM"), Some(target)) if target.eq_ignore_ascii_case("STDIN") => CopyDirection::In,
+        (Some("TO"), Some(target)) if target.eq_ignore_ascii_case("STDOUT") => CopyDirection::Out,
+        (Some("FROM" | "TO"), Some(_)) => return Err(not_supported("COPY with a file or program isn't supported, use STDIN or STDOUT (eg. psql's \\copy)")),
+        _ => return Err(syntax_error(query)),
+    };
+    if direction == CopyDirection::In && matches!(source, CopySource::Query(_)) {
+        return Err(syntax_error(query));
+    }
+
+    let options = parse_options(&mut tokens).ok_or_else(|| syntax_error(query))??;
+    match tokens.token() {
+        None | Some(";") if tokens.token().is_none() => Ok(Some(CopyStatement { source, direction, options })),
+        _ => Err(syntax_error(query)),
+    }
+}
+
+/// The options after the `WITH` - either in parentheses (`(FORMAT csv, DELIMITER ';')`), or the older form (`CSV DELIMITER ';'`).
+/// Returns None when they're not valid syntax
+fn parse_options(tokens:&mut Tokens) -> Option<PgWireResult<CopyOptions>> {
+    if tokens.peek().is_some_and(|token| token.eq_ignore_ascii_case("WITH")) {
+        tokens.token();
+    }
+    let mut options: Vec<(String, Option<String>)> = Vec::new();
+    if tokens.peek() == Some("(") {
+        tokens.token();
+        loop {
+            let name = tokens.token().filter(|name| !is_punctuation(name))?.to_lowercase();
+            let value = match tokens.peek() {
+                Some("," | ")") => None,
+                _ => Some(option_value(tokens.token()?)),
+            };
+            options.push((name, value));
+            match tokens.token()? {
+                "," => continue,
+                ")" => break,
+                _ => return None,
+            }
+        }
+    } else {
+        while let Some(name) = tokens.peek().filter(|name| !is_punctuation(name)).map(str::to_lowercase) {
+            tokens.token();
+            let value = match name.as_str() {
+                "binary" => Some("binary".to_owned()),
+                "csv" => Some("csv".to_owned()),
+                "header" => None,
+                "delimiter" | "null" | "quote" | "escape" => {
+                    let value = match tokens.token()? {
+                        token if token.eq_ignore_ascii_case("AS") => tokens.token()?,
+                        token => token,
+                    };
+                    Some(option_value(value))
+                },
+                _ => return Some(Err(not_supported(&format!("COPY option \"{name}\" isn't supported")))),
+            };
+            match name.as_str() {
+                "binary" | "csv" => options.push(("format".to_owned(), value)),
+                _ => options.push((name, value)),
+            }
+        }
+    }
+    Some(CopyOptions::new(options))
+}
+
+impl CopyOptions {
+    fn new(options:Vec<(String, Option<String>)>) -> PgWireResult<Self> {
+        let csv = match options.iter().find(|(name, _)| name == "format").and_then(|(_, value)| value.as_deref()).map(str::to_lowercase).as_deref() {
+            None | Some("text") => false,
+            Some("csv") => true,
+            Some("binary") => return Err(not_supported("COPY's binary format isn't supported, use the text or CSV format")),
+            Some(format) => return Err(invalid_option(&format!("COPY format \"{format}\" not recognized"))),
+        };
+        let mut copy_options = CopyOptions {
+            csv,
+            delimiter: if csv { b',' } else { b'\t' },
+            null: if csv { String::new() } else { "\\N".to_owned() },
+            header: false,
+            quote: b'"',
+            escape: b'"',
+        };
+        let mut escape = None;
+        for (name, value) in options {
+            match (name.as_str(), value) {
+                ("format", _) => {},
+                ("delimiter", Some(value)) => copy_options.delimiter = single_byte(&value, "COPY delimiter")?,
+                ("null", Some(value)) => copy_options.null = value,
+                ("header", None) => copy_options.header = true,
+                ("header", Some(value)) => copy_options.header = match value.to_lowercase().as_str() {
+                    "true" | "on" | "1" => true,
+                    "false" | "off" | "0" => false,
+                    _ => return Err(invalid_option(&format!("header requires a Boolean value, not \"{value}\""))),
+                },
+                ("quote", Some(value)) if csv => copy_options.quote = single_byte(&value, "COPY quote")?,
+                ("escape", Some(value)) if csv => escape = Some(single_byte(&value, "COPY escape")?),
+                ("quote" | "escape", Some(_)) => return Err(not_supported(&format!("COPY {name} is only available in CSV mode"))),
+                (_, None) => return Err(invalid_option(&format!("COPY option \"{name}\" requires a value"))),
+                _ => return Err(not_supported(&format!("COPY option \"{name}\" isn't supported"))),
+            }
+        }
+        // The escape character defaults to the quote character, like Postgres
+        copy_options.escape = escape.unwrap_or(copy_options.quote);
+        Ok(copy_options)
+    }
+
+    /// The row of column names a header is written as
+    pub fn encode_header<'a>(&self, names:impl Iterator<Item = &'a String>) -> Vec<u8> {
+        let names = names.map(|name| Some(name.as_bytes())).collect::<Vec<Option<&[u8]>>>();
+        self.encode_row(&names)
+    }
+
+    /// Encode a row of (text) values as a line of the COPY's output
+    pub fn encode_row(&self, values:&[Option<&[u8]>]) -> Vec<u8> {
+        let mut line = Vec::with_capacity(values.iter().flatten().map(|value| value.len() + 1).sum::<usize>() + 1);
+        for (idx, value) in values.iter().enumerate() {
+            if idx > 0 {
+                line.push(self.delimiter);
+            }
+            match value {
+                None => line.extend_from_slice(self.null.as_bytes()),
+                Some(value) if self.csv => self.encode_csv_value(value, &mut line),
+                Some(value) => self.encode_text_value(value, &mut line),
+            }
+        }
+        line.push(b'\n');
+        line
+    }
+
+    fn encode_text_value(&self, value:&[u8], line:&mut Vec<u8>) {
+        for &b in value {
+            match b {
+                b'\\' => line.extend_from_slice(b"\\\\"),
+                b'\n' => line.extend_from_slice(b"\\n"),
+                b'\r' => line.extend_from_slice(b"\\r"),
+                b'\t' => line.extend_from_slice(b"\\t"),
+                b if b == self.delimiter => line.extend_from_slice(&[b'\\', b]),
+                b => line.push(b),
+            }
+        }
+    }
+
+    /// CSV values are quoted when they contain anything special - or could be mistaken for a NULL (eg. an empty string)
+    fn encode_csv_value(&self, value:&[u8], line:&mut Vec<u8>) {
+        let needs_quotes = value == self.null.as_bytes() || value == &b"\\."[..]
+            || value.iter().any(|b| matches!(b, b'\n' | b'\r') || *b == self.delimiter || *b == self.quote || *b == self.escape);
+        if !needs_quotes {
+            line.extend_from_slice(value);
+            return;
+        }
+        line.push(self.quote);
+        for &b in value {
+            if b == self.quote || b == self.escape {
+                line.push(self.escape);
+            }
+            line.push(b);
+        }
+        line.push(self.quote);
+    }
+
+    /// Split a line of the COPY's input into its values
+    fn decode_row(&self, line:&[u8]) -> Result<Vec<Option<Vec<u8>>>, String> {
+        match self.csv {
+            true => Ok(self.decode_csv_row(line)),
+            false => self.decode_text_row(line),
+        }
+    }
+
+    /// In the text format a value is NULL when it matches the NULL string before any backslash escapes are processed
+    fn decode_text_row(&self, line:&[u8]) -> Result<Vec<Option<Vec<u8>>>, String> {
+        let mut values = Vec::new();
+        let mut start = 0;
+        let mut idx = 0;
+        loop {
+            match line.get(idx) {
+                Some(b'\\') => idx += 2,
+                Some(b) if *b == self.delimiter => {
+                    values.push(self.decode_text_value(&line[start..idx])?);
+                    start = idx + 1;
+                    idx += 1;
+                },
+                Some(_) => idx += 1,
+                None => {
+                    values.push(self.decode_text_value(&line[start..])?);
+                    return Ok(values);
+                },
+            }
+        }
+    }
+
+    fn decode_text_value(&self, raw:&[u8]) -> Result<Option<Vec<u8>>, String> {
+        if raw == self.null.as_bytes() {
+            return Ok(None);
+        }
+        let mut value = Vec::with_capacity(raw.len());
+        let mut idx = 0;
+        while idx < raw.len() {
+            if raw[idx] != b'\\' {
+                value.push(raw[idx]);
+                idx += 1;
+                continue;
+            }
+            let Some(&escaped) = raw.get(idx + 1) else {
+                return Err("end-of-copy marker corrupt".to_owned());
+            };
+            idx += 2;
+            match escaped {
+                b'b' => value.push(0x08),
+                b'f' => value.push(0x0c),
+                b'n' => value.push(b'\n'),
+                b'r' => value.push(b'\r'),
+                b't' => value.push(b'\t'),
+                b'v' => value.push(0x0b),
+                b'0'..=b'7' => {
+                    // Up to 3 octal digits, eg. `\101`
+                    let digits = raw[idx - 1..].iter().take(3).take_while(|b| (b'0'..=b'7').contains(b)).count();
+                    value.push(raw[idx - 1..idx - 1 + digits].iter().fold(0u32, |byte, digit| byte * 8 + (digit - b'0') as u32) as u8);
+                    idx += digits - 1;
+                },
+                b'x' if raw.get(idx).is_some_and(u8::is_ascii_hexdigit) => {
+                    // Up to 2 hex digits, eg. `\x41`
+                    let digits = raw[idx..].iter().take(2).take_while(|b| b.is_ascii_hexdigit()).count();
+                    value.push(u8::from_str_radix(std::str::from_utf8(&raw[idx..idx + digits]).unwrap_or_default(), 16).unwrap_or_default());
+                    idx += digits;
+                },
+                other => value.push(other),
+            }
+        }
+        Ok(Some(value))
+    }
+
+    /// In CSV a value is NULL when it's unquoted + matches the NULL string
+    fn decode_csv_row(&self, line:&[u8]) -> Vec<Option<Vec<u8>>> {
+        let mut values = Vec::new();
+        let mut value = Vec::new();
+        let (mut in_quotes, mut quoted) = (false, false);
+        let mut idx = 0;
+        while idx < line.len() {
+            let b = line[idx];
+            match b {
+                b if in_quotes && b == self.escape && line.get(idx + 1).is_some_and(|next| *next == self.quote || *next == self.escape) => {
+                    value.push(line[idx + 1]);
+                    idx += 1;
+                },
+                b if b == self.quote => {
+                    in_quotes = !in_quotes;
+                    quoted = true;
+                },
+                b if !in_quotes && b == self.delimiter => {
+                    values.push(self.csv_value(std::mem::take(&mut value), quoted));
+                    quoted = false;
+                },
+                b => value.push(b),
+            }
+            idx += 1;
+        }
+        values.push(self.csv_value(value, quoted));
+        values
+    }
+
+    fn csv_value(&self, value:Vec<u8>, quoted:bool) -> Option<Vec<u8>> {
+        match !quoted && value == self.null.as_bytes() {
+            true => None,
+            false => Some(value),
+        }
+    }
+}
+
+/// A `COPY ... FROM STDIN` that's waiting for the client to send its rows - the rows are split into lines as the data arrives,
+/// which can be in chunks of any size, and kept until the client has sent all of them
+#[derive(Debug)]
+pub struct CopyIn {
+    pub statement: CopyStatement,
+    /// The statement each row is inserted with
+    pub insert_query: String,
+    /// The type of each column, for converting its text values
+    column_types: Vec<Type>,
+    /// The rows the client has sent so far - they're all inserted at once when it's done, so the database's handle (which every
+    /// session shares) isn't held in a transaction while waiting on the client
+    pub copied: Vec<Vec<Value>>,
+    buffer: Vec<u8>,
+    lines: usize,
+    ended: bool,
+}
+
+impl CopyIn {
+    pub fn new(statement:CopyStatement, columns:Vec<(String, Type)>) -> Self {
+        let (names, column_types): (Vec<String>, Vec<Type>) = columns.into_iter().unzip();
+        let insert_query = statement.insert_query(&names);
+        Self { statement, insert_query, column_types, copied: Vec::new(), buffer: Vec::new(), lines: 0, ended: false }
+    }
+
+    /// The number of columns each row has
+    pub fn columns(&self) -> usize {
+        self.column_types.len()
+    }
+
+    /// Add the data the client has sent, returning the values of the rows it completed - once `finished` (the client's sent all
+    /// of its data), the last line doesn't need to end with a newline
+    pub fn rows(&mut self, data:&[u8], finished:bool) -> PgWireResult<Vec<Vec<Value>>> {
+        self.buffer.extend_from_slice(data);
+        let options = &self.statement.options;
+        let mut lines = Vec::new();
+        let (mut start, mut in_quotes) = (0, false);
+        let mut idx = 0;
+        while idx < self.buffer.len() {
+            match self.buffer[idx] {
+                // In CSV, a quoted value can contain newlines
+                b if options.csv && in_quotes && b == options.escape && b != options.quote => idx += 1,
+                b if options.csv && b == options.quote => in_quotes = !in_quotes,
+                b'\n' if !in_quotes => {
+                    lines.push(start..idx);
+                    start = idx + 1;
+                    in_quotes = false;
+                },
+                _ => {},
+            }
+            idx += 1;
+        }
+        if finished && start < self.buffer.len() {
+            lines.push(start..self.buffer.len());
+            start = self.buffer.len();
+        }
+
+        let mut rows = Vec::with_capacity(lines.len());
+        for range in lines {
+            let line = &self.buffer[range];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            self.lines += 1;
+            // Anything after the end-of-data marker is ignored, as are the column names of a header
+            if self.ended || line == &b"\\."[..] {
+                self.ended = true;
+                continue;
+            }
+            if self.lines == 1 && options.header {
+                continue;
+            }
+            let values = options.decode_row(line).map_err(|message| self.bad_copy_data(&message))?;
+            if values.len() < self.column_types.len() {
+                return Err(self.bad_copy_data(&format!("missing data for column {}", values.len() + 1)));
+            }
+            if values.len() > self.column_types.len() {
+                return Err(self.bad_copy_data("extra data after last expected column"));
+            }
+            let row = values.into_iter().zip(&self.column_types).map(|(value, pg_type)| match value {
+                None => Ok(Value::Null),
+                Some(value) => match String::from_utf8(value) {
+                    Ok(text) => Ok(column_value(text, pg_type)),
+                    Err(_) => Err(self.error("22021", "invalid byte sequence for encoding \"UTF8\"")),
+                },
+            }).collect::<PgWireResult<Vec<Value>>>()?;
+            rows.push(row);
+        }
+        self.buffer.drain(..start);
+        Ok(rows)
+    }
+
+    fn bad_copy_data(&self, message:&str) -> PgWireError {
+        self.error("22P04", message)
+    }
+
+    fn error(&self, code:&str, message:&str) -> PgWireError {
+        let table = match &self.statement.source {
+            CopySource::Table { name, .. } => name.as_str(),
+            CopySource::Query(_) => "",
+        };
+        PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), code.to_owned(), format!("{message} (COPY {table}, line {})", self.lines))))
+    }
+}
+
+/// Values are inserted as text, for SQLite's column affinity to convert - except for the types it wouldn't understand the text of,
+/// eg. booleans (`t`/`f`) + bytea (`\x0041ff`)
+fn column_value(text:String, pg_type:&Type) -> Value {
+    match *pg_type {
+        Type::BOOL => match text.to_lowercase().as_str() {
+            "t" | "true" | "y" | "yes" | "on" | "1" => Value::Integer(1),
+            "f" | "false" | "n" | "no" | "off" | "0" => Value::Integer(0),
+            _ => Value::Text(text),
+        },
+        Type::BYTEA => match text.strip_prefix("\\x").and_then(decode_hex) {
+            Some(bytes) => Value::Blob(bytes),
+            None => Value::Blob(text.into_bytes()),
+        },
+        _ => Value::Text(text),
+    }
+}
+
+fn decode_hex(hex:&str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok()).collect()
+}
+
+/// The statement's words, quoted identifiers + strings, and punctuation
+struct Tokens<'a> {
+    query: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        Tokens { query: self.query, pos: self.pos }.token()
+    }
+
+    fn token(&mut self) -> Option<&'a str> {
+        let bytes = self.query.as_bytes();
+        let start = self.pos + bytes[self.pos..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let end = match bytes.get(start)? {
+            b'\'' => quoted_end(bytes, start, b'\''),
+            b'E' | b'e' if bytes.get(start + 1) == Some(&b'\'') => quoted_end(bytes, start + 1, b'\''),
+            b'(' | b')' | b',' | b';' => start + 1,
+            _ => {
+                // A word can be made up of quoted + unquoted parts, eg. `public."My Table"`
+                let mut end = start;
+                while end < bytes.len() {
+                    match bytes[end] {
+                        b'"' => end = quoted_end(bytes, end, b'"'),
+                        b if b.is_ascii_whitespace() || matches!(b, b'(' | b')' | b',' | b';' | b'\'') => break,
+                        _ => end += 1,
+                    }
+                }
+                end
+            }
+        };
+        self.pos = end;
+        Some(&self.query[start..end])
+    }
+
+    /// The text inside the parentheses that start at the next token, eg. the query of `COPY (SELECT ...) TO STDOUT`
+    fn parenthesized(&mut self) -> Option<&'a str> {
+        self.token().filter(|token| *token == "(")?;
+        let start = self.pos;
+        let mut depth = 1;
+        while let Some(token) = self.token() {
+            match token {
+                "(" => depth += 1,
+                ")" if depth == 1 => return Some(&self.query[start..self.pos - 1]),
+                ")" => depth -= 1,
+                _ => {},
+            }
+        }
+        None
+    }
+}
+
+/// The index just after the closing quote of a quoted string or identifier (a doubled quote is part of it)
+fn quoted_end(bytes:&[u8], start:usize, quote:u8) -> usize {
+    let mut idx = start + 1;
+    while idx < bytes.len() {
+        if bytes[idx] == quote {
+            if bytes.get(idx + 1) != Some(&quote) {
+                return idx + 1;
+            }
+            idx += 1;
+        }
+        idx += 1;
+    }
+    bytes.len()
+}
+
+fn is_punctuation(token:&str) -> bool {
+    matches!(token, "(" | ")" | "," | ";")
+}
+
+/// An option's value - a string literal is unquoted, with the backslash escapes of an `E'...'` string, eg. `E'\t'` for a tab
+fn option_value(token:&str) -> String {
+    let (escapes, token) = match token.strip_prefix(['E', 'e']).filter(|rest| rest.starts_with('\'')) {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let Some(value) = token.strip_prefix('\'').and_then(|token| token.strip_suffix('\'')) else {
+        return token.to_owned();
+    };
+    let value = value.replace("''", "'");
+    if !escapes {
+        return value;
+    }
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => {},
+        }
+    }
+    unescaped
+}
+
+fn single_byte(value:&str, option:&str) -> PgWireResult<u8> {
+    match value.as_bytes() {
+        [b] => Ok(*b),
+        _ => Err(invalid_option(&format!("{option} must be a single one-byte character"))),
+    }
+}
+
+fn syntax_error(query:&str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "42601".to_owned(), format!("syntax error in COPY statement: {query}"))))
+}
+
+fn not_supported(message:&str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "0A000".to_owned(), message.to_owned())))
+}
+
+fn invalid_option(message:&str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22023".to_owned(), message.to_owned())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_code(err:PgWireError) -> String {
+        match err {
+            PgWireError::UserError(info) => info.code().to_owned(),
+            err => panic!("Unexpected error: {err:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_copy_statements() {
+        let copy = parse_copy_statement("COPY users (id, \"Name\") FROM STDIN WITH (FORMAT csv, HEADER, DELIMITER ';')").unwrap().unwrap();
+        assert!(matches!(&copy.source, CopySource::Table { name, columns } if name == "users" && columns == &["id", "\"Name\""]));
+        assert_eq!(copy.direction, CopyDirection::In);
+        assert!(copy.options.csv && copy.options.header);
+        assert_eq!(copy.options.delimiter, b';');
+
+        let copy = parse_copy_statement("/* export */ COPY (SELECT a, b FROM t) TO STDOUT WITH CSV HEADER QUOTE AS '''';").unwrap().unwrap();
+        assert!(matches!(&copy.source, CopySource::Query(query) if query == "SELECT a, b FROM t"));
+        assert_eq!(copy.direction, CopyDirection::Out);
+        assert_eq!((copy.options.quote, copy.options.escape), (b'\'', b'\''));
+
+        let copy = parse_copy_statement("copy t from stdin").unwrap().unwrap();
+        assert!(!copy.options.csv && !copy.options.header);
+        assert_eq!((copy.options.delimiter, copy.options.null.as_str()), (b'\t', "\\N"));
+
+        assert!(parse_copy_statement("SELECT 1").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_unsupported_copy_statements() {
+        assert_eq!(error_code(parse_copy_statement("COPY t FROM '/etc/passwd'").unwrap_err()), "0A000");
+        assert_eq!(error_code(parse_copy_statement("COPY t TO STDOUT (FORMAT binary)").unwrap_err()), "0A000");
+        assert_eq!(error_code(parse_copy_statement("COPY (SELECT 1) FROM STDIN").unwrap_err()), "42601");
+        assert_eq!(error_code(parse_copy_statement("COPY t (a FROM STDIN").unwrap_err()), "42601");
+        assert_eq!(error_code(parse_copy_statement("COPY t FROM STDIN (DELIMITER 'ab')").unwrap_err()), "22023");
+        assert_eq!(error_code(parse_copy_statement("COPY t FROM STDIN (QUOTE '|')").unwrap_err()), "0A000");
+    }
+
+    #[test]
+    fn decodes_text_rows() {
+        let options = parse_copy_statement("COPY t FROM STDIN").unwrap().unwrap().options;
+        assert_eq!(options.decode_text_row(b"1\ta\\tb\t\\N").unwrap(), vec![Some(b"1".to_vec()), Some(b"a\tb".to_vec()), None]);
+        assert_eq!(options.decode_text_row(b"\\101\\x42\\\\\t").unwrap(), vec![Some(b"AB\\".to_vec()), Some(Vec::new())]);
+        assert_eq!(options.decode_text_row(b"a\\\tb").unwrap(), vec![Some(b"a\tb".to_vec())]);
+        assert!(options.decode_text_row(b"a\\").is_err());
+    }
+
+    #[test]
+    fn decodes_csv_rows() {
+        let options = parse_copy_statement("COPY t FROM STDIN (FORMAT csv)").unwrap().unwrap().options;
+        assert_eq!(options.decode_csv_row(b"1,\"a,b\",,\"\""), vec![Some(b"1".to_vec()), Some(b"a,b".to_vec()), None, Some(Vec::new())]);
+        assert_eq!(options.decode_csv_row(b"\"say \"\"hi\"\"\",\"x\ny\""), vec![Some(b"say \"hi\"".to_vec()), Some(b"x\ny".to_vec())]);
+    }
+
+    #[test]
+    fn splits_copied_data_into_rows() {
+        let copy = parse_copy_statement("COPY t FROM STDIN (FORMAT csv, HEADER)").unwrap().unwrap();
+        let mut copy_in = CopyIn::new(copy, vec![("a".to_owned(), Type::INT4), ("b".to_owned(), Type::BOOL)]);
+        assert_eq!(copy_in.insert_query, "INSERT INTO t (\"a\", \"b\") VALUES (?1, ?2)");
+        // The data can arrive in chunks that split a row (or a quoted value) anywhere
+        assert_eq!(copy_in.rows(b"a,b\r\n1,t\n2,\"f", false).unwrap(), vec![vec![Value::Text("1".to_owned()), Value::Integer(1)]]);
+        assert_eq!(copy_in.rows(b"\"\n3,", false).unwrap(), vec![vec![Value::Text("2".to_owned()), Value::Integer(0)]]);
+        assert_eq!(copy_in.rows(b"", true).unwrap(), vec![vec![Value::Text("3".to_owned()), Value::Null]]);
+    }
+
+    #[test]
+    fn ignores_data_after_the_end_marker() {
+        let copy = parse_copy_statement("COPY t FROM STDIN").unwrap().unwrap();
+        let mut copy_in = CopyIn::new(copy, vec![("a".to_owned(), Type::TEXT)]);
+        assert_eq!(copy_in.rows(b"x\n\\.\ny\n", true).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_rows_with_the_wrong_number_of_columns() {
+        let copy = parse_copy_statement("COPY t FROM STDIN").unwrap().unwrap();
+        let mut copy_in = CopyIn::new(copy, vec![("a".to_owned(), Type::TEXT), ("b".to_owned(), Type::TEXT)]);
+        assert_eq!(error_code(copy_in.rows(b"x\n", false).unwrap_err()), "22P04");
+        let copy = parse_copy_statement("COPY t FROM STDIN").unwrap().unwrap();
+        let mut copy_in = CopyIn::new(copy, vec![("a".to_owned(), Type::TEXT)]);
+        assert_eq!(error_code(copy_in.rows(b"x\ty\n", false).unwrap_err()), "22P04");
+    }
+}
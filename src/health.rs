@@ -0,0 +1,123 @@
+use std::{net::SocketAddr, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, TryLockError}, time::Duration};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+use crate::{backend::PgLitebackendFactory, server};
+
+/* Follows is the HTTP health check listener (`--health-addr`), for orchestrators' liveness + readiness probes (eg. Kubernetes') -
+   any request is answered `200 OK` while the server is accepting connections and its backend factory can be reached, or
+   `503 Service Unavailable` (with the reason) once it can't, so a failing probe means the server really can't serve clients */
+
+/// How long a health check waits for the backend factory (which connections hold while they open their databases)
+const FACTORY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a health check client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most of a request that's read, the health check doesn't need any of it
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Whether the server's accept loop is running, for the health checks
+#[derive(Clone, Default)]
+pub struct AcceptLoopStatus {
+    running: Arc<AtomicBool>,
+}
+
+impl AcceptLoopStatus {
+    /// Called as the accept loop starts, it's running until the returned guard is dropped (when the loop ends, or panics)
+    pub fn start(&self) -> AcceptLoopGuard {
+        self.running.store(true, Ordering::SeqCst);
+        AcceptLoopGuard { running: self.running.clone() }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+pub struct AcceptLoopGuard {
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for AcceptLoopGuard {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Bind the health check listener - before the server starts, so a health address that can't be used stops it from starting
+pub fn bind(addr:SocketAddr) -> Result<TcpListener, String> {
+    std::net::TcpListener::bind(addr)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .and_then(TcpListener::from_std)
+        .map_err(|err| format!("Unable to listen for health checks at {addr} (--health-addr): {err}"))
+}
+
+/// Answer the health checks, until the server's process exits
+pub async fn serve<F>(listener:TcpListener, backend_factory:Arc<Mutex<F>>, accept_loop:AcceptLoopStatus)
+where F : PgLitebackendFactory + Send + Sync + 'static {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Answering health checks at: {}", addr);
+    }
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                server::accept_failed(&err, "a health check connection").await;
+                continue;
+            }
+        };
+        let backend_factory = backend_factory.clone();
+        let accept_loop = accept_loop.clone();
+        tokio::spawn(async move {
+            if let Err(err) = answer(stream, &backend_factory, &accept_loop).await {
+                debug!("Unable to answer the health check from {}: {}", addr, err);
+            }
+        });
+    }
+}
+
+async fn answer<F:PgLitebackendFactory>(mut stream:TcpStream, backend_factory:&Mutex<F>, accept_loop:&AcceptLoopStatus) -> std::io::Result<()> {
+    // The request is read (up to the end of its headers), so the client isn't reset before it's sent the whole of it
+    let mut request = Vec::with_capacity(512);
+    let mut buffer = [0u8; 1024];
+    let read_request = async {
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+            match stream.read(&mut buffer).await? {
+                0 => break,
+                read => request.extend_from_slice(&buffer[..read]),
+            }
+        }
+        std::io::Result::Ok(())
+    };
+    if tokio::time::timeout(REQUEST_TIMEOUT, read_request).await.is_err() {
+        return Ok(());
+    }
+
+    let response = match check_health(backend_factory, accept_loop).await {
+        Ok(()) => "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\nConnection: close\r\n\r\nOK\n".to_owned(),
+        Err(reason) => {
+            warn!("Failing a health check: {}", reason);
+            format!("HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reason}\n", reason.len() + 1)
+        },
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Whether the server can serve its clients - it's accepting connections, and its backend factory isn't stuck (or poisoned by a
+/// panic) and can still open databases
+async fn check_health<F:PgLitebackendFactory>(backend_factory:&Mutex<F>, accept_loop:&AcceptLoopStatus) -> Result<(), String> {
+    if !accept_loop.is_running() {
+        return Err("The server isn't accepting connections".to_owned());
+    }
+    let waiting_since = tokio::time::Instant::now();
+    loop {
+        match backend_factory.try_lock() {
+            Ok(factory) => return factory.check_health(),
+            Err(TryLockError::Poisoned(_)) => return Err("The backend factory panicked".to_owned()),
+            Err(TryLockError::WouldBlock) if waiting_since.elapsed() >= FACTORY_TIMEOUT => {
+                return Err(format!("The backend factory has been busy for over {}s", FACTORY_TIMEOUT.as_secs()));
+            },
+            Err(TryLockError::WouldBlock) => {},
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
@@ -0,0 +1,23 @@
+#[macro_use]
+extern crate log;
+
+pub mod config;
+pub mod auth;
+pub mod backend;
+pub mod server;
+pub mod connection;
+pub mod query_handler;
+pub mod session;
+pub mod probe;
+pub mod health;
+pub mod statement_log;
+pub mod array_params;
+pub mod numeric;
+pub mod uuid_values;
+pub mod copy;
+pub mod cancel;
+pub mod admin;
+pub mod benchmark;
+pub mod telemetry;
+pub mod tls;
+pub mod stream;
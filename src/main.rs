@@ -1,23 +1,16 @@
 use std::borrow::BorrowMut;
 use clap::Parser;
 
-#[macro_use] 
-extern crate log;
 extern crate simplelog;
 pub use simplelog::*;
 use std::fs::File;
 
-mod config;
-mod auth;
-mod backend;
-mod server;
-mod connection;
-mod query_handler;
-
-use config::{PgLiteConfig, PgLiteLogLevel};
-use backend::load_backend_factory;
-use auth::load_authenticator;
-use server::PgLiteServer;
+use pglite::config::{PgLiteConfig, PgLiteLogLevel};
+use pglite::backend::load_backend_factory;
+use pglite::auth::load_authenticator;
+use pglite::server::PgLiteServer;
+use pglite::benchmark;
+use pglite::telemetry::Telemetry;
 
 #[tokio::main]
 async fn main() {
@@ -31,14 +24,48 @@ async fn main() {
     }
     CombinedLogger::init(loggers).unwrap();
 
-    // Load the DB Backend
-    let backend = load_backend_factory(&config);
+    // Run the benchmark workload instead of the server, if requested
+    if config.benchmark {
+        if let Err(err) = benchmark::run(config).await {
+            eprintln!("Benchmark failed: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Start exporting traces + metrics, if a collector is configured
+    let telemetry = Telemetry::init(&config);
+
+    // Load the DB Backend - checking its configuration before the server starts accepting connections
+    let backend = match load_backend_factory(&config) {
+        Ok(backend) => backend,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
 
     // Load the Authenticator
-    let authenticator = load_authenticator(&config);
+    let authenticator = match load_authenticator(&config) {
+        Ok(authenticator) => authenticator,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
 
     // Start the server
-    let mut server_handle = PgLiteServer::start(config, backend, authenticator);
+    let mut server_handle = match PgLiteServer::start(config, backend, authenticator) {
+        Ok(server_handle) => server_handle,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
     server_handle.borrow_mut().await.unwrap();
+
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
 }
 
@@ -0,0 +1,161 @@
+use pgwire::{api::portal::Portal, error::{PgWireResult, PgWireError, ErrorInfo}};
+use rusqlite::types::Value;
+
+/* Follows is the support for NUMERIC values - SQLite has no decimal type, so they're stored as integers or reals (like a column
+   with NUMERIC affinity would store them), and converted from/to Postgres's decimal text + binary formats */
+
+/// The sign of a NaN in the binary format
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_NEG: u16 = 0x4000;
+
+/// Decode a NUMERIC parameter, in either the text (eg. `12.50`) or binary format - into the value SQLite would store it as
+pub fn decode_numeric_param(portal:&Portal<String>, idx:usize) -> PgWireResult<Option<Value>> {
+    let Some(Some(bytes)) = portal.parameters().get(idx) else { return Ok(None) };
+    let text = match portal.parameter_format().is_text(idx) {
+        true => String::from_utf8_lossy(bytes).trim().to_owned(),
+        false => decode_binary(bytes).ok_or_else(|| PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22P03".to_owned(),
+            "invalid binary value for type numeric".to_owned()))))?,
+    };
+    numeric_value(&text).map(Some).ok_or_else(|| PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22P02".to_owned(),
+        format!("invalid input syntax for type numeric: \"{text}\"")))))
+}
+
+/// A decimal as an integer (if it's a whole number that fits) or a real - `NaN` is kept as text, as SQLite stores NaN as NULL
+fn numeric_value(text:&str) -> Option<Value> {
+    if text.eq_ignore_ascii_case("NaN") {
+        return Some(Value::Text("NaN".to_owned()));
+    }
+    if let Ok(i) = text.parse::<i64>() {
+        return Some(Value::Integer(i));
+    }
+    text.parse::<f64>().ok().filter(|f| f.is_finite()).map(Value::Real)
+}
+
+/// The text of a real in a NUMERIC column - always in positional notation (eg. `100000000000000000000`, not `1e20`) like Postgres
+pub fn format_numeric(value:f64) -> String {
+    match value.is_finite() {
+        true => value.to_string(),
+        false => "NaN".to_owned(),
+    }
+}
+
+/// Encode a decimal (eg. `-12.50`) in Postgres's binary NUMERIC format: the number of base 10000 digits, the weight (exponent) of
+/// the first digit, the sign, the number of decimal places + then the digits
+pub fn encode_binary(text:&str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("NaN") {
+        return Some([0i16.to_be_bytes(), 0i16.to_be_bytes(), NUMERIC_NAN.to_be_bytes(), 0u16.to_be_bytes()].concat());
+    }
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (int_part, frac_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if (int_part.is_empty() && frac_part.is_empty()) || !int_part.chars().chain(frac_part.chars()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // Pad the integer part on the left, and the fraction on the right, to whole base 10000 digits
+    let int_part = int_part.trim_start_matches('0');
+    let int_padded = format!("{}{int_part}", "0".repeat((4 - int_part.len() % 4) % 4));
+    let frac_padded = format!("{frac_part}{}", "0".repeat((4 - frac_part.len() % 4) % 4));
+    let int_digits = int_padded.len() / 4;
+    let mut digits = int_padded.as_bytes().chunks(4).chain(frac_padded.as_bytes().chunks(4))
+        .map(|chunk| std::str::from_utf8(chunk).ok().and_then(|digit| digit.parse::<i16>().ok()))
+        .collect::<Option<Vec<i16>>>()?;
+
+    // Leading + trailing zero digits are left out, so zero has no digits at all
+    let mut weight = int_digits as i16 - 1;
+    let leading_zeros = digits.iter().take_while(|digit| **digit == 0).count();
+    digits.drain(..leading_zeros);
+    weight -= leading_zeros as i16;
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    let sign = match negative && !digits.is_empty() {
+        true => NUMERIC_NEG,
+        false => 0,
+    };
+    if digits.is_empty() {
+        weight = 0;
+    }
+
+    let mut bytes = Vec::with_capacity(8 + digits.len() * 2);
+    bytes.extend((digits.len() as i16).to_be_bytes());
+    bytes.extend(weight.to_be_bytes());
+    bytes.extend(sign.to_be_bytes());
+    bytes.extend((frac_part.len() as u16).to_be_bytes());
+    for digit in digits {
+        bytes.extend(digit.to_be_bytes());
+    }
+    Some(bytes)
+}
+
+/// Decode a decimal from Postgres's binary NUMERIC format, as its text (eg. `-12.50`)
+fn decode_binary(bytes:&[u8]) -> Option<String> {
+    let read_u16 = |offset:usize| bytes.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let ndigits = read_u16(0)? as usize;
+    let weight = read_u16(2)? as i16 as i32;
+    let sign = read_u16(4)?;
+    let dscale = read_u16(6)? as usize;
+    let digits = (0..ndigits).map(|idx| read_u16(8 + idx * 2)).collect::<Option<Vec<u16>>>()?;
+    if sign == NUMERIC_NAN {
+        return Some("NaN".to_owned());
+    }
+    let digit = |idx:i32| usize::try_from(idx).ok().and_then(|idx| digits.get(idx)).copied().unwrap_or(0);
+
+    let mut text = String::new();
+    if sign == NUMERIC_NEG {
+        text.push('-');
+    }
+    match weight < 0 {
+        true => text.push('0'),
+        false => for idx in 0..=weight {
+            match idx {
+                0 => text.push_str(&digit(idx).to_string()),
+                _ => text.push_str(&format!("{:04}", digit(idx))),
+            }
+        },
+    }
+    if dscale > 0 {
+        let mut fraction = (0..dscale.div_ceil(4) as i32).map(|idx| format!("{:04}", digit(weight + 1 + idx))).collect::<String>();
+        fraction.truncate(dscale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_base_10000_digits() {
+        let expected = [2i16, 0, 0, 2, 12, 5000].iter().flat_map(|n| n.to_be_bytes()).collect::<Vec<u8>>();
+        assert_eq!(encode_binary("12.50"), Some(expected));
+        let expected = [1i16, 1, 0, 0, 10].iter().flat_map(|n| n.to_be_bytes()).collect::<Vec<u8>>();
+        assert_eq!(encode_binary("100000"), Some(expected));
+        assert_eq!(encode_binary("-0"), Some(vec![0; 8]));
+        assert_eq!(encode_binary("1e5"), None);
+        assert_eq!(encode_binary("."), None);
+    }
+
+    #[test]
+    fn round_trips_decimals() {
+        for text in ["0", "12.50", "-0.001", "100000", "-123456789.0123456789", "0.0000", "NaN"] {
+            assert_eq!(decode_binary(&encode_binary(text).unwrap()).as_deref(), Some(text));
+        }
+        assert_eq!(decode_binary(&encode_binary("+007.5").unwrap()).as_deref(), Some("7.5"));
+        assert_eq!(decode_binary(&[0, 1, 0, 0]), None);
+    }
+
+    #[test]
+    fn stores_decimals_as_sqlite_would() {
+        assert_eq!(numeric_value("42"), Some(Value::Integer(42)));
+        assert_eq!(numeric_value("4.25"), Some(Value::Real(4.25)));
+        assert_eq!(numeric_value("nan"), Some(Value::Text("NaN".to_owned())));
+        assert_eq!(numeric_value("inf"), None);
+        assert_eq!(format_numeric(1e20), "100000000000000000000");
+    }
+}
@@ -0,0 +1,26 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use futures::stream;
+use pgwire::{api::{query::SimpleQueryHandler, results::{Response, QueryResponse, FieldInfo, FieldFormat, DataRowEncoder}, ClientInfo, Type}, error::{PgWireResult, PgWireError, ErrorInfo}};
+
+/* Follows is the query handler for unauthenticated health probe connections, which never touch a database */
+
+pub struct ProbeQueryHandler;
+
+#[async_trait]
+impl SimpleQueryHandler for ProbeQueryHandler {
+    async fn do_query<'a, 'b:'a, C>(&'b self, _client: &C, query: &'a str) -> PgWireResult<Vec<Response<'a>>>
+    where C: ClientInfo + Unpin + Send + Sync {
+        trace!("Processing Health Probe Query: {:?}", query);
+        let normalised = query.trim().trim_end_matches(';').split_whitespace().collect::<Vec<&str>>().join(" ");
+        if !normalised.eq_ignore_ascii_case("SELECT 1") {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "42501".to_owned(), "Health probe connections may only run: SELECT 1".to_owned()))));
+        }
+
+        let schema = Arc::new(vec![FieldInfo::new("?column?".to_owned(), None, None, Type::INT4, FieldFormat::Text)]);
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&1i32)?;
+        let rows = stream::iter(vec![encoder.finish()]);
+        Ok(vec![Response::Query(QueryResponse::new(schema, rows))])
+    }
+}
@@ -1,42 +1,132 @@
-use std::{sync::Arc, time::Duration};
-use crossbeam_channel::RecvTimeoutError;
+use std::{borrow::Cow, collections::HashMap, sync::{Arc, Mutex}, time::Duration, fmt::Debug};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
 use async_trait::async_trait;
-use futures::stream;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use futures::{stream::{self, BoxStream}, Sink, SinkExt};
 use futures_util::StreamExt;
-use pgwire::{api::{query::{SimpleQueryHandler, ExtendedQueryHandler, StatementOrPortal}, results::{Response, DescribeResponse, DataRowEncoder, QueryResponse, FieldInfo}, ClientInfo, portal::Portal, store::MemPortalStore, stmt::NoopQueryParser, Type}, error::{PgWireResult, ErrorInfo, PgWireError}, messages::data::DataRow};
+use pgwire::{api::{query::{SimpleQueryHandler, ExtendedQueryHandler, StatementOrPortal, send_execution_response}, results::{Response, DescribeResponse, DataRowEncoder, QueryResponse, FieldInfo, FieldFormat, Tag}, ClientInfo, PgWireConnectionState, portal::{Portal, Format}, store::{MemPortalStore, PortalStore}, stmt::{NoopQueryParser, QueryParser, StoredStatement}, Type, DEFAULT_NAME}, error::{PgWireResult, ErrorInfo, PgWireError}, messages::{PgWireBackendMessage, data::{DataRow, RowDescription, FieldDescription, NoData, ParameterDescription}, extendedquery::{Sync as PgSync, Parse as PgParse, Describe as PgDescribe, ParseComplete, Bind as PgBind, Execute as PgExecute, BindComplete, Close as PgClose, CloseComplete, PortalSuspended, TARGET_TYPE_BYTE_STATEMENT, TARGET_TYPE_BYTE_PORTAL}, response::{ReadyForQuery, EmptyQueryResponse, NoticeResponse}, simplequery::Query, startup::ParameterStatus, copy::{CopyData, CopyDone, CopyFail, CopyInResponse, CopyOutResponse}}};
 use rusqlite::types::Value;
-pub use rusqlite::Column;
+use uuid::Uuid;
 
-use crate::backend::{PgLiteDBMessage, BackendConnection, Record, Field, PgLiteDBResponse, PgLiteDBParam};
+use crate::backend::{PgLiteDBMessage, BackendConnection, Record, Field, PgLiteDBResponse, PgLiteDBParam, DatabaseList, database_info_schema, strip_leading_comments, replace_lastval, changes_handle_state, tokens::{tokenize, Token}};
+use crate::admin;
+use crate::session::{PgLiteSession, TransactionStatus, DateStyle, ByteaOutput, TimeZone, parse_begin_statement, parse_set_statement, parse_show_statement, parse_timestamp, parse_time, format_time, format_utc_timestamp};
+use crate::array_params::{self, BoundParam};
+use crate::numeric;
+use crate::uuid_values;
+use crate::config::UuidStorage;
+use crate::copy::{self, CopyDirection, CopyIn, CopyStatement};
+use crate::cancel::CancelToken;
+use crate::telemetry::{QueryTrace, SlowQueryLog};
 
 pub struct PgQueryProcessor {
     db:BackendConnection,
     portal_store: Arc<MemPortalStore<String>>,
     query_parser: Arc<NoopQueryParser>,
+    session: Arc<Mutex<PgLiteSession>>,
+    max_query_length: Option<usize>,
+    max_result_bytes: Option<usize>,
+    max_result_rows: Option<usize>,
+    connection_id: Uuid,
+    databases: Option<DatabaseList>,
+    query_timeout: Option<Duration>,
+    suspended_portals: SuspendedPortals,
+    copy_in: CopyInState,
+    cancel_token: CancelToken,
+    slow_query_log: Option<Arc<SlowQueryLog>>,
+    uuid_storage: UuidStorage,
+    /// The notices from the backend for the statement being run, which are sent to the client ahead of its results
+    notices: Mutex<Vec<ErrorInfo>>,
+    /// The type modifiers of the columns being described to the client (pgwire's FieldInfo has nowhere to keep them)
+    type_modifiers: Mutex<Vec<i32>>,
 }
 
+/// The encoded rows of a result, as they're streamed from the backend
+type RowStream = BoxStream<'static, PgWireResult<DataRow>>;
+
+/// The rows left to send for the portals that were suspended by an Execute's row limit, by portal name - kept by the connection, as
+/// each of its messages gets its own query processor. They're read from the backend when the portal is suspended (up to the result
+/// size limits), as the database's backend thread would otherwise be left waiting on the client - holding up its other clients
+pub type SuspendedPortals = Arc<Mutex<HashMap<String, RowStream>>>;
+
+/// The `COPY ... FROM STDIN` that's waiting for the client's rows - kept by the connection, as the rows come in messages of their own
+pub type CopyInState = Arc<Mutex<Option<CopyIn>>>;
+
 #[async_trait]
 impl SimpleQueryHandler for PgQueryProcessor {
-    async fn do_query<'a, 'b:'a, C>(&'b self, _client: &C, query: &'a str) -> PgWireResult<Vec<Response<'a>>>
+    async fn on_query<C>(&self, client: &mut C, query: Query) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // Same as the default pgwire implementation, but finishes with any parameter changes + the session's transaction status
+        client.set_state(PgWireConnectionState::QueryInProgress);
+        let statements = split_statements(query.query());
+        if statements.is_empty() {
+            client.feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse)).await?;
+        }
+        // Each statement's results are sent before the next is run (so its rows aren't left waiting in the backend), and
+        // like Postgres an error stops the rest of the statements from running
+        for (idx, statement) in statements.iter().enumerate() {
+            // COPY exchanges its rows with the client in messages of its own, rather than as a query's results
+            if let Some(copy) = copy::parse_copy_statement(statement)? {
+                match copy.direction {
+                    CopyDirection::Out => {
+                        self.copy_out(client, copy).await?;
+                        continue;
+                    },
+                    CopyDirection::In if idx + 1 < statements.len() => {
+                        return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "0A000".to_owned(),
+                            "COPY FROM STDIN has to be the last statement in the query".to_owned()))));
+                    },
+                    CopyDirection::In => {
+                        // The client is told it's ready for the next query once it's sent the rows
+                        return self.start_copy_in(client, statement, copy).await;
+                    },
+                }
+            }
+            let resp = SimpleQueryHandler::do_query(self, client, statement).await;
+            self.send_notices(client).await?;
+            for r in resp? {
+                match r {
+                    Response::EmptyQuery => client.feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse)).await?,
+                    Response::Query(results) => send_query_rows(client, statement, results, Some(&self.take_type_modifiers())).await?,
+                    Response::Execution(tag) => send_execution_response(client, tag).await?,
+                    Response::Error(e) => client.feed(PgWireBackendMessage::ErrorResponse((*e).into())).await?,
+                }
+            }
+        }
+        self.send_ready_for_query(client).await?;
+        client.set_state(PgWireConnectionState::ReadyForQuery);
+        Ok(())
+    }
+
+    async fn do_query<'a, 'b:'a, C>(&'b self, client: &C, query: &'a str) -> PgWireResult<Vec<Response<'a>>>
     where C: ClientInfo + Unpin + Send + Sync {
         trace!("Processing Simple Query: {:?}", query);
+        self.check_query_length(query)?;
+        let query = self.check_failed_transaction(query)?;
+        if let Some(response) = self.handle_set_statement(query)? {
+            return Ok(vec![response]);
+        }
+        if let Some(response) = self.handle_show_statement(query)? {
+            return Ok(vec![response]);
+        }
 
+        let trace = self.trace_query(client, query);
         let (resp, waiter) = crossbeam_channel::bounded(1);
-        let msg = PgLiteDBMessage::from_query(String::from(query), resp);
-        let _ = self.db.sender.send(msg);
-        let result = match waiter.recv_timeout(Duration::from_secs(10)) {   // todo make this configurable - currently hard coded to 10s
-            Ok(msg) => msg,
-            Err(RecvTimeoutError::Timeout) => {
-                // Timeout waiting for response - return an error
-                return PgWireResult::Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Timeout waiting for response from the database".to_owned()).into())); 
-            }, 
-            Err(RecvTimeoutError::Disconnected) => {
-                // Connection to the DB was lost for some reason, so exit...
-                return PgWireResult::Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Was disconnected from the database backend".to_owned()).into())); 
-            }
+        let statement = self.replace_lastval(begin_statement(query))?;
+        let (read_only, main_handle) = self.session_state(&statement);
+        let msg = match admin::is_database_info(query) {
+            true => PgLiteDBMessage::from_database_info(resp),
+            false => PgLiteDBMessage::from_query(statement.into_owned(), read_only, resp).with_databases(self.databases.clone()).on_main_handle(main_handle).with_cancel_token(self.cancel_token.clone()),
         };
+        self.db.send(msg)?;
+        let result = self.take_notices(self.wait_for_response(waiter)?);
 
-        self.translate_dbresponse_to_pgwire(result).map(|r| vec![r])
+        self.track_transaction_status(query, &result);
+        self.translate_dbresponse_to_pgwire(query, result, &Format::UnifiedText, trace).map(|r| vec![r])
     }
 }
 
@@ -54,55 +144,218 @@ impl ExtendedQueryHandler for PgQueryProcessor {
         self.query_parser.clone()
     }
 
-    async fn do_query<'a, 'b:'a, C>(&'b self, _client: &mut C,portal: &'a Portal<Self::Statement>, _max_rows: usize) -> PgWireResult<Response<'a>>
+    async fn do_query<'a, 'b:'a, C>(&'b self, client: &mut C,portal: &'a Portal<Self::Statement>, _max_rows: usize) -> PgWireResult<Response<'a>>
     where C: ClientInfo + Unpin + Send + Sync {
-        trace!("Processing Extended Query: {:?}", portal);
-        let query = portal.statement().statement();
-        let params = self.parse_params(portal);
+        // The row limit is applied by `on_execute`, as it sends the rows
+        self.execute_portal(client, portal).await
+    }
 
-        let (resp, waiter) = crossbeam_channel::bounded(1);
-        let msg = PgLiteDBMessage::from_query_with_params(query.to_string(), params, resp);
-        let _ = self.db.sender.send(msg);
-        let result = match waiter.recv_timeout(Duration::from_secs(10)) {
-            Ok(msg) => msg,
-            Err(RecvTimeoutError::Timeout) => {
-                // Timeout waiting for response - return an error
-                return PgWireResult::Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Timeout waiting for response from the database".to_owned()).into())); 
-            }, 
-            Err(RecvTimeoutError::Disconnected) => {
-                // Connection to the DB was lost for some reason, so exit...
-                return PgWireResult::Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Was disconnected from the database backend".to_owned()).into())); 
-            }
+    async fn on_execute<C>(&self, client: &mut C, message: PgExecute) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // Same as the default pgwire implementation, but the rows of an `INSERT/UPDATE/DELETE ... RETURNING` get its command tag,
+        // and with a row limit the portal is suspended once it's sent that many rows - the next Execute carries on from there
+        let portal_name = message.name().as_deref().unwrap_or(DEFAULT_NAME);
+        let Some(portal) = self.portal_store.get_portal(portal_name) else {
+            return Err(PgWireError::PortalNotFound(portal_name.to_owned()));
+        };
+        let max_rows = *message.max_rows() as usize;
+        let suspended = self.suspended_portals.lock().unwrap().remove(portal_name);
+        let rows = match suspended {
+            Some(rows) => rows,
+            None => {
+                let response = self.execute_portal(client, portal.as_ref()).await;
+                self.send_notices(client).await?;
+                match response? {
+                    Response::Query(results) => results.data_rows(),
+                    Response::EmptyQuery => {
+                        client.feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse)).await?;
+                        return Ok(());
+                    },
+                    Response::Execution(tag) => {
+                        send_execution_response(client, tag).await?;
+                        return Ok(());
+                    },
+                    Response::Error(e) => {
+                        client.send(PgWireBackendMessage::ErrorResponse((*e).into())).await?;
+                        return Ok(());
+                    },
+                }
+            },
         };
-        self.translate_dbresponse_to_pgwire(result)
+        if let Some(rows) = send_portal_rows(client, portal.statement().statement(), rows, max_rows).await? {
+            let rows = rows.collect::<Vec<PgWireResult<DataRow>>>().await;
+            self.suspended_portals.lock().unwrap().insert(portal_name.to_owned(), stream::iter(rows).boxed());
+        }
+        Ok(())
+    }
+
+    async fn on_parse<C>(&self, client: &mut C, message: PgParse) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // Same as the default pgwire implementation, but like Postgres a named statement has to be closed before its name can be
+        // parsed again (only the unnamed statement is replaced) - a statement is prepared in SQLite when it's first run, and cached
+        // by its SQL, so re-running a named statement (or parsing the same SQL again) doesn't prepare it again
+        let name = message.name().as_deref().unwrap_or(DEFAULT_NAME);
+        if name != DEFAULT_NAME && self.portal_store.get_statement(name).is_some() {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "42P05".to_owned(),
+                format!("prepared statement \"{name}\" already exists")))));
+        }
+        let types = message.type_oids().iter()
+            .map(|oid| Type::from_oid(*oid).ok_or(PgWireError::UnknownTypeId(*oid)))
+            .collect::<PgWireResult<Vec<Type>>>()?;
+        let statement = self.query_parser.parse_sql(message.query(), &types).await?;
+        self.portal_store.put_statement(Arc::new(StoredStatement::new(name.to_owned(), statement, types)));
+        client.send(PgWireBackendMessage::ParseComplete(ParseComplete::new())).await?;
+        Ok(())
+    }
+
+    async fn on_describe<C>(&self, client: &mut C, message: PgDescribe) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // Same as the default pgwire implementation, but the columns are described with their type modifiers (eg. a varchar's length)
+        let name = message.name().as_deref().unwrap_or(DEFAULT_NAME);
+        let (describe_response, include_parameters) = match message.target_type() {
+            TARGET_TYPE_BYTE_STATEMENT => {
+                let Some(statement) = self.portal_store.get_statement(name) else {
+                    return Err(PgWireError::StatementNotFound(name.to_owned()));
+                };
+                (self.do_describe(client, StatementOrPortal::Statement(&statement)).await?, true)
+            },
+            TARGET_TYPE_BYTE_PORTAL => {
+                let Some(portal) = self.portal_store.get_portal(name) else {
+                    return Err(PgWireError::PortalNotFound(name.to_owned()));
+                };
+                (self.do_describe(client, StatementOrPortal::Portal(&portal)).await?, false)
+            },
+            target_type => return Err(PgWireError::InvalidTargetType(target_type)),
+        };
+        let type_modifiers = self.take_type_modifiers();
+        if describe_response.is_no_data() {
+            client.send(PgWireBackendMessage::NoData(NoData)).await?;
+            return Ok(());
+        }
+        if let (true, Some(parameter_types)) = (include_parameters, describe_response.parameters()) {
+            let parameter_description = ParameterDescription::new(parameter_types.iter().map(|t| t.oid()).collect());
+            client.send(PgWireBackendMessage::ParameterDescription(parameter_description)).await?;
+        }
+        client.send(PgWireBackendMessage::RowDescription(row_description(describe_response.fields(), &type_modifiers))).await?;
+        Ok(())
+    }
+
+    async fn on_bind<C>(&self, client: &mut C, message: PgBind) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // Same as the default pgwire implementation, but keeps track of the portal names - so they can be closed with their statement
+        let statement_name = message.statement_name().as_deref().unwrap_or(DEFAULT_NAME);
+        let Some(statement) = self.portal_store.get_statement(statement_name) else {
+            return Err(PgWireError::StatementNotFound(statement_name.to_owned()));
+        };
+        let portal = Portal::try_new(&message, statement)?;
+        self.session.lock().unwrap().portal_names.insert(portal.name().to_owned());
+        self.suspended_portals.lock().unwrap().remove(portal.name());
+        self.portal_store.put_portal(Arc::new(portal));
+        client.send(PgWireBackendMessage::BindComplete(BindComplete::new())).await?;
+        Ok(())
+    }
+
+    async fn on_close<C>(&self, client: &mut C, message: PgClose) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // SQLite's statement cache is keyed on the SQL (not the statement name), so re-parsing a closed statement's name with
+        // different SQL never gets the old statement - there's only the portal store to clean up
+        let name = message.name().as_deref().unwrap_or(DEFAULT_NAME);
+        {
+            let mut session = self.session.lock().unwrap();
+            match message.target_type() {
+                TARGET_TYPE_BYTE_STATEMENT => {
+                    // Like Postgres, closing a statement also closes the portals that were bound from it
+                    session.portal_names.retain(|portal_name| match self.portal_store.get_portal(portal_name) {
+                        Some(portal) if portal.statement().id() == name => {
+                            self.portal_store.rm_portal(portal_name);
+                            self.suspended_portals.lock().unwrap().remove(portal_name);
+                            false
+                        },
+                        Some(_) => true,
+                        None => false,
+                    });
+                    self.portal_store.rm_statement(name);
+                },
+                TARGET_TYPE_BYTE_PORTAL => {
+                    session.portal_names.remove(name);
+                    self.portal_store.rm_portal(name);
+                    self.suspended_portals.lock().unwrap().remove(name);
+                },
+                _ => {},
+            }
+        }
+        client.send(PgWireBackendMessage::CloseComplete(CloseComplete)).await?;
+        Ok(())
+    }
+
+    async fn on_sync<C>(&self, client: &mut C, _message: PgSync) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // Like Postgres, a Sync outside of a transaction block ends the implicit transaction, closing any suspended portals
+        if self.session.lock().unwrap().transaction_status == TransactionStatus::Idle {
+            self.suspended_portals.lock().unwrap().clear();
+        }
+        self.send_ready_for_query(client).await
     }
 
     async fn do_describe<C>(&self, _client: &mut C, target: StatementOrPortal<'_, Self::Statement>) -> PgWireResult<DescribeResponse>
     where C: ClientInfo + Unpin + Send + Sync {
         trace!("Processing Describe: {:?}", target);
-        let query = match target {
-            StatementOrPortal::Statement(statement) => statement.statement(),
-            StatementOrPortal::Portal(portal) => portal.statement().statement()
+        // A statement's columns are described in the text format, a portal's in the formats it was bound with
+        let (query, formats) = match &target {
+            StatementOrPortal::Statement(statement) => (statement.statement(), &Format::UnifiedText),
+            StatementOrPortal::Portal(portal) => (portal.statement().statement(), portal.result_column_format())
         };
+        self.check_query_length(query)?;
+        let query = array_params::rewrite_any_for_describe(query);
+        // Describing a statement doesn't need the session's lastval() (which may not be defined yet), just a value in its place
+        let query = replace_lastval(query.as_ref(), Some(0))?.into_owned();
+        let query = query.as_str();
+        if parse_set_statement(query).is_some() || parse_begin_statement(query).is_some() {
+            return Ok(DescribeResponse::no_data());
+        }
+        if parse_show_statement(query).is_some_and(|name| name.eq_ignore_ascii_case("ALL")) {
+            return Ok(DescribeResponse::new(None, with_result_formats(show_all_schema().to_vec(), formats)?));
+        }
+        if let Some((name, _)) = parse_show_statement(query).and_then(|name| self.session.lock().unwrap().show_parameter(&name)) {
+            return Ok(DescribeResponse::new(None, with_result_formats(show_schema(name).to_vec(), formats)?));
+        }
+        if admin::is_database_info(query) {
+            return Ok(DescribeResponse::new(None, with_result_formats(database_info_schema().iter().map(|field| field.into()).collect(), formats)?));
+        }
 
         let (resp, waiter) = crossbeam_channel::bounded(1);
         let msg = PgLiteDBMessage::from_describe(query.to_string(), resp);
-        let _ = self.db.sender.send(msg);
-        let result = match waiter.recv_timeout(Duration::from_secs(10)) { // todo make this configurable - currently hard coded to 10s
-            Ok(msg) => msg,
-            Err(RecvTimeoutError::Timeout) => {
-                // Timeout waiting for response - return an error
-                return PgWireResult::Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Timeout waiting for response from the database".to_owned()).into()));
-            }, 
-            Err(RecvTimeoutError::Disconnected) => {
-                // Connection to the DB was lost for some reason, so exit...
-                return PgWireResult::Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Was disconnected from the database backend".to_owned()).into())); 
-            }
-        };
+        self.db.send(msg)?;
+        let result = self.wait_for_response(waiter)?;
         
         if let Some(schema) = result.result_schema {
-            let fields = schema.iter().map(|field| field.into() ).collect();
-            Ok(DescribeResponse::new(None, fields))
+            Ok(DescribeResponse::new(None, self.translate_schema_to_pgwire(schema, formats)?))
+        } else if let Some(err) = result.error {
+            Err(err)
         } else {
             return PgWireResult::Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Was unable to process the query schema".to_owned()).into())); 
         }
@@ -110,53 +363,470 @@ impl ExtendedQueryHandler for PgQueryProcessor {
 }
 
 impl PgQueryProcessor {
-    pub fn create(db:BackendConnection, portal_store:Arc<MemPortalStore<String>>, query_parser:Arc<NoopQueryParser>) -> Self {
-        Self { db, query_parser, portal_store, }
+    pub fn create(db:BackendConnection, portal_store:Arc<MemPortalStore<String>>, query_parser:Arc<NoopQueryParser>, session:Arc<Mutex<PgLiteSession>>, max_query_length:Option<usize>, max_result_bytes:Option<usize>, connection_id:Uuid) -> Self {
+        Self { db, query_parser, portal_store, session, max_query_length, max_result_bytes, max_result_rows:None, connection_id, databases:None, query_timeout:Some(Duration::from_secs(10)), suspended_portals:SuspendedPortals::default(), copy_in:CopyInState::default(), cancel_token:CancelToken::default(), slow_query_log:None, uuid_storage:UuidStorage::default(), notices:Mutex::new(Vec::new()), type_modifiers:Mutex::new(Vec::new()) }
     }
 
-    fn translate_dbresponse_to_pgwire(&self, result:PgLiteDBResponse) -> PgWireResult<Response<'_>> {
-        if let Some(res) = result.result {
-            let schema = Arc::new(self.translate_schema_to_pgwire(result.result_schema.unwrap()));
-            let schema2 = schema.clone();
-            match self.translate_records_to_pgwire(schema, res) {
-                Ok(records) => {
-                    let record_stream = stream::iter(records.into_iter()).boxed();
-                    let response = Response::Query(QueryResponse::new( schema2, record_stream));
-                    PgWireResult::Ok(response)
-                },
-                Err(err) => PgWireResult::Err(err),
+    /// Send the rows of a `COPY ... TO STDOUT` to the client - they're the text values a query would send, in the COPY's format
+    async fn copy_out<C>(&self, client: &mut C, copy: CopyStatement) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let query = copy.select_query();
+        let results = match SimpleQueryHandler::do_query(self, client, &query).await?.pop() {
+            Some(Response::Query(results)) => results,
+            // A statement that doesn't return rows (eg. `COPY (DELETE FROM t) TO STDOUT`) has still been run, it just has nothing to copy
+            _ => {
+                client.send(PgWireBackendMessage::CopyOutResponse(CopyOutResponse::new(0, 0, Vec::new()))).await?;
+                client.feed(PgWireBackendMessage::CopyDone(CopyDone::new())).await?;
+                return send_execution_response(client, Tag::new_for_execution("COPY", Some(0))).await;
+            },
+        };
+        let schema = results.row_schema().clone();
+        client.send(PgWireBackendMessage::CopyOutResponse(CopyOutResponse::new(0, schema.len() as i16, vec![0; schema.len()]))).await?;
+        if copy.options.header {
+            client.feed(PgWireBackendMessage::CopyData(CopyData::new(copy.options.encode_header(schema.iter().map(|field| field.name())).into()))).await?;
+        }
+        let mut rows = 0;
+        let mut data_rows = results.data_rows();
+        while let Some(row) = data_rows.next().await {
+            let row = row?;
+            let values = row.fields().iter().map(|value| value.as_deref()).collect::<Vec<Option<&[u8]>>>();
+            client.feed(PgWireBackendMessage::CopyData(CopyData::new(copy.options.encode_row(&values).into()))).await?;
+            rows += 1;
+        }
+        client.feed(PgWireBackendMessage::CopyDone(CopyDone::new())).await?;
+        send_execution_response(client, Tag::new_for_execution("COPY", Some(rows))).await
+    }
+
+    /// Start a `COPY ... FROM STDIN`, by asking the client for its rows - they're inserted once it's sent all of them, inside a
+    /// transaction (unless the client is already in one), so a COPY that fails doesn't leave some of its rows behind
+    async fn start_copy_in<C>(&self, client: &mut C, statement: &str, copy: CopyStatement) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.check_failed_transaction(statement)?;
+        // The columns are described first, so a missing table (or column) is an error before the client sends any rows
+        let (resp, waiter) = crossbeam_channel::bounded(1);
+        self.db.send(PgLiteDBMessage::from_describe(copy.select_query(), resp))?;
+        let result = self.wait_for_response(waiter)?;
+        let columns = match (result.result_schema, result.error) {
+            (_, Some(err)) => return Err(err),
+            (Some(schema), None) => schema.into_iter().map(|field| (field.name, field.pg_type)).collect::<Vec<_>>(),
+            (None, None) => return Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Was unable to process the query schema".to_owned()).into())),
+        };
+
+        let copy_in = CopyIn::new(copy, columns);
+        let column_count = copy_in.columns();
+        *self.copy_in.lock().unwrap() = Some(copy_in);
+        client.send(PgWireBackendMessage::CopyInResponse(CopyInResponse::new(0, column_count as i16, vec![0; column_count]))).await?;
+        Ok(())
+    }
+
+    /// Decode the rows in the data of a `COPY ... FROM STDIN` - once a COPY has failed, the rest of its data is ignored
+    pub async fn on_copy_data<C>(&self, client: &mut C, message: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(mut copy_in) = self.copy_in.lock().unwrap().take() else { return Ok(()) };
+        match copy_in.rows(message.data(), false) {
+            Ok(rows) => {
+                copy_in.copied.extend(rows);
+                *self.copy_in.lock().unwrap() = Some(copy_in);
+                Ok(())
+            },
+            Err(err) => self.fail_copy_in(client, err),
+        }
+    }
+
+    /// The client has sent all of the COPY's rows, so insert them
+    pub async fn on_copy_done<C>(&self, client: &mut C, _message: CopyDone) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(mut copy_in) = self.copy_in.lock().unwrap().take() else { return Ok(()) };
+        let rows = match copy_in.rows(&[], true).and_then(|rows| {
+            copy_in.copied.extend(rows);
+            self.insert_copy_rows(&mut copy_in)
+        }) {
+            Ok(rows) => rows,
+            Err(err) => return self.fail_copy_in(client, err),
+        };
+        send_execution_response(client, Tag::new_for_execution("COPY", Some(rows))).await?;
+        self.send_ready_for_query(client).await?;
+        client.set_state(PgWireConnectionState::ReadyForQuery);
+        Ok(())
+    }
+
+    /// The client has given up on the COPY, so none of its rows are kept
+    pub async fn on_copy_fail<C>(&self, client: &mut C, message: CopyFail) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if self.copy_in.lock().unwrap().take().is_none() {
+            return Ok(());
+        }
+        let err = PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "57014".to_owned(), format!("COPY from stdin failed: {}", message.message()))));
+        self.fail_copy_in(client, err)
+    }
+
+    /// Insert all of the COPY's rows in a single message, so the backend runs them (+ the transaction around them) without waiting
+    /// on anything else - returning the number of rows inserted
+    fn insert_copy_rows(&self, copy_in:&mut CopyIn) -> PgWireResult<usize> {
+        let (resp, waiter) = crossbeam_channel::bounded(1);
+        let (read_only, main_handle) = self.session_state(&copy_in.insert_query);
+        let rows = std::mem::take(&mut copy_in.copied);
+        self.db.send(PgLiteDBMessage::from_copy_rows(copy_in.insert_query.clone(), rows, read_only, resp).on_main_handle(main_handle).with_cancel_token(self.cancel_token.clone()))?;
+        let mut result = self.wait_for_response(waiter)?;
+        self.track_transaction_status(&copy_in.insert_query, &result);
+        match result.error.take() {
+            Some(err) => Err(err),
+            None => Ok(result.affected_rows.unwrap_or_default()),
+        }
+    }
+
+    /// None of a failed COPY's rows have been inserted, but like Postgres it aborts the client's transaction when it's in one. The
+    /// error is sent to the client by the connection, along with the ReadyForQuery that ends the COPY
+    fn fail_copy_in<C>(&self, client: &mut C, err: PgWireError) -> PgWireResult<()>
+    where C: ClientInfo {
+        let mut session = self.session.lock().unwrap();
+        if session.transaction_status != TransactionStatus::Idle {
+            session.transaction_status = TransactionStatus::Failed;
+        }
+        drop(session);
+        client.set_state(PgWireConnectionState::ReadyForQuery);
+        Err(err)
+    }
+
+    /// Run a portal's statement - its rows are streamed from the backend as they're sent
+    async fn execute_portal<C>(&self, client: &mut C, portal: &Portal<String>) -> PgWireResult<Response<'static>>
+    where C: ClientInfo + Unpin + Send + Sync {
+        trace!("Processing Extended Query: {:?}", portal);
+        let query = portal.statement().statement();
+        self.check_query_length(query)?;
+        let query = self.check_failed_transaction(query)?;
+        if copy::is_copy_statement(query) {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "0A000".to_owned(),
+                "COPY is only supported in simple queries".to_owned()))));
+        }
+        if let Some(response) = self.handle_set_statement(query)? {
+            return Ok(response);
+        }
+        if let Some(response) = self.handle_show_statement(query)? {
+            return Ok(response);
+        }
+        let statement = self.replace_lastval(begin_statement(query))?;
+        let (statement, params) = array_params::expand_array_params(&statement, self.parse_params(portal)?)?;
+
+        let trace = self.trace_query(client, &statement).with_parameters(&params);
+        let (resp, waiter) = crossbeam_channel::bounded(1);
+        let (read_only, main_handle) = self.session_state(&statement);
+        let msg = match admin::is_database_info(&statement) {
+            true => PgLiteDBMessage::from_database_info(resp),
+            false => PgLiteDBMessage::from_query_with_params(statement.to_string(), params, read_only, resp).with_databases(self.databases.clone()).on_main_handle(main_handle).with_cancel_token(self.cancel_token.clone()),
+        };
+        self.db.send(msg)?;
+        let result = self.take_notices(self.wait_for_response(waiter)?);
+        self.track_transaction_status(query, &result);
+        self.translate_dbresponse_to_pgwire(query, result, portal.result_column_format(), trace)
+    }
+
+    /// The databases the client can connect to, for queries of pg_database
+    pub fn with_databases(mut self, databases:Option<DatabaseList>) -> Self {
+        self.databases = databases;
+        self
+    }
+
+    /// The connection's suspended portals, for an Execute to carry on from where the last one stopped
+    pub fn with_suspended_portals(mut self, suspended_portals:SuspendedPortals) -> Self {
+        self.suspended_portals = suspended_portals;
+        self
+    }
+
+    /// The connection's COPY that's waiting for the client's rows
+    pub fn with_copy_in(mut self, copy_in:CopyInState) -> Self {
+        self.copy_in = copy_in;
+        self
+    }
+
+    /// The most rows a query can return, if it's limited
+    pub fn with_max_result_rows(mut self, max_result_rows:Option<usize>) -> Self {
+        self.max_result_rows = max_result_rows;
+        self
+    }
+
+    /// The session's cancel token, so its statements can be cancelled by a CancelRequest while they're running
+    pub fn with_cancel_token(mut self, cancel_token:CancelToken) -> Self {
+        self.cancel_token = cancel_token;
+        self
+    }
+
+    /// The settings of the slow query log, if it's enabled
+    pub fn with_slow_query_log(mut self, slow_query_log:Option<Arc<SlowQueryLog>>) -> Self {
+        self.slow_query_log = slow_query_log;
+        self
+    }
+
+    /// How the values of UUID parameters are stored
+    pub fn with_uuid_storage(mut self, uuid_storage:UuidStorage) -> Self {
+        self.uuid_storage = uuid_storage;
+        self
+    }
+
+    /// How long to wait for the backend to respond to a query (forever if None)
+    pub fn with_query_timeout(mut self, query_timeout:Option<Duration>) -> Self {
+        self.query_timeout = query_timeout;
+        self
+    }
+
+    fn wait_for_response(&self, waiter:Receiver<PgLiteDBResponse>) -> PgWireResult<PgLiteDBResponse> {
+        // The wait blocks, so the runtime hands its other connections to another thread in the meantime - otherwise a long
+        // running statement could hold up every connection on the worker (eg. the CancelRequest that would cancel it)
+        let response = tokio::task::block_in_place(|| match self.query_timeout {
+            Some(timeout) => waiter.recv_timeout(timeout),
+            None => waiter.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        });
+        match response {
+            Ok(msg) => Ok(msg),
+            Err(RecvTimeoutError::Timeout) => {
+                // Timeout waiting for response - return an error
+                Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Timeout waiting for response from the database".to_owned()).into()))
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                // Connection to the DB was lost for some reason, so exit...
+                Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Was disconnected from the database backend".to_owned()).into()))
             }
+        }
+    }
+
+    /// Keep the notices of the backend's response to be sent to the client (unless it's asked not to get them)
+    fn take_notices(&self, mut result:PgLiteDBResponse) -> PgLiteDBResponse {
+        if self.session.lock().unwrap().sends_notices() {
+            self.notices.lock().unwrap().append(&mut result.notices);
+        }
+        result
+    }
+
+    /// Send the notices of the statement that's been run, ahead of its results (or its error)
+    async fn send_notices<C>(&self, client: &mut C) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let notices = std::mem::take(&mut *self.notices.lock().unwrap());
+        for notice in notices {
+            client.feed(PgWireBackendMessage::NoticeResponse(NoticeResponse::from(notice))).await?;
+        }
+        Ok(())
+    }
+
+    /// The type modifiers of the columns that were last translated, for their RowDescription
+    fn take_type_modifiers(&self) -> Vec<i32> {
+        std::mem::take(&mut *self.type_modifiers.lock().unwrap())
+    }
+
+    /// Reject runaway generated statements before the backend spends time trying to prepare them
+    fn check_query_length(&self, query:&str) -> PgWireResult<()> {
+        match self.max_query_length {
+            Some(max_query_length) if query.len() > max_query_length => Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "ERROR".to_owned(),
+                "54000".to_owned(),
+                format!("The statement is too long ({} bytes), the maximum allowed is {} bytes (--max-query-length)", query.len(), max_query_length),
+            )))),
+            _ => Ok(())
+        }
+    }
+
+    /// Report any changed parameters to the client, then let it know we're ready for the next query
+    async fn send_ready_for_query<C>(&self, client: &mut C) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let (parameter_status, ready_status) = {
+            let mut session = self.session.lock().unwrap();
+            (session.take_parameter_status(), session.transaction_status.ready_status())
+        };
+        for (name, value) in parameter_status {
+            client.feed(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(name, value))).await?;
+        }
+        client.feed(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(ready_status))).await?;
+        client.flush().await?;
+        Ok(())
+    }
+
+    /// `SET` statements are handled here, rather than being passed to the backend (SQLite has no `SET`) - the parameters that aren't
+    /// managed by the session (eg. `search_path` or `statement_timeout`) are accepted, but have no effect
+    fn handle_set_statement(&self, query:&str) -> PgWireResult<Option<Response<'static>>> {
+        let Some((name, value)) = parse_set_statement(query) else { return Ok(None) };
+        if !self.session.lock().unwrap().set_parameter(&name, &value)? {
+            warn!("[{}] Ignoring the unsupported parameter: SET {} = {}", self.connection_id, name, value);
+        }
+        Ok(Some(Response::Execution(Tag::new_for_execution("SET", None))))
+    }
+
+    /// Answer `SHOW` for the parameters managed by the session (+ `SHOW ALL`), any other parameter isn't known
+    fn handle_show_statement(&self, query:&str) -> PgWireResult<Option<Response<'static>>> {
+        let Some(name) = parse_show_statement(query) else { return Ok(None) };
+        if name.eq_ignore_ascii_case("ALL") {
+            let schema = show_all_schema();
+            let rows = self.session.lock().unwrap().show_all_parameters().into_iter()
+                .map(|(name, value)| {
+                    let mut encoder = DataRowEncoder::new(schema.clone());
+                    encoder.encode_field(&name)?;
+                    encoder.encode_field(&value)?;
+                    encoder.encode_field(&"")?;
+                    encoder.finish()
+                })
+                .collect::<Vec<_>>();
+            return Ok(Some(Response::Query(QueryResponse::new(schema, stream::iter(rows)))));
+        }
+        let Some((name, value)) = self.session.lock().unwrap().show_parameter(&name) else {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "42704".to_owned(), format!("unrecognized configuration parameter \"{name}\"")))));
+        };
+        let schema = show_schema(name);
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&value)?;
+        Ok(Some(Response::Query(QueryResponse::new(schema, stream::iter(vec![encoder.finish()])))))
+    }
+
+    /// Whether the session is read-only, and whether it has to stay on the main handle (inside a transaction block, or once it's
+    /// created TEMP tables or set PRAGMAs on it) - for the message that sends the statement to the backend
+    fn session_state(&self, statement:&str) -> (bool, bool) {
+        let mut session = self.session.lock().unwrap();
+        session.uses_handle_state |= changes_handle_state(statement);
+        (session.read_only(), session.transaction_status != TransactionStatus::Idle || session.uses_handle_state)
+    }
+
+    /// Replace `lastval()` with the rowid of the last row the session inserted
+    fn replace_lastval<'q>(&self, query:&'q str) -> PgWireResult<Cow<'q, str>> {
+        replace_lastval(query, self.session.lock().unwrap().last_insert_id)
+    }
+
+    fn track_transaction_status(&self, query:&str, result:&PgLiteDBResponse) {
+        let mut session = self.session.lock().unwrap();
+        session.update_transaction_status(result.in_transaction, result.error.is_some());
+        if let Some(last_insert_id) = result.last_insert_id {
+            session.last_insert_id = Some(last_insert_id);
+        }
+        // A transaction block started with a read-only mode of its own, eg. `BEGIN READ ONLY`
+        if let (None, true, Some(Some(read_only))) = (&result.error, result.in_transaction, parse_begin_statement(query)) {
+            session.set_transaction_read_only(read_only);
+        }
+        // Rolling back to a savepoint recovers a failed transaction
+        if result.error.is_none() && result.in_transaction && is_rollback_to_savepoint(query) {
+            session.transaction_status = TransactionStatus::InTransaction;
+        }
+    }
+
+    /// Like Postgres, once a statement has failed inside a transaction block the rest are rejected until the transaction is ended -
+    /// and ending it with COMMIT rolls it back instead (SQLite would commit the statements that succeeded). Returns the statement to run
+    fn check_failed_transaction<'q>(&self, query:&'q str) -> PgWireResult<&'q str> {
+        if self.session.lock().unwrap().transaction_status != TransactionStatus::Failed {
+            return Ok(query);
+        }
+        let command = strip_leading_comments(query).split(|c:char| c.is_whitespace() || c == ';').next().unwrap_or_default().to_uppercase();
+        match command.as_str() {
+            "ROLLBACK" => Ok(query),
+            "COMMIT" | "END" => Ok("ROLLBACK"),
+            _ => Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "25P02".to_owned(),
+                "current transaction is aborted, commands ignored until end of transaction block".to_owned())))),
+        }
+    }
+
+    fn trace_query<C: ClientInfo>(&self, client:&C, query:&str) -> QueryTrace {
+        let database = client.metadata().get(pgwire::api::METADATA_DATABASE).map(String::as_str).unwrap_or_default();
+        QueryTrace::start(&self.connection_id, database, query, self.slow_query_log.clone())
+    }
+
+    fn translate_dbresponse_to_pgwire(&self, query:&str, result:PgLiteDBResponse, formats:&Format, mut trace:QueryTrace) -> PgWireResult<Response<'static>> {
+        // A statement that doesn't return rows (and was described as NoData) only gets its command tag, eg. `UPDATE 3`
+        if let (Some(affected_rows), None) = (result.affected_rows, &result.error) {
+            trace.succeeded(affected_rows);
+            return Ok(Response::Execution(command_tag(query, affected_rows)));
+        }
+        if let Some(res) = result.result {
+            let Some(result_schema) = result.result_schema else {
+                return Err(internal_error("the result has rows but no columns".to_owned()));
+            };
+            let schema = Arc::new(self.translate_schema_to_pgwire(result_schema, formats)?);
+            let settings = {
+                let session = self.session.lock().unwrap();
+                EncodingSettings { date_style: session.date_style, extra_float_digits: session.extra_float_digits, bytea_output: session.bytea_output, time_zone: session.time_zone }
+            };
+
+            // The first batch of records came with the response, any more are streamed from the backend as the client reads them
+            trace.succeeded(0);
+            let first_batch = stream::iter(Some(Ok(res)));
+            let more_batches = stream::unfold(result.more_records, |receiver| async move {
+                let mut receiver = receiver?;
+                receiver.recv().await.map(|batch| (batch, Some(receiver)))
+            });
+            let record_schema = schema.clone();
+            let (max_result_bytes, max_result_rows) = (self.max_result_bytes, self.max_result_rows);
+            let record_stream = first_batch.chain(more_batches)
+                .flat_map(move |batch| stream::iter(match batch {
+                    Ok(records) => encode_records(&record_schema, records, &settings),
+                    Err(err) => vec![Err(err)],
+                }))
+                // Once the result set is over the size (or row) limit the stream ends with an error, which drops the backend's
+                // receiver - so it stops reading any more rows
+                .scan(Some((0, 0)), move |result_size, row| std::future::ready(match result_size.as_mut() {
+                    None => None,
+                    Some(_) if max_result_bytes.is_none() && max_result_rows.is_none() => Some(row),
+                    Some((total_bytes, total_rows)) => {
+                        *total_bytes += row.as_ref().map_or(0, |row| row.fields().iter().flatten().map(|field| field.len()).sum());
+                        *total_rows += 1;
+                        let error = match (max_result_bytes, max_result_rows) {
+                            (Some(max_result_bytes), _) if *total_bytes > max_result_bytes => Some(result_too_large(max_result_bytes)),
+                            (_, Some(max_result_rows)) if *total_rows > max_result_rows => Some(too_many_rows(max_result_rows)),
+                            _ => None,
+                        };
+                        match error {
+                            Some(error) => {
+                                *result_size = None;
+                                Some(Err(error))
+                            },
+                            None => Some(row),
+                        }
+                    }
+                }))
+                // The query's trace is finished when the stream is dropped, once the client has been sent its rows
+                .map(move |row| {
+                    match row.is_ok() {
+                        true => trace.add_row(),
+                        false => trace.failed(),
+                    }
+                    row
+                })
+                .boxed();
+            PgWireResult::Ok(Response::Query(QueryResponse::new(schema, record_stream)))
         } else if let Some(err) = result.error {
             PgWireResult::Err(err)
         } else {
             PgWireResult::Err(PgWireError::UserError(ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), "Unexpected Failure".to_owned()).into()))
         }
     }
-    fn translate_records_to_pgwire(&self, record_schema:Arc<Vec<FieldInfo>>, records:Vec<Record>) -> PgWireResult<Vec<PgWireResult<DataRow>>> {
-        let mut results = Vec::new();
-        let num_cols = record_schema.len();
-        for record in records {
-            let mut encoder = DataRowEncoder::new(record_schema.clone());
-            for col in 0..num_cols {
-                let data = record.values.get(col).unwrap();
-                match data {
-                    Value::Null => encoder.encode_field(&None::<i8>).unwrap(),
-                    Value::Integer(i) => { encoder.encode_field(&i).unwrap(); }
-                    Value::Real(f) => { encoder.encode_field(&f).unwrap(); }
-                    Value::Text(t) => { encoder.encode_field(t).unwrap(); }
-                    Value::Blob(b) => { encoder.encode_field(&b).unwrap(); }
-                }
-            }
-            results.push(encoder.finish());
-        }
-        Ok(results)
-    }
 
-    fn translate_schema_to_pgwire(&self, record_schema:Vec<Field>) -> Vec<FieldInfo> {
-        record_schema.iter().map( | f | f.into()).collect::<Vec<FieldInfo>>()
+    /// The result's columns, in the formats the client asked for in its Bind (all text for a simple query)
+    fn translate_schema_to_pgwire(&self, record_schema:Vec<Field>, formats:&Format) -> PgWireResult<Vec<FieldInfo>> {
+        *self.type_modifiers.lock().unwrap() = record_schema.iter().map(|f| f.type_modifier).collect();
+        with_result_formats(record_schema.iter().map( | f | f.into()).collect::<Vec<FieldInfo>>(), formats)
     }
 
-    fn parse_params(&self, portal: &Portal<String>) -> Vec<PgLiteDBParam> {
+    fn parse_params(&self, portal: &Portal<String>) -> PgWireResult<Vec<BoundParam>> {
         let mut params = Vec::with_capacity(portal.parameter_len());
         for idx in 0..portal.parameter_len() {
             let param = if let Some(param_type) = portal.statement().parameter_types().get(idx) {
@@ -174,11 +844,11 @@ impl PgQueryProcessor {
                         PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
                     },
                     &Type::INT8 => {
-                        let value = portal.parameter::<i64>(idx, param_type).unwrap().map_or(Value::Null, |v| Value::Integer(v.into())); 
+                        let value = portal.parameter::<i64>(idx, param_type).unwrap().map_or(Value::Null, Value::Integer); 
                         PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
                     },
                     &Type::TEXT | &Type::VARCHAR => {
-                        let value = portal.parameter::<String>(idx, param_type).unwrap().map_or(Value::Null, |v| Value::Text(v));
+                        let value = portal.parameter::<String>(idx, param_type).unwrap().map_or(Value::Null, Value::Text);
                         PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value }
                     },
                     &Type::FLOAT4  => {
@@ -186,22 +856,646 @@ impl PgQueryProcessor {
                         PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
                     },
                     &Type::FLOAT8  => {
-                        let value = portal.parameter::<f64>(idx, param_type).unwrap().map_or(Value::Null, |v| Value::Real(v.into())); 
+                        let value = portal.parameter::<f64>(idx, param_type).unwrap().map_or(Value::Null, Value::Real); 
                         PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
                     },
                     &Type::BYTEA  => {
-                        let value = portal.parameter::<Vec<u8>>(idx, param_type).unwrap().map_or(Value::Null, |v| Value::Blob(v.into()));
+                        let value = portal.parameter::<Vec<u8>>(idx, param_type).unwrap().map_or(Value::Null, Value::Blob);
+                        PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
+                    },
+                    &Type::NUMERIC => {
+                        let value = numeric::decode_numeric_param(portal, idx)?.unwrap_or(Value::Null);
+                        PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
+                    },
+                    &Type::UUID => {
+                        let value = uuid_values::decode_uuid_param(portal, idx, self.uuid_storage)?.unwrap_or(Value::Null);
+                        PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
+                    },
+                    &Type::DATE | &Type::TIME | &Type::TIMESTAMP => {
+                        let value = parse_datetime_param(portal, idx, param_type)?.map_or(Value::Null, Value::Text);
                         PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
                     },
-                    _ => {
-                        unimplemented!("This parameter type is not currently supported")
+                    &Type::JSON | &Type::JSONB => {
+                        let value = parse_json_param(portal, idx, param_type)?.map_or(Value::Null, Value::Text);
+                        PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
+                    },
+                    &Type::TIMESTAMPTZ => {
+                        let value = self.parse_timestamptz_param(portal, idx)?.map_or(Value::Null, |utc| Value::Text(format_utc_timestamp(&utc)));
+                        PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value}
+                    },
+                    param_type if array_params::is_array_type(param_type) => {
+                        match array_params::decode_array(portal, idx, param_type)? {
+                            Some(values) => {
+                                params.push(BoundParam::Array(values));
+                                continue;
+                            },
+                            None => PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value:Value::Null }
+                        }
+                    },
+                    param_type => {
+                        return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "0A000".to_owned(),
+                            format!("unsupported parameter type oid={} ({}) for parameter ${}", param_type.oid(), param_type.name(), idx + 1)))));
                     }
                 }
             } else {
                 PgLiteDBParam{ name:None, ordinal:Some(idx), param_type:None, value:Value::Null  }
             };
-            params.push(param);
+            params.push(BoundParam::Value(param));
+        }
+        Ok(params)
+    }
+
+    /// Timestamptz params are converted to UTC, for storage - text values without an offset are in the session's time zone
+    fn parse_timestamptz_param(&self, portal: &Portal<String>, idx:usize) -> PgWireResult<Option<NaiveDateTime>> {
+        if portal.parameter_format().is_text(idx) {
+            let Some(Some(bytes)) = portal.parameters().get(idx) else { return Ok(None) };
+            let text = String::from_utf8_lossy(bytes);
+            let time_zone = self.session.lock().unwrap().time_zone;
+            return time_zone.parse_timestamptz(&text).map(Some).ok_or_else(|| {
+                PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22007".to_owned(),
+                    format!("invalid input syntax for type timestamp with time zone: \"{text}\""))))
+            });
+        }
+        Ok(portal.parameter::<DateTime<Utc>>(idx, &Type::TIMESTAMPTZ)?.map(|ts| ts.naive_utc()))
+    }
+}
+
+/// Date, time + timestamp params are stored as ISO-8601 text (the form SQLite's date + time functions use), eg. `2024-01-02`,
+/// `03:04:05.6` or `2024-01-02 03:04:05.6`
+fn parse_datetime_param(portal: &Portal<String>, idx:usize, pg_type:&Type) -> PgWireResult<Option<String>> {
+    if portal.parameter_format().is_text(idx) {
+        let Some(Some(bytes)) = portal.parameters().get(idx) else { return Ok(None) };
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim();
+        let value = match *pg_type {
+            Type::DATE => NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().map(|d| d.format("%Y-%m-%d").to_string()),
+            Type::TIME => parse_time(text).map(|t| format_time(&t)),
+            _ => parse_timestamp(text).map(|ts| format_utc_timestamp(&ts)),
+        };
+        return value.map(Some).ok_or_else(|| {
+            PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22007".to_owned(),
+                format!("invalid input syntax for type {}: \"{text}\"", pg_type.name()))))
+        });
+    }
+    let value = match *pg_type {
+        Type::DATE => portal.parameter::<NaiveDate>(idx, pg_type)?.map(|d| d.format("%Y-%m-%d").to_string()),
+        Type::TIME => portal.parameter::<NaiveTime>(idx, pg_type)?.map(|t| format_time(&t)),
+        _ => portal.parameter::<NaiveDateTime>(idx, pg_type)?.map(|ts| format_utc_timestamp(&ts)),
+    };
+    Ok(value)
+}
+
+/// json + jsonb params are stored as their text, once it's been checked to be valid JSON - the binary format of jsonb is a version
+/// number (1), followed by the text
+fn parse_json_param(portal: &Portal<String>, idx:usize, pg_type:&Type) -> PgWireResult<Option<String>> {
+    let Some(Some(bytes)) = portal.parameters().get(idx) else { return Ok(None) };
+    let mut bytes = &bytes[..];
+    if *pg_type == Type::JSONB && !portal.parameter_format().is_text(idx) {
+        match bytes.split_first() {
+            Some((1, json)) => bytes = json,
+            version => return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22P02".to_owned(),
+                format!("unsupported jsonb version number {}", version.map_or(0, |(version, _)| *version)))))),
         }
-        params
     }
-}
\ No newline at end of file
+    match std::str::from_utf8(bytes) {
+        Ok(text) if serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok() => Ok(Some(text.to_owned())),
+        _ => Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22P02".to_owned(),
+            format!("invalid input syntax for type {}: \"{}\"", pg_type.name(), String::from_utf8_lossy(bytes)))))),
+    }
+}
+
+/// A `BEGIN` or `START TRANSACTION` with transaction modes (eg. `BEGIN READ ONLY`) is run as a plain `BEGIN` - the read-only mode is
+/// applied by the session once the transaction has started, SQLite has no other modes to apply
+fn begin_statement(query:&str) -> &str {
+    match parse_begin_statement(query) {
+        Some(_) => "BEGIN",
+        None => query,
+    }
+}
+
+/// Split a simple query into its statements, ignoring the semicolons in strings (including dollar-quoted ones), quoted identifiers,
+/// comments + the body of a trigger - any statements that are empty (or just comments) are left out
+fn split_statements(query:&str) -> Vec<&str> {
+    let tokens = tokenize(query);
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut first_token = None;    // The first token of the current statement
+    let mut block_depth = 0;    // The BEGIN/CASE ... END nesting in the body of a CREATE TRIGGER
+    for (idx, token) in tokens.iter().enumerate() {
+        if token.text == ";" {
+            if block_depth == 0 {
+                if first_token.is_some() {
+                    statements.push(query[start..token.start].trim());
+                }
+                start = token.end();
+                first_token = None;
+            }
+            continue;
+        }
+        let first = *first_token.get_or_insert(idx);
+        // Only the body of a trigger can contain semicolons
+        if !is_create_trigger(&tokens[first..idx]) {
+            continue;
+        }
+        if token.text.eq_ignore_ascii_case("BEGIN") || token.text.eq_ignore_ascii_case("CASE") {
+            block_depth += 1;
+        } else if token.text.eq_ignore_ascii_case("END") {
+            block_depth = (block_depth - 1).max(0);
+        }
+    }
+    if first_token.is_some() {
+        statements.push(query[start..].trim());
+    }
+    statements
+}
+
+/// Whether the tokens start a `CREATE [TEMP] TRIGGER`
+fn is_create_trigger(tokens:&[Token]) -> bool {
+    let is = |idx:usize, word:&str| tokens.get(idx).is_some_and(|token| token.text.eq_ignore_ascii_case(word));
+    let temp = is(1, "TEMP") || is(1, "TEMPORARY");
+    is(0, "CREATE") && is(if temp { 2 } else { 1 }, "TRIGGER")
+}
+
+/// The CommandComplete tag for a statement that doesn't return rows, eg. `INSERT 0 5` or `CREATE TABLE`
+fn command_tag(query:&str, affected_rows:usize) -> Tag {
+    let mut words = strip_leading_comments(query).split_whitespace().map(|w| w.to_uppercase());
+    let command = words.next().unwrap_or_default();
+    match command.as_str() {
+        "INSERT" => Tag::new_for_execution("INSERT 0", Some(affected_rows)),
+        "UPDATE" | "DELETE" => Tag::new_for_execution(&command, Some(affected_rows)),
+        "START" => Tag::new_for_execution("START TRANSACTION", None),
+        "CREATE" | "DROP" | "ALTER" => {
+            // Include the object type, skipping any modifiers (eg. `CREATE TEMP TABLE` => `CREATE TABLE`)
+            let object = words.find(|w| !matches!(w.as_str(), "TEMP" | "TEMPORARY" | "UNIQUE" | "VIRTUAL" | "OR" | "REPLACE")).unwrap_or_default();
+            Tag::new_for_execution(&format!("{command} {object}"), None)
+        },
+        _ => Tag::new_for_execution(&command, None),
+    }
+}
+
+/// Whether the statement is a `ROLLBACK [TRANSACTION] TO [SAVEPOINT] name`
+fn is_rollback_to_savepoint(query:&str) -> bool {
+    let words = strip_leading_comments(query).split_whitespace().take(3).map(str::to_uppercase).collect::<Vec<String>>();
+    matches!(words.iter().map(String::as_str).collect::<Vec<&str>>()[..], ["ROLLBACK", "TO", ..] | ["ROLLBACK", "TRANSACTION", "TO"])
+}
+
+/// The CommandComplete tag for a statement that returned rows - `SELECT n`, unless it's an `INSERT/UPDATE/DELETE ... RETURNING`
+fn rows_tag(query:&str, rows:usize) -> Tag {
+    let command = strip_leading_comments(query).split_whitespace().next().unwrap_or_default();
+    match ["INSERT", "UPDATE", "DELETE"].iter().any(|dml| command.eq_ignore_ascii_case(dml)) {
+        true => command_tag(query, rows),
+        false => Tag::new_for_query(rows),
+    }
+}
+
+/// Same as pgwire's `send_query_response`, but the rows are finished with the statement's own command tag
+async fn send_query_rows<C>(client:&mut C, query:&str, results:QueryResponse<'_>, type_modifiers:Option<&[i32]>) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    if let Some(type_modifiers) = type_modifiers {
+        client.send(PgWireBackendMessage::RowDescription(row_description(&results.row_schema(), type_modifiers))).await?;
+    }
+    let mut rows = 0;
+    let mut data_rows = results.data_rows();
+    while let Some(row) = data_rows.next().await {
+        client.send(PgWireBackendMessage::DataRow(row?)).await?;
+        rows += 1;
+    }
+    client.send(PgWireBackendMessage::CommandComplete(rows_tag(query, rows).into())).await?;
+    Ok(())
+}
+
+/// Same as pgwire's RowDescription of the columns, but with their type modifiers (when they were described with them) - and like
+/// Postgres, -1 rather than 0 for a column that has no modifier
+fn row_description(fields:&[FieldInfo], type_modifiers:&[i32]) -> RowDescription {
+    let type_modifiers = match type_modifiers.len() == fields.len() {
+        true => type_modifiers,
+        false => &[],
+    };
+    RowDescription::new(fields.iter().enumerate()
+        .map(|(idx, field)| {
+            let mut description = FieldDescription::from(field);
+            description.set_type_modifier(type_modifiers.get(idx).copied().unwrap_or(-1));
+            description
+        })
+        .collect())
+}
+
+/// Send a portal's rows, finished with the statement's command tag - or with a row limit (`max_rows` > 0) stop once that many rows
+/// have been sent, suspending the portal. The rest of the rows are returned, for the next Execute of the portal
+async fn send_portal_rows<C>(client:&mut C, query:&str, mut rows:RowStream, max_rows:usize) -> PgWireResult<Option<RowStream>>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let mut sent = 0;
+    while max_rows == 0 || sent < max_rows {
+        let Some(row) = rows.next().await else {
+            client.send(PgWireBackendMessage::CommandComplete(rows_tag(query, sent).into())).await?;
+            return Ok(None);
+        };
+        client.send(PgWireBackendMessage::DataRow(row?)).await?;
+        sent += 1;
+    }
+    client.send(PgWireBackendMessage::PortalSuspended(PortalSuspended)).await?;
+    Ok(Some(rows))
+}
+
+/// The result of `SHOW`, a single text column named after the parameter
+/// Set the format of each column from a Bind's result format codes - there can be none (all text), a single one for all of the
+/// columns, or one for each column
+fn with_result_formats(fields:Vec<FieldInfo>, formats:&Format) -> PgWireResult<Vec<FieldInfo>> {
+    if let Format::Individual(codes) = formats {
+        if codes.len() != fields.len() {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "08P01".to_owned(),
+                format!("bind message has {} result formats but query has {} columns", codes.len(), fields.len())))));
+        }
+    }
+    Ok(fields.into_iter().enumerate()
+        .map(|(idx, field)| FieldInfo::new(field.name().clone(), *field.table_id(), *field.column_id(), field.datatype().clone(), formats.format_for(idx)))
+        .collect())
+}
+
+fn show_schema(name:&str) -> Arc<Vec<FieldInfo>> {
+    Arc::new(vec![FieldInfo::new(name.to_owned(), None, None, Type::TEXT, FieldFormat::Text)])
+}
+
+/// The columns of `SHOW ALL`, which are the same as Postgres's (there are no descriptions of the parameters)
+fn show_all_schema() -> Arc<Vec<FieldInfo>> {
+    Arc::new(["name", "setting", "description"].into_iter()
+        .map(|name| FieldInfo::new(name.to_owned(), None, None, Type::TEXT, FieldFormat::Text))
+        .collect())
+}
+
+/// The session settings that affect how values are encoded, captured when a query's results start being sent
+struct EncodingSettings {
+    date_style: DateStyle,
+    extra_float_digits: i32,
+    bytea_output: ByteaOutput,
+    time_zone: TimeZone,
+}
+
+fn encode_records(record_schema:&Arc<Vec<FieldInfo>>, records:Vec<Record>, settings:&EncodingSettings) -> Vec<PgWireResult<DataRow>> {
+    records.into_iter().map(|record| encode_record(record_schema, record, settings)).collect()
+}
+
+fn encode_record(record_schema:&Arc<Vec<FieldInfo>>, record:Record, settings:&EncodingSettings) -> PgWireResult<DataRow> {
+    // A row that doesn't match its columns would be sent as a malformed DataRow, so it fails the query instead
+    if record.values.len() != record_schema.len() {
+        return Err(internal_error(format!("a row has {} values, but the result has {} columns", record.values.len(), record_schema.len())));
+    }
+    let mut encoder = DataRowEncoder::new(record_schema.clone());
+    for (field, value) in record_schema.iter().zip(&record.values) {
+        match field.format() {
+            FieldFormat::Text => encode_text_value(&mut encoder, value, field.datatype(), settings)?,
+            FieldFormat::Binary => encode_binary_value(&mut encoder, value, field.datatype(), settings)?,
+        }
+    }
+    encoder.finish()
+}
+
+/// In the text format each value is sent as the text of whatever it's stored as - SQLite lets any column hold any type of value
+/// (eg. a NUMERIC column can have integers, reals + text in different rows), so it's not always the column's type
+fn encode_text_value(encoder:&mut DataRowEncoder, value:&Value, pg_type:&Type, settings:&EncodingSettings) -> PgWireResult<()> {
+    match value {
+        Value::Null => encoder.encode_field(&None::<i8>),
+        // A timestamptz stored as an integer is a unix time (eg. from `unixepoch()`)
+        Value::Integer(i) if *pg_type == Type::TIMESTAMPTZ => match DateTime::from_timestamp(*i, 0) {
+            Some(utc) => encoder.encode_field(&settings.date_style.format_timestamptz(&utc.naive_utc(), &settings.time_zone)),
+            None => encoder.encode_field(i),
+        },
+        // Dates + times stored as numbers are converted the way SQLite's date + time functions take them
+        Value::Integer(_) | Value::Real(_) if matches!(*pg_type, Type::DATE | Type::TIME | Type::TIMESTAMP) => match numeric_datetime(value) {
+            Some(ts) => encoder.encode_field(&format_datetime_value(&ts, pg_type, settings)),
+            None => encoder.encode_field(&text_value(value, settings)),
+        },
+        Value::Integer(i) if *pg_type == Type::INT4 => encoder.encode_field(&i32::try_from(*i).map_err(|_| out_of_range(pg_type))?),
+        // Booleans are stored as 0/1 integers, and sent as `t`/`f`
+        Value::Integer(i) if *pg_type == Type::BOOL => encoder.encode_field(&if *i != 0 { "t" } else { "f" }),
+        Value::Integer(i) if *pg_type == Type::INT2 => encoder.encode_field(&i16::try_from(*i).map_err(|_| out_of_range(pg_type))?),
+        Value::Integer(i) => encoder.encode_field(i),
+        Value::Real(f) if *pg_type == Type::NUMERIC => encoder.encode_field(&numeric::format_numeric(*f)),
+        Value::Real(f) => encoder.encode_field(&format_float(*f, settings.extra_float_digits)),
+        // UUIDs are sent in their canonical form, whether they're stored as text (in any form) or as 16 byte blobs
+        Value::Text(_) | Value::Blob(_) if *pg_type == Type::UUID => match uuid_values::uuid_value(value) {
+            Some(uuid) => encoder.encode_field(&uuid.hyphenated().to_string()),
+            None => encoder.encode_field(&text_value(value, settings)),
+        },
+        // Arrays are stored as JSON arrays (see `array_params`), and sent in the Postgres array format (eg. `{1,2,3}`)
+        Value::Text(t) if array_params::is_array_column_type(pg_type) => match array_params::format_text_array(t) {
+            Some(formatted) => encoder.encode_field(&formatted),
+            None => encoder.encode_field(t),
+        },
+        Value::Text(t) => match format_datetime(t, pg_type, settings) {
+            Some(formatted) => encoder.encode_field(&formatted),
+            None => encoder.encode_field(t),
+        },
+        Value::Blob(b) => encoder.encode_field(&format_bytea(b, settings.bytea_output)),
+    }
+}
+
+/// In the binary format a value has to be sent as the column's type, so values stored as another type are converted to it - or
+/// rejected, when they can't be converted without losing data (eg. `1.5` or `'abc'` in an INT8 column)
+fn encode_binary_value(encoder:&mut DataRowEncoder, value:&Value, pg_type:&Type, settings:&EncodingSettings) -> PgWireResult<()> {
+    if let Value::Null = value {
+        return encoder.encode_field(&None::<i8>);
+    }
+    match *pg_type {
+        Type::INT2 => encoder.encode_field(&i16::try_from(integer_value(value, pg_type, settings)?).map_err(|_| out_of_range(pg_type))?),
+        Type::INT4 => encoder.encode_field(&i32::try_from(integer_value(value, pg_type, settings)?).map_err(|_| out_of_range(pg_type))?),
+        Type::INT8 => encoder.encode_field(&integer_value(value, pg_type, settings)?),
+        Type::FLOAT4 => encoder.encode_field(&(float_value(value, pg_type, settings)? as f32)),
+        Type::FLOAT8 => encoder.encode_field(&float_value(value, pg_type, settings)?),
+        Type::BOOL => encoder.encode_field(&bool_value(value, pg_type, settings)?),
+        Type::NUMERIC => {
+            let text = match value {
+                Value::Real(f) => numeric::format_numeric(*f),
+                _ => text_value(value, settings),
+            };
+            // Text in another notation (eg. `1e5`) is converted through a real
+            let binary = numeric::encode_binary(&text)
+                .or_else(|| text.trim().parse::<f64>().ok().and_then(|f| numeric::encode_binary(&numeric::format_numeric(f))));
+            encoder.encode_field(&binary.ok_or_else(|| invalid_value(&text, pg_type))?)
+        },
+        Type::UUID => {
+            let uuid = uuid_values::uuid_value(value).ok_or_else(|| invalid_value(&text_value(value, settings), pg_type))?;
+            encoder.encode_field(&uuid.as_bytes().to_vec())
+        },
+        Type::OID => encoder.encode_field(&u32::try_from(integer_value(value, pg_type, settings)?).map_err(|_| out_of_range(pg_type))?),
+        Type::DATE => encoder.encode_field(&datetime_value(value, pg_type, settings)?.date()),
+        Type::TIME => encoder.encode_field(&datetime_value(value, pg_type, settings)?.time()),
+        Type::TIMESTAMP => encoder.encode_field(&datetime_value(value, pg_type, settings)?),
+        Type::TIMESTAMPTZ => encoder.encode_field(&datetime_value(value, pg_type, settings)?.and_utc()),
+        // jsonb's binary format is a version number, followed by the json text
+        Type::JSONB => {
+            let mut bytes = vec![1];
+            bytes.extend(text_value(value, settings).into_bytes());
+            encoder.encode_field(&bytes)
+        },
+        Type::INT8_ARRAY | Type::FLOAT8_ARRAY | Type::TEXT_ARRAY => {
+            encoder.encode_field(&array_params::encode_binary_array(&text_value(value, settings), pg_type)?)
+        },
+        // Blobs are sent as they are, for bytea columns + any others (eg. a blob in a text column) - the binary format of the text
+        // types is just their text
+        Type::BYTEA | Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::CHAR | Type::JSON | Type::XML | Type::UNKNOWN => match value {
+            Value::Blob(b) => encoder.encode_field(b),
+            _ => encoder.encode_field(&text_value(value, settings)),
+        },
+        _ => Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "0A000".to_owned(),
+            format!("the binary format isn't supported for type {}, request the column in the text format", pg_type.name()))))),
+    }
+}
+
+/// A date or time as a timestamp, for the binary format - stored as ISO-8601 text, or as a number (see `numeric_datetime`)
+fn datetime_value(value:&Value, pg_type:&Type, settings:&EncodingSettings) -> PgWireResult<NaiveDateTime> {
+    let timestamp = match (value, pg_type) {
+        (Value::Text(t), &Type::DATE) => NaiveDate::parse_from_str(t, "%Y-%m-%d").ok().map(|date| date.and_time(NaiveTime::MIN)),
+        (Value::Text(t), &Type::TIME) => parse_time(t).map(|time| DateTime::UNIX_EPOCH.date_naive().and_time(time)),
+        (Value::Text(t), &Type::TIMESTAMPTZ) => TimeZone::default().parse_timestamptz(t),
+        (Value::Text(t), _) => parse_timestamp(t),
+        // A timestamptz stored as a number can only be a unix time
+        (Value::Real(_), &Type::TIMESTAMPTZ) => None,
+        _ => numeric_datetime(value),
+    };
+    timestamp.ok_or_else(|| invalid_value(&text_value(value, settings), pg_type))
+}
+
+fn integer_value(value:&Value, pg_type:&Type, settings:&EncodingSettings) -> PgWireResult<i64> {
+    match value {
+        Value::Integer(i) => Ok(*i),
+        Value::Real(f) if f.fract() == 0.0 && *f >= i64::MIN as f64 && *f < i64::MAX as f64 => Ok(*f as i64),
+        Value::Text(t) => t.trim().parse().map_err(|_| invalid_value(t, pg_type)),
+        _ => Err(invalid_value(&text_value(value, settings), pg_type)),
+    }
+}
+
+fn float_value(value:&Value, pg_type:&Type, settings:&EncodingSettings) -> PgWireResult<f64> {
+    match value {
+        Value::Integer(i) => Ok(*i as f64),
+        Value::Real(f) => Ok(*f),
+        Value::Text(t) => t.trim().parse().map_err(|_| invalid_value(t, pg_type)),
+        _ => Err(invalid_value(&text_value(value, settings), pg_type)),
+    }
+}
+
+fn bool_value(value:&Value, pg_type:&Type, settings:&EncodingSettings) -> PgWireResult<bool> {
+    match value {
+        Value::Integer(i) => Ok(*i != 0),
+        Value::Real(f) => Ok(*f != 0.0),
+        Value::Text(t) => match t.trim().to_lowercase().as_str() {
+            "t" | "true" | "y" | "yes" | "on" | "1" => Ok(true),
+            "f" | "false" | "n" | "no" | "off" | "0" => Ok(false),
+            _ => Err(invalid_value(t, pg_type)),
+        },
+        _ => Err(invalid_value(&text_value(value, settings), pg_type)),
+    }
+}
+
+/// A value as text, the way it's sent in the text format
+fn text_value(value:&Value, settings:&EncodingSettings) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => format_float(*f, settings.extra_float_digits),
+        Value::Text(t) => t.clone(),
+        Value::Blob(b) => format_bytea(b, settings.bytea_output),
+    }
+}
+
+fn invalid_value(value:&str, pg_type:&Type) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22P02".to_owned(),
+        format!("invalid input syntax for type {}: \"{}\"", pg_type.name(), value))))
+}
+
+fn internal_error(message:String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), message)))
+}
+
+fn out_of_range(pg_type:&Type) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22003".to_owned(), format!("value out of range for type {}", pg_type.name()))))
+}
+
+fn result_too_large(max_result_bytes:usize) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "54000".to_owned(),
+        format!("The result set is too large, the maximum allowed is {max_result_bytes} bytes (--max-result-bytes)"),
+    )))
+}
+
+fn too_many_rows(max_result_rows:usize) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "54000".to_owned(),
+        format!("The result set is too large, the maximum allowed is {max_result_rows} rows (--max-result-rows)"),
+    )))
+}
+
+/// Dates + timestamps are stored as ISO-8601 text in SQLite, so re-format them to match the client's DateStyle - timestamptz values
+/// are stored in UTC (any without an offset are taken to be UTC), and shown in the client's TimeZone
+fn format_datetime(value:&str, pg_type:&Type, settings:&EncodingSettings) -> Option<String> {
+    match *pg_type {
+        Type::DATE => NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().map(|d| settings.date_style.format_date(&d)),
+        Type::TIME => parse_time(value).map(|t| format_time(&t)),
+        Type::TIMESTAMP => parse_timestamp(value).map(|ts| settings.date_style.format_timestamp(&ts)),
+        Type::TIMESTAMPTZ => TimeZone::default().parse_timestamptz(value).map(|utc| settings.date_style.format_timestamptz(&utc, &settings.time_zone)),
+        _ => None,
+    }
+}
+
+/// A date or time stored as a number - an integer is a unix time (eg. from `unixepoch()`), a real is a Julian day number (eg. from
+/// `julianday()`)
+fn numeric_datetime(value:&Value) -> Option<NaiveDateTime> {
+    match value {
+        Value::Integer(i) => DateTime::from_timestamp(*i, 0).map(|utc| utc.naive_utc()),
+        Value::Real(f) if f.is_finite() => {
+            let millis = ((f - UNIX_EPOCH_JULIAN_DAY) * 86_400_000.0).round();
+            match millis.abs() < i64::MAX as f64 {
+                true => DateTime::from_timestamp_millis(millis as i64).map(|utc| utc.naive_utc()),
+                false => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// The Julian day number of 1970-01-01 00:00:00
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+
+fn format_datetime_value(timestamp:&NaiveDateTime, pg_type:&Type, settings:&EncodingSettings) -> String {
+    match *pg_type {
+        Type::DATE => settings.date_style.format_date(&timestamp.date()),
+        Type::TIME => format_time(&timestamp.time()),
+        _ => settings.date_style.format_timestamp(timestamp),
+    }
+}
+
+/// Format a blob in the client's `bytea_output` format: hex (`\x0041ff`), or escape - where the printable ascii characters are
+/// output as they are, a backslash is doubled + any other byte is output as a backslash followed by its 3 digit octal value
+fn format_bytea(value:&[u8], bytea_output:ByteaOutput) -> String {
+    match bytea_output {
+        ByteaOutput::Hex => value.iter().fold(String::from("\\x"), |mut hex, b| {
+            hex.push_str(&format!("{b:02x}"));
+            hex
+        }),
+        ByteaOutput::Escape => value.iter().fold(String::with_capacity(value.len()), |mut escaped, b| {
+            match b {
+                b'\\' => escaped.push_str("\\\\"),
+                0x20..=0x7e => escaped.push(*b as char),
+                _ => escaped.push_str(&format!("\\{b:03o}")),
+            }
+            escaped
+        }),
+    }
+}
+
+/// Format a float the way Postgres does: the shortest round-trippable representation when `extra_float_digits` > 0,
+/// otherwise with `15 + extra_float_digits` significant digits (like `%g`)
+fn format_float(value:f64, extra_float_digits:i32) -> String {
+    if value.is_nan() {
+        return "NaN".to_owned();
+    } else if value.is_infinite() {
+        return if value > 0.0 { "Infinity".to_owned() } else { "-Infinity".to_owned() };
+    }
+
+    let (precision, scientific) = match extra_float_digits > 0 {
+        true => (17, format!("{value:e}")),
+        false => {
+            let precision = (15 + extra_float_digits).max(1) as usize;
+            (precision, format!("{:.*e}", precision - 1, value))
+        }
+    };
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap();
+    if exponent < -4 || exponent >= precision.min(15) as i32 {
+        let mantissa = match mantissa.contains('.') {
+            true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+            false => mantissa,
+        };
+        format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+    } else if extra_float_digits > 0 {
+        value.to_string()
+    } else {
+        let decimals = (precision as i32 - 1 - exponent).max(0) as usize;
+        let fixed = format!("{value:.decimals$}");
+        match fixed.contains('.') {
+            true => fixed.trim_end_matches('0').trim_end_matches('.').to_owned(),
+            false => fixed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_statements() {
+        assert_eq!(split_statements("SELECT 1; SELECT 2;"), ["SELECT 1", "SELECT 2"]);
+        assert_eq!(split_statements("SELECT 1;;  ; -- just a comment\n"), ["SELECT 1"]);
+        assert_eq!(split_statements("-- first\nSELECT 1"), ["-- first\nSELECT 1"]);
+        assert!(split_statements("  ").is_empty());
+    }
+
+    #[test]
+    fn ignores_semicolons_in_strings_and_comments() {
+        assert_eq!(split_statements("SELECT 'a;b', \"c;d\"; SELECT 2"), ["SELECT 'a;b', \"c;d\"", "SELECT 2"]);
+        assert_eq!(split_statements("SELECT 'it''s; ok'; SELECT E'\\';'"), ["SELECT 'it''s; ok'", "SELECT E'\\';'"]);
+        assert_eq!(split_statements("SELECT 1 /* ; */ -- ;\n; SELECT 2"), ["SELECT 1 /* ; */ -- ;", "SELECT 2"]);
+        assert_eq!(split_statements("SELECT $$a;b$$; SELECT $body$ ; $body$"), ["SELECT $$a;b$$", "SELECT $body$ ; $body$"]);
+    }
+
+    #[test]
+    fn keeps_trigger_bodies_together() {
+        let trigger = "CREATE TEMP TRIGGER t AFTER INSERT ON x BEGIN UPDATE y SET n = CASE WHEN 1 THEN 2 END; DELETE FROM z; END";
+        assert_eq!(split_statements(&format!("{trigger}; SELECT 1")), [trigger, "SELECT 1"]);
+    }
+
+    #[test]
+    fn tags_statements_without_rows() {
+        assert_eq!(command_tag("insert into t values (1), (2)", 2), Tag::new_for_execution("INSERT 0", Some(2)));
+        assert_eq!(command_tag("UPDATE t SET n = 1", 3), Tag::new_for_execution("UPDATE", Some(3)));
+        assert_eq!(command_tag("DELETE FROM t", 0), Tag::new_for_execution("DELETE", Some(0)));
+        assert_eq!(command_tag("CREATE TEMP TABLE t (n)", 0), Tag::new_for_execution("CREATE TABLE", None));
+        assert_eq!(command_tag("CREATE UNIQUE INDEX i ON t (n)", 0), Tag::new_for_execution("CREATE INDEX", None));
+        assert_eq!(command_tag("drop view v", 0), Tag::new_for_execution("DROP VIEW", None));
+        assert_eq!(command_tag("START TRANSACTION", 0), Tag::new_for_execution("START TRANSACTION", None));
+        assert_eq!(command_tag("BEGIN", 0), Tag::new_for_execution("BEGIN", None));
+    }
+
+    #[test]
+    fn tags_returning_statements_by_their_command() {
+        assert_eq!(rows_tag("INSERT INTO t (n) VALUES (1) RETURNING id", 1), Tag::new_for_execution("INSERT 0", Some(1)));
+        assert_eq!(rows_tag("update t set n = 2 returning *", 4), Tag::new_for_execution("UPDATE", Some(4)));
+        assert_eq!(rows_tag("DELETE FROM t RETURNING n", 0), Tag::new_for_execution("DELETE", Some(0)));
+        assert_eq!(rows_tag("SELECT * FROM t", 5), Tag::new_for_query(5));
+    }
+
+    #[test]
+    fn tags_statements_after_leading_comments() {
+        assert_eq!(command_tag("-- add a row\nINSERT INTO t VALUES (1)", 1), Tag::new_for_execution("INSERT 0", Some(1)));
+        assert_eq!(command_tag("/* new */ CREATE TABLE t (n)", 0), Tag::new_for_execution("CREATE TABLE", None));
+        assert_eq!(rows_tag("/* ids */ DELETE FROM t RETURNING id", 2), Tag::new_for_execution("DELETE", Some(2)));
+        assert!(is_rollback_to_savepoint("-- undo\nROLLBACK TO SAVEPOINT s"));
+    }
+
+    #[test]
+    fn tags_other_statements_with_rows_as_selects() {
+        assert_eq!(rows_tag("WITH x AS (SELECT 1) SELECT * FROM x", 1), Tag::new_for_query(1));
+        assert_eq!(rows_tag("VALUES (1), (2)", 2), Tag::new_for_query(2));
+        assert_eq!(rows_tag("PRAGMA table_info(t)", 3), Tag::new_for_query(3));
+        assert_eq!(rows_tag("-- insert a row\nSELECT 1", 1), Tag::new_for_query(1));
+    }
+
+    #[test]
+    fn formats_bytea_in_the_clients_output_format() {
+        assert_eq!(format_bytea(&[0x00, 0x41, 0xff], ByteaOutput::Hex), "\\x0041ff");
+        assert_eq!(format_bytea(&[], ByteaOutput::Hex), "\\x");
+        assert_eq!(format_bytea(b"a\\b '~", ByteaOutput::Escape), "a\\\\b '~");
+        assert_eq!(format_bytea(&[0x00, b'\n', 0x7f, 0xff], ByteaOutput::Escape), "\\000\\012\\177\\377");
+    }
+}
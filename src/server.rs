@@ -1,62 +1,418 @@
-use std::{sync::{Arc, Mutex}, collections::HashMap};
-use pgwire::api::{auth::ServerParameterProvider, ClientInfo};
-use tokio::{net::TcpListener, task::JoinHandle};
+use std::{sync::{Arc, Mutex}, collections::HashMap, fmt::Debug, io::ErrorKind, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, time::Duration};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use futures::{stream, Sink, SinkExt};
+use pgwire::{api::{auth::ServerParameterProvider, ClientInfo, PgWireConnectionState, METADATA_USER}, error::{ErrorInfo, PgWireError, PgWireResult}};
+use pgwire::messages::{PgWireBackendMessage, response::{ReadyForQuery, READY_STATUS_IDLE}, startup::{Authentication, BackendKeyData, ParameterStatus}};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpListener, TcpStream}, sync::{mpsc, watch, Semaphore}, task::JoinHandle};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::TlsAcceptor;
 
-use crate::{config::PgLiteConfig, backend::PgLitebackendFactory, auth::PgLiteAuthenticator, connection::PgLiteConnection};
+use crate::{cancel::{CancelRegistry, METADATA_BACKEND_KEY}, health::{self, AcceptLoopStatus}, config::{MaxConnectionsMode, PgLiteConfig}, backend::PgLitebackendFactory, auth::PgLiteAuthenticator, connection::PgLiteConnection, session::PgLiteSession, statement_log::StatementLogs, tls::load_tls_acceptor};
+
+/// How long the server waits before accepting connections again, after running out of a resource it needs to accept them
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 pub struct PgLiteServerParameterProvider;
 
 impl ServerParameterProvider for PgLiteServerParameterProvider {
-    fn server_parameters<C>(&self, _client: &C) -> Option<HashMap<String, String>>
+    fn server_parameters<C>(&self, client: &C) -> Option<HashMap<String, String>>
     where
         C: ClientInfo,
     {
-        let mut params = HashMap::with_capacity(4);
-        params.insert("server_version".to_owned(), env!("CARGO_PKG_VERSION").to_owned());
-        params.insert("server_encoding".to_owned(), "UTF8".to_owned());
-        params.insert("client_encoding".to_owned(), "UTF8".to_owned());
-        params.insert("DateStyle".to_owned(), "ISO YMD".to_owned());
+        // The authenticator can give the user (or database) its own defaults, which override the session's
+        let mut session = PgLiteSession::new();
+        let parameters = session.apply_client_parameters(client.metadata());
+        let mut params = session.reported_parameters().into_iter().map(|(name, value)| (name.to_owned(), value)).collect::<HashMap<String, String>>();
+        for parameter in parameters {
+            match parameter {
+                Ok((name, value)) => {
+                    params.retain(|default_name, _| !default_name.eq_ignore_ascii_case(&name));
+                    params.insert(name, value);
+                },
+                Err(PgWireError::UserError(error)) => warn!("Ignoring a session default for user {:?}: {}", client.metadata().get(METADATA_USER), error.message()),
+                Err(err) => warn!("Ignoring a session default for user {:?}: {}", client.metadata().get(METADATA_USER), err),
+            }
+        }
         Some(params)
     }
 }
 
+/// Same as pgwire's `finish_authentication`, but the client is sent the key it can cancel its statements with (pgwire sends a
+/// random key that can't be used)
+pub async fn finish_authentication<C>(client:&mut C) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let mut messages = vec![PgWireBackendMessage::Authentication(Authentication::Ok)];
+    if let Some(parameters) = PgLiteServerParameterProvider.server_parameters(client) {
+        messages.extend(parameters.into_iter().map(|(name, value)| PgWireBackendMessage::ParameterStatus(ParameterStatus::new(name, value))));
+    }
+    let backend_key = client.metadata_mut().remove(METADATA_BACKEND_KEY);
+    if let Some((process_id, secret_key)) = backend_key.as_deref().and_then(|key| key.split_once(' ')) {
+        if let (Ok(process_id), Ok(secret_key)) = (process_id.parse(), secret_key.parse()) {
+            messages.push(PgWireBackendMessage::BackendKeyData(BackendKeyData::new(process_id, secret_key)));
+        }
+    }
+    messages.push(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(READY_STATUS_IDLE)));
+    client.send_all(&mut stream::iter(messages.into_iter().map(Ok))).await?;
+    client.set_state(PgWireConnectionState::ReadyForQuery);
+    Ok(())
+}
+
+/// Starts a graceful shutdown of the server - it stops accepting new connections, then waits for the open ones to finish
+#[derive(Clone)]
+pub struct ShutdownTrigger {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownTrigger {
+    fn new() -> Self {
+        Self { sender: Arc::new(watch::channel(false).0) }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    async fn triggered(&self) {
+        let _ = self.sender.subscribe().wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// The number of open connections from each client IP, for `--max-connections-per-ip` - an IP is only in the map while it has
+/// connections open, so it never holds more entries than there are open connections
+#[derive(Clone, Default)]
+struct ConnectionsPerIp {
+    open: Arc<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+impl ConnectionsPerIp {
+    /// Count a new connection from the IP, unless it already has the maximum number open
+    fn open(&self, ip:IpAddr, max_connections:u32) -> Option<IpConnection> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= max_connections {
+            return None;
+        }
+        *count += 1;
+        Some(IpConnection { ip, connections: self.clone() })
+    }
+}
+
+/// An open connection counted against its IP, until it's dropped
+struct IpConnection {
+    ip: IpAddr,
+    connections: ConnectionsPerIp,
+}
+
+impl Drop for IpConnection {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
 
 pub struct PgLiteServer<F,A> {
-    config:PgLiteConfig, 
+    config:Arc<PgLiteConfig>, 
     backend_factory:Arc<Mutex<F>>,
     authenticator:Arc<A>,
+    shutdown:ShutdownTrigger,
+    connections_per_ip:ConnectionsPerIp,
+    tls_acceptor:Option<Arc<TlsAcceptor>>,
+    cancel_registry:CancelRegistry,
+    statement_logs:StatementLogs,
+    accept_loop:AcceptLoopStatus,
  }
 
 impl <F,A> PgLiteServer<F,A>
 where   F : PgLitebackendFactory + Send + Sync + 'static,
         A : PgLiteAuthenticator + Send + 'static { 
 
-    pub fn start(config:PgLiteConfig, backend_factory:F, authenticator:A) -> JoinHandle<()> {
-        let server = Self { config, backend_factory:Arc::new(Mutex::new(backend_factory)), authenticator:Arc::new(authenticator) };
-        let handle = tokio::spawn( async move {  server.run().await } );
-        handle
+    /// Start the server, unless its TLS certificate can't be loaded
+    pub fn start(config:PgLiteConfig, backend_factory:F, authenticator:A) -> Result<JoinHandle<()>, String> {
+        let tls_acceptor = load_tls_acceptor(&config)?;
+        let (listeners, unix_socket) = bind_listeners(&config)?;
+        let health_listener = config.health_addr.map(health::bind).transpose()?;
+        let server = Self { config:Arc::new(config), backend_factory:Arc::new(Mutex::new(backend_factory)), authenticator:Arc::new(authenticator), shutdown:ShutdownTrigger::new(), connections_per_ip:ConnectionsPerIp::default(), tls_acceptor, cancel_registry:CancelRegistry::default(), statement_logs:StatementLogs::default(), accept_loop:AcceptLoopStatus::default() };
+        if let Some(health_listener) = health_listener {
+            tokio::spawn(health::serve(health_listener, server.backend_factory.clone(), server.accept_loop.clone()));
+        }
+        tokio::spawn(listen_for_signals(server.shutdown.clone()));
+        #[cfg(unix)]
+        tokio::spawn(reload_on_sighup(server.authenticator.clone()));
+        let handle = tokio::spawn( async move {  server.run(listeners, unix_socket).await } );
+        Ok(handle)
     }
 
-    async fn run(&self) {
-        // Bind to the server address and process every new connection
-        let listen_addr = self.config.listen_addr;
-        let listener: TcpListener = TcpListener::bind(listen_addr).await.unwrap();
-        info!("PGLite is up and running! Listening at: {}", listen_addr);
+    /// Process every new connection to the server address, until the server's shut down
+    async fn run(&self, listeners:Vec<TcpListener>, unix_socket:Option<UnixSocket>) {
+        let listen_addrs = listeners.iter().filter_map(|listener| listener.local_addr().ok()).map(|addr| addr.to_string()).collect::<Vec<String>>();
+        info!("PGLite is up and running! Listening at: {}", listen_addrs.join(", "));
+        if let Some(unix_socket) = &unix_socket {
+            info!("Listening at the Unix socket: {}", unix_socket.path.to_string_lossy());
+        }
+        // The health checks fail once the loop has ended (from the start of a shutdown)
+        let accept_loop = self.accept_loop.start();
 
+        // Every connection holds a clone of the sender, so the receiver sees the channel close once they've all finished
+        let (open_connections, mut all_closed) = mpsc::channel::<()>(1);
+        // Each connection holds one of the slots while it's open, for `--max-connections`
+        let connection_slots = self.config.max_connections.map(|max_connections| Arc::new(Semaphore::new(max_connections as usize)));
         loop {
             trace!("Ready for next connection...");
-            let (stream, addr) = listener.accept().await.unwrap();
+            tokio::select! {
+                accepted = accept_tcp(&listeners) => match accepted {
+                    Ok((stream, addr)) => {
+                        if let Err(err) = stream.set_nodelay(true) {
+                            debug!("Unable to set TCP_NODELAY for the connection from {}: {}", addr, err);
+                        }
+                        // Connections over the client IP's limit are still accepted, so the client can be told why it's being turned away
+                        let ip_connection = self.config.max_connections_per_ip.map(|max_connections| self.connections_per_ip.open(addr.ip(), max_connections));
+                        self.spawn_connection(stream, addr, ip_connection, open_connections.clone(), connection_slots.clone());
+                    },
+                    Err(err) => accept_failed(&err, "a connection").await,
+                },
+                accepted = accept_unix(&unix_socket) => match accepted {
+                    // Unix socket clients are local, so they're given the loopback address (they aren't limited by --max-connections-per-ip)
+                    Ok(stream) => self.spawn_connection(stream, SocketAddr::from(([127, 0, 0, 1], 0)), None, open_connections.clone(), connection_slots.clone()),
+                    Err(err) => accept_failed(&err, "a Unix socket connection").await,
+                },
+                _ = self.shutdown.triggered() => break,
+            }
+        }
+
+        // Stop listening, then give the open connections the grace period to finish up
+        drop(accept_loop);
+        drop(listeners);
+        drop(unix_socket);
+        drop(open_connections);
+        let grace_period = Duration::from_secs(self.config.shutdown_timeout);
+        info!("Shutting down, waiting up to {}s for the open connections to close...", grace_period.as_secs());
+        match tokio::time::timeout(grace_period, all_closed.recv()).await {
+            Ok(_) => info!("All connections have closed"),
+            Err(_) => warn!("Connections were still open after the shutdown timeout, closing them"),
+        }
+        self.backend_factory.lock().unwrap().shutdown();
+    }
+
+    /// Handle a new connection (over TCP or the Unix socket) on a task of its own - `open_connection` is held until it's closed
+    fn spawn_connection<S>(&self, stream:S, addr:SocketAddr, ip_connection:Option<Option<IpConnection>>, open_connection:mpsc::Sender<()>, connection_slots:Option<Arc<Semaphore>>)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {
+        let backend_factory = self.backend_factory.clone();
+        let authenticator = self.authenticator.clone();
+        let config = self.config.clone();
+        let shutdown = self.shutdown.clone();
+        let tls_acceptor = self.tls_acceptor.clone();
+        let cancel_registry = self.cancel_registry.clone();
+        let statement_logs = self.statement_logs.clone();
+        let (max_connections, max_connections_mode, max_connections_per_ip) = (self.config.max_connections, self.config.max_connections_mode, self.config.max_connections_per_ip);
+        tokio::spawn(async move {
+            let mut conn = PgLiteConnection::create(backend_factory, authenticator, config, shutdown, tls_acceptor, cancel_registry, statement_logs);
+            debug!("Processing new connection, ID: {}, Address: {}", &conn.connection_id, addr);
+            if let (Some(None), Some(max_connections)) = (&ip_connection, max_connections_per_ip) {
+                warn!("[{}] Rejecting connection from {}, it has too many connections open", &conn.connection_id, addr);
+                conn.reject(ErrorInfo::new("FATAL".to_owned(), "53300".to_owned(),
+                    format!("too many connections from {}, the maximum is {max_connections} (--max-connections-per-ip)", addr.ip())));
+            }
+            // Connections over the server's limit are either turned away too, or wait for a connection to close
+            let connection_slot = match (connection_slots, max_connections_mode) {
+                (None, _) => None,
+                (Some(_), _) if matches!(ip_connection, Some(None)) => None,
+                (Some(slots), MaxConnectionsMode::Queue) => {
+                    if slots.available_permits() == 0 {
+                        debug!("[{}] Queueing connection from {}, the server has the maximum number of connections open", &conn.connection_id, addr);
+                    }
+                    slots.acquire_owned().await.ok()
+                },
+                (Some(slots), MaxConnectionsMode::Reject) => match slots.try_acquire_owned() {
+                    Ok(slot) => Some(slot),
+                    Err(_) => {
+                        warn!("[{}] Rejecting connection from {}, the server has too many connections open", &conn.connection_id, addr);
+                        conn.reject(ErrorInfo::new("FATAL".to_owned(), "53300".to_owned(),
+                            format!("sorry, too many clients already (the maximum is {} connections, --max-connections)", max_connections.unwrap_or_default())));
+                        None
+                    }
+                },
+            };
+            if let Err(err) = conn.handle(stream, addr).await {
+                error!("[{}] Unhandled error in connection processor: {:#?}", &conn.connection_id, err);
+            }
+            debug!("[{} ]Connection Closed", &conn.connection_id);
+            drop(connection_slot);
+            drop(ip_connection);
+            drop(open_connection);
+        });
+    }
+}
+
+/// Bind to the server addresses + Unix socket - before the server starts, so an address that can't be used (eg. the port's already in
+/// use) stops it from starting. With `--allow-partial-listen`, it only has to be able to listen at one of them
+fn bind_listeners(config:&PgLiteConfig) -> Result<(Vec<TcpListener>, Option<UnixSocket>), String> {
+    let mut errors = Vec::new();
+    let mut listeners = Vec::with_capacity(config.listen_addrs.len());
+    for addr in &config.listen_addrs {
+        match bind(*addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => errors.push(err),
+        }
+    }
+    let port = config.listen_addrs.first().map(|addr| addr.port()).unwrap_or(5432);
+    let unix_socket = match config.unix_socket.as_deref().map(|path| UnixSocket::bind(path, port)).transpose() {
+        Ok(unix_socket) => unix_socket,
+        Err(err) => {
+            errors.push(err);
+            None
+        }
+    };
+
+    if listeners.is_empty() && unix_socket.is_none() {
+        return Err(match errors.is_empty() {
+            true => "There's nothing to listen at, give at least one --listen-address (or --unix-socket)".to_owned(),
+            false => errors.join("; "),
+        });
+    }
+    if !errors.is_empty() && !config.allow_partial_listen {
+        return Err(errors.join("; "));
+    }
+    for err in errors {
+        error!("{err}, listening at the others (--allow-partial-listen)");
+    }
+    Ok((listeners, unix_socket))
+}
+
+fn bind(addr:SocketAddr) -> Result<TcpListener, String> {
+    std::net::TcpListener::bind(addr)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .and_then(TcpListener::from_std)
+        .map_err(|err| format!("Failed to bind to {addr} (--listen-address): {err}"))
+}
+
+/// The next connection to any of the server addresses
+async fn accept_tcp(listeners:&[TcpListener]) -> std::io::Result<(TcpStream, SocketAddr)> {
+    if listeners.is_empty() {
+        return std::future::pending().await;
+    }
+    let (accepted, _, _) = futures::future::select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))).await;
+    accepted
+}
+
+/// Log an error accepting a connection, which never stops the server - an error with the connection itself (eg. the client gave
+/// up on it before it was accepted) is retried straight away, but for anything else (eg. the process has run out of file
+/// descriptors) it waits a moment, rather than spinning until some connections have closed
+pub async fn accept_failed(err:&std::io::Error, connection:&str) {
+    match err.kind() {
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused | ErrorKind::Interrupted | ErrorKind::WouldBlock => {
+            debug!("Unable to accept {}: {}", connection, err);
+        },
+        _ => {
+            error!("Unable to accept {}, retrying in {}ms: {}", connection, ACCEPT_RETRY_DELAY.as_millis(), err);
+            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+        },
+    }
+}
+
+/// The server's Unix socket (`--unix-socket`), which is removed once the server stops listening
+pub struct UnixSocket {
+    #[cfg(unix)]
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    /// Bind to the socket - when the path is a directory, to the socket in it that Postgres's clients look for (eg.
+    /// `/var/run/postgresql/.s.PGSQL.5432`). A socket left behind by a server that's no longer running is replaced
+    #[cfg(unix)]
+    fn bind(path:&Path, port:u16) -> Result<Self, String> {
+        let path = match path.is_dir() {
+            true => path.join(format!(".s.PGSQL.{port}")),
+            false => path.to_owned(),
+        };
+        let path_name = path.to_string_lossy().to_string();
+        if path.exists() {
+            if !path.metadata().is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                return Err(format!("Unable to listen at the Unix socket {path_name} (--unix-socket): the file already exists"));
+            }
+            if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                return Err(format!("Unable to listen at the Unix socket {path_name} (--unix-socket): another server is listening at it"));
+            }
+            debug!("Removing the stale Unix socket: {}", path_name);
+            let _ = std::fs::remove_file(&path);
+        }
+        let listener = UnixListener::bind(&path).map_err(|err| format!("Unable to listen at the Unix socket {path_name} (--unix-socket): {err}"))?;
+        Ok(Self { listener, path })
+    }
+
+    #[cfg(not(unix))]
+    fn bind(_path:&Path, _port:u16) -> Result<Self, String> {
+        Err("Unix sockets (--unix-socket) aren't supported on this platform".to_owned())
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The next connection to the Unix socket - never, when the server doesn't have one
+#[cfg(unix)]
+async fn accept_unix(unix_socket:&Option<UnixSocket>) -> std::io::Result<UnixStream> {
+    match unix_socket {
+        Some(unix_socket) => unix_socket.listener.accept().await.map(|(stream, _)| stream),
+        None => std::future::pending().await,
+    }
+}
 
-            let backend_factory = self.backend_factory.clone();
-            let authenticator = self.authenticator.clone();
-            tokio::spawn(async move {
-                let mut conn = PgLiteConnection::create(backend_factory, authenticator);
-                debug!("Processing new connection, ID: {}, Address: {}", &conn.connection_id, addr);
-                if let Err(err) = conn.handle(stream, addr).await {
-                    error!("[{}] Unhandled error in connection processor: {:#?}", &conn.connection_id, err);
-                }
-                debug!("[{} ]Connection Closed", &conn.connection_id);
-            });
+#[cfg(not(unix))]
+async fn accept_unix(_unix_socket:&Option<UnixSocket>) -> std::io::Result<TcpStream> {
+    std::future::pending().await
+}
+
+/// SIGHUP reloads the authenticator's users (eg. the credentials file), so they can be changed without a restart
+#[cfg(unix)]
+async fn reload_on_sighup<A:PgLiteAuthenticator>(authenticator:Arc<A>) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(err) => {
+            error!("Unable to listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the authenticator");
+        if let Err(err) = authenticator.reload() {
+            error!("{err}, keeping the users that were already loaded");
         }
     }
-}
\ No newline at end of file
+}
+
+/// SIGTERM (or Ctrl+C) starts a graceful shutdown
+async fn listen_for_signals(shutdown:ShutdownTrigger) {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => { signal.recv().await; },
+            Err(err) => {
+                error!("Unable to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = terminate => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received Ctrl+C"),
+    }
+    shutdown.trigger();
+}
@@ -0,0 +1,689 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone as _, Timelike};
+use chrono_tz::{OffsetName, Tz};
+use pgwire::{error::{PgWireResult, PgWireError, ErrorInfo}, messages::response::{READY_STATUS_IDLE, READY_STATUS_TRANSACTION_BLOCK, READY_STATUS_FAILED_TRANSACTION_BLOCK}};
+
+const DEFAULT_EXTRA_FLOAT_DIGITS: i32 = 1;
+
+/// The prefix of the client metadata entries (eg. `parameter.TimeZone`) an authenticator can return from `verify_identity`, to give
+/// a user's (or database's) sessions their own defaults for the server parameters
+pub const METADATA_PARAMETER_PREFIX: &str = "parameter.";
+
+/* Follows is the per-connection session state shared between the pglite "connection" and its query handlers */
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TransactionStatus {
+    #[default]
+    Idle,
+    InTransaction,
+    Failed,
+}
+
+impl TransactionStatus {
+    /// The status byte to send to the client in a ReadyForQuery message
+    pub fn ready_status(&self) -> u8 {
+        match self {
+            TransactionStatus::Idle => READY_STATUS_IDLE,
+            TransactionStatus::InTransaction => READY_STATUS_TRANSACTION_BLOCK,
+            TransactionStatus::Failed => READY_STATUS_FAILED_TRANSACTION_BLOCK,
+        }
+    }
+}
+
+/// The text format of bytea values, selected with the `bytea_output` parameter
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ByteaOutput {
+    /// eg. `\x0041ff`
+    #[default]
+    Hex,
+    /// The legacy format, eg. `\000A\377`
+    Escape,
+}
+
+impl ByteaOutput {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ByteaOutput::Hex => "hex",
+            ByteaOutput::Escape => "escape",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PgLiteSession {
+    pub transaction_status: TransactionStatus,
+    pub date_style: DateStyle,
+    pub extra_float_digits: i32,
+    pub bytea_output: ByteaOutput,
+    pub time_zone: TimeZone,
+    /// The name the client gave itself, eg. in its startup message - it's only reported back
+    pub application_name: String,
+    /// Whether the client has made its session read-only, for the transactions that don't say otherwise
+    pub default_transaction_read_only: bool,
+    /// The least severe level of the notices the client is sent (`client_min_messages`)
+    pub client_min_messages: &'static str,
+    /// Whether the current transaction block is read-only, if it's been set for just this transaction
+    transaction_read_only: Option<bool>,
+    /// The names of the portals the client has bound, as the portal store can't list them
+    pub portal_names: HashSet<String>,
+    /// The rowid of the last row inserted by the session's statements, for `lastval()` - the database handle is shared, so its
+    /// own `last_insert_rowid()` can be another session's
+    pub last_insert_id: Option<i64>,
+    /// Whether the session has created TEMP tables or set PRAGMAs on the database's main handle, so its SELECTs have to stay on it
+    /// rather than being run by a reader (whose handles don't have them)
+    pub uses_handle_state: bool,
+    /// Parameters changed by the client that need to be reported back to it in a ParameterStatus message
+    pending_parameter_status: Vec<(String, String)>,
+    /// The value of each parameter as it was last reported to the client, so only the changes to them are reported
+    reported_parameters: HashMap<String, String>,
+}
+
+impl Default for PgLiteSession {
+    fn default() -> Self {
+        Self {
+            transaction_status: TransactionStatus::default(),
+            date_style: DateStyle::default(),
+            extra_float_digits: DEFAULT_EXTRA_FLOAT_DIGITS,
+            bytea_output: ByteaOutput::default(),
+            time_zone: TimeZone::default(),
+            application_name: String::new(),
+            default_transaction_read_only: false,
+            client_min_messages: "notice",
+            transaction_read_only: None,
+            portal_names: HashSet::new(),
+            last_insert_id: None,
+            uses_handle_state: false,
+            pending_parameter_status: Vec::new(),
+            reported_parameters: HashMap::new(),
+        }
+    }
+}
+
+impl PgLiteSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the transaction status based on the backend's view of the connection after a statement has been run
+    pub fn update_transaction_status(&mut self, in_transaction:bool, failed:bool) {
+        self.transaction_status = match (in_transaction, self.transaction_status) {
+            (false, _) => TransactionStatus::Idle,
+            (true, TransactionStatus::Failed) => TransactionStatus::Failed,     // Once failed, the transaction stays failed until it's ended
+            (true, _) if failed => TransactionStatus::Failed,
+            (true, _) => TransactionStatus::InTransaction,
+        };
+        if !in_transaction {
+            self.transaction_read_only = None;
+        }
+    }
+
+    /// Set the read-only mode of the transaction block that's just been started with one (eg. `BEGIN READ ONLY`)
+    pub fn set_transaction_read_only(&mut self, read_only:bool) {
+        self.transaction_read_only = Some(read_only);
+    }
+
+    /// Whether write statements are currently rejected
+    pub fn read_only(&self) -> bool {
+        self.transaction_read_only.unwrap_or(self.default_transaction_read_only)
+    }
+
+    /// Whether the client is sent notices - pglite's are all NOTICEs, which are left out once `client_min_messages` is above them
+    pub fn sends_notices(&self) -> bool {
+        MESSAGE_LEVELS.iter().position(|level| *level == self.client_min_messages) <= MESSAGE_LEVELS.iter().position(|level| *level == "notice")
+    }
+
+    /// Any error raised inside a transaction block aborts the transaction (the client will need to ROLLBACK)
+    pub fn fail_transaction(&mut self) {
+        if self.transaction_status == TransactionStatus::InTransaction {
+            self.transaction_status = TransactionStatus::Failed;
+        }
+    }
+
+    /// Set a session parameter - returns false if the parameter isn't one managed by the session
+    pub fn set_parameter(&mut self, name:&str, value:&str) -> PgWireResult<bool> {
+        match name.to_lowercase().as_str() {
+            "datestyle" => {
+                self.date_style = self.date_style.apply(value).ok_or_else(|| invalid_parameter_value("DateStyle", value))?;
+                self.pending_parameter_status.push(("DateStyle".to_owned(), self.date_style.to_string()));
+            },
+            "extra_float_digits" => {
+                self.extra_float_digits = match value.to_uppercase().as_str() {
+                    "DEFAULT" => DEFAULT_EXTRA_FLOAT_DIGITS,
+                    _ => value.parse::<i32>().ok()
+                        .filter(|digits| (-15..=3).contains(digits))
+                        .ok_or_else(|| invalid_parameter_value("extra_float_digits", value))?,
+                };
+            },
+            "bytea_output" => {
+                self.bytea_output = match value.to_lowercase().as_str() {
+                    "hex" | "default" => ByteaOutput::Hex,
+                    "escape" => ByteaOutput::Escape,
+                    _ => return Err(invalid_parameter_value("bytea_output", value)),
+                };
+                self.pending_parameter_status.push(("bytea_output".to_owned(), self.bytea_output.name().to_owned()));
+            },
+            "timezone" => {
+                self.time_zone = TimeZone::parse(value).ok_or_else(|| invalid_parameter_value("TimeZone", value))?;
+                self.pending_parameter_status.push(("TimeZone".to_owned(), self.time_zone.name()));
+            },
+            "client_encoding" => {
+                // Every string is sent + received as UTF8, there's no conversion to any other encoding
+                if !matches!(value.to_uppercase().as_str(), "UTF8" | "UTF-8" | "UNICODE" | "DEFAULT") {
+                    return Err(PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "0A000".to_owned(),
+                        format!("the client encoding \"{value}\" isn't supported, only UTF8 is")))));
+                }
+                self.pending_parameter_status.push(("client_encoding".to_owned(), "UTF8".to_owned()));
+            },
+            "application_name" => {
+                self.application_name = value.to_owned();
+                self.pending_parameter_status.push(("application_name".to_owned(), self.application_name.clone()));
+            },
+            "default_transaction_read_only" => {
+                self.default_transaction_read_only = parse_bool(value).ok_or_else(|| invalid_parameter_value("default_transaction_read_only", value))?;
+                self.pending_parameter_status.push(("default_transaction_read_only".to_owned(), on_off(self.default_transaction_read_only).to_owned()));
+            },
+            "client_min_messages" => {
+                let value = value.to_lowercase();
+                self.client_min_messages = match value.as_str() {
+                    "default" => "notice",
+                    "debug" => "debug2",
+                    _ => MESSAGE_LEVELS.iter().find(|level| **level == value).ok_or_else(|| invalid_parameter_value("client_min_messages", &value))?,
+                };
+            },
+            "transaction_read_only" => {
+                let read_only = parse_bool(value).ok_or_else(|| invalid_parameter_value("transaction_read_only", value))?;
+                // Like Postgres, this only lasts until the end of the transaction block - so outside of one it has no effect
+                if self.transaction_status != TransactionStatus::Idle {
+                    self.transaction_read_only = Some(read_only);
+                }
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// The name + current value of a parameter managed by the session (or one of the server's fixed parameters), for `SHOW`
+    pub fn show_parameter(&self, name:&str) -> Option<(&'static str, String)> {
+        let parameter = match name.to_lowercase().as_str() {
+            "datestyle" => ("DateStyle", self.date_style.to_string()),
+            "extra_float_digits" => ("extra_float_digits", self.extra_float_digits.to_string()),
+            "bytea_output" => ("bytea_output", self.bytea_output.name().to_owned()),
+            "timezone" => ("TimeZone", self.time_zone.name()),
+            "client_encoding" => ("client_encoding", "UTF8".to_owned()),
+            "application_name" => ("application_name", self.application_name.clone()),
+            "default_transaction_read_only" => ("default_transaction_read_only", on_off(self.default_transaction_read_only).to_owned()),
+            "transaction_read_only" => ("transaction_read_only", on_off(self.read_only()).to_owned()),
+            "client_min_messages" => ("client_min_messages", self.client_min_messages.to_owned()),
+            "server_version" => ("server_version", env!("CARGO_PKG_VERSION").to_owned()),
+            "server_encoding" => ("server_encoding", "UTF8".to_owned()),
+            "lc_collate" => ("lc_collate", "C".to_owned()),
+            "lc_ctype" => ("lc_ctype", "C".to_owned()),
+            "integer_datetimes" => ("integer_datetimes", "on".to_owned()),
+            "standard_conforming_strings" => ("standard_conforming_strings", "on".to_owned()),
+            // Every table is in the one schema
+            "search_path" => ("search_path", "\"$user\", public".to_owned()),
+            "is_superuser" => ("is_superuser", "off".to_owned()),
+            _ => return None,
+        };
+        Some(parameter)
+    }
+
+    /// The name + current value of every parameter `SHOW` knows, for `SHOW ALL`
+    pub fn show_all_parameters(&self) -> Vec<(&'static str, String)> {
+        SHOW_PARAMETERS.iter().filter_map(|name| self.show_parameter(name)).collect()
+    }
+
+    /// Apply the parameter defaults the authenticator gave the client (see `METADATA_PARAMETER_PREFIX`), and the `application_name`
+    /// from its startup message, returning the name + value each is reported to the client as - parameters the session doesn't manage
+    /// (eg. `search_path`) are only reported
+    pub fn apply_client_parameters(&mut self, metadata:&HashMap<String, String>) -> Vec<PgWireResult<(String, String)>> {
+        let application_name = metadata.get("application_name").map(|value| ("application_name", value));
+        let parameters = application_name.into_iter()
+            .chain(metadata.iter().filter_map(|(key, value)| Some((key.strip_prefix(METADATA_PARAMETER_PREFIX)?, value))))
+            .map(|(name, value)| Ok(match self.set_parameter(name, value)?.then(|| self.show_parameter(name)).flatten() {
+                Some((name, value)) => (name.to_owned(), value),
+                None => (name.to_owned(), value.to_owned()),
+            }))
+            .collect();
+        // These are the session's starting values, they're reported at startup rather than as changes
+        self.pending_parameter_status.clear();
+        self.reported_parameters = self.reported_parameters().into_iter().map(|(name, value)| (name.to_owned(), value)).collect();
+        parameters
+    }
+
+    /// The name + current value of each parameter Postgres reports to its clients (its `GUC_REPORT` parameters), which are sent at
+    /// startup and again whenever they're changed
+    pub fn reported_parameters(&self) -> Vec<(&'static str, String)> {
+        REPORTED_PARAMETERS.iter().filter_map(|name| self.show_parameter(name)).collect()
+    }
+
+    /// Take the parameter changes that haven't been reported to the client yet - like Postgres, a parameter is only reported once
+    /// (with its latest value) however many times it's been set, and not at all when it's been set to the value the client already has
+    pub fn take_parameter_status(&mut self) -> Vec<(String, String)> {
+        let mut parameter_status:Vec<(String, String)> = Vec::new();
+        for (name, value) in std::mem::take(&mut self.pending_parameter_status) {
+            parameter_status.retain(|(pending_name, _)| *pending_name != name);
+            parameter_status.push((name, value));
+        }
+        parameter_status.retain(|(name, value)| self.reported_parameters.get(name) != Some(value));
+        self.reported_parameters.extend(parameter_status.iter().cloned());
+        parameter_status
+    }
+}
+
+/// Parse a boolean parameter value, accepting the same spellings as Postgres (eg. `on`, `true`, `yes`, `1`)
+fn parse_bool(value:&str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" | "t" | "y" => Some(true),
+        "off" | "false" | "no" | "0" | "f" | "n" => Some(false),
+        _ => None,
+    }
+}
+
+fn on_off(value:bool) -> &'static str {
+    match value {
+        true => "on",
+        false => "off",
+    }
+}
+
+fn invalid_parameter_value(name:&str, value:&str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22023".to_owned(), format!("invalid value for parameter \"{name}\": \"{value}\""))))
+}
+
+/// Parse a `SET [SESSION | LOCAL] name { TO | = } value` statement into the parameter name + value (with any quotes removed)
+pub fn parse_set_statement(query:&str) -> Option<(String, String)> {
+    let statement = query.trim().trim_end_matches(';').trim_end();
+    let (keyword, rest) = statement.split_once(char::is_whitespace)?;
+    if !keyword.eq_ignore_ascii_case("SET") {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let rest = match rest.split_once(char::is_whitespace) {
+        Some((scope, after)) if scope.eq_ignore_ascii_case("SESSION") || scope.eq_ignore_ascii_case("LOCAL") => after.trim_start(),
+        _ => rest,
+    };
+    if let Some(parameter) = parse_transaction_modes(rest) {
+        return Some(parameter);
+    }
+
+    let (name, value) = if rest.get(..10).is_some_and(|p| p.eq_ignore_ascii_case("TIME ZONE ")) {
+        ("TimeZone", &rest[10..])
+    } else if let Some((name, value)) = rest.split_once('=') {
+        (name.trim(), value)
+    } else {
+        let (name, value) = rest.split_once(char::is_whitespace)?;
+        let (to, value) = value.trim_start().split_once(char::is_whitespace)?;
+        if !to.eq_ignore_ascii_case("TO") {
+            return None;
+        }
+        (name, value)
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    let value = value.split(',').map(|v| v.trim().trim_matches('\'').trim_matches('"')).collect::<Vec<&str>>().join(", ");
+    Some((name.to_owned(), value))
+}
+
+/// Parse the `TRANSACTION modes` (or `SESSION CHARACTERISTICS AS TRANSACTION modes`) of a `SET` into the parameter it sets - the
+/// read-only mode, when it's given, otherwise the isolation level
+fn parse_transaction_modes(rest:&str) -> Option<(String, String)> {
+    let words = rest.split(|c:char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty()).map(str::to_uppercase).collect::<Vec<String>>();
+    let (prefix, modes) = match words.as_slice() {
+        [transaction, modes @ ..] if transaction == "TRANSACTION" => ("", modes),
+        [characteristics, as_, transaction, modes @ ..] if characteristics == "CHARACTERISTICS" && as_ == "AS" && transaction == "TRANSACTION" => ("default_", modes),
+        _ => return None,
+    };
+    let read_only = read_only_mode(modes).map(on_off);
+    // eg. `ISOLATION LEVEL SERIALIZABLE` or `ISOLATION LEVEL READ COMMITTED`
+    let isolation_level = modes.iter().position(|w| w == "LEVEL").map(|idx| {
+        let words = if modes.get(idx + 1).is_some_and(|w| w == "SERIALIZABLE") { 1 } else { 2 };
+        modes[idx + 1..].iter().take(words).map(|w| w.to_lowercase()).collect::<Vec<String>>().join(" ")
+    });
+    match (read_only, isolation_level) {
+        (Some(read_only), _) => Some((format!("{prefix}transaction_read_only"), read_only.to_owned())),
+        (None, Some(isolation_level)) => Some((format!("{prefix}transaction_isolation"), isolation_level)),
+        (None, None) => None,
+    }
+}
+
+/// The words that can make up the transaction modes of a `BEGIN` or `START TRANSACTION`
+const TRANSACTION_MODE_WORDS: [&str; 11] = ["ISOLATION", "LEVEL", "SERIALIZABLE", "REPEATABLE", "READ", "COMMITTED", "UNCOMMITTED", "ONLY", "WRITE", "NOT", "DEFERRABLE"];
+
+/// The read-only mode given by the (uppercase) words of some transaction modes, if they give one - `READ ONLY` or `READ WRITE`
+fn read_only_mode(modes:&[String]) -> Option<bool> {
+    modes.windows(2).find_map(|pair| match (pair[0].as_str(), pair[1].as_str()) {
+        ("READ", "ONLY") => Some(true),
+        ("READ", "WRITE") => Some(false),
+        _ => None,
+    })
+}
+
+/// Parse a `BEGIN [WORK | TRANSACTION] [modes]` or `START TRANSACTION [modes]` - which SQLite can't run when it has transaction
+/// modes, or is `START TRANSACTION` - into the read-only mode it gives (if any). SQLite's own `BEGIN IMMEDIATE` etc. aren't parsed
+pub fn parse_begin_statement(query:&str) -> Option<Option<bool>> {
+    let statement = query.trim().trim_end_matches(';').trim_end();
+    let words = statement.split(|c:char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty()).map(str::to_uppercase).collect::<Vec<String>>();
+    let modes = match words.as_slice() {
+        [start, transaction, modes @ ..] if start == "START" && transaction == "TRANSACTION" => modes,
+        [begin, work, modes @ ..] if begin == "BEGIN" && (work == "WORK" || work == "TRANSACTION") => modes,
+        [begin, modes @ ..] if begin == "BEGIN" => modes,
+        _ => return None,
+    };
+    modes.iter().all(|word| TRANSACTION_MODE_WORDS.contains(&word.as_str())).then(|| read_only_mode(modes))
+}
+
+/// The parameters that can be shown, in the order of `SHOW ALL` (which Postgres sorts by name)
+const SHOW_PARAMETERS: [&str; 17] = [
+    "application_name", "bytea_output", "client_encoding", "client_min_messages", "datestyle", "default_transaction_read_only", "extra_float_digits",
+    "integer_datetimes", "is_superuser", "lc_collate", "lc_ctype", "search_path", "server_encoding", "server_version",
+    "standard_conforming_strings", "timezone", "transaction_read_only",
+];
+
+/// The levels of `client_min_messages`, from the least severe
+/// The parameters that are reported to the client in ParameterStatus messages
+const REPORTED_PARAMETERS: [&str; 10] = [
+    "application_name", "client_encoding", "DateStyle", "default_transaction_read_only", "integer_datetimes", "is_superuser",
+    "server_encoding", "server_version", "standard_conforming_strings", "TimeZone",
+];
+
+const MESSAGE_LEVELS: [&str; 9] = ["debug5", "debug4", "debug3", "debug2", "debug1", "log", "notice", "warning", "error"];
+
+/// Parse a `SHOW name` statement into the parameter name
+pub fn parse_show_statement(query:&str) -> Option<String> {
+    let statement = query.trim().trim_end_matches(';').trim_end();
+    let (keyword, name) = statement.split_once(char::is_whitespace)?;
+    let name = name.trim().trim_matches('"');
+    match keyword.eq_ignore_ascii_case("SHOW") && !name.is_empty() && !name.contains(char::is_whitespace) {
+        true => Some(name.to_owned()),
+        false => None,
+    }
+}
+
+/// The output style part of the `DateStyle` parameter
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DateOutputStyle {
+    #[default]
+    Iso,
+    Sql,
+    German,
+    Postgres,
+}
+
+/// The field ordering part of the `DateStyle` parameter
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DateOrder {
+    #[default]
+    Ymd,
+    Dmy,
+    Mdy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DateStyle {
+    pub style: DateOutputStyle,
+    pub order: DateOrder,
+}
+
+impl DateStyle {
+    /// Apply a `DateStyle` setting (eg. `SQL, DMY`, `German` or `DMY`) - like Postgres, any part that isn't specified is left unchanged
+    pub fn apply(&self, value:&str) -> Option<DateStyle> {
+        let mut date_style = *self;
+        for part in value.split(|c:char| c == ',' || c.is_whitespace()).filter(|p| !p.is_empty()) {
+            match part.to_uppercase().as_str() {
+                "ISO" => date_style.style = DateOutputStyle::Iso,
+                "SQL" => date_style.style = DateOutputStyle::Sql,
+                "GERMAN" => date_style.style = DateOutputStyle::German,
+                "POSTGRES" => date_style.style = DateOutputStyle::Postgres,
+                "YMD" => date_style.order = DateOrder::Ymd,
+                "DMY" | "EURO" | "EUROPEAN" => date_style.order = DateOrder::Dmy,
+                "MDY" | "US" | "NONEURO" | "NONEUROPEAN" => date_style.order = DateOrder::Mdy,
+                "DEFAULT" => date_style = DateStyle::default(),
+                _ => return None,
+            }
+        }
+        Some(date_style)
+    }
+
+    /// Format a date the way Postgres does for this DateStyle
+    pub fn format_date(&self, date:&NaiveDate) -> String {
+        let fmt = match (self.style, self.order) {
+            (DateOutputStyle::Iso, _) => "%Y-%m-%d",
+            (DateOutputStyle::Sql, DateOrder::Dmy) => "%d/%m/%Y",
+            (DateOutputStyle::Sql, _) => "%m/%d/%Y",
+            (DateOutputStyle::German, _) => "%d.%m.%Y",
+            (DateOutputStyle::Postgres, DateOrder::Dmy) => "%d-%m-%Y",
+            (DateOutputStyle::Postgres, _) => "%m-%d-%Y",
+        };
+        date.format(fmt).to_string()
+    }
+
+    /// Format a timestamp (without time zone) the way Postgres does for this DateStyle - fractional seconds are only shown when present
+    pub fn format_timestamp(&self, timestamp:&NaiveDateTime) -> String {
+        let time = format!("{}{}", timestamp.format("%H:%M:%S"), fractional_seconds(timestamp));
+        match (self.style, self.order) {
+            (DateOutputStyle::Postgres, DateOrder::Dmy) => format!("{} {} {}", timestamp.format("%a %d %b"), time, timestamp.format("%Y")),
+            (DateOutputStyle::Postgres, _) => format!("{} {} {}", timestamp.format("%a %b %d"), time, timestamp.format("%Y")),
+            (_, _) => format!("{} {}", self.format_date(&timestamp.date()), time),
+        }
+    }
+
+    /// Format a timestamptz (given in UTC) the way Postgres does for this DateStyle, in the session's time zone - with its UTC offset
+    /// for the ISO style, otherwise the zone's abbreviation
+    pub fn format_timestamptz(&self, utc:&NaiveDateTime, time_zone:&TimeZone) -> String {
+        let (local, offset, abbreviation) = time_zone.localize(utc);
+        match self.style {
+            DateOutputStyle::Iso => format!("{}{}", self.format_timestamp(&local), format_offset(offset)),
+            _ => format!("{} {}", self.format_timestamp(&local), abbreviation),
+        }
+    }
+}
+
+/// Postgres shows fractional seconds to microsecond precision, without any trailing zeros
+fn fractional_seconds(time:&impl Timelike) -> String {
+    match time.nanosecond() / 1000 {
+        0 => String::new(),
+        micros => format!(".{micros:06}").trim_end_matches('0').to_owned(),
+    }
+}
+
+impl std::fmt::Display for DateStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let style = match self.style {
+            DateOutputStyle::Iso => "ISO",
+            DateOutputStyle::Sql => "SQL",
+            DateOutputStyle::German => "German",
+            DateOutputStyle::Postgres => "Postgres",
+        };
+        let order = match self.order {
+            DateOrder::Ymd => "YMD",
+            DateOrder::Dmy => "DMY",
+            DateOrder::Mdy => "MDY",
+        };
+        write!(f, "{style}, {order}")
+    }
+}
+
+/// The session's `TimeZone` - timestamptz values are stored in UTC, and shown to the client in this zone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeZone {
+    Named(Tz),
+    /// A fixed offset from UTC, eg. `SET TIME ZONE -7`
+    Offset(FixedOffset),
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        TimeZone::Named(Tz::UTC)
+    }
+}
+
+impl TimeZone {
+    /// Parse a `TimeZone` setting: a zone name (eg. `Europe/London`, in any case), or a number of hours east of UTC (eg. `-7` or `5.5`)
+    pub fn parse(value:&str) -> Option<TimeZone> {
+        if value.eq_ignore_ascii_case("DEFAULT") || value.eq_ignore_ascii_case("LOCAL") {
+            return Some(TimeZone::default());
+        }
+        if let Ok(hours) = value.parse::<f64>() {
+            return FixedOffset::east_opt((hours * 3600.0).round() as i32).map(TimeZone::Offset);
+        }
+        chrono_tz::TZ_VARIANTS.iter()
+            .find(|tz| tz.name().eq_ignore_ascii_case(value))
+            .map(|tz| TimeZone::Named(*tz))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            TimeZone::Named(tz) => tz.name().to_owned(),
+            // Like Postgres, a fixed offset is shown as a POSIX zone - where the sign of the offset is inverted
+            TimeZone::Offset(offset) => format!("<{}>{}", format_offset(offset.local_minus_utc()), format_offset(-offset.local_minus_utc())),
+        }
+    }
+
+    /// Convert a UTC timestamp to the local time of this zone, along with its UTC offset (in seconds) + abbreviation at that time
+    fn localize(&self, utc:&NaiveDateTime) -> (NaiveDateTime, i32, String) {
+        let (offset, abbreviation) = match self {
+            TimeZone::Named(tz) => {
+                let offset = tz.offset_from_utc_datetime(utc);
+                let seconds = offset.fix().local_minus_utc();
+                (seconds, offset.abbreviation().map_or_else(|| format_offset(seconds), str::to_owned))
+            },
+            TimeZone::Offset(offset) => (offset.local_minus_utc(), format_offset(offset.local_minus_utc())),
+        };
+        (*utc + Duration::seconds(offset.into()), offset, abbreviation)
+    }
+
+    /// Parse a timestamptz (eg. `2024-01-02 03:04:05.6+02` or `2024-01-02T03:04:05Z`), returning it in UTC - a timestamp without
+    /// an offset is taken to be in this zone
+    pub fn parse_timestamptz(&self, value:&str) -> Option<NaiveDateTime> {
+        let (timestamp, offset) = split_offset(value.trim());
+        let timestamp = parse_timestamp(timestamp.trim_end())?;
+        let offset = match offset {
+            Some(offset) => parse_offset(offset)?,
+            None => match self {
+                // A local time skipped by a DST change is taken to be in the offset from before the change, like Postgres
+                TimeZone::Named(tz) => match tz.from_local_datetime(&timestamp).earliest() {
+                    Some(local) => local.offset().fix().local_minus_utc(),
+                    None => tz.offset_from_utc_datetime(&(timestamp - Duration::days(1))).fix().local_minus_utc(),
+                },
+                TimeZone::Offset(offset) => offset.local_minus_utc(),
+            },
+        };
+        Some(timestamp - Duration::seconds(offset.into()))
+    }
+}
+
+/// Parse a timestamp (without time zone) in the ISO-8601 forms SQLite stores them in - a date on its own is taken as midnight
+pub fn parse_timestamp(value:&str) -> Option<NaiveDateTime> {
+    ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"].iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(value, fmt).ok())
+        .or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)))
+}
+
+/// Parse a time of day (without time zone) in the forms SQLite stores them in, eg. `03:04:05.6` or `03:04`
+pub fn parse_time(value:&str) -> Option<NaiveTime> {
+    ["%H:%M:%S%.f", "%H:%M"].iter().find_map(|fmt| NaiveTime::parse_from_str(value, fmt).ok())
+}
+
+/// Format a time of day the way Postgres does (the same for every DateStyle), eg. `03:04:05.6`
+pub fn format_time(time:&NaiveTime) -> String {
+    format!("{}{}", time.format("%H:%M:%S"), fractional_seconds(time))
+}
+
+/// Format a UTC timestamp the way timestamptz values are stored, the same form as SQLite's `CURRENT_TIMESTAMP` (eg. `2024-01-02 03:04:05.6`)
+pub fn format_utc_timestamp(utc:&NaiveDateTime) -> String {
+    format!("{}{}", utc.format("%Y-%m-%d %H:%M:%S"), fractional_seconds(utc))
+}
+
+/// Split a timestamp from its UTC offset, if it has one (eg. `Z`, `UTC`, `+02`, `-03:30` or `+0530`)
+fn split_offset(value:&str) -> (&str, Option<&str>) {
+    for utc in ["Z", "UTC", "GMT"] {
+        if value.len() > utc.len() && value[value.len() - utc.len()..].eq_ignore_ascii_case(utc) {
+            return (&value[..value.len() - utc.len()], Some("+00"));
+        }
+    }
+    // The offset's sign has to come after the date, which has its own dashes
+    match value.rfind(['+', '-']) {
+        Some(idx) if idx > 10 => (&value[..idx], Some(&value[idx..])),
+        _ => (value, None),
+    }
+}
+
+/// Parse a UTC offset (eg. `+02`, `-03:30` or `+0530`) into seconds east of UTC
+fn parse_offset(offset:&str) -> Option<i32> {
+    let sign = match offset.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let digits = offset[1..].trim().replace(':', "");
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes, seconds) = match digits.len() {
+        1 | 2 => (digits.parse::<i32>().ok()?, 0, 0),
+        4 => (digits[..2].parse().ok()?, digits[2..].parse().ok()?, 0),
+        6 => (digits[..2].parse().ok()?, digits[2..4].parse().ok()?, digits[4..].parse().ok()?),
+        _ => return None,
+    };
+    match hours < 16 && minutes < 60 && seconds < 60 {
+        true => Some(sign * (hours * 3600 + minutes * 60 + seconds)),
+        false => None,
+    }
+}
+
+/// Format a UTC offset the way Postgres does, eg. `+00`, `+05:30` or `-03`
+fn format_offset(seconds:i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    match (seconds / 60 % 60, seconds % 60) {
+        (0, 0) => format!("{sign}{:02}", seconds / 3600),
+        (minutes, 0) => format!("{sign}{:02}:{minutes:02}", seconds / 3600),
+        (minutes, secs) => format!("{sign}{:02}:{minutes:02}:{secs:02}", seconds / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_begin_statements() {
+        assert_eq!(parse_begin_statement("BEGIN"), Some(None));
+        assert_eq!(parse_begin_statement("begin work;"), Some(None));
+        assert_eq!(parse_begin_statement("BEGIN READ ONLY"), Some(Some(true)));
+        assert_eq!(parse_begin_statement("BEGIN TRANSACTION ISOLATION LEVEL SERIALIZABLE, READ WRITE"), Some(Some(false)));
+        assert_eq!(parse_begin_statement("START TRANSACTION"), Some(None));
+        assert_eq!(parse_begin_statement("start transaction read only, deferrable"), Some(Some(true)));
+        assert_eq!(parse_begin_statement("START TRANSACTION ISOLATION LEVEL REPEATABLE READ"), Some(None));
+    }
+
+    #[test]
+    fn leaves_sqlite_begin_statements_alone() {
+        assert_eq!(parse_begin_statement("BEGIN IMMEDIATE"), None);
+        assert_eq!(parse_begin_statement("BEGIN EXCLUSIVE TRANSACTION"), None);
+        assert_eq!(parse_begin_statement("START"), None);
+        assert_eq!(parse_begin_statement("SELECT 1"), None);
+    }
+
+    #[test]
+    fn begin_read_only_lasts_until_the_end_of_the_transaction() {
+        let mut session = PgLiteSession::new();
+        session.update_transaction_status(true, false);
+        session.set_transaction_read_only(true);
+        assert!(session.read_only());
+        session.update_transaction_status(false, false);
+        assert!(!session.read_only());
+    }
+
+    #[test]
+    fn parses_show_statements() {
+        assert_eq!(parse_show_statement("SHOW transaction_read_only"), Some("transaction_read_only".to_owned()));
+        assert_eq!(parse_show_statement("  show \"DateStyle\" ; "), Some("DateStyle".to_owned()));
+        assert_eq!(parse_show_statement("SHOW ALL"), Some("ALL".to_owned()));
+        assert_eq!(parse_show_statement("SHOW"), None);
+        assert_eq!(parse_show_statement("SHOW TRANSACTION ISOLATION LEVEL"), None);
+        assert_eq!(parse_show_statement("SELECT transaction_read_only"), None);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+use chrono::{DateTime, Utc};
+use pgwire::{error::PgWireResult, messages::PgWireFrontendMessage};
+
+/// Statements longer than this are truncated in the log, so the memory used by the log stays bounded
+const MAX_LOGGED_STATEMENT_LENGTH: usize = 1024;
+
+pub struct StatementLogEntry {
+    pub at: DateTime<Utc>,
+    pub statement: String,
+    pub error: Option<String>,
+}
+
+/// A small ring buffer of the most recent statements on a connection (+ their outcomes), for post-mortem debugging
+pub struct StatementLog {
+    capacity: usize,
+    entries: VecDeque<StatementLogEntry>,
+}
+
+impl StatementLog {
+    pub fn new(capacity:usize) -> Self {
+        Self { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+
+    /// The statement that a frontend message runs (if any), as it should appear in the log
+    pub fn statement_for(message:&PgWireFrontendMessage) -> Option<String> {
+        match message {
+            PgWireFrontendMessage::Query(query) => Some(query.query().to_owned()),
+            PgWireFrontendMessage::Parse(parse) => Some(format!("PARSE {}", parse.query())),
+            PgWireFrontendMessage::Execute(execute) => Some(format!("EXECUTE portal '{}'", execute.name().as_deref().unwrap_or_default())),
+            _ => None
+        }
+    }
+
+    pub fn record(&mut self, statement:String, result:&PgWireResult<()>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        let statement = match statement.char_indices().nth(MAX_LOGGED_STATEMENT_LENGTH) {
+            Some((idx, _)) => format!("{}...", &statement[..idx]),
+            None => statement
+        };
+        self.entries.push_back(StatementLogEntry { at: Utc::now(), statement, error: result.as_ref().err().map(|e| e.to_string()) });
+    }
+
+    /// The recent statements, oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &StatementLogEntry> {
+        self.entries.iter()
+    }
+
+    /// Write the recent statements to the debug log
+    pub fn dump(&self, connection_id:&uuid::Uuid) {
+        if self.entries.is_empty() {
+            return;
+        }
+        debug!("[{}] The last {} statement(s) run on this connection:", connection_id, self.entries.len());
+        for entry in &self.entries {
+            match &entry.error {
+                None => debug!("[{}]   {} OK: {}", connection_id, entry.at.format("%H:%M:%S%.3f"), entry.statement),
+                Some(error) => debug!("[{}]   {} FAILED ({}): {}", connection_id, entry.at.format("%H:%M:%S%.3f"), error, entry.statement),
+            }
+        }
+    }
+}
+
+/// The statement logs of the open connections, by their process id (as sent to the client in its BackendKeyData) - so the admin can
+/// look at what a connection has been running while it's still open, with `SELECT pglite.statement_log(pid)`
+#[derive(Clone, Default)]
+pub struct StatementLogs {
+    logs: Arc<Mutex<HashMap<i32, Arc<Mutex<StatementLog>>>>>,
+}
+
+impl StatementLogs {
+    /// Register a connection's statement log - it's removed once the returned registration is dropped
+    pub fn register(&self, process_id:i32, log:Arc<Mutex<StatementLog>>) -> StatementLogRegistration {
+        self.logs.lock().unwrap().insert(process_id, log);
+        StatementLogRegistration { process_id, logs: self.clone() }
+    }
+
+    pub fn get(&self, process_id:i32) -> Option<Arc<Mutex<StatementLog>>> {
+        self.logs.lock().unwrap().get(&process_id).cloned()
+    }
+}
+
+/// A connection's statement log, registered while the connection is open
+pub struct StatementLogRegistration {
+    process_id: i32,
+    logs: StatementLogs,
+}
+
+impl Drop for StatementLogRegistration {
+    fn drop(&mut self) {
+        self.logs.logs.lock().unwrap().remove(&self.process_id);
+    }
+}
@@ -0,0 +1,63 @@
+use std::{io::Error as IOError, pin::Pin, task::{Context, Poll}};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/* Follows is the client's end of a connection (over TCP or a Unix socket), which can be peeked at before its messages are framed -
+   for the requests a client can send before its startup message (eg. SSLRequest or CancelRequest). The bytes that are peeked at
+   are read from the socket, and read again from here before anything else the client sends */
+
+pub struct ClientStream<S> {
+    inner: S,
+    /// What's been read from the socket by peeking, but not read from the stream yet
+    peeked: Vec<u8>,
+}
+
+impl <S> ClientStream<S>
+where S: AsyncRead + Unpin {
+    pub fn new(inner:S) -> Self {
+        Self { inner, peeked: Vec::new() }
+    }
+
+    /// The next `len` bytes the client sends, without reading them from the stream - waiting until it's sent them, or None if it
+    /// closed the connection first
+    pub async fn peek(&mut self, len:usize) -> Result<Option<&[u8]>, IOError> {
+        while self.peeked.len() < len {
+            if self.inner.read_buf(&mut self.peeked).await? == 0 {
+                return Ok(None);
+            }
+        }
+        Ok(Some(&self.peeked[..len]))
+    }
+
+    /// Skip the next `len` bytes, which have already been peeked at
+    pub fn consume(&mut self, len:usize) {
+        self.peeked.drain(..len.min(self.peeked.len()));
+    }
+}
+
+impl <S> AsyncRead for ClientStream<S>
+where S: AsyncRead + Unpin {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), IOError>> {
+        if self.peeked.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let len = self.peeked.len().min(buf.remaining());
+        buf.put_slice(&self.peeked[..len]);
+        self.peeked.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl <S> AsyncWrite for ClientStream<S>
+where S: AsyncWrite + Unpin {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, IOError>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IOError>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IOError>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,266 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+use rusqlite::types::Value;
+use tracing::{Span, field::Empty};
+use uuid::Uuid;
+
+use crate::{backend::PgLiteDBParam, config::PgLiteConfig};
+
+/* Follows is the export of query traces + metrics to an OpenTelemetry collector (`--otlp-endpoint`, in builds with the `otel`
+   feature) - without an exporter running, the spans are disabled and nothing is recorded */
+
+/// The running exporters, which are shut down (flushing the last of the spans + metrics) when the server stops
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    tracer_provider: opentelemetry_sdk::trace::TracerProvider,
+    #[cfg(feature = "otel")]
+    meter_provider: opentelemetry_sdk::metrics::SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Start exporting to the configured collector, if there is one
+    #[cfg(feature = "otel")]
+    pub fn init(config:&PgLiteConfig) -> Option<Self> {
+        let endpoint = config.otlp_endpoint.as_ref()?;
+        match otel::start(endpoint, config.otlp_sample_ratio) {
+            Ok(telemetry) => {
+                info!("Exporting traces + metrics to the OpenTelemetry collector at: {}", endpoint);
+                Some(telemetry)
+            },
+            Err(err) => {
+                error!("Unable to start exporting to the OpenTelemetry collector at {}: {}", endpoint, err);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    pub fn init(config:&PgLiteConfig) -> Option<Self> {
+        if config.otlp_endpoint.is_some() {
+            warn!("Ignoring --otlp-endpoint, this build of pglite doesn't include the `otel` feature");
+        }
+        None
+    }
+
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        {
+            if let Err(err) = self.tracer_provider.shutdown() {
+                warn!("Unable to flush the remaining traces: {}", err);
+            }
+            if let Err(err) = self.meter_provider.shutdown() {
+                warn!("Unable to flush the remaining metrics: {}", err);
+            }
+        }
+    }
+}
+
+/// The span of a connection, which its queries are nested under
+pub fn connection_span(connection_id:&Uuid, peer:&std::net::SocketAddr) -> Span {
+    tracing::info_span!("connection", %connection_id, %peer, user = Empty, database = Empty)
+}
+
+/// The settings of the slow query log (`--log-min-duration`), which logs the queries that take at least the minimum duration
+#[derive(Debug)]
+pub struct SlowQueryLog {
+    pub min_duration: Duration,
+    /// The number of characters of a statement that are logged (all of them if None)
+    pub statement_max_length: Option<usize>,
+    /// Whether the values of the bound parameters are logged
+    pub log_parameters: bool,
+}
+
+impl SlowQueryLog {
+    pub fn from_config(config:&PgLiteConfig) -> Option<Arc<Self>> {
+        let min_duration = Duration::from_millis(config.log_min_duration?);
+        Some(Arc::new(Self { min_duration, statement_max_length: config.log_statement_max_length, log_parameters: config.log_parameters }))
+    }
+
+    fn statement<'a>(&self, query:&'a str) -> std::borrow::Cow<'a, str> {
+        match self.statement_max_length.and_then(|max| query.char_indices().nth(max)) {
+            Some((end, _)) => format!("{}...", &query[..end]).into(),
+            None => query.into(),
+        }
+    }
+
+    /// The parameters as they're logged, eg. `$1 = 'abc', $2 = 5` - only their number when the values aren't to be logged
+    fn parameters(&self, params:&[PgLiteDBParam]) -> String {
+        if !self.log_parameters {
+            return format!("{} (values not logged)", params.len());
+        }
+        params.iter().enumerate().map(|(idx, param)| {
+            let value = match &param.value {
+                Value::Null => "NULL".to_owned(),
+                Value::Integer(i) => i.to_string(),
+                Value::Real(f) => f.to_string(),
+                Value::Text(text) => format!("'{}'", text.replace('\'', "''")),
+                Value::Blob(blob) => format!("'\\x{}'", blob.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+            };
+            format!("${} = {}", idx + 1, value)
+        }).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// A query that's being timed for the slow query log
+struct SlowQuery {
+    log: Arc<SlowQueryLog>,
+    connection_id: Uuid,
+    statement: String,
+    parameters: Option<String>,
+}
+
+/// Traces a query run on the database - its span + metrics are finished once it's dropped, ie. after its results have been
+/// streamed to the client. Any query that doesn't get as far as its results is counted as failed.
+pub struct QueryTrace {
+    span: Span,
+    started: Option<Instant>,
+    #[cfg_attr(not(feature = "otel"), allow(unused))]
+    database: String,
+    slow_query: Option<SlowQuery>,
+    rows: usize,
+    failed: bool,
+}
+
+impl QueryTrace {
+    pub fn start(connection_id:&Uuid, database:&str, query:&str, slow_query_log:Option<Arc<SlowQueryLog>>) -> Self {
+        let span = tracing::info_span!("query", %connection_id, database, statement = query, rows = Empty, otel.status_code = Empty);
+        // Nothing is timed or counted when nothing is being exported or logged
+        let started = match span.is_disabled() && slow_query_log.is_none() {
+            true => None,
+            false => Some(Instant::now()),
+        };
+        let database = match started {
+            Some(_) => database.to_owned(),
+            None => String::new(),
+        };
+        let slow_query = slow_query_log.map(|log| SlowQuery { statement: log.statement(query).into_owned(), log, connection_id: *connection_id, parameters: None });
+        Self { span, started, database, slow_query, rows: 0, failed: true }
+    }
+
+    /// The parameters bound to the query, for the slow query log
+    pub fn with_parameters(mut self, params:&[PgLiteDBParam]) -> Self {
+        if let Some(slow_query) = self.slow_query.as_mut().filter(|_| !params.is_empty()) {
+            slow_query.parameters = Some(slow_query.log.parameters(params));
+        }
+        self
+    }
+
+    /// The query returned results (or ran), which are counted from here on
+    pub fn succeeded(&mut self, rows:usize) {
+        self.failed = false;
+        self.rows = rows;
+    }
+
+    pub fn add_row(&mut self) {
+        self.rows += 1;
+    }
+
+    pub fn failed(&mut self) {
+        self.failed = true;
+    }
+}
+
+impl Drop for QueryTrace {
+    fn drop(&mut self) {
+        let Some(started) = self.started else { return };
+        let elapsed = started.elapsed();
+        if !self.span.is_disabled() {
+            self.span.record("rows", self.rows as i64);
+            if self.failed {
+                self.span.record("otel.status_code", "ERROR");
+            }
+            #[cfg(feature = "otel")]
+            otel::record_query(&self.database, elapsed, self.rows, self.failed);
+        }
+        if let Some(slow_query) = self.slow_query.as_ref().filter(|slow_query| elapsed >= slow_query.log.min_duration) {
+            let parameters = slow_query.parameters.as_ref().map(|params| format!(", parameters: {}", params)).unwrap_or_default();
+            info!("[{}] duration: {:.3} ms, rows: {}{}, statement: {}{}", slow_query.connection_id, elapsed.as_secs_f64() * 1000.0,
+                self.rows, if self.failed { " (failed)" } else { "" }, slow_query.statement, parameters);
+        }
+    }
+}
+
+/// Count whether a statement came from a database handle's prepared statement cache
+#[cfg_attr(not(feature = "otel"), allow(unused))]
+pub fn record_statement_cache(database:&str, hit:bool) {
+    #[cfg(feature = "otel")]
+    otel::record_statement_cache(database, hit);
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::{sync::OnceLock, time::Duration};
+    use opentelemetry::{global, KeyValue, metrics::{Counter, Histogram}, trace::TracerProvider as _};
+    use opentelemetry_otlp::{SpanExporter, MetricExporter, WithExportConfig};
+    use opentelemetry_sdk::{runtime, Resource, trace::{Sampler, TracerProvider}, metrics::{SdkMeterProvider, PeriodicReader}};
+    use tracing::Level;
+    use tracing_subscriber::{filter::Targets, layer::{Layer, SubscriberExt}};
+
+    use super::Telemetry;
+
+    struct QueryMetrics {
+        queries: Counter<u64>,
+        errors: Counter<u64>,
+        rows: Counter<u64>,
+        duration: Histogram<f64>,
+        statement_cache_hits: Counter<u64>,
+        statement_cache_misses: Counter<u64>,
+    }
+
+    static QUERY_METRICS: OnceLock<QueryMetrics> = OnceLock::new();
+
+    pub fn start(endpoint:&str, sample_ratio:f64) -> Result<Telemetry, String> {
+        let resource = Resource::new([KeyValue::new("service.name", "pglite"), KeyValue::new("service.version", env!("CARGO_PKG_VERSION"))]);
+
+        // The sampling decision is made for each connection, and followed by its queries
+        let span_exporter = SpanExporter::builder().with_tonic().with_endpoint(endpoint).build().map_err(|e| e.to_string())?;
+        let tracer_provider = TracerProvider::builder()
+            .with_batch_exporter(span_exporter, runtime::Tokio)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_ratio))))
+            .with_resource(resource.clone())
+            .build();
+        // Only pglite's own spans are exported, not those of its dependencies (including the exporter's)
+        let layer = tracing_opentelemetry::layer()
+            .with_tracer(tracer_provider.tracer("pglite"))
+            .with_filter(Targets::new().with_target("pglite", Level::INFO));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::set_global_default(subscriber).map_err(|e| e.to_string())?;
+
+        let metric_exporter = MetricExporter::builder().with_tonic().with_endpoint(endpoint).build().map_err(|e| e.to_string())?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metric_exporter, runtime::Tokio).build())
+            .with_resource(resource)
+            .build();
+        global::set_meter_provider(meter_provider.clone());
+
+        let meter = global::meter("pglite");
+        let _ = QUERY_METRICS.set(QueryMetrics {
+            queries: meter.u64_counter("pglite.queries").with_description("Queries run on the databases").build(),
+            errors: meter.u64_counter("pglite.query.errors").with_description("Queries that failed").build(),
+            rows: meter.u64_counter("pglite.query.rows").with_description("Rows returned (or affected) by queries").build(),
+            duration: meter.f64_histogram("pglite.query.duration").with_description("Time taken to run queries + stream their results").with_unit("ms").build(),
+            statement_cache_hits: meter.u64_counter("pglite.statement_cache.hits").with_description("Statements that were already prepared, in the statement cache").build(),
+            statement_cache_misses: meter.u64_counter("pglite.statement_cache.misses").with_description("Statements that had to be prepared, as they weren't in the statement cache").build(),
+        });
+        Ok(Telemetry { tracer_provider, meter_provider })
+    }
+
+    pub fn record_query(database:&str, elapsed:Duration, rows:usize, failed:bool) {
+        let Some(metrics) = QUERY_METRICS.get() else { return };
+        let attributes = [KeyValue::new("database", database.to_owned())];
+        metrics.queries.add(1, &attributes);
+        metrics.rows.add(rows as u64, &attributes);
+        metrics.duration.record(elapsed.as_secs_f64() * 1000.0, &attributes);
+        if failed {
+            metrics.errors.add(1, &attributes);
+        }
+    }
+
+    pub fn record_statement_cache(database:&str, hit:bool) {
+        let Some(metrics) = QUERY_METRICS.get() else { return };
+        let attributes = [KeyValue::new("database", database.to_owned())];
+        match hit {
+            true => metrics.statement_cache_hits.add(1, &attributes),
+            false => metrics.statement_cache_misses.add(1, &attributes),
+        }
+    }
+}
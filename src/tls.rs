@@ -0,0 +1,39 @@
+use std::{fs, path::Path, sync::Arc};
+use tokio_rustls::{TlsAcceptor, rustls::{Certificate, PrivateKey, ServerConfig}};
+
+use crate::config::PgLiteConfig;
+
+/// The PEM labels of the private key formats rustls can load (PKCS#8, PKCS#1 + SEC1)
+const PRIVATE_KEY_TAGS: [&str; 3] = ["PRIVATE KEY", "RSA PRIVATE KEY", "EC PRIVATE KEY"];
+
+/// Build the acceptor for TLS connections from the configured certificate + key, or None if TLS isn't configured (so clients
+/// that request it are told the server doesn't support it)
+pub fn load_tls_acceptor(config:&PgLiteConfig) -> Result<Option<Arc<TlsAcceptor>>, String> {
+    let (Some(cert_path), Some(key_path)) = (&config.tls_cert, &config.tls_key) else {
+        return Ok(None);
+    };
+    let certs = read_pem(cert_path)?.into_iter()
+        .filter(|pem| pem.tag() == "CERTIFICATE")
+        .map(|pem| Certificate(pem.into_contents()))
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(format!("The TLS certificate {cert_path:?} doesn't contain any certificates"));
+    }
+    let key = read_pem(key_path)?.into_iter()
+        .find(|pem| PRIVATE_KEY_TAGS.contains(&pem.tag()))
+        .map(|pem| PrivateKey(pem.into_contents()))
+        .ok_or_else(|| format!("The TLS key {key_path:?} doesn't contain a private key"))?;
+
+    let tls_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("Unable to use the TLS certificate {cert_path:?} with the key {key_path:?}: {err}"))?;
+    info!("Accepting TLS connections, using the certificate {:?}", cert_path);
+    Ok(Some(Arc::new(TlsAcceptor::from(Arc::new(tls_config)))))
+}
+
+fn read_pem(path:&Path) -> Result<Vec<pem::Pem>, String> {
+    let contents = fs::read(path).map_err(|err| format!("Unable to read {path:?}: {err}"))?;
+    pem::parse_many(contents).map_err(|err| format!("Unable to parse {path:?} as PEM: {err}"))
+}
@@ -0,0 +1,85 @@
+use pgwire::{api::portal::Portal, error::{PgWireResult, PgWireError, ErrorInfo}};
+use rusqlite::types::Value;
+use uuid::Uuid;
+
+use crate::config::UuidStorage;
+
+/* Follows is the support for UUID values - SQLite has no uuid type, so they're stored as text (or 16 byte blobs, with
+   `--uuid-storage blob`), and converted from/to Postgres's uuid text + binary formats */
+
+/// Decode a UUID parameter, in either the text (eg. `{A0EEBC99-9C0B-4EF8-BB6D-6BB9BD380A11}`) or binary format - into the value
+/// it's stored as
+pub fn decode_uuid_param(portal:&Portal<String>, idx:usize, storage:UuidStorage) -> PgWireResult<Option<Value>> {
+    let Some(Some(bytes)) = portal.parameters().get(idx) else { return Ok(None) };
+    let uuid = match portal.parameter_format().is_text(idx) {
+        true => {
+            let text = String::from_utf8_lossy(bytes);
+            Uuid::parse_str(text.trim()).map_err(|_| PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22P02".to_owned(),
+                format!("invalid input syntax for type uuid: \"{text}\"")))))?
+        },
+        false => Uuid::from_slice(bytes).map_err(|_| PgWireError::UserError(Box::new(ErrorInfo::new("ERROR".to_owned(), "22P03".to_owned(),
+            "invalid binary value for type uuid".to_owned()))))?,
+    };
+    Ok(Some(match storage {
+        UuidStorage::Text => Value::Text(uuid.hyphenated().to_string()),
+        UuidStorage::Blob => Value::Blob(uuid.as_bytes().to_vec()),
+    }))
+}
+
+/// A stored value as a uuid - either a 16 byte blob, or text in any of the forms the uuid's can be written in (eg. without the
+/// hyphens, or in upper case)
+pub fn uuid_value(value:&Value) -> Option<Uuid> {
+    match value {
+        Value::Text(text) => Uuid::parse_str(text.trim()).ok(),
+        Value::Blob(bytes) => Uuid::from_slice(bytes).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use pgwire::api::portal::Format;
+
+    const UUID:&str = "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11";
+
+    /// A portal with a text parameter followed by a binary one
+    fn portal(text:&str, binary:&[u8]) -> Portal<String> {
+        let mut portal = Portal::default();
+        portal.set_parameter_format(Format::Individual(vec![0, 1]));
+        portal.set_parameters(vec![Some(Bytes::from(text.to_owned())), Some(Bytes::from(binary.to_vec())), None]);
+        portal
+    }
+
+    #[test]
+    fn decodes_text_and_binary_params() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let portal = portal(" {A0EEBC99-9C0B-4EF8-BB6D-6BB9BD380A11} ", uuid.as_bytes());
+        assert_eq!(decode_uuid_param(&portal, 0, UuidStorage::Text).unwrap(), Some(Value::Text(UUID.to_owned())));
+        assert_eq!(decode_uuid_param(&portal, 1, UuidStorage::Text).unwrap(), Some(Value::Text(UUID.to_owned())));
+        assert_eq!(decode_uuid_param(&portal, 0, UuidStorage::Blob).unwrap(), Some(Value::Blob(uuid.as_bytes().to_vec())));
+        assert_eq!(decode_uuid_param(&portal, 2, UuidStorage::Text).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_params() {
+        let portal = portal("not-a-uuid", &[1, 2, 3]);
+        let code = |result:PgWireResult<Option<Value>>| match result {
+            Err(PgWireError::UserError(info)) => info.code().to_owned(),
+            _ => String::new(),
+        };
+        assert_eq!(code(decode_uuid_param(&portal, 0, UuidStorage::Text)), "22P02");
+        assert_eq!(code(decode_uuid_param(&portal, 1, UuidStorage::Text)), "22P03");
+    }
+
+    #[test]
+    fn reads_stored_values_as_uuids() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        assert_eq!(uuid_value(&Value::Text(UUID.to_owned())), Some(uuid));
+        assert_eq!(uuid_value(&Value::Text("A0EEBC999C0B4EF8BB6D6BB9BD380A11".to_owned())), Some(uuid));
+        assert_eq!(uuid_value(&Value::Blob(uuid.as_bytes().to_vec())), Some(uuid));
+        assert_eq!(uuid_value(&Value::Blob(vec![0; 15])), None);
+        assert_eq!(uuid_value(&Value::Integer(1)), None);
+    }
+}
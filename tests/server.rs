@@ -0,0 +1,893 @@
+use std::{io::{BufRead, BufReader, Read, Write}, net::{TcpListener, TcpStream}, path::PathBuf, process::{Child, Command, Stdio}, sync::{Arc, Mutex}, time::Duration};
+
+/* Follows are end to end tests - each runs its own pglite server (on a free port, with trust auth + a temporary database root),
+   and talks to it over the Postgres wire protocol with the minimal client below */
+
+const TEXT:i16 = 0;
+const BINARY:i16 = 1;
+const BOOL_OID:u32 = 16;
+const INT8_OID:u32 = 20;
+const INT4_OID:u32 = 23;
+const TEXT_OID:u32 = 25;
+const FLOAT8_OID:u32 = 701;
+const DATE_OID:u32 = 1082;
+const NUMERIC_OID:u32 = 1700;
+const VARCHAR_OID:u32 = 1043;
+const UUID_OID:u32 = 2950;
+
+struct TestServer {
+    process:Child,
+    port:u16,
+    root:PathBuf,
+    log:Arc<Mutex<Vec<String>>>,    // The lines the server has logged since it started listening
+}
+
+impl TestServer {
+    /// Start a server whose database root has user `u`'s database `t`, with any extra arguments (trust auth, unless they've an
+    /// `--auth`) - once it's listening
+    fn start(name:&str, args:&[&str]) -> Self {
+        let root = std::env::temp_dir().join(format!("pglite-test-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("u")).unwrap();
+        std::fs::write(root.join("u").join("t"), b"").unwrap();
+        std::fs::write(root.join("secret"), b"").unwrap();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let auth = match args.contains(&"--auth") {
+            true => &[][..],
+            false => &["--auth", "trust", "--allow-trust-auth"][..],
+        };
+        let mut process = Command::new(env!("CARGO_BIN_EXE_pglite"))
+            .args(["-a", &format!("127.0.0.1:{port}"), "-c", "info", "-l", "off"])
+            .args(auth)
+            .arg("-p").arg(&root)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut log = BufReader::new(process.stdout.take().unwrap());
+        let mut line = String::new();
+        while !line.contains("Listening at") {
+            line.clear();
+            assert!(log.read_line(&mut line).unwrap() > 0, "the server exited before it was listening");
+        }
+        // Keep reading the log, so the server is never blocked writing to it
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log_lines = lines.clone();
+        std::thread::spawn(move || log.lines().map_while(Result::ok).for_each(|line| log_lines.lock().unwrap().push(line)));
+        TestServer { process, port, root, log:lines }
+    }
+
+    /// Whether the server has logged a line containing the text - waiting a moment for it, as the log is read on another thread
+    fn logged(&self, text:&str) -> bool {
+        (0..20).any(|_| {
+            let found = self.log.lock().unwrap().iter().any(|line| line.contains(text));
+            if !found {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            found
+        })
+    }
+
+    fn connect(&self, database:&str) -> Result<Client, PgError> {
+        Client::connect(self.port, "u", database)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/// The code + message of an ErrorResponse
+#[derive(Debug, Clone, PartialEq)]
+struct PgError {
+    code:String,
+    message:String,
+}
+
+/// What the server sent for a query, up to its ReadyForQuery
+#[derive(Debug, Default)]
+struct QueryResult {
+    columns:Vec<(String, u32)>,     // The name + type oid of each column
+    rows:Vec<Vec<Option<Vec<u8>>>>,
+    tags:Vec<String>,
+    error:Option<PgError>,
+    notices:Vec<PgError>,
+    parameters:Vec<(String, String)>,   // The name + value of each ParameterStatus
+    messages:Vec<u8>,   // The type of each message, in the order they were sent
+}
+
+impl QueryResult {
+    fn text_rows(&self) -> Vec<Vec<Option<String>>> {
+        self.rows.iter()
+            .map(|row| row.iter().map(|value| value.as_ref().map(|value| String::from_utf8_lossy(value).into_owned())).collect())
+            .collect()
+    }
+}
+
+struct Client {
+    stream:TcpStream,
+    pipeline:Option<Vec<u8>>,   // The messages being held back, to be sent in a single write
+}
+
+impl Client {
+    fn connect(port:u16, user:&str, database:&str) -> Result<Self, PgError> {
+        let mut client = Client::open(port);
+        client.startup(user, database);
+        match client.read_until_ready().error {
+            Some(err) => Err(err),
+            None => Ok(client),
+        }
+    }
+
+    /// A connection that hasn't sent its startup message yet
+    fn open(port:u16) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Client { stream, pipeline:None }
+    }
+
+    fn startup(&mut self, user:&str, database:&str) {
+        let mut startup = 196608i32.to_be_bytes().to_vec();
+        for value in ["user", user, "database", database] {
+            put_cstring(&mut startup, value);
+        }
+        startup.push(0);
+        let mut message = ((startup.len() + 4) as i32).to_be_bytes().to_vec();
+        message.extend(startup);
+        self.stream.write_all(&message).unwrap();
+    }
+
+    /// Run a query through the simple query protocol
+    fn query(&mut self, query:&str) -> QueryResult {
+        let mut body = Vec::new();
+        put_cstring(&mut body, query);
+        self.send(b'Q', &body);
+        self.read_until_ready()
+    }
+
+    /// Run a query through the extended query protocol (Parse, Bind, Describe, Execute + Sync), with its parameters in the given
+    /// formats - and the result columns all in one format
+    fn query_with_params(&mut self, query:&str, param_types:&[u32], params:&[(i16, Option<&[u8]>)], result_format:i16) -> QueryResult {
+        self.parse("", query, param_types);
+        self.bind("", "", params, result_format);
+        self.describe_portal("");
+        self.execute("", 0);
+        self.sync();
+        self.read_until_ready()
+    }
+
+    fn parse(&mut self, name:&str, query:&str, param_types:&[u32]) {
+        let mut parse = Vec::new();
+        put_cstring(&mut parse, name);
+        put_cstring(&mut parse, query);
+        parse.extend((param_types.len() as i16).to_be_bytes());
+        for oid in param_types {
+            parse.extend(oid.to_be_bytes());
+        }
+        self.send(b'P', &parse);
+    }
+
+    fn bind(&mut self, portal:&str, statement:&str, params:&[(i16, Option<&[u8]>)], result_format:i16) {
+        let mut bind = Vec::new();
+        put_cstring(&mut bind, portal);
+        put_cstring(&mut bind, statement);
+        bind.extend((params.len() as i16).to_be_bytes());
+        for (format, _) in params {
+            bind.extend(format.to_be_bytes());
+        }
+        bind.extend((params.len() as i16).to_be_bytes());
+        for (_, value) in params {
+            match value {
+                Some(value) => {
+                    bind.extend((value.len() as i32).to_be_bytes());
+                    bind.extend(*value);
+                },
+                None => bind.extend((-1i32).to_be_bytes()),
+            }
+        }
+        bind.extend(1i16.to_be_bytes());
+        bind.extend(result_format.to_be_bytes());
+        self.send(b'B', &bind);
+    }
+
+    fn describe_portal(&mut self, portal:&str) {
+        let mut describe = vec![b'P'];
+        put_cstring(&mut describe, portal);
+        self.send(b'D', &describe);
+    }
+
+    /// Execute a portal, returning at most `max_rows` rows (0 for all of them)
+    fn execute(&mut self, portal:&str, max_rows:i32) {
+        let mut execute = Vec::new();
+        put_cstring(&mut execute, portal);
+        execute.extend(max_rows.to_be_bytes());
+        self.send(b'E', &execute);
+    }
+
+    fn close_statement(&mut self, name:&str) {
+        let mut close = vec![b'S'];
+        put_cstring(&mut close, name);
+        self.send(b'C', &close);
+    }
+
+    fn terminate(&mut self) {
+        self.send(b'X', &[]);
+    }
+
+    fn flush(&mut self) {
+        self.send(b'H', &[]);
+    }
+
+    fn sync(&mut self) {
+        self.send(b'S', &[]);
+    }
+
+    /// Hold back the messages that follow, until they're all sent in a single write by `send_pipeline`
+    fn start_pipeline(&mut self) {
+        self.pipeline = Some(Vec::new());
+    }
+
+    fn send_pipeline(&mut self) {
+        let messages = self.pipeline.take().unwrap_or_default();
+        self.stream.write_all(&messages).unwrap();
+    }
+
+    fn send(&mut self, message_type:u8, body:&[u8]) {
+        let mut message = vec![message_type];
+        message.extend(((body.len() + 4) as i32).to_be_bytes());
+        message.extend(body);
+        match self.pipeline.as_mut() {
+            Some(pipeline) => pipeline.extend(message),
+            None => self.stream.write_all(&message).unwrap(),
+        }
+    }
+
+    /// The type + body of the server's next message, or None once it's closed the connection
+    fn read_message(&mut self) -> Option<(u8, Vec<u8>)> {
+        let mut header = [0u8; 5];
+        self.stream.read_exact(&mut header).ok()?;
+        let len = i32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize - 4;
+        let mut body = vec![0u8; len];
+        self.stream.read_exact(&mut body).unwrap();
+        Some((header[0], body))
+    }
+
+    /// Read the server's messages up to its ReadyForQuery - or until it closes the connection (eg. after a FATAL error)
+    fn read_until_ready(&mut self) -> QueryResult {
+        let mut result = QueryResult::default();
+        while let Some((message_type, body)) = self.read_message() {
+            if result.add_message(message_type, &body) {
+                break;
+            }
+        }
+        result
+    }
+}
+
+impl QueryResult {
+    /// Add a message from the server to the result, returning whether it was the ReadyForQuery that ends it
+    fn add_message(&mut self, message_type:u8, body:&[u8]) -> bool {
+        self.messages.push(message_type);
+        let mut reader = Reader { body, pos:0 };
+        match message_type {
+            b'Z' => return true,
+            b'T' => self.columns = (0..reader.i16()).map(|_| {
+                let name = reader.cstring();
+                reader.skip(6);
+                let oid = reader.i32() as u32;
+                reader.skip(8);
+                (name, oid)
+            }).collect(),
+            b'D' => self.rows.push((0..reader.i16()).map(|_| match reader.i32() {
+                -1 => None,
+                len => Some(reader.bytes(len as usize).to_vec()),
+            }).collect()),
+            b'C' => self.tags.push(reader.cstring()),
+            b'S' => self.parameters.push((reader.cstring(), reader.cstring())),
+            b'E' | b'N' => {
+                let mut err = PgError { code:String::new(), message:String::new() };
+                loop {
+                    match reader.bytes(1)[0] {
+                        0 => break,
+                        b'C' => err.code = reader.cstring(),
+                        b'M' => err.message = reader.cstring(),
+                        _ => { reader.cstring(); },
+                    }
+                }
+                match message_type {
+                    b'E' => self.error = Some(err),
+                    _ => self.notices.push(err),
+                }
+            },
+            _ => {},
+        }
+        false
+    }
+}
+
+struct Reader<'a> {
+    body:&'a [u8],
+    pos:usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len:usize) -> &'a [u8] {
+        let bytes = &self.body[self.pos..self.pos + len];
+        self.pos += len;
+        bytes
+    }
+    fn skip(&mut self, len:usize) {
+        self.pos += len;
+    }
+    fn i16(&mut self) -> i16 {
+        let bytes = self.bytes(2);
+        i16::from_be_bytes([bytes[0], bytes[1]])
+    }
+    fn i32(&mut self) -> i32 {
+        let bytes = self.bytes(4);
+        i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+    fn cstring(&mut self) -> String {
+        let len = self.body[self.pos..].iter().position(|b| *b == 0).unwrap();
+        let text = String::from_utf8_lossy(self.bytes(len)).into_owned();
+        self.skip(1);
+        text
+    }
+}
+
+fn put_cstring(buf:&mut Vec<u8>, value:&str) {
+    buf.extend(value.as_bytes());
+    buf.push(0);
+}
+
+fn text(value:&str) -> Option<String> {
+    Some(value.to_owned())
+}
+
+#[test]
+fn rejects_databases_outside_the_users_root() {
+    let server = TestServer::start("traversal", &[]);
+    // The database is opened by the connection's first query
+    for database in ["../secret", "/etc/passwd", "sub/../../secret"] {
+        let err = server.connect(database).unwrap().query("SELECT 1").error;
+        assert_eq!(err.map(|err| err.code).as_deref(), Some("42501"), "{database}");
+    }
+    assert_eq!(server.connect("t").unwrap().query("SELECT 1").text_rows(), [[text("1")]]);
+}
+
+#[test]
+fn rejects_connections_over_the_limit() {
+    let server = TestServer::start("max-connections", &["--max-connections", "2"]);
+    let first = server.connect("t").unwrap();
+    let _second = server.connect("t").unwrap();
+    let err = server.connect("t").err().unwrap();
+    assert_eq!(err.code, "53300", "{}", err.message);
+
+    // Once a connection closes its slot is free again
+    drop(first);
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(server.connect("t").is_ok());
+}
+
+#[test]
+fn round_trips_uuids_in_text_and_binary() {
+    let server = TestServer::start("uuid", &[]);
+    let mut client = server.connect("t").unwrap();
+    let uuid = "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11";
+    let uuid_bytes = [0xa0, 0xee, 0xbc, 0x99, 0x9c, 0x0b, 0x4e, 0xf8, 0xbb, 0x6d, 0x6b, 0xb9, 0xbd, 0x38, 0x0a, 0x11];
+
+    assert_eq!(client.query("CREATE TABLE item (id UUID, n INTEGER)").error, None);
+    let inserted = client.query_with_params("INSERT INTO item VALUES ($1, 1)", &[UUID_OID], &[(BINARY, Some(&uuid_bytes))], TEXT);
+    assert_eq!(inserted.tags, ["INSERT 0 1"]);
+    let inserted = client.query_with_params("INSERT INTO item VALUES ($1, 2)", &[UUID_OID], &[(TEXT, Some(b"{A0EEBC99-9C0B-4EF8-BB6D-6BB9BD380A11}"))], TEXT);
+    assert_eq!(inserted.tags, ["INSERT 0 1"]);
+
+    let selected = client.query("SELECT id FROM item ORDER BY n");
+    assert_eq!(selected.columns, [("id".to_owned(), UUID_OID)]);
+    assert_eq!(selected.text_rows(), [[text(uuid)], [text(uuid)]]);
+    let selected = client.query_with_params("SELECT id FROM item WHERE id = $1", &[UUID_OID], &[(BINARY, Some(&uuid_bytes))], BINARY);
+    assert_eq!(selected.rows, [[Some(uuid_bytes.to_vec())], [Some(uuid_bytes.to_vec())]]);
+
+    let invalid = client.query_with_params("SELECT id FROM item WHERE id = $1", &[UUID_OID], &[(TEXT, Some(b"not-a-uuid"))], TEXT);
+    assert_eq!(invalid.error.map(|err| err.code).as_deref(), Some("22P02"));
+}
+
+#[test]
+fn names_the_postgres_type_of_each_storage_class() {
+    let server = TestServer::start("pg-typeof", &[]);
+    let mut client = server.connect("t").unwrap();
+    let result = client.query("SELECT pg_typeof(1), pg_typeof(1.5), pg_typeof('a'), pg_typeof(x'00'), pg_typeof(NULL)");
+    assert_eq!(result.text_rows(), [[text("int8"), text("float8"), text("text"), text("bytea"), text("unknown")]]);
+}
+
+#[test]
+fn tags_statements_by_their_command() {
+    let server = TestServer::start("tags", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (id INTEGER PRIMARY KEY, n INTEGER)").tags, ["CREATE TABLE"]);
+
+    let with = client.query("WITH x AS (SELECT 1 AS n UNION ALL SELECT 2) SELECT n FROM x");
+    assert_eq!((with.text_rows(), with.tags), (vec![vec![text("1")], vec![text("2")]], vec!["SELECT 2".to_owned()]));
+    assert_eq!(client.query("VALUES (1), (2), (3)").tags, ["SELECT 3"]);
+    let pragma = client.query("PRAGMA table_info(item)");
+    assert_eq!((pragma.rows.len(), pragma.tags), (2, vec!["SELECT 2".to_owned()]));
+
+    let returning = client.query("-- add two rows\nINSERT INTO item (n) VALUES (10), (20) RETURNING id");
+    assert_eq!((returning.text_rows(), returning.tags), (vec![vec![text("1")], vec![text("2")]], vec!["INSERT 0 2".to_owned()]));
+    assert_eq!(client.query("UPDATE item SET n = n + 1 RETURNING n").tags, ["UPDATE 2"]);
+    assert_eq!(client.query("/* clear */ DELETE FROM item WHERE id = 1 RETURNING *").tags, ["DELETE 1"]);
+}
+
+#[test]
+fn returns_the_rows_of_insert_returning_in_the_extended_protocol() {
+    let server = TestServer::start("returning", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (id INTEGER PRIMARY KEY, name TEXT)").error, None);
+
+    for (name, id) in [("first", "1"), ("second", "2")] {
+        let result = client.query_with_params("INSERT INTO item (name) VALUES ($1) RETURNING id", &[TEXT_OID], &[(TEXT, Some(name.as_bytes()))], TEXT);
+        assert_eq!(result.error, None);
+        assert_eq!(result.columns.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>(), ["id"]);
+        assert_eq!(result.text_rows(), [[text(id)]]);
+        assert_eq!(result.tags, ["INSERT 0 1"]);
+    }
+}
+
+#[test]
+fn counts_only_the_rows_the_statement_changed() {
+    let server = TestServer::start("affected-rows", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (id INTEGER PRIMARY KEY, version INTEGER)").error, None);
+    assert_eq!(client.query("CREATE TABLE audit (item_id INTEGER)").error, None);
+    assert_eq!(client.query("CREATE TRIGGER audit_item AFTER UPDATE ON item BEGIN INSERT INTO audit VALUES (new.id); END").error, None);
+    assert_eq!(client.query("INSERT INTO item VALUES (1, 1), (2, 1)").tags, ["INSERT 0 2"]);
+
+    let update = "UPDATE item SET version = version + 1 WHERE id = $1 AND version = $2";
+    let (id, version) = (1i64.to_be_bytes(), 1i64.to_be_bytes());
+    assert_eq!(client.query_with_params(update, &[INT8_OID, INT8_OID], &[(BINARY, Some(&id)), (BINARY, Some(&version))], TEXT).tags, ["UPDATE 1"]);
+    assert_eq!(client.query_with_params(update, &[INT8_OID, INT8_OID], &[(BINARY, Some(&id)), (BINARY, Some(&version))], TEXT).tags, ["UPDATE 0"]);
+    assert_eq!(client.query("CREATE TABLE other (n INTEGER)").tags, ["CREATE TABLE"]);
+}
+
+#[test]
+fn suspended_portals_dont_hold_up_the_other_connections() {
+    // With a batch of one row the backend is soon waiting for the client to read more of the rows, unless they've been read
+    let server = TestServer::start("suspended-portal", &["--result-batch-size", "1"]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (n INTEGER)").error, None);
+    assert_eq!(client.query("INSERT INTO item VALUES (1), (2), (3), (4), (5), (6)").tags, ["INSERT 0 6"]);
+
+    assert_eq!(client.query("BEGIN").tags, ["BEGIN"]);
+    client.parse("", "SELECT n FROM item ORDER BY n", &[]);
+    client.bind("", "", &[], TEXT);
+    client.execute("", 2);
+    client.sync();
+    let suspended = client.read_until_ready();
+    assert_eq!(suspended.text_rows(), [[text("1")], [text("2")]]);
+    assert!(suspended.messages.contains(&b's') && suspended.tags.is_empty());
+
+    let mut other = server.connect("t").unwrap();
+    other.stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+    assert_eq!(other.query("SELECT count(*) FROM item").text_rows(), [[text("6")]]);
+
+    client.execute("", 0);
+    client.sync();
+    let rest = client.read_until_ready();
+    assert_eq!(rest.text_rows(), [[text("3")], [text("4")], [text("5")], [text("6")]]);
+    assert_eq!(rest.tags, ["SELECT 4"]);
+    assert_eq!(client.query("COMMIT").tags, ["COMMIT"]);
+}
+
+#[test]
+fn rolls_back_the_transaction_a_client_leaves_open() {
+    let server = TestServer::start("rollback-on-close", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (n INTEGER)").error, None);
+    assert_eq!(client.query("BEGIN").tags, ["BEGIN"]);
+    assert_eq!(client.query("INSERT INTO item VALUES (1)").tags, ["INSERT 0 1"]);
+    // Along with a suspended portal, which is closed before the transaction is rolled back
+    client.parse("", "SELECT n FROM item", &[]);
+    client.bind("", "", &[], TEXT);
+    client.execute("", 1);
+    client.sync();
+    assert!(client.read_until_ready().messages.contains(&b's'));
+    drop(client);
+
+    // The rollback runs once the server has seen the connection close
+    let mut other = server.connect("t").unwrap();
+    let started = std::time::Instant::now();
+    while other.query("SELECT count(*) FROM item").text_rows() != [[text("0")]] {
+        assert!(started.elapsed() < Duration::from_secs(5), "the open transaction wasn't rolled back");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(other.query("BEGIN").error, None);
+    assert_eq!(other.query("INSERT INTO item VALUES (2)").tags, ["INSERT 0 1"]);
+    assert_eq!(other.query("COMMIT").error, None);
+    assert_eq!(other.query("SELECT n FROM item").text_rows(), [[text("2")]]);
+}
+
+#[test]
+fn returns_expression_columns_without_a_declared_type() {
+    let expressions = "SELECT 1 AS one, count(*) AS n, 1 + 2 * 3 AS i, 7 / 2.0 AS f, 2 - 5 AS neg FROM item";
+    let values = [[text("1"), text("2"), text("7"), text("3.5"), text("-3")]];
+    let columns = |types:[u32; 5]| ["one", "n", "i", "f", "neg"].into_iter().map(str::to_owned).zip(types).collect::<Vec<(String, u32)>>();
+
+    // By default a column without a declared type is varchar, in both protocols
+    let server = TestServer::start("expressions", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (n INTEGER)").error, None);
+    assert_eq!(client.query("INSERT INTO item VALUES (1), (2)").tags, ["INSERT 0 2"]);
+    assert_eq!(client.query("SELECT 1").text_rows(), [[text("1")]]);
+    for result in [client.query(expressions), client.query_with_params(expressions, &[], &[], TEXT)] {
+        assert_eq!(result.columns, columns([VARCHAR_OID; 5]));
+        assert_eq!(result.text_rows(), values);
+    }
+
+    // With `--type-inference value` a simple query's columns get the type of their values
+    let server = TestServer::start("expressions-by-value", &["--type-inference", "value"]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (n INTEGER)").error, None);
+    assert_eq!(client.query("INSERT INTO item VALUES (1), (2)").tags, ["INSERT 0 2"]);
+    let result = client.query("SELECT 1");
+    assert_eq!((result.columns[0].1, result.text_rows()), (INT8_OID, vec![vec![text("1")]]));
+    let result = client.query(expressions);
+    assert_eq!(result.columns, columns([INT8_OID, INT8_OID, INT8_OID, FLOAT8_OID, INT8_OID]));
+    assert_eq!(result.text_rows(), values);
+}
+
+#[test]
+fn flushes_the_responses_to_pipelined_messages_without_a_sync() {
+    let server = TestServer::start("flush", &[]);
+    let mut client = server.connect("t").unwrap();
+    client.parse("", "SELECT 41 + 1 AS answer", &[]);
+    client.bind("", "", &[], TEXT);
+    client.describe_portal("");
+    client.execute("", 0);
+    client.flush();
+
+    // Everything up to the CommandComplete arrives before there's been a Sync
+    let mut result = QueryResult::default();
+    while result.messages.last() != Some(&b'C') {
+        let (message_type, body) = client.read_message().expect("the responses weren't flushed");
+        result.add_message(message_type, &body);
+    }
+    assert_eq!(result.messages, [b'1', b'2', b'T', b'D', b'C']);
+    assert_eq!((result.text_rows(), result.tags), (vec![vec![text("42")]], vec!["SELECT 1".to_owned()]));
+
+    client.sync();
+    assert_eq!(client.read_until_ready().messages, [b'Z']);
+}
+
+#[test]
+fn types_view_and_cte_columns_by_their_table_columns() {
+    let server = TestServer::start("view-types", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (n INTEGER, price REAL, added DATE, code VARCHAR(8), note TEXT)").error, None);
+    assert_eq!(client.query("INSERT INTO item VALUES (1, 2.5, '2024-01-31', 'a1', 'first')").tags, ["INSERT 0 1"]);
+    assert_eq!(client.query("CREATE VIEW item_view AS SELECT * FROM item").error, None);
+    let expected = ["n", "price", "added", "code", "note"].into_iter().map(str::to_owned)
+        .zip([INT8_OID, FLOAT8_OID, DATE_OID, VARCHAR_OID, TEXT_OID])
+        .collect::<Vec<(String, u32)>>();
+
+    for query in [
+        "SELECT n, price, added, code, note FROM item",
+        "SELECT n, price, added, code, note FROM item_view",
+        "WITH recent AS (SELECT * FROM item WHERE n > 0) SELECT n, price, added, code, note FROM recent",
+        "SELECT n, price, added, code, note FROM (SELECT * FROM item_view)",
+    ] {
+        for result in [client.query(query), client.query_with_params(query, &[], &[], TEXT)] {
+            assert_eq!(result.columns, expected, "{query}");
+            assert_eq!(result.text_rows(), [[text("1"), text("2.5"), text("2024-01-31"), text("a1"), text("first")]], "{query}");
+        }
+    }
+}
+
+#[test]
+fn reads_generated_columns_and_rejects_writes_to_them() {
+    let server = TestServer::start("generated-columns", &[]);
+    let mut client = server.connect("t").unwrap();
+    let create = "CREATE TABLE item (price INTEGER, qty INTEGER, total INTEGER GENERATED ALWAYS AS (price * qty) STORED, label TEXT AS ('x' || qty) VIRTUAL)";
+    assert_eq!(client.query(create).error, None);
+    assert_eq!(client.query("INSERT INTO item (price, qty) VALUES (3, 4)").tags, ["INSERT 0 1"]);
+
+    for result in [client.query("SELECT * FROM item"), client.query_with_params("SELECT * FROM item", &[], &[], TEXT)] {
+        assert_eq!(result.columns.iter().map(|(_, oid)| *oid).collect::<Vec<u32>>(), [INT8_OID, INT8_OID, INT8_OID, TEXT_OID]);
+        assert_eq!(result.text_rows(), [[text("3"), text("4"), text("12"), text("x4")]]);
+    }
+    assert_eq!(client.query("UPDATE item SET qty = 5").tags, ["UPDATE 1"]);
+    assert_eq!(client.query("SELECT total, label FROM item").text_rows(), [[text("15"), text("x5")]]);
+
+    for write in ["INSERT INTO item (price, qty, total) VALUES (1, 1, 1)", "UPDATE item SET total = 0", "UPDATE item SET label = 'y'"] {
+        let err = client.query(write).error.unwrap();
+        assert_eq!(err.code, "428C9", "{write}: {}", err.message);
+        let err = client.query_with_params(write, &[], &[], TEXT).error.unwrap();
+        assert_eq!(err.code, "428C9", "{write}: {}", err.message);
+    }
+    assert_eq!(client.query("SELECT total FROM item").text_rows(), [[text("15")]]);
+}
+
+#[test]
+fn tags_each_statement_of_a_batch() {
+    let server = TestServer::start("batch", &[]);
+    let mut client = server.connect("t").unwrap();
+    let setup = client.query("CREATE TABLE a (n INTEGER); CREATE TABLE b (n INTEGER); CREATE TABLE c (n INTEGER);
+        INSERT INTO a VALUES (1), (2); INSERT INTO b VALUES (1), (2); INSERT INTO c VALUES (1), (2), (3), (4)");
+    assert_eq!(setup.tags, ["CREATE TABLE", "CREATE TABLE", "CREATE TABLE", "INSERT 0 2", "INSERT 0 2", "INSERT 0 4"]);
+
+    let batch = client.query("UPDATE a SET n = n + 10; UPDATE b SET n = 0 WHERE n = 1; DELETE FROM c WHERE n < 4");
+    assert_eq!(batch.error, None);
+    assert_eq!(batch.tags, ["UPDATE 2", "UPDATE 1", "DELETE 3"]);
+    let mixed = client.query("SELECT n FROM c; UPDATE c SET n = 5; SELECT n FROM a ORDER BY n");
+    assert_eq!(mixed.tags, ["SELECT 1", "UPDATE 1", "SELECT 2"]);
+    assert_eq!(mixed.text_rows(), [[text("4")], [text("11")], [text("12")]]);
+}
+
+#[test]
+fn reparses_a_closed_statement_name_with_new_sql() {
+    let server = TestServer::start("close", &[]);
+    let mut client = server.connect("t").unwrap();
+    client.parse("s1", "SELECT 1 AS a", &[]);
+    client.bind("p1", "s1", &[], TEXT);
+    client.sync();
+    assert_eq!(client.read_until_ready().messages, [b'1', b'2', b'Z']);
+
+    // A name can't be parsed again while it's still open
+    client.parse("s1", "SELECT 2 AS b", &[]);
+    client.sync();
+    assert_eq!(client.read_until_ready().error.map(|err| err.code).as_deref(), Some("42P05"));
+
+    // Closing the statement closes the portal bound from it too
+    client.close_statement("s1");
+    client.sync();
+    assert_eq!(client.read_until_ready().messages, [b'3', b'Z']);
+    client.execute("p1", 0);
+    client.sync();
+    assert_eq!(client.read_until_ready().error.map(|err| err.code).as_deref(), Some("34000"));
+
+    client.parse("s1", "SELECT 2 AS b", &[]);
+    client.bind("p1", "s1", &[], TEXT);
+    client.describe_portal("p1");
+    client.execute("p1", 0);
+    client.sync();
+    let result = client.read_until_ready();
+    assert_eq!(result.error, None);
+    assert_eq!((result.columns[0].0.as_str(), result.text_rows()), ("b", vec![vec![text("2")]]));
+}
+
+#[test]
+fn skips_the_rest_of_a_pipeline_after_an_error_until_sync() {
+    let server = TestServer::start("pipeline", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (n INTEGER)").error, None);
+
+    client.start_pipeline();
+    for query in ["INSERT INTO item VALUES (1)", "SELECT n FROM item", "SELECT * FROM missing", "INSERT INTO item VALUES (2)"] {
+        client.parse("", query, &[]);
+        client.bind("", "", &[], TEXT);
+        client.describe_portal("");
+        client.execute("", 0);
+    }
+    client.sync();
+    client.parse("", "SELECT count(*) FROM item", &[]);
+    client.bind("", "", &[], TEXT);
+    client.execute("", 0);
+    client.sync();
+    client.send_pipeline();
+
+    // The statements ahead of the error run, and the ones after it are skipped - up to the Sync, after which they run again
+    let first = client.read_until_ready();
+    assert_eq!(first.messages, [b'1', b'2', b'n', b'C', b'1', b'2', b'T', b'D', b'C', b'1', b'2', b'E', b'Z']);
+    assert_eq!(first.tags, ["INSERT 0 1", "SELECT 1"]);
+    assert!(first.error.unwrap().message.contains("missing"));
+    let second = client.read_until_ready();
+    assert_eq!(second.messages, [b'1', b'2', b'D', b'C', b'Z']);
+    assert_eq!(second.text_rows(), [[text("1")]]);
+}
+
+#[test]
+fn reruns_a_prepared_statement_after_another_connection_alters_its_table() {
+    let server = TestServer::start("schema-change", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (n INTEGER)").error, None);
+    assert_eq!(client.query("INSERT INTO item VALUES (1)").tags, ["INSERT 0 1"]);
+    client.parse("s1", "SELECT * FROM item WHERE n > $1", &[INT8_OID]);
+    let zero = 0i64.to_be_bytes();
+    client.bind("", "s1", &[(BINARY, Some(&zero))], TEXT);
+    client.describe_portal("");
+    client.execute("", 0);
+    client.sync();
+    assert_eq!(client.read_until_ready().text_rows(), [[text("1")]]);
+
+    let mut other = server.connect("t").unwrap();
+    assert_eq!(other.query("ALTER TABLE item ADD COLUMN label TEXT DEFAULT 'x'").error, None);
+
+    client.bind("", "s1", &[(BINARY, Some(&zero))], TEXT);
+    client.describe_portal("");
+    client.execute("", 0);
+    client.sync();
+    let result = client.read_until_ready();
+    assert_eq!(result.error, None);
+    assert_eq!(result.columns, [("n".to_owned(), INT8_OID), ("label".to_owned(), TEXT_OID)]);
+    assert_eq!(result.text_rows(), [[text("1"), text("x")]]);
+
+    // And once the column's dropped again
+    assert_eq!(other.query("ALTER TABLE item DROP COLUMN label").error, None);
+    client.bind("", "s1", &[(BINARY, Some(&zero))], TEXT);
+    client.describe_portal("");
+    client.execute("", 0);
+    client.sync();
+    let result = client.read_until_ready();
+    assert_eq!(result.columns, [("n".to_owned(), INT8_OID)]);
+    assert_eq!(result.text_rows(), [[text("1")]]);
+}
+
+#[test]
+fn closes_the_connection_on_terminate_during_authentication() {
+    let server = TestServer::start("terminate-auth", &["--auth", "basic", "--auth-config", "secret"]);
+    let mut client = Client::open(server.port);
+    client.startup("u", "t");
+
+    // The cleartext password request
+    let (message_type, body) = client.read_message().unwrap();
+    assert_eq!((message_type, body), (b'R', 3i32.to_be_bytes().to_vec()));
+
+    let started = std::time::Instant::now();
+    client.terminate();
+    assert!(client.read_message().is_none());
+    assert!(started.elapsed() < Duration::from_secs(1), "the connection wasn't closed promptly");
+}
+
+#[test]
+fn encodes_each_value_of_a_mixed_type_column_by_its_storage_class() {
+    let server = TestServer::start("mixed-types", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (id INTEGER, v NUMERIC, i INT8)").error, None);
+    // With NUMERIC affinity, the text '7' is stored as an integer - and 'abc' stays text
+    let insert = client.query("INSERT INTO item VALUES (1, 42, 42), (2, 1.5, 1.5), (3, 'abc', 'abc'), (4, '7', 3.0)");
+    assert_eq!(insert.tags, ["INSERT 0 4"]);
+    assert_eq!(client.query("SELECT typeof(v) FROM item ORDER BY id").text_rows(), [[text("integer")], [text("real")], [text("text")], [text("integer")]]);
+
+    // In the text format each value is sent as it's stored
+    let result = client.query_with_params("SELECT v, i FROM item ORDER BY id", &[], &[], TEXT);
+    assert_eq!(result.columns, [("v".to_owned(), NUMERIC_OID), ("i".to_owned(), INT8_OID)]);
+    assert_eq!(result.text_rows(), [[text("42"), text("42")], [text("1.5"), text("1.5")], [text("abc"), text("abc")], [text("7"), text("3")]]);
+
+    // In the binary format it's converted to the column's type, when that's lossless
+    let numeric = |words:[i16; 4], digits:&[i16]| words.iter().chain(digits).flat_map(|word| word.to_be_bytes()).collect::<Vec<u8>>();
+    let binary = |client:&mut Client, column:&str, id:i64| {
+        client.query_with_params(&format!("SELECT {column} FROM item WHERE id = $1"), &[INT8_OID], &[(BINARY, Some(&id.to_be_bytes()))], BINARY)
+    };
+    assert_eq!(binary(&mut client, "v", 1).rows, [[Some(numeric([1, 0, 0, 0], &[42]))]]);
+    assert_eq!(binary(&mut client, "v", 2).rows, [[Some(numeric([2, 0, 0, 1], &[1, 5000]))]]);
+    assert_eq!(binary(&mut client, "v", 4).rows, [[Some(numeric([1, 0, 0, 0], &[7]))]]);
+    assert_eq!(binary(&mut client, "i", 1).rows, [[Some(42i64.to_be_bytes().to_vec())]]);
+    assert_eq!(binary(&mut client, "i", 4).rows, [[Some(3i64.to_be_bytes().to_vec())]]);
+    for (column, id) in [("v", 3), ("i", 2), ("i", 3)] {
+        assert_eq!(binary(&mut client, column, id).error.map(|err| err.code).as_deref(), Some("22P02"), "{column} of {id}");
+    }
+}
+
+#[test]
+fn round_trips_booleans() {
+    let server = TestServer::start("boolean", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("CREATE TABLE item (id INTEGER, done BOOLEAN)").error, None);
+    for (id, done) in [(1i64, Some(true)), (2, Some(false)), (3, None)] {
+        let done = done.map(|done| [done as u8]);
+        let params = [(BINARY, Some(&id.to_be_bytes()[..])), (BINARY, done.as_ref().map(|done| &done[..]))];
+        assert_eq!(client.query_with_params("INSERT INTO item VALUES ($1, $2)", &[INT8_OID, BOOL_OID], &params, TEXT).tags, ["INSERT 0 1"]);
+    }
+
+    for result in [client.query("SELECT done FROM item ORDER BY id"), client.query_with_params("SELECT done FROM item ORDER BY id", &[], &[], TEXT)] {
+        assert_eq!(result.columns, [("done".to_owned(), BOOL_OID)]);
+        assert_eq!(result.text_rows(), [[text("t")], [text("f")], [None]]);
+    }
+    let binary = client.query_with_params("SELECT done FROM item ORDER BY id", &[], &[], BINARY);
+    assert_eq!(binary.rows, [[Some(vec![1])], [Some(vec![0])], [None]]);
+    let found = client.query_with_params("SELECT id FROM item WHERE done = $1", &[BOOL_OID], &[(BINARY, Some(&[1]))], TEXT);
+    assert_eq!(found.text_rows(), [[text("1")]]);
+}
+
+#[test]
+fn sets_the_configured_journal_mode() {
+    let server = TestServer::start("journal-mode", &["--sqlite-journal-mode", "wal", "--sqlite-synchronous", "normal"]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("PRAGMA journal_mode").text_rows(), [[text("wal")]]);
+    // NORMAL is 1
+    assert_eq!(client.query("PRAGMA synchronous").text_rows(), [[text("1")]]);
+
+    let server = TestServer::start("journal-mode-default", &[]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("PRAGMA journal_mode").text_rows(), [[text("delete")]]);
+}
+
+#[test]
+fn rejects_unsupported_parameter_types_and_carries_on() {
+    let server = TestServer::start("unsupported-param", &[]);
+    let mut client = server.connect("t").unwrap();
+    // 600 is point
+    let result = client.query_with_params("SELECT $1", &[600], &[(TEXT, Some(b"(1,2)"))], TEXT);
+    let err = result.error.unwrap();
+    assert_eq!(err.code, "0A000");
+    assert_eq!(err.message, "unsupported parameter type oid=600 (point) for parameter $1");
+
+    let result = client.query_with_params("SELECT $1 AS n", &[INT8_OID], &[(BINARY, Some(&7i64.to_be_bytes()))], TEXT);
+    assert_eq!((result.text_rows(), result.error), (vec![vec![text("7")]], None));
+    assert_eq!(client.query("SELECT 1").text_rows(), [[text("1")]]);
+}
+
+#[test]
+fn sets_the_session_parameters_clients_send_when_they_connect() {
+    let server = TestServer::start("set-parameters", &[]);
+    let mut client = server.connect("t").unwrap();
+    client.query("CREATE TABLE d (d DATE, f REAL)");
+    client.query("INSERT INTO d VALUES ('2024-01-02', 0.1)");
+
+    // The client encoding is already UTF8, so it isn't reported again
+    let result = client.query("SET client_encoding = 'UTF8'");
+    assert_eq!((result.tags, result.error, result.parameters), (vec!["SET".to_owned()], None, vec![]));
+    let err = client.query("SET client_encoding = 'LATIN1'").error.unwrap();
+    assert_eq!(err.code, "0A000");
+
+    let result = client.query("SET datestyle = 'German, DMY'");
+    assert_eq!(result.parameters, [("DateStyle".to_owned(), "German, DMY".to_owned())]);
+    assert_eq!(client.query("SHOW datestyle").text_rows(), [[text("German, DMY")]]);
+    assert_eq!(client.query("SELECT d, f, 0.1 + 0.2 AS s FROM d").text_rows(), [[text("02.01.2024"), text("0.1"), text("0.30000000000000004")]]);
+
+    assert_eq!(client.query("SET extra_float_digits = 0").tags, ["SET"]);
+    assert_eq!(client.query("SHOW extra_float_digits").text_rows(), [[text("0")]]);
+    assert_eq!(client.query("SELECT 0.1 + 0.2 AS s").text_rows(), [[text("0.3")]]);
+    assert_eq!(client.query("SET extra_float_digits = 4").error.unwrap().code, "22023");
+
+    // A parameter the session doesn't manage is accepted, with a warning that it's ignored
+    let result = client.query("SET search_path = public");
+    assert_eq!((result.tags, result.error), (vec!["SET".to_owned()], None));
+    assert!(server.logged("Ignoring the unsupported parameter: SET search_path = public"));
+}
+
+#[test]
+fn sends_integer_primary_keys_as_int4() {
+    let server = TestServer::start("int4-primary-keys", &["--int4-primary-keys"]);
+    let mut client = server.connect("t").unwrap();
+    client.query("CREATE TABLE p (id INTEGER PRIMARY KEY, n INTEGER)");
+    client.query("INSERT INTO p VALUES (1, 2)");
+
+    let result = client.query("SELECT id AS key, n FROM p");
+    assert_eq!(result.columns, [("key".to_owned(), INT4_OID), ("n".to_owned(), INT8_OID)]);
+    assert_eq!(result.text_rows(), [[text("1"), text("2")]]);
+    let result = client.query_with_params("SELECT id, n FROM p WHERE id = $1", &[INT8_OID], &[(BINARY, Some(&1i64.to_be_bytes()))], BINARY);
+    assert_eq!(result.columns, [("id".to_owned(), INT4_OID), ("n".to_owned(), INT8_OID)]);
+    assert_eq!(result.rows, [[Some(1i32.to_be_bytes().to_vec()), Some(2i64.to_be_bytes().to_vec())]]);
+
+    // A rowid outside of the int4 range can't be sent as one
+    client.query("INSERT INTO p VALUES (3000000000, 3)");
+    let result = client.query("SELECT id FROM p WHERE n = 3");
+    assert_eq!(result.error.unwrap().code, "22003");
+    let result = client.query_with_params("SELECT id FROM p WHERE n = 3", &[], &[], BINARY);
+    assert_eq!(result.error.unwrap().code, "22003");
+    assert_eq!(client.query("SELECT n FROM p WHERE id = 1").text_rows(), [[text("2")]]);
+}
+
+#[test]
+fn reads_the_temp_tables_and_pragmas_of_a_session_on_the_main_handle() {
+    let server = TestServer::start("handle-state", &["--db-readers", "2", "--sqlite-journal-mode", "wal"]);
+    let mut client = server.connect("t").unwrap();
+    assert_eq!(client.query("SELECT 'a' LIKE 'A'").text_rows(), [[text("1")]]);
+
+    // The readers' handles have neither the TEMP table nor the PRAGMA setting, so the SELECTs that follow have to stay on the main handle
+    let result = client.query("CREATE TEMP TABLE tt (a INTEGER); INSERT INTO tt VALUES (1)");
+    assert_eq!(result.error, None);
+    let result = client.query("SELECT a FROM tt");
+    assert_eq!((result.text_rows(), result.error), (vec![vec![text("1")]], None));
+    let result = client.query_with_params("SELECT a FROM tt WHERE a = $1", &[INT8_OID], &[(BINARY, Some(&1i64.to_be_bytes()))], TEXT);
+    assert_eq!((result.text_rows(), result.error), (vec![vec![text("1")]], None));
+
+    let mut other = server.connect("t").unwrap();
+    assert_eq!(other.query("PRAGMA case_sensitive_like = on").error, None);
+    assert_eq!(other.query("SELECT 'a' LIKE 'A'").text_rows(), [[text("0")]]);
+}